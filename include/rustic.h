@@ -0,0 +1,42 @@
+/* C API of rustic, see src/ffi.rs.
+ *
+ * Functions returning int return 0 on success and -1 on error; functions returning pointers
+ * return NULL on error. Use rustic_last_error() to get the error message. Internal panics are
+ * caught and also reported as errors.
+ */
+#ifndef RUSTIC_H
+#define RUSTIC_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct RusticRepo RusticRepo;
+
+/* Error message of the last failed call in this thread or NULL */
+const char *rustic_last_error(void);
+
+/* Initialize a new repository */
+int rustic_init(const char *repository, const char *password);
+
+/* Open a repository and verify the password; free with rustic_close().
+ * Use password NULL for unencrypted repositories, which are refused otherwise. */
+RusticRepo *rustic_open(const char *repository, const char *password);
+void rustic_close(RusticRepo *repo);
+
+/* Backup the given path using the options of the config profile */
+int rustic_backup(const RusticRepo *repo, const char *path);
+
+/* List all snapshots as JSON array; free with rustic_free_string() */
+char *rustic_snapshots(const RusticRepo *repo);
+
+/* Restore snapshot (SNAPSHOT[:PATH]) into dest */
+int rustic_restore(const RusticRepo *repo, const char *snapshot, const char *dest);
+
+void rustic_free_string(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSTIC_H */
@@ -0,0 +1,105 @@
+"""Python bindings for the rustic C API (see include/rustic.h).
+
+Build the library with `cargo build --release` and point RUSTIC_LIB to
+target/release/librustic_rs.so (or .dylib / .dll) if it is not in the
+library search path.
+
+Example:
+
+    import rustic
+    with rustic.Repository("/srv/backup", "secret") as repo:
+        repo.backup("/home")
+        for snap in repo.snapshots():
+            print(snap["id"], snap["time"])
+"""
+
+import ctypes
+import ctypes.util
+import json
+import os
+
+_lib = ctypes.CDLL(
+    os.environ.get("RUSTIC_LIB")
+    or ctypes.util.find_library("rustic_rs")
+    or "librustic_rs.so"
+)
+
+_lib.rustic_last_error.restype = ctypes.c_char_p
+_lib.rustic_last_error.argtypes = []
+_lib.rustic_init.restype = ctypes.c_int
+_lib.rustic_init.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+_lib.rustic_open.restype = ctypes.c_void_p
+_lib.rustic_open.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+_lib.rustic_close.restype = None
+_lib.rustic_close.argtypes = [ctypes.c_void_p]
+_lib.rustic_backup.restype = ctypes.c_int
+_lib.rustic_backup.argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+# c_void_p instead of c_char_p to be able to free the string
+_lib.rustic_snapshots.restype = ctypes.c_void_p
+_lib.rustic_snapshots.argtypes = [ctypes.c_void_p]
+_lib.rustic_restore.restype = ctypes.c_int
+_lib.rustic_restore.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p]
+_lib.rustic_free_string.restype = None
+_lib.rustic_free_string.argtypes = [ctypes.c_void_p]
+
+
+class RusticError(Exception):
+    pass
+
+
+def _error():
+    msg = _lib.rustic_last_error()
+    return RusticError(msg.decode() if msg else "unknown error")
+
+
+def _check(code):
+    if code != 0:
+        raise _error()
+
+
+def init(repository, password):
+    """Initialize a new repository"""
+    _check(_lib.rustic_init(repository.encode(), password.encode()))
+
+
+class Repository:
+    """An opened repository; the password is verified when opening.
+    Use password None for unencrypted repositories."""
+
+    def __init__(self, repository, password):
+        password = None if password is None else password.encode()
+        self._repo = _lib.rustic_open(repository.encode(), password)
+        if not self._repo:
+            raise _error()
+
+    def close(self):
+        if self._repo:
+            _lib.rustic_close(self._repo)
+            self._repo = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def backup(self, path):
+        """Backup the given path using the options of the config profile"""
+        _check(_lib.rustic_backup(self._repo, os.fsencode(path)))
+
+    def snapshots(self):
+        """List all snapshots as list of dicts"""
+        ptr = _lib.rustic_snapshots(self._repo)
+        if not ptr:
+            raise _error()
+        try:
+            return json.loads(ctypes.string_at(ptr).decode())
+        finally:
+            _lib.rustic_free_string(ptr)
+
+    def restore(self, snapshot, dest):
+        """Restore snapshot (SNAPSHOT[:PATH]) into dest"""
+        _check(_lib.rustic_restore(self._repo, snapshot.encode(), os.fsencode(dest)))
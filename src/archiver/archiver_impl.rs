@@ -1,81 +1,331 @@
-use std::fs::File;
+use std::fmt;
 use std::io::Read;
 use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::anyhow;
+use backoff::{Error, ExponentialBackoffBuilder};
 use bytesize::ByteSize;
-use chrono::Local;
+use chrono::{DateTime, Local};
 use indicatif::ProgressBar;
 use log::*;
 use pariter::IteratorExt;
 
-use crate::backend::DecryptWriteBackend;
-use crate::blob::{BlobType, Metadata, Node, NodeType, Packer, Tree};
+use crate::backend::{BackendError, DecryptWriteBackend, ReadSource};
+use crate::blob::{BlobType, EncryptedBlob, Metadata, Node, NodeType, Packer, Tree};
 use crate::chunker::ChunkIter;
 use crate::crypto::hash;
 use crate::id::Id;
 use crate::index::{IndexedBackend, Indexer, SharedIndexer};
 use crate::repo::{ConfigFile, SnapshotFile, SnapshotSummary};
 
-use super::{Parent, ParentResult};
+use super::{ArchiverError, ArchiverResult, Parent, ParentResult};
 
-pub struct Archiver<BE: DecryptWriteBackend, I: IndexedBackend> {
-    path: PathBuf,
-    tree: Tree,
-    parent: Parent<I>,
-    stack: Vec<(Node, Tree, Parent<I>)>,
-    index: I,
+/// Data and tree packer together with the indexer. These can be shared between
+/// several archivers such that they don't write their own partially filled packs.
+#[derive(Clone)]
+pub struct SharedPackers<BE: DecryptWriteBackend> {
     indexer: SharedIndexer<BE>,
-    data_packer: Packer<BE>,
-    tree_packer: Packer<BE>,
-    be: BE,
-    poly: u64,
-    snap: SnapshotFile,
-    summary: SnapshotSummary,
+    data_packer: Arc<Mutex<Packer<BE>>>,
+    tree_packer: Arc<Mutex<Packer<BE>>>,
 }
 
-impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
-    pub fn new(
-        be: BE,
-        index: I,
-        config: &ConfigFile,
-        parent: Parent<I>,
-        mut snap: SnapshotFile,
-    ) -> Result<Self> {
-        let indexer = Indexer::new(be.clone()).into_shared();
-        let mut summary = snap.summary.take().unwrap();
-        summary.backup_start = Local::now();
-        let poly = config.poly()?;
+impl<BE: DecryptWriteBackend> fmt::Debug for SharedPackers<BE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedPackers").finish_non_exhaustive()
+    }
+}
 
+impl<BE: DecryptWriteBackend> SharedPackers<BE> {
+    pub fn new(be: BE, index: &impl IndexedBackend, config: &ConfigFile) -> ArchiverResult<Self> {
+        let indexer = Indexer::new(be.clone()).into_shared();
         let data_packer = Packer::new(
             be.clone(),
             BlobType::Data,
             indexer.clone(),
             config,
             index.total_size(&BlobType::Data),
-        )?;
+        )
+        .map_err(ArchiverError::Other)?;
         let tree_packer = Packer::new(
-            be.clone(),
+            be,
             BlobType::Tree,
             indexer.clone(),
             config,
             index.total_size(&BlobType::Tree),
-        )?;
+        )
+        .map_err(ArchiverError::Other)?;
         Ok(Self {
+            indexer,
+            data_packer: Arc::new(Mutex::new(data_packer)),
+            tree_packer: Arc::new(Mutex::new(tree_packer)),
+        })
+    }
+
+    /// Add a data blob, e.g. a backup report, which is not part of a tree. Returns the id of the blob.
+    pub fn add_data(&self, index: &impl IndexedBackend, data: &[u8]) -> ArchiverResult<Id> {
+        let id = hash(data);
+        if !index.has_data(&id) {
+            self.data_packer
+                .lock()
+                .unwrap()
+                .add(data, &id)
+                .map_err(ArchiverError::Other)?;
+        }
+        Ok(id)
+    }
+
+    /// Save all remaining packs and the index. Must only be called when no archiver uses the packers any longer.
+    pub fn finalize(self) -> ArchiverResult<()> {
+        let unwrap = |packer: Arc<Mutex<Packer<BE>>>| {
+            Arc::try_unwrap(packer)
+                .map_err(|_| ArchiverError::PackerInUse)
+                .map(|packer| packer.into_inner().unwrap())
+        };
+        unwrap(self.data_packer)?
+            .finalize()
+            .map_err(ArchiverError::Other)?;
+        unwrap(self.tree_packer)?
+            .finalize()
+            .map_err(ArchiverError::Other)?;
+        let indexer = self.indexer.write().unwrap();
+        indexer.finalize()?;
+        Ok(())
+    }
+}
+
+/// Progress of an archiver, i.e. the number of processed bytes.
+pub trait Progress: Clone {
+    fn inc(&self, bytes: u64);
+
+    /// Called for the `bytes` of an unchanged file which is not read; defaults to `inc`
+    fn inc_unchanged(&self, bytes: u64) {
+        self.inc(bytes);
+    }
+
+    /// Called when reading the file at `path` with expected `size` starts
+    fn start_file(&self, _path: &Path, _size: u64) {}
+
+    /// Called when reading the file given in `start_file` has finished
+    fn finish_file(&self) {}
+}
+
+impl Progress for ProgressBar {
+    fn inc(&self, bytes: u64) {
+        ProgressBar::inc(self, bytes);
+    }
+}
+
+/// Hook deciding if an entry, given by its path in the snapshot and its node, is skipped
+pub type SkipHook = Box<dyn Fn(&Path, &Node) -> bool + Send>;
+
+/// Hook handling errors of the source or of backing up an entry (given by its path, if known).
+/// If it returns an error, the backup is aborted; otherwise the entry is skipped.
+pub type ErrorHook = Box<dyn Fn(Option<&Path>, ArchiverError) -> ArchiverResult<()> + Send>;
+
+/// Builder for an [`Archiver`]. Only the backend, the index and the snapshot to create are
+/// needed, everything else is optional:
+///
+/// ```no_run
+/// # use std::path::PathBuf;
+/// # use indicatif::ProgressBar;
+/// # use rustic_rs::api::*;
+/// # use rustic_rs::archiver::ArchiverBuilder;
+/// # fn main() -> anyhow::Result<()> {
+/// let be = LocalBackend::new("/srv/repo");
+/// let key = find_key_in_backend(&be, &"password", None)?;
+/// let be = DecryptBackend::new(&be, key);
+/// let config: ConfigFile = be.get_file(&be.list(FileType::Config)?[0])?;
+/// let index = IndexBackend::new(&be, ProgressBar::hidden())?;
+/// let source = LocalSource::new(LocalSourceOptions::default(), PathBuf::from("/data"))?;
+///
+/// let mut archiver = ArchiverBuilder::new(be, index, SnapshotFile::default())
+///     .should_skip(Box::new(|path, _| path.ends_with("cache")))
+///     .build(&config)?;
+/// archiver.backup_source(source, &ProgressBar::hidden())?;
+/// let snap = archiver.finalize_snapshot()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ArchiverBuilder<BE: DecryptWriteBackend, I: IndexedBackend> {
+    be: BE,
+    index: I,
+    snap: SnapshotFile,
+    parent: Option<Parent<I>>,
+    packers: Option<SharedPackers<BE>>,
+    open_retry: Option<Duration>,
+    as_path: Option<(PathBuf, PathBuf)>,
+    should_skip: Option<SkipHook>,
+    on_error: Option<ErrorHook>,
+}
+
+impl<BE: DecryptWriteBackend, I: IndexedBackend> fmt::Debug for ArchiverBuilder<BE, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArchiverBuilder")
+            .field("snap", &self.snap)
+            .field("open_retry", &self.open_retry)
+            .field("as_path", &self.as_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<BE: DecryptWriteBackend, I: IndexedBackend> ArchiverBuilder<BE, I> {
+    pub fn new(be: BE, index: I, snap: SnapshotFile) -> Self {
+        Self {
+            be,
+            index,
+            snap,
+            parent: None,
+            packers: None,
+            open_retry: None,
+            as_path: None,
+            should_skip: None,
+            on_error: None,
+        }
+    }
+
+    /// Compare the entries with this parent to find unchanged files. Default: no parent
+    pub fn parent(mut self, parent: Parent<I>) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Use the given (possibly shared) packers. Use `finish_snapshot` and save the snapshot
+    /// after finalizing the packers. Default: the archiver uses its own packers
+    pub fn packers(mut self, packers: SharedPackers<BE>) -> Self {
+        self.packers = Some(packers);
+        self
+    }
+
+    /// Retry opening files which are locked or busy up to the given duration.
+    /// Default: such files are skipped immediately
+    pub fn open_retry(mut self, retry: Duration) -> Self {
+        self.open_retry = Some(retry);
+        self
+    }
+
+    /// Save the entries of the source below `path` below `as_path` in the snapshot
+    pub fn backup_as(mut self, path: PathBuf, as_path: PathBuf) -> Self {
+        self.as_path = Some((path, as_path));
+        self
+    }
+
+    /// Skip entries of the source for which the hook returns `true`
+    pub fn should_skip(mut self, hook: SkipHook) -> Self {
+        self.should_skip = Some(hook);
+        self
+    }
+
+    /// Handle errors with the given hook. Default: errors are logged and the entry is skipped
+    pub fn on_error(mut self, hook: ErrorHook) -> Self {
+        self.on_error = Some(hook);
+        self
+    }
+
+    pub fn build(self, config: &ConfigFile) -> ArchiverResult<Archiver<BE, I>> {
+        let packers = match self.packers {
+            Some(packers) => packers,
+            None => SharedPackers::new(self.be.clone(), &self.index, config)?,
+        };
+        let parent = self
+            .parent
+            .unwrap_or_else(|| Parent::new(&self.index, None, false, false, false));
+        let mut snap = self.snap;
+        let mut summary = snap.summary.take().unwrap_or_default();
+        summary.backup_start = Local::now();
+        let poly = config.poly().map_err(ArchiverError::Other)?;
+
+        Ok(Archiver {
             path: PathBuf::default(),
             tree: Tree::new(),
             parent,
             stack: Vec::new(),
-            index,
-            data_packer,
-            tree_packer,
-            be,
+            index: self.index,
+            packers,
+            be: self.be,
             poly,
-            indexer,
             snap,
             summary,
+            open_retry: self.open_retry,
+            as_path: self.as_path,
+            should_skip: self.should_skip,
+            on_error: self.on_error,
         })
     }
+}
+
+pub struct Archiver<BE: DecryptWriteBackend, I: IndexedBackend> {
+    path: PathBuf,
+    tree: Tree,
+    parent: Parent<I>,
+    stack: Vec<(Node, Tree, Parent<I>)>,
+    index: I,
+    packers: SharedPackers<BE>,
+    be: BE,
+    poly: u64,
+    snap: SnapshotFile,
+    summary: SnapshotSummary,
+    open_retry: Option<Duration>,
+    as_path: Option<(PathBuf, PathBuf)>,
+    should_skip: Option<SkipHook>,
+    on_error: Option<ErrorHook>,
+}
+
+impl<BE: DecryptWriteBackend, I: IndexedBackend> fmt::Debug for Archiver<BE, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Archiver")
+            .field("path", &self.path)
+            .field("snap", &self.snap)
+            .field("summary", &self.summary)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
+    /// Back up all entries of the source, reading files using the source.
+    pub fn backup_source<S: ReadSource>(
+        &mut self,
+        src: S,
+        p: &impl Progress,
+    ) -> ArchiverResult<()> {
+        for item in src {
+            match item {
+                Err(err) => self.handle_error(None, ArchiverError::Source(err))?,
+                Ok((path, node)) => {
+                    let snapshot_path = match &self.as_path {
+                        Some((base, as_path)) => match path.strip_prefix(base) {
+                            Ok(rel_path) => as_path.join(rel_path),
+                            Err(_) => path.clone(),
+                        },
+                        None => path.clone(),
+                    };
+                    if matches!(&self.should_skip, Some(skip) if skip(&snapshot_path, &node)) {
+                        debug!("skipping {path:?}");
+                        continue;
+                    }
+                    if let Err(err) = self.add_entry::<S>(&snapshot_path, &path, node, p.clone()) {
+                        self.handle_error(Some(&path), err)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_error(&self, path: Option<&Path>, err: ArchiverError) -> ArchiverResult<()> {
+        match (&self.on_error, path) {
+            (Some(hook), path) => hook(path, err),
+            (None, Some(path)) => {
+                warn!("ignoring error {err} for {path:?}\n");
+                Ok(())
+            }
+            (None, None) => {
+                warn!("ignoring error {err}\n");
+                Ok(())
+            }
+        }
+    }
 
     pub fn add_file(&mut self, node: Node, size: u64) {
         let filename = self.path.join(node.name());
@@ -104,23 +354,27 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         self.summary.total_dirsize_processed += size;
     }
 
-    pub fn add_entry(
+    /// Add an entry given by its path in the snapshot; files are read from `real_path` using
+    /// the source `S`.
+    pub fn add_entry<S: ReadSource>(
         &mut self,
         path: &Path,
         real_path: &Path,
         node: Node,
-        p: ProgressBar,
-    ) -> Result<()> {
+        p: impl Progress,
+    ) -> ArchiverResult<()> {
         let basepath = if node.is_dir() {
             path
         } else {
             path.parent()
-                .ok_or_else(|| anyhow!("file path should have a parent!"))?
+                .ok_or_else(|| ArchiverError::InvalidPath(path.to_path_buf()))?
         };
 
         self.finish_trees(basepath)?;
 
-        let missing_dirs = basepath.strip_prefix(&self.path)?;
+        let missing_dirs = basepath
+            .strip_prefix(&self.path)
+            .map_err(|_| ArchiverError::InvalidPath(basepath.to_path_buf()))?;
         for p in missing_dirs.components() {
             self.path.push(p);
             match p {
@@ -142,13 +396,14 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
                         self.stack.push((node, tree, parent));
                     }
                 }
-                _ => bail!("path should not contain current or parent dir, path: {basepath:?}"),
+                // path should not contain current or parent dir
+                _ => return Err(ArchiverError::InvalidPath(basepath.to_path_buf())),
             }
         }
 
         match node.node_type() {
             NodeType::File => {
-                self.backup_file(real_path, node, p)?;
+                self.backup_file::<S>(real_path, node, p)?;
             }
             NodeType::Dir => {}          // is already handled, see above
             _ => self.add_file(node, 0), // all other cases: just save the given node
@@ -156,15 +411,15 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         Ok(())
     }
 
-    pub fn finish_trees(&mut self, path: &Path) -> Result<()> {
+    pub fn finish_trees(&mut self, path: &Path) -> ArchiverResult<()> {
         while !path.starts_with(&self.path) {
             // save tree and go back to parent dir
-            let (chunk, id) = self.tree.serialize()?;
+            let (chunk, id) = self.tree.serialize().map_err(ArchiverError::Other)?;
 
             let (mut node, tree, parent) = self
                 .stack
                 .pop()
-                .ok_or_else(|| anyhow!("tree stack empty??"))?;
+                .ok_or_else(|| ArchiverError::Other(anyhow!("tree stack empty??")))?;
 
             node.set_subtree(id);
             self.tree = tree;
@@ -176,7 +431,7 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         Ok(())
     }
 
-    pub fn backup_tree(&mut self, node: Node, chunk: Vec<u8>) -> Result<()> {
+    pub fn backup_tree(&mut self, node: Node, chunk: Vec<u8>) -> ArchiverResult<()> {
         let dirsize = chunk.len() as u64;
         let dirsize_bytes = ByteSize(dirsize).to_string_as(true);
         let id = node.subtree().unwrap();
@@ -200,7 +455,14 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         }
 
         if !self.index.has_tree(&id) {
-            match self.tree_packer.add(&chunk, &id)? {
+            let packed_size = self
+                .packers
+                .tree_packer
+                .lock()
+                .unwrap()
+                .add(&chunk, &id)
+                .map_err(ArchiverError::Other)?;
+            match packed_size {
                 0 => {}
                 packed_size => {
                     self.summary.tree_blobs += 1;
@@ -215,14 +477,19 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         Ok(())
     }
 
-    pub fn backup_file(&mut self, path: &Path, node: Node, p: ProgressBar) -> Result<()> {
+    pub fn backup_file<S: ReadSource>(
+        &mut self,
+        path: &Path,
+        node: Node,
+        p: impl Progress,
+    ) -> ArchiverResult<()> {
         if let ParentResult::Matched(p_node) = self.parent.is_parent(&node) {
-            if p_node.content().iter().all(|id| self.index.has_data(id)) {
+            if self.index.has_all_data(p_node.content()) {
                 let size = *p_node.meta().size();
                 let mut node = node;
                 node.set_content(p_node.content().to_vec());
                 self.add_file(node, size);
-                p.inc(size);
+                p.inc_unchanged(size);
                 return Ok(());
             } else {
                 warn!(
@@ -231,54 +498,119 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
                 );
             }
         }
-        let f = File::open(path)?;
+        let f = self.open_file::<S>(path)?;
         self.backup_reader(f, node, p)
     }
 
+    fn open_file<S: ReadSource>(&mut self, path: &Path) -> ArchiverResult<S::Reader> {
+        let res = match self.open_retry {
+            None => S::read(path),
+            Some(duration) => {
+                let backoff = ExponentialBackoffBuilder::new()
+                    .with_max_elapsed_time(Some(duration))
+                    .build();
+                backoff::retry_notify(
+                    backoff,
+                    || {
+                        S::read(path).map_err(|err| match err {
+                            BackendError::NotFound(_) | BackendError::PermissionDenied(_) => {
+                                Error::permanent(err)
+                            }
+                            _ => Error::transient(err),
+                        })
+                    },
+                    |err, duration| {
+                        warn!("error opening {path:?}: {err} at {duration:?}, retrying")
+                    },
+                )
+                .map_err(|err| match err {
+                    Error::Permanent(err) | Error::Transient { err, .. } => err,
+                })
+            }
+        };
+
+        res.map_err(|err| {
+            self.summary.files_skipped += 1;
+            ArchiverError::OpenFile(err)
+        })
+    }
+
     pub fn backup_reader(
         &mut self,
         r: impl Read + 'static,
         node: Node,
-        p: ProgressBar,
-    ) -> Result<()> {
-        let chunk_iter = ChunkIter::new(r, *node.meta().size() as usize, &self.poly);
+        p: impl Progress,
+    ) -> ArchiverResult<()> {
+        // the node size is used as size hint for the chunker and the pack size; it may be 0 if unknown
+        let size_hint = *node.meta().size();
+        if size_hint > 0 {
+            self.packers
+                .data_packer
+                .lock()
+                .unwrap()
+                .add_size_hint(size_hint);
+        }
+        let chunk_iter = ChunkIter::new(r, size_hint.try_into().unwrap_or(usize::MAX), &self.poly);
         let mut content = Vec::new();
         let mut filesize: u64 = 0;
 
-        chunk_iter
+        // new blobs are compressed and encrypted in parallel with reading and chunking; only
+        // adding them to the pack is serialized and the pack files are uploaded by the packer
+        let key = self.be.key().clone();
+        let zstd = self.packers.data_packer.lock().unwrap().zstd();
+        let index = self.index.clone();
+
+        p.start_file(&self.path.join(node.name()), size_hint);
+        let res = chunk_iter
             .into_iter()
             // TODO: This parallelization works pretty well for big files. For small files this produces a lot of
             // unneccessary overhead. Maybe use a parallel hashing actor?
-            .parallel_map(|chunk| {
-                let chunk = chunk?;
+            .parallel_map(move |chunk| {
+                let chunk = chunk.map_err(|err| ArchiverError::Source(err.into()))?;
                 let id = hash(&chunk);
-                Ok((chunk, id))
+                let blob = match index.has_data(&id) {
+                    true => None,
+                    false => {
+                        Some(EncryptedBlob::new(&key, zstd, &chunk).map_err(ArchiverError::Other)?)
+                    }
+                };
+                Ok((id, chunk.len() as u64, blob))
             })
-            .try_for_each(|data: Result<_>| -> Result<_> {
-                let (chunk, id) = data?;
-                let size = chunk.len() as u64;
+            .try_for_each(|data: ArchiverResult<_>| -> ArchiverResult<_> {
+                let (id, size, blob) = data?;
                 filesize += size;
 
                 content.push(id);
-                self.process_data_junk(id, &chunk, size, &p)?;
+                self.process_data_junk(id, blob, size, &p)?;
                 Ok(())
-            })?;
+            });
+        p.finish_file();
+        res?;
 
         let mut node = node;
         node.set_content(content);
+        node.meta.size = filesize;
         self.add_file(node, filesize);
         Ok(())
     }
 
+    /// Add a data blob to the pack unless it is already in the index, i.e. `blob` is `None`
     fn process_data_junk(
         &mut self,
         id: Id,
-        chunk: &[u8],
+        blob: Option<EncryptedBlob>,
         size: u64,
-        p: &ProgressBar,
-    ) -> Result<()> {
-        if !self.index.has_data(&id) {
-            match self.data_packer.add(chunk, &id)? {
+        p: &impl Progress,
+    ) -> ArchiverResult<()> {
+        if let Some(blob) = blob {
+            let packed_size = self
+                .packers
+                .data_packer
+                .lock()
+                .unwrap()
+                .add_encrypted(blob, &id)
+                .map_err(ArchiverError::Other)?;
+            match packed_size {
                 0 => {}
                 packed_size => {
                     self.summary.data_blobs += 1;
@@ -293,31 +625,130 @@ impl<BE: DecryptWriteBackend, I: IndexedBackend> Archiver<BE, I> {
         Ok(())
     }
 
-    pub fn finalize_snapshot(mut self) -> Result<SnapshotFile> {
+    /// Finish all trees and the snapshot, finalize the packers and save the snapshot.
+    pub fn finalize_snapshot(self) -> ArchiverResult<SnapshotFile> {
+        let be = self.be.clone();
+        let mut snap = self.finalize()?;
+        snap.id = be.save_file(&snap)?;
+        Ok(snap)
+    }
+
+    /// Finish all trees and the snapshot and finalize the packers without saving the snapshot.
+    pub fn finalize(self) -> ArchiverResult<SnapshotFile> {
+        let packers = self.packers.clone();
+        let snap = self.finish_snapshot()?;
+        packers.finalize()?;
+        Ok(snap)
+    }
+
+    /// Finish all trees and the snapshot without saving it.
+    pub fn finish_snapshot(mut self) -> ArchiverResult<SnapshotFile> {
         self.finish_trees(&PathBuf::from("/"))?;
 
-        let (chunk, id) = self.tree.serialize()?;
+        let (chunk, id) = self.tree.serialize().map_err(ArchiverError::Other)?;
         if !self.index.has_tree(&id) {
-            self.tree_packer.add(&chunk, &id)?;
+            self.packers
+                .tree_packer
+                .lock()
+                .unwrap()
+                .add(&chunk, &id)
+                .map_err(ArchiverError::Other)?;
         }
         self.snap.tree = id;
 
-        self.data_packer.finalize()?;
-        self.tree_packer.finalize()?;
-        {
-            let indexer = self.indexer.write().unwrap();
-            indexer.finalize()?;
-        }
         let end_time = Local::now();
-        self.summary.backup_duration = (end_time - self.summary.backup_start)
-            .to_std()?
-            .as_secs_f64();
-        self.summary.total_duration = (end_time - self.snap.time).to_std()?.as_secs_f64();
+        let duration = |start: DateTime<Local>| {
+            (end_time - start)
+                .to_std()
+                .map_err(|err| ArchiverError::Other(err.into()))
+        };
+        self.summary.backup_duration = duration(self.summary.backup_start)?.as_secs_f64();
+        self.summary.total_duration = duration(self.snap.time)?.as_secs_f64();
         self.summary.backup_end = end_time;
         self.snap.summary = Some(self.summary);
-        let id = self.be.save_file(&self.snap)?;
-        self.snap.id = id;
 
         Ok(self.snap)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+    use std::io::Cursor;
+
+    use crate::backend::{BackendResult, DecryptBackend, LocalBackend, WriteBackend};
+    use crate::chunker::random_poly;
+    use crate::crypto::Key;
+    use crate::index::IndexBackend;
+
+    /// Source returning the given entries; the content of each file is its path
+    struct TestSource(std::vec::IntoIter<BackendResult<(PathBuf, Node)>>);
+
+    impl Iterator for TestSource {
+        type Item = BackendResult<(PathBuf, Node)>;
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next()
+        }
+    }
+
+    impl ReadSource for TestSource {
+        type Reader = Cursor<Vec<u8>>;
+        fn read(path: &Path) -> BackendResult<Self::Reader> {
+            Ok(Cursor::new(path.to_str().unwrap().as_bytes().to_vec()))
+        }
+        fn size(&self) -> BackendResult<u64> {
+            Ok(0)
+        }
+    }
+
+    fn source() -> TestSource {
+        let node = |name: &str, tpe| Node::new_node(OsStr::new(name), tpe, Metadata::default());
+        TestSource(
+            vec![
+                Ok((PathBuf::from("/dir"), node("dir", NodeType::Dir))),
+                Ok((PathBuf::from("/dir/file"), node("file", NodeType::File))),
+                Err(BackendError::Other(anyhow!("source error"))),
+                Ok((PathBuf::from("/dir/skip"), node("skip", NodeType::File))),
+            ]
+            .into_iter(),
+        )
+    }
+
+    #[test]
+    fn archiver_hooks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let be = LocalBackend::new(dir.to_str().unwrap());
+        be.create().unwrap();
+        let be = DecryptBackend::new(&be, Key::new());
+        let index = IndexBackend::new(&be, ProgressBar::hidden()).unwrap();
+        let config = ConfigFile::new(2, Id::random(), random_poly().unwrap());
+        let snap = SnapshotFile {
+            summary: Some(SnapshotSummary::default()),
+            ..Default::default()
+        };
+
+        // the source error is ignored by default
+        let mut archiver = ArchiverBuilder::new(be.clone(), index.clone(), snap.clone())
+            .should_skip(Box::new(|path, _| path.ends_with("skip")))
+            .build(&config)
+            .unwrap();
+        archiver
+            .backup_source(source(), &ProgressBar::hidden())
+            .unwrap();
+        let summary = archiver.finalize_snapshot().unwrap().summary.unwrap();
+        assert_eq!(summary.files_new, 1);
+        assert_eq!(summary.dirs_new, 1);
+
+        let mut archiver = ArchiverBuilder::new(be, index, snap)
+            .on_error(Box::new(|_, err| Err(err)))
+            .build(&config)
+            .unwrap();
+        assert!(matches!(
+            archiver.backup_source(source(), &ProgressBar::hidden()),
+            Err(ArchiverError::Source(_))
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::backend::BackendError;
+use crate::index::IndexError;
+
+/// ArchiverError describes the errors that can be returned by the archiver.
+///
+/// Errors of a single entry, e.g. a file which can't be opened, are passed to the error hook
+/// of the archiver which decides whether the backup is aborted.
+#[derive(Error, Debug)]
+pub enum ArchiverError {
+    #[error("source error: {0}")]
+    Source(BackendError),
+
+    #[error("could not open file, skipping it: {0}")]
+    OpenFile(BackendError),
+
+    #[error("invalid path {0:?}")]
+    InvalidPath(PathBuf),
+
+    #[error("packer is still in use")]
+    PackerInUse,
+
+    #[error(transparent)]
+    Index(#[from] IndexError),
+
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type ArchiverResult<T> = Result<T, ArchiverError>;
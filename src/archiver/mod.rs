@@ -1,5 +1,7 @@
 mod archiver_impl;
+mod error;
 mod parent;
 
 pub use archiver_impl::*;
+pub use error::*;
 pub use parent::*;
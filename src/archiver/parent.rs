@@ -1,19 +1,69 @@
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use anyhow::Result;
+use indicatif::ProgressBar;
+use log::*;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::blob::{Node, Tree};
+use crate::blob::{Node, Tree, TreeStreamerOnce};
 use crate::id::Id;
 use crate::index::IndexedBackend;
 
+use super::ArchiverResult;
+
+#[derive(Debug)]
 pub struct Parent<BE: IndexedBackend> {
     tree: Option<Tree>,
     be: BE,
     node_idx: usize,
     ignore_ctime: bool,
     ignore_inode: bool,
+    dirs: Option<Arc<DirLookup>>,
+    // index of the nodes by their name in NFC, built when it is first needed
+    nfc_nodes: Option<HashMap<String, usize>>,
+}
+
+/// Lookup of the directories of the parent snapshot by device id and inode. This allows to find
+/// the parent tree of renamed or moved directories. The lookup is built when it is first needed.
+#[derive(Debug)]
+struct DirLookup {
+    root: Id,
+    dirs: Mutex<Option<HashMap<(u64, u64), Id>>>,
+}
+
+impl DirLookup {
+    fn get(&self, be: &impl IndexedBackend, device_id: u64, inode: u64) -> Option<Id> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let dirs = dirs.get_or_insert_with(|| {
+            info!("reading all trees of the parent snapshot to detect renamed directories...");
+            let mut dirs = HashMap::new();
+            let streamer = match TreeStreamerOnce::new(be.clone(), vec![self.root], ProgressBar::hidden()) {
+                Ok(streamer) => streamer,
+                Err(err) => {
+                    warn!("error reading the parent snapshot, not detecting renamed directories: {err}");
+                    return dirs;
+                }
+            };
+            for item in streamer {
+                match item {
+                    Ok((_, tree)) => {
+                        for node in tree.nodes() {
+                            if let (true, Some(id)) = (node.meta.inode != 0, node.subtree()) {
+                                dirs.insert((node.meta.device_id, node.meta.inode), *id);
+                            }
+                        }
+                    }
+                    Err(err) => warn!("error reading tree of the parent snapshot: {err}"),
+                }
+            }
+            dirs
+        });
+        dirs.get(&(device_id, inode)).copied()
+    }
 }
 
+#[derive(Debug)]
 pub enum ParentResult<T> {
     Matched(T),
     NotFound,
@@ -21,19 +71,37 @@ pub enum ParentResult<T> {
 }
 
 impl<BE: IndexedBackend> Parent<BE> {
-    pub fn new(be: &BE, tree_id: Option<Id>, ignore_ctime: bool, ignore_inode: bool) -> Self {
+    /// Create a new parent from the tree `tree_id`. If `detect_renames` is set, directories which
+    /// are not found in the parent tree are looked up by their inode in all trees of the parent.
+    pub fn new(
+        be: &BE,
+        tree_id: Option<Id>,
+        ignore_ctime: bool,
+        ignore_inode: bool,
+        detect_renames: bool,
+    ) -> Self {
         // if tree_id is given, load tree from backend. Turn errors into None.
         // TODO: print warning when loading failed
         let tree = match tree_id {
             None => None,
             Some(id) => Tree::from_backend(be, id).ok(),
         };
+        let dirs = tree_id
+            .filter(|_| detect_renames && !ignore_inode)
+            .map(|root| {
+                Arc::new(DirLookup {
+                    root,
+                    dirs: Mutex::new(None),
+                })
+            });
         Self {
             tree,
             be: be.clone(),
             node_idx: 0,
             ignore_ctime,
             ignore_inode,
+            dirs,
+            nfc_nodes: None,
         }
     }
 
@@ -43,7 +111,7 @@ impl<BE: IndexedBackend> Parent<BE> {
             Some(tree) => {
                 let name = node.name();
                 let p_nodes = tree.nodes();
-                loop {
+                let p_node = loop {
                     match p_nodes.get(self.node_idx) {
                         None => break None,
                         Some(p_node) => match p_node.name().cmp(&name) {
@@ -56,6 +124,27 @@ impl<BE: IndexedBackend> Parent<BE> {
                             }
                         },
                     }
+                };
+
+                match (p_node, name.to_str()) {
+                    // The name may be stored in another unicode normalization form in the parent,
+                    // e.g. if the parent was created from a filesystem using NFC and now NFD is used.
+                    // As the sort order may differ, we have to look up the name in NFC in this case.
+                    (None, Some(name)) if !name.is_ascii() => {
+                        let nfc_nodes = self.nfc_nodes.get_or_insert_with(|| {
+                            let mut nfc_nodes = HashMap::new();
+                            for (idx, p_node) in p_nodes.iter().enumerate() {
+                                if let Some(p_name) = p_node.name().to_str() {
+                                    nfc_nodes.entry(p_name.nfc().collect()).or_insert(idx);
+                                }
+                            }
+                            nfc_nodes
+                        });
+                        nfc_nodes
+                            .get(&name.nfc().collect::<String>())
+                            .map(|idx| &p_nodes[*idx])
+                    }
+                    (p_node, _) => p_node,
                 }
             }
         }
@@ -85,9 +174,21 @@ impl<BE: IndexedBackend> Parent<BE> {
         }
     }
 
-    pub fn sub_parent(&mut self, node: &Node) -> Result<Self> {
+    /// Find the parent tree of a directory which is not contained in the parent tree, e.g. as it
+    /// has been renamed or moved, using its device id and inode.
+    fn renamed_tree(&self, node: &Node) -> Option<Tree> {
+        let dirs = self.dirs.as_ref()?;
+        if !node.is_dir() || node.meta.inode == 0 {
+            return None;
+        }
+        let id = dirs.get(&self.be, node.meta.device_id, node.meta.inode)?;
+        debug!("using parent tree {id} for renamed dir {:?}", node.name());
+        Tree::from_backend(&self.be, id).ok()
+    }
+
+    pub fn sub_parent(&mut self, node: &Node) -> ArchiverResult<Self> {
         let tree = match self.p_node(node) {
-            None => None,
+            None => self.renamed_tree(node),
             Some(p_node) => {
                 if p_node.node_type() == node.node_type() {
                     // TODO: print warning when loading failed
@@ -104,6 +205,48 @@ impl<BE: IndexedBackend> Parent<BE> {
             node_idx: 0,
             ignore_ctime: self.ignore_ctime,
             ignore_inode: self.ignore_inode,
+            dirs: self.dirs.clone(),
+            nfc_nodes: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+
+    use crate::backend::{DecryptBackend, LocalBackend, WriteBackend};
+    use crate::blob::{Metadata, NodeType};
+    use crate::crypto::Key;
+    use crate::index::IndexBackend;
+
+    #[test]
+    fn p_node_other_normalization() {
+        let tmp = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(tmp.path().to_str().unwrap());
+        be.create().unwrap();
+        let be = DecryptBackend::new(&be, Key::new());
+        let be = IndexBackend::new(&be, ProgressBar::hidden()).unwrap();
+
+        let node = |name: &str, size| {
+            let meta = Metadata {
+                size,
+                ..Default::default()
+            };
+            Node::new_node(OsStr::new(name), NodeType::File, meta)
+        };
+        let mut tree = Tree::new();
+        tree.add(node("a", 1));
+        // "é" in NFC
+        tree.add(node("\u{e9}", 2));
+        let mut parent = Parent::new(&be, None, false, false, false);
+        parent.tree = Some(tree);
+
+        assert_eq!(parent.p_node(&node("a", 0)).unwrap().meta.size, 1);
+        // "é" in NFD
+        assert_eq!(parent.p_node(&node("e\u{301}", 0)).unwrap().meta.size, 2);
+        assert!(parent.p_node(&node("\u{e8}", 0)).is_none());
+    }
+}
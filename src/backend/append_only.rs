@@ -0,0 +1,159 @@
+use bytes::Bytes;
+
+use super::{BackendError, BackendResult, Capabilities, FileType, Id, ReadBackend, WriteBackend};
+
+/// Backend wrapper which refuses to remove or to overwrite existing files, including the
+/// repository config.
+/// This makes sure that a client which is only used for backups cannot destroy existing backups.
+#[derive(Clone)]
+pub struct AppendOnlyBackend<BE: WriteBackend> {
+    be: BE,
+    append_only: bool,
+}
+
+impl<BE: WriteBackend> AppendOnlyBackend<BE> {
+    pub fn new(be: BE, append_only: bool) -> Self {
+        Self { be, append_only }
+    }
+
+    // Returns an error if the file exists and files must not be changed.
+    fn check_new(&self, tpe: FileType, id: &Id) -> BackendResult<()> {
+        if !self.append_only {
+            return Ok(());
+        }
+        match self.be.read_partial(tpe, id, false, 0, 1) {
+            Ok(_) => Err(BackendError::PermissionDenied(format!(
+                "overwriting {tpe:?} file {id} is not allowed in append-only mode"
+            ))),
+            Err(BackendError::NotFound(_)) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<BE: WriteBackend> ReadBackend for AppendOnlyBackend<BE> {
+    fn location(&self) -> &str {
+        self.be.location()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        self.be.set_option(option, value)
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        self.be.list_with_size(tpe)
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        self.be.read_full(tpe, id)
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        self.be.read_partial(tpe, id, cacheable, offset, length)
+    }
+}
+
+impl<BE: WriteBackend> WriteBackend for AppendOnlyBackend<BE> {
+    fn create(&self) -> BackendResult<()> {
+        self.be.create()
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
+        if self.append_only && tpe == FileType::Config && !self.be.list(tpe)?.is_empty() {
+            return Err(BackendError::PermissionDenied(
+                "changing the config is not allowed in append-only mode".to_string(),
+            ));
+        }
+        self.check_new(tpe, id)?;
+        self.be.write_bytes(tpe, id, cacheable, buf)
+    }
+
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        if self.append_only && tpe == FileType::Config {
+            return Err(BackendError::PermissionDenied(
+                "changing the config is not allowed in append-only mode".to_string(),
+            ));
+        }
+        self.be
+            .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
+        if self.append_only {
+            return Err(BackendError::PermissionDenied(format!(
+                "removing {tpe:?} file {id} is not allowed in append-only mode"
+            )));
+        }
+        self.be.remove(tpe, id, cacheable)
+    }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+    ) -> BackendResult<bool> {
+        self.check_new(tpe, id)?;
+        self.be.copy_from(&from.be, tpe, id, cacheable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+
+    #[test]
+    fn append_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let local = LocalBackend::new(tmp.path().to_str().unwrap());
+        local.create().unwrap();
+        let be = AppendOnlyBackend::new(local, true);
+
+        let id = Id::random();
+        be.write_bytes(FileType::Snapshot, &id, false, "first".into())
+            .unwrap();
+        assert!(matches!(
+            be.write_bytes(FileType::Snapshot, &id, false, "second".into()),
+            Err(BackendError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            be.remove(FileType::Snapshot, &id, false),
+            Err(BackendError::PermissionDenied(_))
+        ));
+        assert_eq!(be.read_full(FileType::Snapshot, &id).unwrap(), "first");
+
+        be.write_bytes(FileType::Snapshot, &Id::random(), false, "other".into())
+            .unwrap();
+        assert_eq!(be.list(FileType::Snapshot).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,393 @@
+use std::sync::{Arc, RwLock};
+
+use backoff::Error;
+use bytes::Bytes;
+use log::*;
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::rest::{notify, retry_notify, CheckError, RetryPolicy};
+use super::{
+    BackendError, BackendResult, Capabilities, FileRange, FileType, Id, ReadBackend, WriteBackend,
+};
+
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+const MAX_FILE_COUNT: u32 = 10_000;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Authorization {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+    allowed: Allowed,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Allowed {
+    bucket_id: Option<String>,
+    bucket_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+}
+
+#[derive(Deserialize)]
+struct Buckets {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileEntry {
+    file_name: String,
+    file_id: String,
+    content_length: u64,
+    action: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    files: Vec<FileEntry>,
+    next_file_name: Option<String>,
+    next_file_id: Option<String>,
+}
+
+/// Backend using the native Backblaze B2 API.
+///
+/// The url is given as `b2:bucket` or `b2:bucket/prefix`; the credentials are taken
+/// from the environment variables `B2_ACCOUNT_ID` and `B2_ACCOUNT_KEY`.
+#[derive(Clone)]
+pub struct B2Backend {
+    location: String,
+    bucket: String,
+    bucket_id: String,
+    prefix: String,
+    account_id: String,
+    account_key: String,
+    client: Client,
+    auth: Arc<RwLock<Authorization>>,
+    retry: RetryPolicy,
+}
+
+impl B2Backend {
+    pub fn new(path: &str) -> BackendResult<Self> {
+        let (bucket, prefix) = match path.split_once('/') {
+            Some((bucket, prefix)) => (bucket, prefix.trim_matches('/')),
+            None => (path, ""),
+        };
+        if bucket.is_empty() {
+            return Err(BackendError::Config(
+                "no bucket given for b2 backend. Please use b2:bucket/prefix".to_string(),
+            ));
+        }
+        let prefix = match prefix {
+            "" => String::new(),
+            prefix => prefix.to_string() + "/",
+        };
+
+        let account_id = std::env::var("B2_ACCOUNT_ID")
+            .map_err(|_| BackendError::Config("b2 backend: please set B2_ACCOUNT_ID".into()))?;
+        let account_key = std::env::var("B2_ACCOUNT_KEY")
+            .map_err(|_| BackendError::Config("b2 backend: please set B2_ACCOUNT_KEY".into()))?;
+
+        let client = Client::new();
+        let auth = authorize(&client, &account_id, &account_key)?;
+
+        let bucket_id = match (&auth.allowed.bucket_id, &auth.allowed.bucket_name) {
+            // the application key is restricted to this bucket
+            (Some(id), Some(name)) if name == bucket => id.clone(),
+            (Some(_), _) => {
+                return Err(BackendError::PermissionDenied(format!(
+                    "b2 application key is not allowed to access bucket {bucket}"
+                )))
+            }
+            (None, _) => {
+                let buckets: Buckets = client
+                    .post(format!("{}/b2api/v2/b2_list_buckets", auth.api_url))
+                    .header("Authorization", &auth.authorization_token)
+                    .json(&json!({"accountId": auth.account_id, "bucketName": bucket}))
+                    .send()?
+                    .error_for_status()?
+                    .json()?;
+                match buckets.buckets.into_iter().next() {
+                    Some(b) => b.bucket_id,
+                    None => {
+                        return Err(BackendError::NotFound(format!(
+                            "b2 bucket {bucket} not found"
+                        )))
+                    }
+                }
+            }
+        };
+
+        Ok(Self {
+            location: format!("b2:{path}"),
+            bucket: bucket.to_string(),
+            bucket_id,
+            prefix,
+            account_id,
+            account_key,
+            client,
+            auth: Arc::new(RwLock::new(auth)),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    fn path(&self, tpe: FileType, id: &Id) -> String {
+        let hex_id = id.to_hex();
+        match tpe {
+            FileType::Config => format!("{}config", self.prefix),
+            FileType::Pack => format!("{}data/{}/{hex_id}", self.prefix, &hex_id[0..2]),
+            _ => format!("{}{}/{hex_id}", self.prefix, tpe.name()),
+        }
+    }
+
+    fn auth(&self) -> Authorization {
+        self.auth.read().unwrap().clone()
+    }
+
+    // Check the response for errors. An expired authorization is renewed and the request retried.
+    fn check(&self, resp: Response) -> Result<Response, Error<reqwest::Error>> {
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            match authorize(&self.client, &self.account_id, &self.account_key) {
+                Ok(auth) => {
+                    debug!("renewed b2 authorization for {}", self.location);
+                    *self.auth.write().unwrap() = auth;
+                    return resp.error_for_status().map_err(Error::transient);
+                }
+                Err(err) => warn!("error renewing b2 authorization: {err}"),
+            }
+        }
+        resp.check_error()
+    }
+
+    // list all versions of all files starting with prefix
+    fn list_files(&self, prefix: &str, versions: bool) -> BackendResult<Vec<FileEntry>> {
+        let api = match versions {
+            true => "b2_list_file_versions",
+            false => "b2_list_file_names",
+        };
+        let mut files = Vec::new();
+        let mut start: (Option<String>, Option<String>) = (None, None);
+        loop {
+            let list: FileList = retry_notify(
+                self.retry.backoff(),
+                || {
+                    let auth = self.auth();
+                    let mut body = json!({
+                        "bucketId": self.bucket_id,
+                        "prefix": prefix,
+                        "maxFileCount": MAX_FILE_COUNT,
+                    });
+                    if let Some(name) = &start.0 {
+                        body["startFileName"] = json!(name);
+                    }
+                    if let Some(id) = &start.1 {
+                        body["startFileId"] = json!(id);
+                    }
+                    let resp = self
+                        .client
+                        .post(format!("{}/b2api/v2/{api}", auth.api_url))
+                        .header("Authorization", &auth.authorization_token)
+                        .json(&body)
+                        .send()?;
+                    Ok(self.check(resp)?.json()?)
+                },
+                notify,
+            )?;
+            files.extend(list.files);
+            match list.next_file_name {
+                None => break,
+                name => start = (name, list.next_file_id),
+            }
+        }
+        Ok(files)
+    }
+
+    fn download(&self, tpe: FileType, id: &Id, range: Option<String>) -> BackendResult<Bytes> {
+        let path = self.path(tpe, id);
+        Ok(retry_notify(
+            self.retry.backoff(),
+            || {
+                let auth = self.auth();
+                let mut req = self
+                    .client
+                    .get(format!("{}/file/{}/{path}", auth.download_url, self.bucket))
+                    .header("Authorization", &auth.authorization_token);
+                if let Some(range) = &range {
+                    req = req.header("Range", range);
+                }
+                Ok(self.check(req.send()?)?.bytes()?)
+            },
+            notify,
+        )?)
+    }
+}
+
+fn authorize(
+    client: &Client,
+    account_id: &str,
+    account_key: &str,
+) -> reqwest::Result<Authorization> {
+    client
+        .get(AUTHORIZE_URL)
+        .basic_auth(account_id, Some(account_key))
+        .send()?
+        .error_for_status()?
+        .json()
+}
+
+impl ReadBackend for B2Backend {
+    fn location(&self) -> &str {
+        &self.location
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ranged_reads: true,
+            atomic_writes: true,
+            cheap_list: false,
+            server_side_copy: false,
+            compare_and_swap: false,
+        }
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        self.retry.set_option(option, value)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        if tpe == FileType::Config {
+            let path = format!("{}config", self.prefix);
+            let files = self.list_files(&path, false)?;
+            return Ok(match files.iter().any(|f| f.file_name == path) {
+                true => vec![(Id::default(), 0)],
+                false => Vec::new(),
+            });
+        }
+
+        let dir = match tpe {
+            FileType::Pack => "data",
+            _ => tpe.name(),
+        };
+        let files = self.list_files(&format!("{}{dir}/", self.prefix), false)?;
+        Ok(files
+            .into_iter()
+            .filter_map(|f| {
+                let name = f.file_name.rsplit('/').next()?;
+                let id = Id::from_hex(name).ok()?;
+                Some((id, f.content_length))
+            })
+            .collect())
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        self.download(tpe, id, None)
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        let range = FileRange::new(offset, length)?;
+        range.check_read(self.download(tpe, id, Some(range.http_range()))?)
+    }
+}
+
+impl WriteBackend for B2Backend {
+    fn create(&self) -> BackendResult<()> {
+        // B2 has no directories; the bucket must already exist
+        Ok(())
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
+        trace!("writing tpe: {:?}, id: {}", &tpe, &id);
+        let path = self.path(tpe, id);
+        Ok(retry_notify(
+            self.retry.backoff(),
+            || {
+                // an upload url must not be used concurrently, so get a new one for each upload
+                let auth = self.auth();
+                let resp = self
+                    .client
+                    .post(format!("{}/b2api/v2/b2_get_upload_url", auth.api_url))
+                    .header("Authorization", &auth.authorization_token)
+                    .json(&json!({ "bucketId": self.bucket_id }))
+                    .send()?;
+                let upload: UploadUrl = self.check(resp)?.json()?;
+
+                // Note: The content is verified by its id (SHA256 hash), so we don't provide a SHA1 hash here
+                let resp = self
+                    .client
+                    .post(upload.upload_url)
+                    .header("Authorization", upload.authorization_token)
+                    .header("X-Bz-File-Name", &path)
+                    .header("Content-Type", "application/octet-stream")
+                    .header("X-Bz-Content-Sha1", "do_not_verify")
+                    .body(buf.clone())
+                    .send()?;
+                self.check(resp)?;
+                Ok(())
+            },
+            notify,
+        )?)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, _cacheable: bool) -> BackendResult<()> {
+        trace!("removing tpe: {:?}, id: {}", &tpe, &id);
+        let path = self.path(tpe, id);
+        // remove all versions of the file
+        for file in self
+            .list_files(&path, true)?
+            .into_iter()
+            .filter(|f| f.file_name == path)
+        {
+            debug!(
+                "removing {} version {} ({})",
+                path, file.file_id, file.action
+            );
+            retry_notify(
+                self.retry.backoff(),
+                || {
+                    let auth = self.auth();
+                    let resp = self
+                        .client
+                        .post(format!("{}/b2api/v2/b2_delete_file_version", auth.api_url))
+                        .header("Authorization", &auth.authorization_token)
+                        .json(&json!({"fileName": file.file_name, "fileId": file.file_id}))
+                        .send()?;
+                    self.check(resp)?;
+                    Ok(())
+                },
+                notify,
+            )?;
+        }
+        Ok(())
+    }
+}
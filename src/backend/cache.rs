@@ -1,15 +1,39 @@
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use dirs::cache_dir;
+use filetime::{set_file_mtime, FileTime};
 use log::*;
+use tempfile::Builder;
 use walkdir::WalkDir;
 
-use super::{FileType, Id, ReadBackend, WriteBackend};
+use super::{
+    BackendError, BackendResult, Capabilities, DecryptReadBackend, FileRange, FileType, Id,
+    ReadBackend, WriteBackend,
+};
+use crate::crypto::hash;
+
+// prefix of the temporary files created by `write_replace`
+const TMP_PREFIX: &str = ".tmp";
+// temporary files older than this are considered as left over from interrupted writes
+const MAX_TMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Write a file of the cache using a temporary file with a unique name which is then renamed.
+/// An interrupted write never leaves an incomplete file and concurrent writers of the same file
+/// don't interfere. The file is only readable by the user.
+fn write_replace(filename: &Path, data: &[u8]) -> BackendResult<()> {
+    let mut file = Builder::new()
+        .prefix(TMP_PREFIX)
+        .tempfile_in(filename.parent().unwrap())?;
+    file.write_all(data)?;
+    file.persist(filename).map_err(|err| err.error)?;
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct CachedBackend<BE: WriteBackend> {
@@ -28,15 +52,29 @@ impl<BE: WriteBackend> ReadBackend for CachedBackend<BE> {
         self.be.location()
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         self.be.set_option(option, value)
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         let list = self.be.list_with_size(tpe)?;
 
+        // Remove cache files which are no longer present in the repository or have changed,
+        // e.g. after prune or forget was run by another client. This includes cached tree packs.
         if let Some(cache) = &self.cache {
-            if tpe.is_cacheable() {
+            if tpe.is_cacheable() || tpe == FileType::Pack {
                 cache.remove_not_in_list(tpe, &list)?;
             }
         }
@@ -44,7 +82,7 @@ impl<BE: WriteBackend> ReadBackend for CachedBackend<BE> {
         Ok(list)
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         match (&self.cache, tpe.is_cacheable()) {
             (None, _) | (Some(_), false) => self.be.read_full(tpe, id),
             (Some(cache), true) => match cache.read_full(tpe, id) {
@@ -65,9 +103,9 @@ impl<BE: WriteBackend> ReadBackend for CachedBackend<BE> {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         match (&self.cache, cacheable || tpe.is_cacheable()) {
             (None, _) | (Some(_), false) => {
                 self.be.read_partial(tpe, id, cacheable, offset, length)
@@ -80,7 +118,7 @@ impl<BE: WriteBackend> ReadBackend for CachedBackend<BE> {
                     //       - Don't read from cache, but use the right part of the read content
                     Ok(data) => {
                         if cache.write_bytes(tpe, id, data).is_ok() {
-                            cache.read_partial(tpe, id, offset, length)
+                            Ok(cache.read_partial(tpe, id, offset, length)?)
                         } else {
                             self.be.read_partial(tpe, id, false, offset, length)
                         }
@@ -93,11 +131,17 @@ impl<BE: WriteBackend> ReadBackend for CachedBackend<BE> {
 }
 
 impl<BE: WriteBackend> WriteBackend for CachedBackend<BE> {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         self.be.create()
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         if let Some(cache) = &self.cache {
             if cacheable || tpe.is_cacheable() {
                 let _ = cache.write_bytes(tpe, id, buf.clone());
@@ -106,7 +150,26 @@ impl<BE: WriteBackend> WriteBackend for CachedBackend<BE> {
         self.be.write_bytes(tpe, id, cacheable, buf)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        let replaced =
+            self.be
+                .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf.clone())?;
+        if let Some(cache) = &self.cache {
+            if replaced && (cacheable || tpe.is_cacheable()) {
+                let _ = cache.write_bytes(tpe, id, buf);
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         if let Some(cache) = &self.cache {
             if cacheable || tpe.is_cacheable() {
                 let _ = cache.remove(tpe, id);
@@ -116,18 +179,26 @@ impl<BE: WriteBackend> WriteBackend for CachedBackend<BE> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Cache {
     path: PathBuf,
 }
 
+// name of the file which identifies the repository a cache belongs to
+const REPOSITORY_FILE: &str = "repository";
+
 impl Cache {
-    pub fn new(id: Id, path: Option<PathBuf>) -> Result<Self> {
-        let mut path = path.unwrap_or({
-            let mut dir = cache_dir().ok_or_else(|| anyhow!("no cache dir"))?;
-            dir.push("rustic");
-            dir
-        });
+    fn root(path: Option<PathBuf>) -> BackendResult<PathBuf> {
+        Ok(match path {
+            Some(path) => path,
+            None => cache_dir()
+                .ok_or_else(|| BackendError::Config("no cache dir".to_string()))?
+                .join("rustic"),
+        })
+    }
+
+    pub fn new(id: Id, path: Option<PathBuf>) -> BackendResult<Self> {
+        let mut path = Self::root(path)?;
         fs::create_dir_all(&path)?;
         cachedir::ensure_tag(&path)?;
         path.push(id.to_hex());
@@ -135,10 +206,73 @@ impl Cache {
         Ok(Self { path })
     }
 
+    /// Find the cache of the repository given by `location`, see `save_repository_files`.
+    pub fn find(location: &str, path: Option<PathBuf>) -> BackendResult<Option<Self>> {
+        let root = Self::root(path)?;
+        if !root.exists() {
+            return Ok(None);
+        }
+        let location = hash(location.as_bytes()).to_hex();
+        for entry in fs::read_dir(root)? {
+            let path = entry?.path();
+            if fs::read_to_string(path.join(REPOSITORY_FILE)).ok() == Some(location.clone()) {
+                return Ok(Some(Self { path }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Save the config and key files of the repository given by `location` into the cache.
+    /// Together with the cached snapshot, index and tree pack files, this allows to use the
+    /// repository offline. Only a hash of the location is saved as it may contain credentials.
+    pub fn save_repository_files(
+        &self,
+        be: &impl ReadBackend,
+        location: &str,
+    ) -> BackendResult<()> {
+        fs::write(
+            self.path.join(REPOSITORY_FILE),
+            hash(location.as_bytes()).to_hex(),
+        )?;
+        // the config file is always saved as changes need not change its size
+        let config = be.read_full(FileType::Config, &Id::default())?;
+        self.write_bytes(FileType::Config, &Id::default(), config)?;
+
+        let keys = be.list_with_size(FileType::Key)?;
+        self.remove_not_in_list(FileType::Key, &keys)?;
+        let cached_keys = self.list_with_size(FileType::Key)?;
+        for (id, _) in keys {
+            if !cached_keys.contains_key(&id) {
+                self.write_bytes(FileType::Key, &id, be.read_full(FileType::Key, &id)?)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn location(&self) -> &str {
         self.path.to_str().unwrap()
     }
 
+    /// Read a file with decrypted data from the cache, see `write_decrypted`.
+    pub fn read_decrypted(&self, name: &str) -> Option<Bytes> {
+        fs::read(self.path.join(name)).ok().map(Bytes::from)
+    }
+
+    /// Save decrypted data, e.g. metadata of all snapshots, into a file of the cache. The data
+    /// is not verified when reading, so it must be derived from content-addressed files only.
+    /// As the data is not encrypted, the file is only readable by the user.
+    pub fn write_decrypted(&self, name: &str, data: &[u8]) -> BackendResult<()> {
+        trace!("cache writing decrypted file {name}");
+        write_replace(&self.path.join(name), data)
+    }
+
+    /// Get a cache for decrypted data blobs within this cache which is limited to max_size bytes.
+    pub fn blob_cache(&self, max_size: u64) -> BackendResult<BlobCache> {
+        let path = self.path.join("blobs");
+        fs::create_dir_all(&path)?;
+        Ok(BlobCache { path, max_size })
+    }
+
     fn dir(&self, tpe: FileType, id: &Id) -> PathBuf {
         let hex_id = id.to_hex();
         self.path.join(tpe.name()).join(&hex_id[0..2])
@@ -149,7 +283,7 @@ impl Cache {
         self.path.join(tpe.name()).join(&hex_id[0..2]).join(&hex_id)
     }
 
-    pub fn list_with_size(&self, tpe: FileType) -> Result<HashMap<Id, u32>> {
+    pub fn list_with_size(&self, tpe: FileType) -> BackendResult<HashMap<Id, u64>> {
         let path = self.path.join(tpe.name());
 
         let walker = WalkDir::new(path)
@@ -171,14 +305,14 @@ impl Cache {
                 (
                     Id::from_hex(e.file_name().to_str().unwrap()).unwrap(),
                     // handle errors in metadata by returning a size of 0
-                    e.metadata().map_or(0, |m| m.len().try_into().unwrap_or(0)),
+                    e.metadata().map_or(0, |m| m.len()),
                 )
             });
 
         Ok(walker.collect())
     }
 
-    pub fn remove_not_in_list(&self, tpe: FileType, list: &Vec<(Id, u32)>) -> Result<()> {
+    pub fn remove_not_in_list(&self, tpe: FileType, list: &Vec<(Id, u64)>) -> BackendResult<()> {
         let mut list_cache = self.list_with_size(tpe)?;
         // remove present files from the cache list
         for (id, size) in list {
@@ -193,47 +327,254 @@ impl Cache {
         for id in list_cache.keys() {
             self.remove(tpe, id)?;
         }
+        self.remove_stale_tmp_files(tpe);
         Ok(())
     }
 
-    pub fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    /// Remove temporary files which are left over from interrupted writes. Only files older than
+    /// `MAX_TMP_AGE` are removed as newer ones may still be written by another process.
+    fn remove_stale_tmp_files(&self, tpe: FileType) {
+        let stale = |e: &walkdir::DirEntry| {
+            e.file_type().is_file()
+                && e.file_name().to_string_lossy().starts_with(TMP_PREFIX)
+                && matches!(
+                    e.metadata().map(|m| m.modified().map(|t| t.elapsed())),
+                    Ok(Ok(Ok(age))) if age > MAX_TMP_AGE
+                )
+        };
+        for e in WalkDir::new(self.path.join(tpe.name()))
+            .into_iter()
+            .filter_map(walkdir::Result::ok)
+            .filter(stale)
+        {
+            debug!("removing stale temporary cache file {:?}", e.path());
+            if let Err(err) = fs::remove_file(e.path()) {
+                warn!("error removing {:?}: {err}", e.path());
+            }
+        }
+    }
+
+    /// Read a file from the cache. As repository files (except the config) are content-addressed,
+    /// the content is verified and the cached file is removed if it doesn't match.
+    pub fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         trace!("cache reading tpe: {:?}, id: {}", &tpe, &id);
         let data = fs::read(self.path(tpe, id))?;
+        if tpe != FileType::Config && &hash(&data) != id {
+            warn!("cache: content of {tpe:?} file {id} does not match, removing it.");
+            self.remove(tpe, id)?;
+            return Err(BackendError::Corruption(format!(
+                "cache file {id} is corrupted"
+            )));
+        }
         trace!("cache hit!");
         Ok(data.into())
     }
 
-    fn read_partial(&self, tpe: FileType, id: &Id, offset: u32, length: u32) -> Result<Bytes> {
+    pub fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
         trace!(
             "cache reading tpe: {:?}, id: {}, offset: {}",
             &tpe,
             &id,
             &offset
         );
+        let range = FileRange::new(offset, length)?;
         let mut file = File::open(self.path(tpe, id))?;
-        file.seek(SeekFrom::Start(offset as u64))?;
+        range.check_size(file.metadata()?.len())?;
+        file.seek(SeekFrom::Start(offset))?;
         let mut vec = vec![0; length as usize];
         file.read_exact(&mut vec)?;
         trace!("cache hit!");
         Ok(vec.into())
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, buf: Bytes) -> Result<()> {
+    fn write_bytes(&self, tpe: FileType, id: &Id, buf: Bytes) -> BackendResult<()> {
         trace!("cache writing tpe: {:?}, id: {}", &tpe, &id);
         fs::create_dir_all(self.dir(tpe, id))?;
-        let filename = self.path(tpe, id);
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&filename)?;
-        file.write_all(&buf)?;
-        Ok(())
+        write_replace(&self.path(tpe, id), &buf)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id) -> Result<()> {
+    fn remove(&self, tpe: FileType, id: &Id) -> BackendResult<()> {
         trace!("cache writing tpe: {:?}, id: {}", &tpe, &id);
         let filename = self.path(tpe, id);
         fs::remove_file(filename)?;
         Ok(())
     }
 }
+
+/// Content-addressed cache for data blobs. Blobs are stored encrypted like in the pack files and
+/// are only decrypted when reading them. The cache size is bounded by removing the least recently
+/// used blobs when calling `trim`.
+#[derive(Clone, Debug)]
+pub struct BlobCache {
+    path: PathBuf,
+    max_size: u64,
+}
+
+impl BlobCache {
+    fn path(&self, id: &Id) -> PathBuf {
+        let hex_id = id.to_hex();
+        self.path.join(&hex_id[0..2]).join(&hex_id)
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.path(id).exists()
+    }
+
+    /// Get the decrypted blob from the cache. The content is verified and the blob is marked as
+    /// recently used.
+    pub fn get(
+        &self,
+        be: &impl DecryptReadBackend,
+        id: &Id,
+        uncompressed_length: Option<NonZeroU32>,
+    ) -> Option<Bytes> {
+        let path = self.path(id);
+        let data = fs::read(&path).ok()?;
+        match be.decrypt_blob(&data, uncompressed_length) {
+            Ok(data) if &hash(&data) == id => {
+                let _ = set_file_mtime(&path, FileTime::now());
+                trace!("blob cache hit: {id}");
+                Some(data)
+            }
+            _ => {
+                warn!("blob cache: content of {id} does not match, removing it.");
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Save the encrypted blob (as read from the pack file) in the cache
+    pub fn put(&self, id: &Id, encrypted: &[u8]) -> BackendResult<()> {
+        trace!("blob cache writing {id}");
+        let path = self.path(id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_replace(&path, encrypted)
+    }
+
+    /// Remove least recently used blobs until the cache size is below max_size.
+    pub fn trim(&self) -> BackendResult<()> {
+        let mut entries: Vec<_> = WalkDir::new(&self.path)
+            .into_iter()
+            .filter_map(walkdir::Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| {
+                let meta = e.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), e.into_path()))
+            })
+            .collect();
+
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        if size <= self.max_size {
+            return Ok(());
+        }
+
+        entries.sort_unstable();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(path)?;
+            size -= len;
+        }
+        debug!("blob cache trimmed to {size} bytes");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_not_in_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache = Cache::new(Id::default(), Some(dir.to_path_buf())).unwrap();
+
+        let data = |s: &str| Bytes::from(s.to_string());
+        let (unchanged, changed, removed) = (hash(b"a"), hash(b"bb"), hash(b"c"));
+        for (id, content) in [(unchanged, "a"), (changed, "bb"), (removed, "c")] {
+            cache
+                .write_bytes(FileType::Pack, &id, data(content))
+                .unwrap();
+        }
+
+        cache
+            .remove_not_in_list(FileType::Pack, &vec![(unchanged, 1), (changed, 3)])
+            .unwrap();
+        let cached = cache.list_with_size(FileType::Pack).unwrap();
+        assert_eq!(cached.into_keys().collect::<Vec<_>>(), vec![unchanged]);
+    }
+
+    #[test]
+    fn stale_tmp_files_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Id::default(), Some(dir.path().to_path_buf())).unwrap();
+        let id = hash(b"a");
+        cache.write_bytes(FileType::Index, &id, "a".into()).unwrap();
+
+        let tmp_dir = cache.dir(FileType::Index, &id);
+        let (stale, recent) = (tmp_dir.join(".tmpstale"), tmp_dir.join(".tmprecent"));
+        fs::write(&stale, "").unwrap();
+        fs::write(&recent, "").unwrap();
+        set_file_mtime(&stale, FileTime::from_unix_time(0, 0)).unwrap();
+
+        cache
+            .remove_not_in_list(FileType::Index, &vec![(id, 1)])
+            .unwrap();
+        assert!(!stale.exists());
+        assert!(recent.exists());
+        assert!(cache.read_full(FileType::Index, &id).is_ok());
+    }
+
+    #[test]
+    fn blob_cache() {
+        use crate::backend::{DecryptBackend, DecryptWriteBackend, LocalBackend};
+        use crate::crypto::{CryptoKey, Key};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(dir.path().join("repo").to_str().unwrap());
+        let be = DecryptBackend::new(&be, Key::new());
+        let cache = Cache::new(Id::default(), Some(dir.path().join("cache"))).unwrap();
+        let blob_cache = cache.blob_cache(1024).unwrap();
+
+        let data = b"secret data";
+        let id = hash(data);
+        let encrypted = be.key().encrypt_data(data).unwrap();
+        blob_cache.put(&id, &encrypted).unwrap();
+        assert!(blob_cache.contains(&id));
+        let path = blob_cache.path(&id);
+        assert_eq!(fs::read(&path).unwrap(), encrypted);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(blob_cache.get(&be, &id, None).unwrap(), &data[..]);
+
+        // a blob with wrong content is removed
+        let other = hash(b"other");
+        blob_cache.put(&other, &encrypted).unwrap();
+        assert!(blob_cache.get(&be, &other, None).is_none());
+        assert!(!blob_cache.contains(&other));
+    }
+
+    #[test]
+    fn decrypted_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Id::default(), Some(dir.path().to_path_buf())).unwrap();
+        cache.write_decrypted("snapshots.json", b"[]").unwrap();
+        let meta = fs::metadata(cache.path.join("snapshots.json")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(cache.read_decrypted("snapshots.json").unwrap(), "[]");
+    }
+}
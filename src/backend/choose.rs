@@ -1,25 +1,41 @@
-use anyhow::{bail, Result};
 use bytes::Bytes;
 
-use super::{FileType, Id, ReadBackend, WriteBackend};
-use super::{LocalBackend, RcloneBackend, RestBackend};
+#[cfg(feature = "opendal")]
+use super::OpenDALBackend;
+use super::{B2Backend, LocalBackend, OfflineBackend, RcloneBackend, RestBackend};
+use super::{BackendError, BackendResult, Capabilities, FileType, Id, ReadBackend, WriteBackend};
 
 #[derive(Clone)]
 pub enum ChooseBackend {
     Local(LocalBackend),
     Rest(RestBackend),
     Rclone(RcloneBackend),
+    B2(B2Backend),
+    #[cfg(feature = "opendal")]
+    OpenDAL(OpenDALBackend),
+    Offline(OfflineBackend),
 }
 
-use ChooseBackend::{Local, Rclone, Rest};
+#[cfg(feature = "opendal")]
+use ChooseBackend::OpenDAL;
+use ChooseBackend::{Local, Offline, Rclone, Rest, B2};
 
 impl ChooseBackend {
-    pub fn from_url(url: &str) -> Result<Self> {
+    pub fn from_url(url: &str) -> BackendResult<Self> {
         Ok(match url.split_once(':') {
             Some(("rclone", path)) => Rclone(RcloneBackend::new(path)?),
-            Some(("rest", path)) => Rest(RestBackend::new(path)),
+            Some(("rest", path)) => Rest(RestBackend::new(path)?),
+            Some(("b2", path)) => B2(B2Backend::new(path)?),
+            #[cfg(feature = "opendal")]
+            Some(("opendal", path)) => OpenDAL(OpenDALBackend::new(path)?),
+            #[cfg(not(feature = "opendal"))]
+            Some(("opendal", _)) => return Err(BackendError::Config("backend opendal is not supported by this build! Please compile rustic with feature \"opendal\".".to_string())),
             Some(("local", path)) => Local(LocalBackend::new(path)),
-            Some((backend, _)) => bail!("backend {backend} is not supported!"),
+            Some((backend, _)) => {
+                return Err(BackendError::Config(format!(
+                    "backend {backend} is not supported!"
+                )))
+            }
             None => Local(LocalBackend::new(url)),
         })
     }
@@ -31,30 +47,70 @@ impl ReadBackend for ChooseBackend {
             Local(local) => local.location(),
             Rest(rest) => rest.location(),
             Rclone(rclone) => rclone.location(),
+            B2(b2) => b2.location(),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.location(),
+            Offline(offline) => offline.location(),
         }
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        match self {
+            Local(local) => local.capabilities(),
+            Rest(rest) => rest.capabilities(),
+            Rclone(rclone) => rclone.capabilities(),
+            B2(b2) => b2.capabilities(),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.capabilities(),
+            Offline(offline) => offline.capabilities(),
+        }
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         match self {
             Local(local) => local.set_option(option, value),
             Rest(rest) => rest.set_option(option, value),
             Rclone(rclone) => rclone.set_option(option, value),
+            B2(b2) => b2.set_option(option, value),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.set_option(option, value),
+            Offline(offline) => offline.set_option(option, value),
+        }
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        match self {
+            Local(local) => local.list_foreign(tpe),
+            Rest(rest) => rest.list_foreign(tpe),
+            Rclone(rclone) => rclone.list_foreign(tpe),
+            B2(b2) => b2.list_foreign(tpe),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.list_foreign(tpe),
+            Offline(offline) => offline.list_foreign(tpe),
         }
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         match self {
             Local(local) => local.list_with_size(tpe),
             Rest(rest) => rest.list_with_size(tpe),
             Rclone(rclone) => rclone.list_with_size(tpe),
+            B2(b2) => b2.list_with_size(tpe),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.list_with_size(tpe),
+            Offline(offline) => offline.list_with_size(tpe),
         }
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         match self {
             Local(local) => local.read_full(tpe, id),
             Rest(rest) => rest.read_full(tpe, id),
             Rclone(rclone) => rclone.read_full(tpe, id),
+            B2(b2) => b2.read_full(tpe, id),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.read_full(tpe, id),
+            Offline(offline) => offline.read_full(tpe, id),
         }
     }
 
@@ -63,39 +119,93 @@ impl ReadBackend for ChooseBackend {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         match self {
             Local(local) => local.read_partial(tpe, id, cacheable, offset, length),
             Rest(rest) => rest.read_partial(tpe, id, cacheable, offset, length),
             Rclone(rclone) => rclone.read_partial(tpe, id, cacheable, offset, length),
+            B2(b2) => b2.read_partial(tpe, id, cacheable, offset, length),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.read_partial(tpe, id, cacheable, offset, length),
+            Offline(offline) => offline.read_partial(tpe, id, cacheable, offset, length),
         }
     }
 }
 
 impl WriteBackend for ChooseBackend {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         match self {
             Local(local) => local.create(),
             Rest(rest) => rest.create(),
             Rclone(rclone) => rclone.create(),
+            B2(b2) => b2.create(),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.create(),
+            Offline(offline) => offline.create(),
         }
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         match self {
             Local(local) => local.write_bytes(tpe, id, cacheable, buf),
             Rest(rest) => rest.write_bytes(tpe, id, cacheable, buf),
             Rclone(rclone) => rclone.write_bytes(tpe, id, cacheable, buf),
+            B2(b2) => b2.write_bytes(tpe, id, cacheable, buf),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.write_bytes(tpe, id, cacheable, buf),
+            Offline(offline) => offline.write_bytes(tpe, id, cacheable, buf),
         }
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        match self {
+            Local(local) => local.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+            Rest(rest) => rest.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+            Rclone(rclone) => rclone.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+            B2(b2) => b2.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+            Offline(offline) => offline.write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+        }
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         match self {
             Local(local) => local.remove(tpe, id, cacheable),
             Rest(rest) => rest.remove(tpe, id, cacheable),
             Rclone(rclone) => rclone.remove(tpe, id, cacheable),
+            B2(b2) => b2.remove(tpe, id, cacheable),
+            #[cfg(feature = "opendal")]
+            OpenDAL(opendal) => opendal.remove(tpe, id, cacheable),
+            Offline(offline) => offline.remove(tpe, id, cacheable),
+        }
+    }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+    ) -> BackendResult<bool> {
+        match (self, from) {
+            (Local(local), Local(from)) => local.copy_from(from, tpe, id, cacheable),
+            _ => Ok(false),
         }
     }
 }
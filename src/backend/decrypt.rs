@@ -1,27 +1,36 @@
 use std::num::NonZeroU32;
 
-use anyhow::{bail, Result};
 use bytes::Bytes;
 use crossbeam_channel::{unbounded, Receiver};
 use indicatif::ProgressBar;
 use rayon::prelude::*;
 use zstd::stream::{copy_encode, decode_all};
 
-use super::{FileType, Id, ReadBackend, RepoFile, WriteBackend};
+use super::{
+    BackendError, BackendResult, Cache, Capabilities, FileType, Id, ReadBackend, RepoFile,
+    WriteBackend,
+};
 use crate::crypto::{hash, CryptoKey};
 
 pub trait DecryptFullBackend: DecryptWriteBackend + DecryptReadBackend {}
 impl<T: DecryptWriteBackend + DecryptReadBackend> DecryptFullBackend for T {}
 
 pub trait DecryptReadBackend: ReadBackend {
-    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>>;
+    fn decrypt(&self, data: &[u8]) -> BackendResult<Vec<u8>>;
 
-    fn read_encrypted_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_encrypted_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         let decrypted = self.decrypt(&self.read_full(tpe, id)?)?;
         Ok(match decrypted[0] {
-            b'{' | b'[' => decrypted,          // not compressed
-            2 => decode_all(&decrypted[1..])?, // 2 indicates compressed data following
-            _ => bail!("not supported"),
+            b'{' | b'[' => decrypted, // not compressed
+            // 2 indicates compressed data following
+            2 => decode_all(&decrypted[1..]).map_err(|err| {
+                BackendError::Corruption(format!("error decompressing {tpe:?} file {id}: {err}"))
+            })?,
+            _ => {
+                return Err(BackendError::Corruption(format!(
+                    "{tpe:?} file {id} has an unsupported format"
+                )))
+            }
         }
         .into())
     }
@@ -31,31 +40,53 @@ pub trait DecryptReadBackend: ReadBackend {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
         uncompressed_length: Option<NonZeroU32>,
-    ) -> Result<Bytes> {
-        let mut data = self.decrypt(&self.read_partial(tpe, id, cacheable, offset, length)?)?;
+    ) -> BackendResult<Bytes> {
+        self.decrypt_blob(
+            &self.read_partial(tpe, id, cacheable, offset, length)?,
+            uncompressed_length,
+        )
+    }
+
+    /// decrypt (and decompress, if needed) an encrypted blob
+    fn decrypt_blob(
+        &self,
+        data: &[u8],
+        uncompressed_length: Option<NonZeroU32>,
+    ) -> BackendResult<Bytes> {
+        let mut data = self.decrypt(data)?;
         if let Some(length) = uncompressed_length {
-            data = decode_all(&*data).unwrap();
+            data = decode_all(&*data).map_err(|err| {
+                BackendError::Corruption(format!("error decompressing blob: {err}"))
+            })?;
             if data.len() != length.get() as usize {
-                bail!("length of uncompressed data does not match!");
+                return Err(BackendError::Corruption(
+                    "length of uncompressed data does not match!".to_string(),
+                ));
             }
         }
         Ok(data.into())
     }
 
-    fn get_file<F: RepoFile>(&self, id: &Id) -> Result<F> {
+    fn get_file<F: RepoFile>(&self, id: &Id) -> BackendResult<F> {
         let data = self.read_encrypted_full(F::TYPE, id)?;
-        Ok(serde_json::from_slice(&data)?)
+        serde_json::from_slice(&data).map_err(|err| {
+            BackendError::Corruption(format!("error parsing {:?} file {id}: {err}", F::TYPE))
+        })
     }
 
-    fn stream_all<F: RepoFile>(&self, p: ProgressBar) -> Result<Receiver<(Id, F)>> {
+    fn stream_all<F: RepoFile>(&self, p: ProgressBar) -> BackendResult<Receiver<(Id, F)>> {
         let list = self.list(F::TYPE)?;
         self.stream_list(list, p)
     }
 
-    fn stream_list<F: RepoFile>(&self, list: Vec<Id>, p: ProgressBar) -> Result<Receiver<(Id, F)>> {
+    fn stream_list<F: RepoFile>(
+        &self,
+        list: Vec<Id>,
+        p: ProgressBar,
+    ) -> BackendResult<Receiver<(Id, F)>> {
         p.set_length(list.len() as u64);
         let (tx, rx) = unbounded();
 
@@ -72,14 +103,26 @@ pub trait DecryptReadBackend: ReadBackend {
 pub trait DecryptWriteBackend: WriteBackend {
     type Key: CryptoKey;
     fn key(&self) -> &Self::Key;
-    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> Result<Id>;
+    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> BackendResult<Id>;
 
-    fn save_file<F: RepoFile>(&self, file: &F) -> Result<Id> {
-        let data = serde_json::to_vec(file)?;
+    fn save_file<F: RepoFile>(&self, file: &F) -> BackendResult<Id> {
+        let data = serde_json::to_vec(file).map_err(|err| BackendError::Other(err.into()))?;
         self.hash_write_full(F::TYPE, &data)
     }
 
-    fn save_list<F: RepoFile>(&self, list: Vec<F>, p: ProgressBar) -> Result<()> {
+    /// Save a file which is overwritten, like the config file, only if the current file has the
+    /// hash `expected`. Returns `false` if it was changed in the meantime.
+    /// The file is never compressed.
+    fn save_file_if_unchanged<F: RepoFile>(&self, file: &F, expected: &Id) -> BackendResult<bool> {
+        let data = serde_json::to_vec(file).map_err(|err| BackendError::Other(err.into()))?;
+        let data = self
+            .key()
+            .encrypt_data(&data)
+            .map_err(|err| BackendError::Other(err.into()))?;
+        self.write_bytes_if_unchanged(F::TYPE, &Id::default(), false, expected, data.into())
+    }
+
+    fn save_list<F: RepoFile>(&self, list: Vec<F>, p: ProgressBar) -> BackendResult<()> {
         p.set_length(list.len() as u64);
         list.par_iter().for_each(|file| {
             self.save_file(file).unwrap();
@@ -95,12 +138,13 @@ pub trait DecryptWriteBackend: WriteBackend {
         cacheable: bool,
         list: Vec<Id>,
         p: ProgressBar,
-    ) -> Result<()> {
+    ) -> BackendResult<()> {
         p.set_length(list.len() as u64);
-        list.par_iter().for_each(|id| {
-            self.remove(tpe, id, cacheable).unwrap();
+        list.par_iter().try_for_each(|id| -> BackendResult<()> {
+            self.remove(tpe, id, cacheable)?;
             p.inc(1);
-        });
+            Ok(())
+        })?;
 
         p.finish();
         Ok(())
@@ -109,7 +153,7 @@ pub trait DecryptWriteBackend: WriteBackend {
     fn set_zstd(&mut self, zstd: Option<i32>);
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct DecryptBackend<R, C> {
     backend: R,
     key: C,
@@ -132,15 +176,17 @@ impl<R: WriteBackend, C: CryptoKey> DecryptWriteBackend for DecryptBackend<R, C>
         &self.key
     }
 
-    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> Result<Id> {
-        let data = match self.zstd {
+    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> BackendResult<Id> {
+        // the config file is never compressed as it defines whether compression is used
+        let data = match self.zstd.filter(|_| tpe != FileType::Config) {
             Some(level) => {
                 let mut out = vec![2_u8];
                 copy_encode(data, &mut out, level)?;
-                self.key().encrypt_data(&out)?
+                self.key().encrypt_data(&out)
             }
-            None => self.key().encrypt_data(data)?,
-        };
+            None => self.key().encrypt_data(data),
+        }
+        .map_err(|err| BackendError::Other(err.into()))?;
         let id = hash(&data);
         self.write_bytes(tpe, &id, false, data.into())?;
         Ok(id)
@@ -152,8 +198,10 @@ impl<R: WriteBackend, C: CryptoKey> DecryptWriteBackend for DecryptBackend<R, C>
 }
 
 impl<R: ReadBackend, C: CryptoKey> DecryptReadBackend for DecryptBackend<R, C> {
-    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        Ok(self.key.decrypt_data(data)?)
+    fn decrypt(&self, data: &[u8]) -> BackendResult<Vec<u8>> {
+        self.key
+            .decrypt_data(data)
+            .map_err(|err| BackendError::Corruption(format!("decryption failed: {err}")))
     }
 }
 
@@ -162,19 +210,31 @@ impl<R: ReadBackend, C: CryptoKey> ReadBackend for DecryptBackend<R, C> {
         self.backend.location()
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        self.backend.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         self.backend.set_option(option, value)
     }
 
-    fn list(&self, tpe: FileType) -> Result<Vec<Id>> {
+    fn cache(&self) -> Option<&Cache> {
+        self.backend.cache()
+    }
+
+    fn list(&self, tpe: FileType) -> BackendResult<Vec<Id>> {
         self.backend.list(tpe)
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.backend.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         self.backend.list_with_size(tpe)
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         self.backend.read_full(tpe, id)
     }
 
@@ -183,24 +243,42 @@ impl<R: ReadBackend, C: CryptoKey> ReadBackend for DecryptBackend<R, C> {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         self.backend
             .read_partial(tpe, id, cacheable, offset, length)
     }
 }
 
 impl<R: WriteBackend, C: CryptoKey> WriteBackend for DecryptBackend<R, C> {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         self.backend.create()
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         self.backend.write_bytes(tpe, id, cacheable, buf)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        self.backend
+            .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         self.backend.remove(tpe, id, cacheable)
     }
 }
@@ -1,9 +1,8 @@
-use anyhow::Result;
 use bytes::Bytes;
 
 use super::{
-    DecryptFullBackend, DecryptReadBackend, DecryptWriteBackend, FileType, Id, ReadBackend,
-    WriteBackend,
+    BackendResult, Cache, Capabilities, DecryptFullBackend, DecryptReadBackend,
+    DecryptWriteBackend, FileType, Id, ReadBackend, WriteBackend,
 };
 
 #[derive(Clone)]
@@ -19,7 +18,7 @@ impl<BE: DecryptFullBackend> DryRunBackend<BE> {
 }
 
 impl<BE: DecryptFullBackend> DecryptReadBackend for DryRunBackend<BE> {
-    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+    fn decrypt(&self, data: &[u8]) -> BackendResult<Vec<u8>> {
         self.be.decrypt(data)
     }
 }
@@ -29,15 +28,27 @@ impl<BE: DecryptFullBackend> ReadBackend for DryRunBackend<BE> {
         self.be.location()
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         self.be.set_option(option, value)
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn cache(&self) -> Option<&Cache> {
+        self.be.cache()
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         self.be.list_with_size(tpe)
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         self.be.read_full(tpe, id)
     }
 
@@ -46,9 +57,9 @@ impl<BE: DecryptFullBackend> ReadBackend for DryRunBackend<BE> {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         self.be.read_partial(tpe, id, cacheable, offset, length)
     }
 }
@@ -60,7 +71,7 @@ impl<BE: DecryptFullBackend> DecryptWriteBackend for DryRunBackend<BE> {
         self.be.key()
     }
 
-    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> Result<Id> {
+    fn hash_write_full(&self, tpe: FileType, data: &[u8]) -> BackendResult<Id> {
         match self.dry_run {
             true => Ok(Id::default()),
             false => self.be.hash_write_full(tpe, data),
@@ -76,21 +87,43 @@ impl<BE: DecryptFullBackend> DecryptWriteBackend for DryRunBackend<BE> {
 }
 
 impl<BE: DecryptFullBackend> WriteBackend for DryRunBackend<BE> {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         match self.dry_run {
             true => Ok(()),
             false => self.be.create(),
         }
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         match self.dry_run {
             true => Ok(()),
             false => self.be.write_bytes(tpe, id, cacheable, buf),
         }
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        match self.dry_run {
+            true => Ok(true),
+            false => self
+                .be
+                .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf),
+        }
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         match self.dry_run {
             true => Ok(()),
             false => self.be.remove(tpe, id, cacheable),
@@ -0,0 +1,152 @@
+use std::io;
+
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// BackendError describes the errors that can be returned by backends.
+///
+/// It allows to react on the kind of error, e.g. retry transient network errors or
+/// treat missing files differently, without inspecting error messages.
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("corrupted data: {0}")]
+    Corruption(String),
+
+    #[error("transient network error: {0}")]
+    NetworkTransient(String),
+
+    #[error("network error: {0}")]
+    Network(String),
+
+    #[error("invalid backend configuration: {0}")]
+    Config(String),
+
+    #[error("not enough space: {0}")]
+    InsufficientSpace(String),
+
+    #[error("IO error: {0}")]
+    Io(io::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+
+impl BackendError {
+    /// Returns true if the operation may succeed when retried later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::NetworkTransient(_) => true,
+            Self::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for BackendError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(err.to_string()),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(err.to_string()),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                Self::Corruption(err.to_string())
+            }
+            _ => Self::Io(err),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<nix::errno::Errno> for BackendError {
+    fn from(err: nix::errno::Errno) -> Self {
+        io::Error::from(err).into()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<reqwest::Error> for BackendError {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(StatusCode::NOT_FOUND) => Self::NotFound(err.to_string()),
+            Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                Self::PermissionDenied(err.to_string())
+            }
+            Some(status) if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => {
+                Self::NetworkTransient(err.to_string())
+            }
+            None if err.is_timeout() || err.is_connect() || err.is_request() || err.is_body() => {
+                Self::NetworkTransient(err.to_string())
+            }
+            _ => Self::Network(err.to_string()),
+        }
+    }
+}
+
+impl<E: Into<BackendError>> From<backoff::Error<E>> for BackendError {
+    fn from(err: backoff::Error<E>) -> Self {
+        match err {
+            backoff::Error::Permanent(err) | backoff::Error::Transient { err, .. } => err.into(),
+        }
+    }
+}
+
+#[cfg(feature = "opendal")]
+impl From<opendal::Error> for BackendError {
+    fn from(err: opendal::Error) -> Self {
+        match err.kind() {
+            opendal::ErrorKind::NotFound => Self::NotFound(err.to_string()),
+            opendal::ErrorKind::PermissionDenied => Self::PermissionDenied(err.to_string()),
+            opendal::ErrorKind::ConfigInvalid => Self::Config(err.to_string()),
+            _ if err.is_temporary() => Self::NetworkTransient(err.to_string()),
+            _ => Self::Other(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_kinds() {
+        let err: BackendError = io::Error::from(io::ErrorKind::NotFound).into();
+        assert!(matches!(err, BackendError::NotFound(_)));
+        let err: BackendError = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert!(matches!(err, BackendError::PermissionDenied(_)));
+        let err: BackendError = io::Error::from(io::ErrorKind::TimedOut).into();
+        assert!(err.is_transient());
+        let err: BackendError =
+            backoff::Error::permanent(io::Error::from(io::ErrorKind::NotFound)).into();
+        assert!(matches!(err, BackendError::NotFound(_)));
+    }
+
+    #[test]
+    fn corrupted_file_range() {
+        use crate::backend::FileRange;
+
+        let range = FileRange::new(10, 5).unwrap();
+        assert_eq!(range.http_range(), "bytes=10-14");
+        assert!(range.check_size(15).is_ok());
+        assert!(matches!(
+            range.check_size(14),
+            Err(BackendError::Corruption(_))
+        ));
+        assert!(range.check_read(vec![0; 4].into()).is_err());
+        assert!(matches!(
+            FileRange::new(u64::MAX, 1),
+            Err(BackendError::Corruption(_))
+        ));
+        assert!(FileRange::new(0, 0).is_err());
+    }
+}
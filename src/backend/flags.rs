@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use super::BackendResult;
+
+// consts from linux/fs.h, see also chattr(1)
+pub const FS_NODUMP_FL: u32 = 0x00000040; // do not dump file
+
+// flags which can be changed by the user (FS_FL_USER_MODIFIABLE). All other flags are managed by
+// the filesystem, e.g. FS_EXTENT_FL, and are neither saved nor restored.
+pub const FS_USER_FLAGS: u32 = 0x000380FF;
+
+/// Merge the user-modifiable flags `flags` into the `current` flags of a file.
+pub fn merge_flags(current: u32, flags: u32) -> u32 {
+    (current & !FS_USER_FLAGS) | (flags & FS_USER_FLAGS)
+}
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use std::mem::size_of;
+
+    use nix::libc::{c_int, c_long};
+    use nix::{ioctl_read_bad, ioctl_write_ptr_bad, request_code_read, request_code_write};
+
+    // The kernel defines FS_IOC_GETFLAGS/FS_IOC_SETFLAGS with type long, but actually uses an int.
+    ioctl_read_bad!(
+        fs_ioc_getflags,
+        request_code_read!(b'f', 1, size_of::<c_long>()),
+        c_int
+    );
+    ioctl_write_ptr_bad!(
+        fs_ioc_setflags,
+        request_code_write!(b'f', 2, size_of::<c_long>()),
+        c_int
+    );
+}
+
+/// Get the user-modifiable file attributes (as shown by lsattr) of a regular file or directory.
+/// Returns `None` if the filesystem doesn't support file attributes.
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub fn get_flags(path: &Path) -> BackendResult<Option<u32>> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    use nix::errno::Errno;
+    use nix::libc::{O_NOFOLLOW, O_NONBLOCK};
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_NONBLOCK | O_NOFOLLOW)
+        .open(path)?;
+    let mut flags = 0;
+    match unsafe { ioctl::fs_ioc_getflags(file.as_raw_fd(), &mut flags) } {
+        Ok(_) => Ok(Some(flags as u32 & FS_USER_FLAGS)),
+        Err(Errno::ENOTTY | Errno::EOPNOTSUPP | Errno::EINVAL) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_flags(_path: &Path) -> BackendResult<Option<u32>> {
+    Ok(None)
+}
+
+/// Set the user-modifiable file attributes (as done by chattr) of a regular file or directory.
+/// Flags managed by the filesystem are kept.
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub fn set_flags(path: &Path, flags: u32) -> BackendResult<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    use nix::libc::{O_NOFOLLOW, O_NONBLOCK};
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_NONBLOCK | O_NOFOLLOW)
+        .open(path)?;
+    let mut current = 0;
+    unsafe { ioctl::fs_ioc_getflags(file.as_raw_fd(), &mut current) }?;
+    let flags = merge_flags(current as u32, flags) as i32;
+    unsafe { ioctl::fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_flags(_path: &Path, _flags: u32) -> BackendResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS_IMMUTABLE_FL: u32 = 0x00000010;
+    const FS_APPEND_FL: u32 = 0x00000020;
+    const FS_EXTENT_FL: u32 = 0x00080000;
+
+    #[test]
+    fn user_flags() {
+        let flags = FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL;
+        assert_eq!(flags & FS_USER_FLAGS, flags);
+        assert_eq!(FS_EXTENT_FL & FS_USER_FLAGS, 0);
+        assert_eq!(
+            merge_flags(FS_EXTENT_FL | FS_APPEND_FL, FS_NODUMP_FL | FS_EXTENT_FL),
+            FS_EXTENT_FL | FS_NODUMP_FL
+        );
+    }
+}
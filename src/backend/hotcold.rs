@@ -1,7 +1,6 @@
-use anyhow::Result;
 use bytes::Bytes;
 
-use super::{FileType, Id, ReadBackend, WriteBackend};
+use super::{BackendResult, Capabilities, FileType, Id, ReadBackend, WriteBackend};
 
 #[derive(Clone)]
 pub struct HotColdBackend<BE: WriteBackend> {
@@ -13,6 +12,11 @@ impl<BE: WriteBackend> HotColdBackend<BE> {
     pub fn new(be: BE, hot_be: Option<BE>) -> Self {
         Self { be, hot_be }
     }
+
+    /// The cold backend which contains all files of the repository
+    pub fn cold(&self) -> &BE {
+        &self.be
+    }
 }
 
 impl<BE: WriteBackend> ReadBackend for HotColdBackend<BE> {
@@ -20,15 +24,31 @@ impl<BE: WriteBackend> ReadBackend for HotColdBackend<BE> {
         self.be.location()
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         self.be.set_option(option, value)
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
-        self.be.list_with_size(tpe)
+    // foreign files of the hot repository are checked separately using the hot backend
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        // The hot backend contains all files except data packs, so only listing packs needs
+        // to access the cold backend.
+        match (&self.hot_be, tpe) {
+            (Some(be), FileType::Snapshot | FileType::Index | FileType::Key) => {
+                be.list_with_size(tpe)
+            }
+            _ => self.be.list_with_size(tpe),
+        }
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         match &self.hot_be {
             None => self.be.read_full(tpe, id),
             Some(be) => be.read_full(tpe, id),
@@ -40,9 +60,9 @@ impl<BE: WriteBackend> ReadBackend for HotColdBackend<BE> {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         match (&self.hot_be, cacheable || tpe != FileType::Pack) {
             (None, _) | (Some(_), false) => {
                 self.be.read_partial(tpe, id, cacheable, offset, length)
@@ -53,11 +73,17 @@ impl<BE: WriteBackend> ReadBackend for HotColdBackend<BE> {
 }
 
 impl<BE: WriteBackend> WriteBackend for HotColdBackend<BE> {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         self.be.create()
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         if let Some(be) = &self.hot_be {
             if tpe != FileType::Config && (cacheable || tpe != FileType::Pack) {
                 be.write_bytes(tpe, id, cacheable, buf.clone())?;
@@ -66,7 +92,30 @@ impl<BE: WriteBackend> WriteBackend for HotColdBackend<BE> {
         self.be.write_bytes(tpe, id, cacheable, buf)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        // the cold file is compared; the hot file is only written if it was replaced
+        if !self
+            .be
+            .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf.clone())?
+        {
+            return Ok(false);
+        }
+        if let Some(be) = &self.hot_be {
+            if tpe != FileType::Config && (cacheable || tpe != FileType::Pack) {
+                be.write_bytes(tpe, id, cacheable, buf)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         // First remove cold file
         self.be.remove(tpe, id, cacheable)?;
         if let Some(be) = &self.hot_be {
@@ -76,4 +125,18 @@ impl<BE: WriteBackend> WriteBackend for HotColdBackend<BE> {
         }
         Ok(())
     }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+    ) -> BackendResult<bool> {
+        match &self.hot_be {
+            // hot files would need to be written to the hot backend, too
+            Some(_) => Ok(false),
+            None => self.be.copy_from(&from.be, tpe, id, cacheable),
+        }
+    }
 }
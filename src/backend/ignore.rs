@@ -1,8 +1,8 @@
+use std::fmt;
 use std::fs::{read_link, File};
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
 use bytesize::ByteSize;
 use chrono::{Local, TimeZone, Utc};
 use clap::Parser;
@@ -11,31 +11,57 @@ use log::*;
 use merge::Merge;
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
+use unicode_normalization::UnicodeNormalization;
 use users::{Groups, Users, UsersCache};
 
-use super::{node::Metadata, node::NodeType, Node, ReadSource};
+use super::{
+    flags, node::ExtendedAttribute, node::Metadata, node::NodeType, BackendError, BackendResult,
+    Node, ReadSource,
+};
 
 pub struct LocalSource {
     builder: WalkBuilder,
     walker: Walk,
     with_atime: bool,
     ignore_devid: bool,
+    deterministic: bool,
     cache: UsersCache,
 }
 
+impl fmt::Debug for LocalSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalSource")
+            .field("builder", &self.builder)
+            .field("with_atime", &self.with_atime)
+            .field("ignore_devid", &self.ignore_devid)
+            .field("deterministic", &self.deterministic)
+            .finish_non_exhaustive()
+    }
+}
+
 #[serde_as]
-#[derive(Default, Clone, Parser, Deserialize, Merge)]
+#[derive(Default, Clone, Debug, Parser, Deserialize, Merge)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct LocalSourceOptions {
     /// Save access time for files and directories
-    #[clap(long)]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    with_atime: bool,
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    with_atime: Option<bool>,
 
     /// Don't save device ID for files and directories
-    #[clap(long)]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    ignore_devid: bool,
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    ignore_devid: Option<bool>,
 
     /// Glob pattern to exclude/include (can be specified multiple times)
     #[clap(long, short = 'g', help_heading = "EXCLUDE OPTIONS")]
@@ -57,20 +83,54 @@ pub struct LocalSourceOptions {
     #[merge(strategy = merge::vec::overwrite_empty)]
     iglob_file: Vec<String>,
 
+    /// Exclude files matching this pattern, ignoring the casing of filenames (can be specified multiple times)
+    #[clap(long, value_name = "PATTERN", help_heading = "EXCLUDE OPTIONS")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    iexclude: Vec<String>,
+
+    /// Re-include files matching this pattern, ignoring the casing of filenames (can be specified multiple times)
+    #[clap(long, value_name = "PATTERN", help_heading = "EXCLUDE OPTIONS")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    iinclude: Vec<String>,
+
     /// Ignore files based on .gitignore files
-    #[clap(long, help_heading = "EXCLUDE OPTIONS")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    git_ignore: bool,
+    #[clap(
+        long,
+        help_heading = "EXCLUDE OPTIONS",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    git_ignore: Option<bool>,
 
     /// Exclude contents of directories containing this filename (can be specified multiple times)
     #[clap(long, value_name = "FILE", help_heading = "EXCLUDE OPTIONS")]
     #[merge(strategy = merge::vec::overwrite_empty)]
     exclude_if_present: Vec<String>,
 
+    /// Exclude files and directories which have the nodump attribute set (see chattr(1))
+    #[clap(
+        long,
+        help_heading = "EXCLUDE OPTIONS",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    exclude_nodump: Option<bool>,
+
     /// Exclude other file systems, don't cross filesystem boundaries and subvolumes
-    #[clap(long, short = 'x', help_heading = "EXCLUDE OPTIONS")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    one_file_system: bool,
+    #[clap(
+        long,
+        short = 'x',
+        help_heading = "EXCLUDE OPTIONS",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    one_file_system: Option<bool>,
 
     /// Maximum size of files to be backuped. Larger files will be excluded.
     #[clap(long, value_name = "SIZE", help_heading = "EXCLUDE OPTIONS")]
@@ -79,7 +139,7 @@ pub struct LocalSourceOptions {
 }
 
 impl LocalSource {
-    pub fn new(opts: LocalSourceOptions, backup_path: PathBuf) -> Result<Self> {
+    pub fn new(opts: LocalSourceOptions, backup_path: PathBuf) -> BackendResult<Self> {
         let mut walk_builder = WalkBuilder::new(backup_path);
         /*
          for path in &paths[1..] {
@@ -90,37 +150,52 @@ impl LocalSource {
         let mut override_builder = OverrideBuilder::new("/");
 
         for g in opts.glob {
-            override_builder.add(&g)?;
+            add_pattern(&mut override_builder, &g)?;
         }
 
         for file in opts.glob_file {
             for line in std::fs::read_to_string(file)?.lines() {
-                override_builder.add(line)?;
+                add_pattern(&mut override_builder, line)?;
             }
         }
 
-        override_builder.case_insensitive(true)?;
+        override_builder
+            .case_insensitive(true)
+            .map_err(|err| BackendError::Config(err.to_string()))?;
         for g in opts.iglob {
-            override_builder.add(&g)?;
+            add_pattern(&mut override_builder, &g)?;
         }
 
         for file in opts.iglob_file {
             for line in std::fs::read_to_string(file)?.lines() {
-                override_builder.add(line)?;
+                add_pattern(&mut override_builder, line)?;
             }
         }
 
+        for g in opts.iexclude {
+            add_pattern(&mut override_builder, &format!("!{g}"))?;
+        }
+
+        for g in opts.iinclude {
+            add_pattern(&mut override_builder, &g)?;
+        }
+
         walk_builder
             .follow_links(false)
             .hidden(false)
             .ignore(false)
-            .git_ignore(opts.git_ignore)
+            .git_ignore(opts.git_ignore.unwrap_or(false))
             .sort_by_file_path(Path::cmp)
-            .same_file_system(opts.one_file_system)
+            .same_file_system(opts.one_file_system.unwrap_or(false))
             .max_filesize(opts.exclude_larger_than.map(|s| s.as_u64()))
-            .overrides(override_builder.build()?);
-
-        if !opts.exclude_if_present.is_empty() {
+            .overrides(
+                override_builder
+                    .build()
+                    .map_err(|err| BackendError::Config(err.to_string()))?,
+            );
+
+        if !opts.exclude_if_present.is_empty() || opts.exclude_nodump == Some(true) {
+            let exclude_nodump = opts.exclude_nodump.unwrap_or(false);
             walk_builder.filter_entry(move |entry| match entry.file_type() {
                 None => true,
                 Some(tpe) if tpe.is_dir() => {
@@ -129,8 +204,9 @@ impl LocalSource {
                             return false;
                         }
                     }
-                    true
+                    !(exclude_nodump && has_nodump(entry.path()))
                 }
+                Some(tpe) if tpe.is_file() => !(exclude_nodump && has_nodump(entry.path())),
                 Some(_) => true,
             });
         }
@@ -141,19 +217,68 @@ impl LocalSource {
         Ok(Self {
             builder,
             walker,
-            with_atime: opts.with_atime,
-            ignore_devid: opts.ignore_devid,
+            with_atime: opts.with_atime.unwrap_or(false),
+            ignore_devid: opts.ignore_devid.unwrap_or(false),
+            deterministic: false,
             cache: UsersCache::new(),
         })
     }
+
+    /// Only save metadata which doesn't change when identical content is recreated elsewhere:
+    /// atime, ctime, inode and device ID are not saved and extended attributes are sorted by name.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+}
+
+/// Check if the nodump attribute is set for a file or directory
+fn has_nodump(path: &Path) -> bool {
+    match flags::get_flags(path) {
+        Ok(flags) => flags.unwrap_or_default() & flags::FS_NODUMP_FL != 0,
+        Err(err) => {
+            warn!("{path:?}: could not get file attributes: {err}");
+            false
+        }
+    }
+}
+
+/// Add a glob pattern to the override builder.
+///
+/// Filenames may be stored in different unicode normalization forms (e.g. macOS uses NFD),
+/// so the NFC and NFD variants of the pattern are added as well if they differ.
+pub(crate) fn add_pattern(builder: &mut OverrideBuilder, pattern: &str) -> BackendResult<()> {
+    let mut add = |pattern: &str| {
+        builder
+            .add(pattern)
+            .map(|_| ())
+            .map_err(|err| BackendError::Config(format!("invalid glob {pattern}: {err}")))
+    };
+    add(pattern)?;
+    let nfc: String = pattern.nfc().collect();
+    if nfc != pattern {
+        add(&nfc)?;
+    }
+    let nfd: String = pattern.nfd().collect();
+    if nfd != pattern {
+        add(&nfd)?;
+    }
+    Ok(())
+}
+
+/// Convert an error of walking the backup source, keeping IO errors
+fn walk_error(err: ignore::Error) -> BackendError {
+    if err.io_error().is_some() {
+        return err.into_io_error().unwrap().into();
+    }
+    BackendError::Other(err.into())
 }
 
 impl ReadSource for LocalSource {
     type Reader = File;
-    fn read(path: &Path) -> Result<Self::Reader> {
+    fn read(path: &Path) -> BackendResult<Self::Reader> {
         Ok(File::open(path)?)
     }
-    fn size(&self) -> Result<u64> {
+    fn size(&self) -> BackendResult<u64> {
         let mut size = 0;
         for entry in self.builder.build() {
             if let Err(e) = entry.and_then(|e| e.metadata()).map(|m| {
@@ -167,7 +292,7 @@ impl ReadSource for LocalSource {
 }
 
 impl Iterator for LocalSource {
-    type Item = Result<(PathBuf, Node)>;
+    type Item = BackendResult<(PathBuf, Node)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.walker.next() {
@@ -177,7 +302,24 @@ impl Iterator for LocalSource {
             }
             item => item,
         }
-        .map(|e| map_entry(e?, self.with_atime, self.ignore_devid, &self.cache))
+        .map(|e| {
+            let (path, mut node) = map_entry(
+                e.map_err(walk_error)?,
+                self.with_atime,
+                self.ignore_devid,
+                &self.cache,
+            )?;
+            if self.deterministic {
+                let meta = &mut node.meta;
+                meta.atime = None;
+                meta.ctime = None;
+                meta.inode = 0;
+                meta.device_id = 0;
+                meta.extended_attributes
+                    .sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            }
+            Ok((path, node))
+        })
     }
 }
 
@@ -187,9 +329,10 @@ fn map_entry(
     with_atime: bool,
     ignore_devid: bool,
     cache: &UsersCache,
-) -> Result<(PathBuf, Node)> {
+) -> BackendResult<(PathBuf, Node)> {
     let name = entry.file_name();
-    let m = entry.metadata()?;
+    let m = entry.metadata().map_err(walk_error)?;
+    let nsec = |nsec: i64| u32::try_from(nsec).map_err(|err| BackendError::Other(err.into()));
 
     let uid = m.uid();
     let gid = m.gid();
@@ -201,11 +344,11 @@ fn map_entry(
         .map(|g| g.name().to_str().unwrap().to_string());
 
     let mtime = Utc
-        .timestamp_opt(m.mtime(), m.mtime_nsec().try_into()?)
+        .timestamp_opt(m.mtime(), nsec(m.mtime_nsec())?)
         .single()
         .map(|dt| dt.with_timezone(&Local));
     let atime = if with_atime {
-        Utc.timestamp_opt(m.atime(), m.atime_nsec().try_into()?)
+        Utc.timestamp_opt(m.atime(), nsec(m.atime_nsec())?)
             .single()
             .map(|dt| dt.with_timezone(&Local))
     } else {
@@ -213,7 +356,7 @@ fn map_entry(
         mtime
     };
     let ctime = Utc
-        .timestamp_opt(m.ctime(), m.ctime_nsec().try_into()?)
+        .timestamp_opt(m.ctime(), nsec(m.ctime_nsec())?)
         .single()
         .map(|dt| dt.with_timezone(&Local));
     let size = if m.is_dir() { 0 } else { m.len() };
@@ -221,6 +364,16 @@ fn map_entry(
     let inode = m.ino();
     let device_id = if ignore_devid { 0 } else { m.dev() };
     let links = if m.is_dir() { 0 } else { m.nlink() };
+    let extended_attributes = read_extended_attributes(entry.path());
+    let flags = if m.is_dir() || m.is_file() {
+        flags::get_flags(entry.path()).unwrap_or_else(|err| {
+            warn!("{:?}: could not get file attributes: {err}", entry.path());
+            None
+        })
+    } else {
+        None
+    }
+    .filter(|flags| *flags != 0);
 
     let meta = Metadata {
         size,
@@ -232,9 +385,11 @@ fn map_entry(
         gid: Some(gid),
         user,
         group,
+        flags,
         inode,
         device_id,
         links,
+        extended_attributes,
     };
     let filetype = m.file_type();
 
@@ -262,6 +417,31 @@ fn map_entry(
     Ok((entry.path().to_path_buf(), node))
 }
 
+/// read all extended attributes of path, e.g. to save file capabilities (security.capability).
+/// Errors are only logged as not all filesystems support extended attributes.
+fn read_extended_attributes(path: &Path) -> Vec<ExtendedAttribute> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) => {
+            debug!("{path:?}: could not list extended attributes: {err}");
+            return Vec::new();
+        }
+    };
+
+    names
+        .filter_map(|name| match xattr::get(path, &name) {
+            Ok(value) => Some(ExtendedAttribute::new(
+                name.to_string_lossy().to_string(),
+                &value.unwrap_or_default(),
+            )),
+            Err(err) => {
+                warn!("{path:?}: could not read extended attribute {name:?}: {err}");
+                None
+            }
+        })
+        .collect()
+}
+
 const MODE_PERM: u32 = 0o777; // permission bits
 
 // consts from https://pkg.go.dev/io/fs#ModeType
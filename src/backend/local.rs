@@ -1,30 +1,80 @@
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::{symlink, FileExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{symlink, FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::Result;
 use bytes::Bytes;
-use filetime::{set_file_atime, set_file_mtime, FileTime};
+use bytesize::ByteSize;
+use filetime::{set_symlink_file_times, FileTime};
 use log::*;
+use nix::fcntl::{flock, FlockArg};
+use nix::libc::O_NOFOLLOW;
 use nix::sys::stat::{mknod, Mode, SFlag};
-use nix::unistd::chown;
+use nix::sys::statvfs::statvfs;
+use nix::unistd::{fchownat, FchownatFlags};
 use nix::unistd::{Gid, Group, Uid, User};
 use walkdir::WalkDir;
 
 use super::node::{Metadata, Node, NodeType};
-use super::{map_mode_from_go, FileType, Id, ReadBackend, WriteBackend, ALL_FILE_TYPES};
+use super::{
+    flags, map_mode_from_go, BackendError, BackendResult, Capabilities, FileRange, FileType, Id,
+    ReadBackend, WriteBackend, ALL_FILE_TYPES,
+};
+use crate::crypto::hash;
+
+/// How files are copied from another local repository, see option `copy-mode`
+#[derive(Clone, Copy, Debug)]
+enum CopyMode {
+    Copy,
+    Hardlink,
+    Reflink,
+}
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct LocalBackend {
     path: PathBuf,
+    copy_mode: CopyMode,
+    /// If set, check before writing that at least this space remains free, see option `min-free-space`
+    min_free_space: Option<u64>,
+    /// Warn about foreign files when listing, see option `foreign-files`
+    warn_foreign_files: bool,
 }
 
 impl LocalBackend {
     pub fn new(path: &str) -> Self {
         let path = path.into();
         fs::create_dir_all(&path).unwrap();
-        Self { path }
+        Self {
+            path,
+            copy_mode: CopyMode::Copy,
+            min_free_space: None,
+            warn_foreign_files: false,
+        }
+    }
+
+    /// Check that writing `size` bytes leaves at least `min-free-space` bytes available
+    fn check_free_space(&self, size: u64) -> BackendResult<()> {
+        let min_free_space = match self.min_free_space {
+            Some(min_free_space) => min_free_space,
+            None => return Ok(()),
+        };
+        let stat = statvfs(&self.path)?;
+        // the types of the statvfs fields differ between platforms
+        #[allow(clippy::useless_conversion)]
+        let available = u64::from(stat.blocks_available()) * u64::from(stat.fragment_size());
+        if available < size.saturating_add(min_free_space) {
+            return Err(BackendError::InsufficientSpace(format!(
+                "writing {} to {:?} would leave less than {} available (available: {})",
+                ByteSize(size),
+                self.path,
+                ByteSize(min_free_space),
+                ByteSize(available)
+            )));
+        }
+        Ok(())
     }
 
     fn path(&self, tpe: FileType, id: &Id) -> PathBuf {
@@ -37,16 +87,73 @@ impl LocalBackend {
     }
 }
 
+/// Check if `s` consists only of lowercase hex digits
+fn is_hex(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Check if a file name is a valid id, i.e. 64 lowercase hex digits
+fn is_id_name(name: Option<&str>) -> bool {
+    matches!(name, Some(name) if name.len() == 64 && is_hex(name))
+}
+
 impl ReadBackend for LocalBackend {
     fn location(&self) -> &str {
         self.path.to_str().unwrap()
     }
 
-    fn set_option(&mut self, _option: &str, _value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ranged_reads: true,
+            // files are written to a temporary file which is renamed
+            atomic_writes: true,
+            cheap_list: true,
+            server_side_copy: true,
+            // the repository directory is locked while comparing and replacing
+            compare_and_swap: true,
+        }
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        match option {
+            "copy-mode" => {
+                self.copy_mode = match value {
+                    "copy" => CopyMode::Copy,
+                    "hardlink" => CopyMode::Hardlink,
+                    "reflink" => CopyMode::Reflink,
+                    val => {
+                        return Err(BackendError::Config(format!(
+                            "value {val} not supported for option copy-mode!"
+                        )))
+                    }
+                };
+            }
+            "min-free-space" => {
+                let size: ByteSize = value.parse().map_err(|err| {
+                    BackendError::Config(format!(
+                        "invalid value {value} for option min-free-space: {err}"
+                    ))
+                })?;
+                self.min_free_space = Some(size.as_u64());
+            }
+            "foreign-files" => {
+                self.warn_foreign_files = match value {
+                    "ignore" => false,
+                    "warn" => true,
+                    val => {
+                        return Err(BackendError::Config(format!(
+                            "value {val} not supported for option foreign-files!"
+                        )))
+                    }
+                };
+            }
+            _ => {}
+        }
         Ok(())
     }
 
-    fn list(&self, tpe: FileType) -> Result<Vec<Id>> {
+    fn list(&self, tpe: FileType) -> BackendResult<Vec<Id>> {
         if tpe == FileType::Config {
             return Ok(match self.path.join("config").exists() {
                 true => vec![Id::default()],
@@ -63,46 +170,79 @@ impl ReadBackend for LocalBackend {
         Ok(walker.collect())
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         let path = self.path.join(tpe.name());
 
         if tpe == FileType::Config {
             return Ok(match path.exists() {
-                true => vec![(
-                    Id::default(),
-                    path.metadata().unwrap().len().try_into().unwrap(),
-                )],
+                true => vec![(Id::default(), path.metadata()?.len())],
                 false => Vec::new(),
             });
         }
 
+        if self.warn_foreign_files {
+            for name in self.list_foreign(tpe)? {
+                warn!("unexpected file {name} in the repository");
+            }
+        }
+
         let walker = WalkDir::new(path)
             .into_iter()
             .filter_map(walkdir::Result::ok)
-            .filter(|e| {
-                // only use files with length of 64 which are valid hex
-                // TODO: maybe add an option which warns if other files exist?
-                e.file_type().is_file()
-                    && e.file_name().len() == 64
-                    && e.file_name().is_ascii()
-                    && e.file_name()
-                        .to_str()
-                        .unwrap()
-                        .chars()
-                        .into_iter()
-                        .all(|c| ('0'..='9').contains(&c) || ('a'..='f').contains(&c))
-            })
+            // only use files with valid ids as name
+            .filter(|e| e.file_type().is_file() && is_id_name(e.file_name().to_str()))
             .map(|e| {
                 (
                     Id::from_hex(e.file_name().to_str().unwrap()).unwrap(),
-                    e.metadata().unwrap().len().try_into().unwrap(),
+                    // handle errors in metadata by returning a size of 0
+                    e.metadata().map_or(0, |m| m.len()),
                 )
             });
 
         Ok(walker.collect())
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        if tpe == FileType::Config {
+            return Ok(Vec::new());
+        }
+        let path = self.path.join(tpe.name());
+        let mut foreign = Vec::new();
+        for entry in WalkDir::new(&path).min_depth(1) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    foreign.push(format!(
+                        "{} (error: {err})",
+                        err.path().unwrap_or(&path).display()
+                    ));
+                    continue;
+                }
+            };
+            let name = entry.file_name().to_str();
+            let expected = match (tpe, entry.depth()) {
+                // packs are saved in the subdirectories data/00 to data/ff
+                (FileType::Pack, 1) => {
+                    entry.file_type().is_dir()
+                        && matches!(name, Some(n) if n.len() == 2 && is_hex(n))
+                }
+                (FileType::Pack, 2) => {
+                    let dir = entry.path().parent().and_then(Path::file_name);
+                    entry.file_type().is_file()
+                        && is_id_name(name)
+                        && dir.and_then(|d| d.to_str()) == name.map(|n| &n[..2])
+                }
+                (FileType::Pack, _) => false,
+                (_, depth) => depth == 1 && entry.file_type().is_file() && is_id_name(name),
+            };
+            if !expected {
+                foreign.push(entry.path().display().to_string());
+            }
+        }
+        Ok(foreign)
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         Ok(fs::read(self.path(tpe, id))?.into())
     }
 
@@ -111,19 +251,21 @@ impl ReadBackend for LocalBackend {
         tpe: FileType,
         id: &Id,
         _cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
+        let range = FileRange::new(offset, length)?;
         let mut file = File::open(self.path(tpe, id))?;
-        file.seek(SeekFrom::Start(offset.try_into().unwrap()))?;
-        let mut vec = vec![0; length.try_into().unwrap()];
+        range.check_size(file.metadata()?.len())?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut vec = vec![0; length as usize];
         file.read_exact(&mut vec)?;
         Ok(vec.into())
     }
 }
 
 impl WriteBackend for LocalBackend {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         for tpe in ALL_FILE_TYPES {
             fs::create_dir_all(self.path.join(tpe.name()))?;
         }
@@ -133,88 +275,262 @@ impl WriteBackend for LocalBackend {
         Ok(())
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, _cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         trace!("writing tpe: {:?}, id: {}", &tpe, &id);
+        let len = buf.len() as u64;
+        self.check_free_space(len)?;
+        write_replace(&self.path(tpe, id), &buf)
+    }
+
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        trace!("replacing tpe: {:?}, id: {}", &tpe, &id);
+        // the lock is held until the directory is closed; other clients using this method wait
+        // for it. It is released automatically if the process dies.
+        let dir = File::open(&self.path)?;
+        flock(dir.as_raw_fd(), FlockArg::LockExclusive).map_err(std::io::Error::from)?;
+
         let filename = self.path(tpe, id);
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&filename)?;
-        file.set_len(buf.len().try_into()?)?;
-        file.write_all(&buf)?;
-        file.sync_all()?;
-        Ok(())
+        if hash(&fs::read(&filename)?) != *expected {
+            return Ok(false);
+        }
+
+        write_replace(&filename, &buf)?;
+        Ok(true)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, _cacheable: bool) -> Result<()> {
+    fn remove(&self, tpe: FileType, id: &Id, _cacheable: bool) -> BackendResult<()> {
         trace!("writing tpe: {:?}, id: {}", &tpe, &id);
         let filename = self.path(tpe, id);
         fs::remove_file(filename)?;
         Ok(())
     }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+    ) -> BackendResult<bool> {
+        trace!("copying tpe: {:?}, id: {}", &tpe, &id);
+        let (from, to) = (from.path(tpe, id), self.path(tpe, id));
+        // An existing file may be left over from an aborted copy and might even be a hardlink to
+        // `from`, so copying onto it could destroy the source.
+        if to.exists() {
+            fs::remove_file(&to)?;
+        }
+        // Files are never changed once written, so they can be shared between repositories.
+        // If this is not possible, e.g. because the repositories are on different filesystems,
+        // fall back to a normal copy.
+        let linked = match self.copy_mode {
+            CopyMode::Copy => Ok(false),
+            CopyMode::Hardlink => fs::hard_link(&from, &to).map(|_| true).map_err(Into::into),
+            CopyMode::Reflink => reflink(&from, &to).map(|_| true),
+        };
+        match linked {
+            Ok(true) => {}
+            Ok(false) => {
+                // Note: fs::copy uses copy_file_range or similar if supported by the OS and filesystem
+                fs::copy(&from, &to)?;
+            }
+            Err(err) => {
+                debug!(
+                    "{:?} of {from:?} failed: {err}, copying instead",
+                    self.copy_mode
+                );
+                fs::copy(&from, &to)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Write the file using a temporary file which is then renamed. This never leaves a partially
+/// written file and an existing file is only replaced if the write succeeded.
+/// The temporary file gets a random name such that concurrent writers of the same file don't
+/// interfere. It is created with the default permissions like all repository files.
+fn write_replace(filename: &Path, buf: &[u8]) -> BackendResult<()> {
+    let tmp = filename.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+    let write = || -> BackendResult<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, filename)?;
+        Ok(())
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// FICLONE from linux/fs.h
+#[cfg(target_os = "linux")]
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Create `to` as a copy-on-write clone of `from`. This needs filesystem support, e.g. btrfs or xfs.
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn reflink(from: &Path, to: &Path) -> BackendResult<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    unsafe { ficlone(dst.as_raw_fd(), src.as_raw_fd() as _) }?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> BackendResult<()> {
+    Err(BackendError::Other(anyhow::anyhow!(
+        "reflinks are only supported on Linux"
+    )))
 }
 
 impl LocalBackend {
-    pub fn remove_dir(&self, dirname: impl AsRef<Path>) -> Result<()> {
+    /// Get the path of `item` within the backend path.
+    ///
+    /// As `item` may come from an untrusted snapshot, it must be a relative path without `..`
+    /// and no parent directory of it within the backend path may be a symlink. This ensures that
+    /// nothing outside of the backend path is changed.
+    fn target_path(&self, item: impl AsRef<Path>) -> BackendResult<PathBuf> {
+        let item = item.as_ref();
+        let mut path = self.path.clone();
+        let mut components = item.components().peekable();
+        while let Some(component) = components.next() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                _ => {
+                    return Err(BackendError::Corruption(format!(
+                        "{item:?} is not a valid relative path"
+                    )))
+                }
+            }
+            if components.peek().is_some()
+                && matches!(fs::symlink_metadata(&path), Ok(m) if m.file_type().is_symlink())
+            {
+                return Err(BackendError::PermissionDenied(format!(
+                    "{path:?} is a symlink, refusing to follow it"
+                )));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Options to open a file which fail if the file is a symlink
+    fn open_options(&self) -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        options.custom_flags(O_NOFOLLOW);
+        options
+    }
+
+    pub fn remove_dir(&self, dirname: impl AsRef<Path>) -> BackendResult<()> {
         Ok(fs::remove_dir(dirname)?)
     }
 
-    pub fn remove_file(&self, filename: impl AsRef<Path>) -> Result<()> {
+    pub fn remove_file(&self, filename: impl AsRef<Path>) -> BackendResult<()> {
         Ok(fs::remove_file(&filename)?)
     }
 
-    pub fn create_dir(&self, item: impl AsRef<Path>) -> Result<()> {
-        let dirname = self.path.join(item);
+    pub fn remove_dir_all(&self, dirname: impl AsRef<Path>) -> BackendResult<()> {
+        Ok(fs::remove_dir_all(dirname)?)
+    }
+
+    pub fn create_dir(&self, item: impl AsRef<Path>) -> BackendResult<()> {
+        let dirname = self.target_path(item)?;
         fs::create_dir_all(&dirname)?;
         Ok(())
     }
 
-    pub fn set_times(&self, item: impl AsRef<Path>, meta: &Metadata) -> Result<()> {
-        let filename = self.path.join(item);
-        if let Some(mtime) = meta.mtime.map(|t| FileTime::from_system_time(t.into())) {
-            set_file_mtime(&filename, mtime)?;
-        }
-        if let Some(atime) = meta.atime.map(|t| FileTime::from_system_time(t.into())) {
-            set_file_atime(&filename, atime)?;
+    pub fn set_times(&self, item: impl AsRef<Path>, meta: &Metadata) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
+        let mtime = meta.mtime.map(|t| FileTime::from_system_time(t.into()));
+        let atime = meta.atime.map(|t| FileTime::from_system_time(t.into()));
+        if mtime.is_none() && atime.is_none() {
+            return Ok(());
         }
+        // times which are not saved are kept
+        let current = fs::symlink_metadata(&filename)?;
+        let mtime = mtime.unwrap_or_else(|| FileTime::from_last_modification_time(&current));
+        let atime = atime.unwrap_or_else(|| FileTime::from_last_access_time(&current));
+        // this sets the times of a symlink itself and not of its target
+        set_symlink_file_times(&filename, atime, mtime)?;
         Ok(())
     }
 
-    pub fn set_user_group(&self, item: impl AsRef<Path>, meta: &Metadata) -> Result<()> {
-        let filename = self.path.join(item);
+    pub fn set_user_group(
+        &self,
+        item: impl AsRef<Path>,
+        meta: &Metadata,
+        owner_map: &IdMap,
+        group_map: &IdMap,
+    ) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
 
-        let user = meta
-            .user
-            .as_ref()
-            .and_then(|name| User::from_name(name).unwrap());
+        let (user, uid) = owner_map.map(meta.user.as_deref(), meta.uid);
+        let user = user.and_then(|name| User::from_name(&name).unwrap());
 
         // use uid from user if valid, else from saved uid (if saved)
-        let uid = user.map(|u| u.uid).or_else(|| meta.uid.map(Uid::from_raw));
+        let uid = user.map(|u| u.uid).or_else(|| uid.map(Uid::from_raw));
+
+        let (group, gid) = group_map.map(meta.group.as_deref(), meta.gid);
+        let group = group.and_then(|name| Group::from_name(&name).unwrap());
 
-        let group = meta
-            .group
-            .as_ref()
-            .and_then(|name| Group::from_name(name).unwrap());
         // use gid from group if valid, else from saved gid (if saved)
-        let gid = group.map(|g| g.gid).or_else(|| meta.gid.map(Gid::from_raw));
+        let gid = group.map(|g| g.gid).or_else(|| gid.map(Gid::from_raw));
 
-        chown(&filename, uid, gid)?;
+        fchownat(None, &filename, uid, gid, FchownatFlags::NoFollowSymlink)?;
         Ok(())
     }
 
-    pub fn set_uid_gid(&self, item: impl AsRef<Path>, meta: &Metadata) -> Result<()> {
-        let filename = self.path.join(item);
-
-        let uid = meta.uid.map(Uid::from_raw);
-        let gid = meta.gid.map(Gid::from_raw);
-
-        chown(&filename, uid, gid)?;
+    pub fn set_uid_gid(
+        &self,
+        item: impl AsRef<Path>,
+        meta: &Metadata,
+        owner_map: &IdMap,
+        group_map: &IdMap,
+    ) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
+
+        // only ids are used here; mapping to a name is however allowed
+        let (user, uid) = owner_map.map(None, meta.uid);
+        let uid = match user {
+            Some(name) => User::from_name(&name).unwrap().map(|u| u.uid),
+            None => uid.map(Uid::from_raw),
+        };
+
+        let (group, gid) = group_map.map(None, meta.gid);
+        let gid = match group {
+            Some(name) => Group::from_name(&name).unwrap().map(|g| g.gid),
+            None => gid.map(Gid::from_raw),
+        };
+
+        fchownat(None, &filename, uid, gid, FchownatFlags::NoFollowSymlink)?;
         Ok(())
     }
 
-    pub fn set_permission(&self, item: impl AsRef<Path>, meta: &Metadata) -> Result<()> {
-        let filename = self.path.join(item);
+    pub fn set_permission(&self, item: impl AsRef<Path>, meta: &Metadata) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
 
+        // permissions of symlinks can't be changed; changing them would change the target
+        if fs::symlink_metadata(&filename)?.file_type().is_symlink() {
+            return Ok(());
+        }
         if let Some(mode) = meta.mode() {
             let mode = map_mode_from_go(*mode);
             std::fs::set_permissions(&filename, fs::Permissions::from_mode(mode))?;
@@ -222,15 +538,52 @@ impl LocalBackend {
         Ok(())
     }
 
-    pub fn create_file(&self, item: impl AsRef<Path>, size: u64) -> Result<()> {
-        let filename = self.path.join(item);
-        let f = fs::File::create(filename)?;
+    pub fn set_extended_attributes(
+        &self,
+        item: impl AsRef<Path>,
+        meta: &Metadata,
+    ) -> BackendResult<()> {
+        // Note: xattr::set doesn't follow symlinks
+        let filename = self.target_path(item)?;
+
+        for attr in &meta.extended_attributes {
+            if let Err(err) = xattr::set(&filename, &attr.name, &attr.value()?) {
+                if attr.is_capability() {
+                    warn!("{filename:?}: file capabilities could not be restored, target filesystem may not support them or missing privileges: {err}");
+                } else {
+                    warn!(
+                        "{filename:?}: setting extended attribute {} failed: {err}",
+                        attr.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_flags(&self, item: impl AsRef<Path>, meta: &Metadata) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
+
+        if let Some(flags) = meta.flags {
+            flags::set_flags(&filename, flags)?;
+        }
+        Ok(())
+    }
+
+    pub fn create_file(&self, item: impl AsRef<Path>, size: u64) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
+        let f = self
+            .open_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filename)?;
         f.set_len(size)?;
         Ok(())
     }
 
-    pub fn create_special(&self, item: impl AsRef<Path>, node: &Node) -> Result<()> {
-        let filename = self.path.join(item);
+    pub fn create_special(&self, item: impl AsRef<Path>, node: &Node) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
 
         match node.node_type() {
             NodeType::Symlink { linktarget } => {
@@ -261,32 +614,55 @@ impl LocalBackend {
         Ok(())
     }
 
-    pub fn read_at(&self, item: impl AsRef<Path>, offset: u64, length: u64) -> Result<Bytes> {
-        let filename = self.path.join(item);
-        let mut file = File::open(&filename)?;
+    pub fn read_at(
+        &self,
+        item: impl AsRef<Path>,
+        offset: u64,
+        length: u64,
+    ) -> BackendResult<Bytes> {
+        let filename = self.target_path(item)?;
+        let mut file = self.open_options().read(true).open(&filename)?;
         file.seek(SeekFrom::Start(offset))?;
         let mut vec = vec![0; length.try_into().unwrap()];
-        file.read_exact(&mut vec).unwrap();
+        file.read_exact(&mut vec)?;
         Ok(vec.into())
     }
 
-    pub fn get_matching_file(&self, item: impl AsRef<Path>, size: u64) -> Option<File> {
-        let filename = self.path.join(item);
+    /// Open an existing regular file to compare its contents with the contents to restore.
+    /// Returns the file and its current size.
+    pub fn get_matching_file(&self, item: impl AsRef<Path>) -> Option<(File, u64)> {
+        let filename = self.target_path(item).ok()?;
         match fs::symlink_metadata(&filename) {
-            Ok(meta) => {
-                if meta.is_file() && meta.len() == size {
-                    File::open(&filename).ok()
-                } else {
-                    None
-                }
+            Ok(meta) if meta.is_file() => {
+                let file = self.open_options().read(true).open(&filename).ok()?;
+                Some((file, meta.len()))
             }
-            Err(_) => None,
+            _ => None,
         }
     }
 
-    pub fn write_at(&self, item: impl AsRef<Path>, offset: u64, data: &[u8]) -> Result<()> {
-        let filename = self.path.join(item);
-        let file = fs::OpenOptions::new()
+    /// Set the size of an existing regular file, keeping its contents.
+    /// Other existing entries, e.g. symlinks, are replaced by a new file of the given size.
+    pub fn resize_file(&self, item: impl AsRef<Path>, size: u64) -> BackendResult<()> {
+        let filename = self.target_path(&item)?;
+        match fs::symlink_metadata(&filename) {
+            Ok(meta) if meta.is_file() => {
+                let f = self.open_options().write(true).open(&filename)?;
+                f.set_len(size)?;
+                Ok(())
+            }
+            Ok(_) => {
+                fs::remove_file(&filename)?;
+                self.create_file(item, size)
+            }
+            Err(_) => self.create_file(item, size),
+        }
+    }
+
+    pub fn write_at(&self, item: impl AsRef<Path>, offset: u64, data: &[u8]) -> BackendResult<()> {
+        let filename = self.target_path(item)?;
+        let file = self
+            .open_options()
             .create(true)
             .write(true)
             .open(&filename)?;
@@ -294,3 +670,237 @@ impl LocalBackend {
         Ok(())
     }
 }
+
+/// Mapping of user or group names/ids, given as comma-separated list of FROM:TO pairs.
+/// FROM and TO can be either numeric ids or names, e.g. "1000:2000,alice:bob".
+#[derive(Clone, Debug, Default)]
+pub struct IdMap(HashMap<String, String>);
+
+impl FromStr for IdMap {
+    type Err = BackendError;
+    fn from_str(s: &str) -> BackendResult<Self> {
+        let map = s
+            .split(',')
+            .map(|pair| match pair.split_once(':') {
+                Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+                    Ok((from.to_string(), to.to_string()))
+                }
+                _ => Err(BackendError::Config(format!(
+                    "invalid mapping \"{pair}\", expected FROM:TO"
+                ))),
+            })
+            .collect::<BackendResult<_>>()?;
+        Ok(Self(map))
+    }
+}
+
+impl IdMap {
+    /// Map a (name, id) pair. A mapping for the name takes precedence over a mapping for the id.
+    /// If the mapping yields a numeric id, the name is dropped and vice versa.
+    pub fn map(&self, name: Option<&str>, id: Option<u32>) -> (Option<String>, Option<u32>) {
+        let to = name
+            .and_then(|name| self.0.get(name))
+            .or_else(|| id.and_then(|id| self.0.get(&id.to_string())));
+
+        match to {
+            None => (name.map(String::from), id),
+            Some(to) => match to.parse() {
+                Ok(id) => (None, Some(id)),
+                Err(_) => (Some(to.clone()), None),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Some("alice"), Some(1000), Some("bob"), None)]
+    #[case(Some("carol"), Some(1000), None, Some(2000))]
+    #[case(None, Some(1001), Some("dave"), None)]
+    #[case(Some("carol"), Some(1002), Some("carol"), Some(1002))]
+    fn id_map(
+        #[case] name: Option<&str>,
+        #[case] id: Option<u32>,
+        #[case] expected_name: Option<&str>,
+        #[case] expected_id: Option<u32>,
+    ) {
+        let map = IdMap::from_str("1000:2000,alice:bob,1001:dave").unwrap();
+        assert_eq!(
+            (expected_name.map(String::from), expected_id),
+            map.map(name, id)
+        );
+    }
+
+    #[test]
+    fn id_map_invalid() {
+        assert!(IdMap::from_str("1000").is_err());
+        assert!(IdMap::from_str("1000:").is_err());
+    }
+
+    #[test]
+    fn failed_write_keeps_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // the name of the temporary file is too long, so writing it fails
+        let filename = dir.path().join("x".repeat(250));
+        fs::write(&filename, "data").unwrap();
+        assert!(write_replace(&filename, b"new data").is_err());
+        assert_eq!(fs::read(&filename).unwrap(), b"data");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("file");
+        std::thread::scope(|s| {
+            for byte in 0..8 {
+                let filename = &filename;
+                s.spawn(move || write_replace(filename, &[byte; 100_000]).unwrap());
+            }
+        });
+        // the file is completely written by one of the writers
+        let data = fs::read(&filename).unwrap();
+        assert_eq!(data.len(), 100_000);
+        assert!(data.iter().all(|b| *b == data[0]));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn min_free_space() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut be = LocalBackend::new(dir.to_str().unwrap());
+        be.create().unwrap();
+        assert!(be.set_option("min-free-space", "much").is_err());
+
+        let id = Id::random();
+        be.set_option("min-free-space", "1kiB").unwrap();
+        be.write_bytes(FileType::Key, &id, false, Bytes::from("data"))
+            .unwrap();
+        be.set_option("min-free-space", "1000000TiB").unwrap();
+        let err = be
+            .write_bytes(FileType::Key, &id, false, Bytes::from("data"))
+            .unwrap_err();
+        assert!(matches!(err, BackendError::InsufficientSpace(_)));
+    }
+
+    #[test]
+    fn foreign_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut be = LocalBackend::new(dir.to_str().unwrap());
+        be.create().unwrap();
+        assert!(be.set_option("foreign-files", "maybe").is_err());
+        be.set_option("foreign-files", "warn").unwrap();
+
+        let id = Id::random();
+        be.write_bytes(FileType::Pack, &id, false, Bytes::from("data"))
+            .unwrap();
+        be.write_bytes(FileType::Key, &id, false, Bytes::from("data"))
+            .unwrap();
+        assert!(be.list_foreign(FileType::Pack).unwrap().is_empty());
+
+        fs::write(dir.join("keys").join("notes.txt"), "data").unwrap();
+        // a pack in the wrong subdirectory
+        let hex = id.to_hex();
+        let other = if hex.starts_with("00") { "01" } else { "00" };
+        fs::write(dir.join("data").join(other).join(&hex), "data").unwrap();
+
+        assert_eq!(be.list_foreign(FileType::Key).unwrap().len(), 1);
+        assert_eq!(be.list_foreign(FileType::Pack).unwrap().len(), 1);
+        assert_eq!(be.list(FileType::Key).unwrap(), vec![id]);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let be = LocalBackend::new(dir.to_str().unwrap());
+        be.create().unwrap();
+        let id = Id::default();
+        be.write_bytes(FileType::Config, &id, false, Bytes::from("old"))
+            .unwrap();
+
+        // another client changed the config in the meantime
+        let changed = be
+            .write_bytes_if_unchanged(FileType::Config, &id, false, &hash(b"other"), "new".into())
+            .unwrap();
+        assert!(!changed);
+        assert_eq!(be.read_full(FileType::Config, &id).unwrap(), "old");
+
+        let changed = be
+            .write_bytes_if_unchanged(FileType::Config, &id, false, &hash(b"old"), "new".into())
+            .unwrap();
+        assert!(changed);
+        assert_eq!(be.read_full(FileType::Config, &id).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), ALL_FILE_TYPES.len() + 1);
+    }
+
+    #[rstest]
+    #[case("/etc/passwd")]
+    #[case("../outside")]
+    #[case("dir/../../outside")]
+    #[case("./dir/../../outside")]
+    fn target_path_invalid(#[case] item: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(dir.path().join("restore").to_str().unwrap());
+        assert!(be.target_path(item).is_err());
+        assert!(be.create_file(item, 0).is_err());
+        assert!(be.create_dir(item).is_err());
+    }
+
+    #[test]
+    fn no_symlink_following() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (dest, outside) = (dir.join("dest"), dir.join("outside"));
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let target = outside.join("file");
+        fs::write(&target, "data").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        symlink(&outside, dest.join("dir-link")).unwrap();
+        symlink(&target, dest.join("file-link")).unwrap();
+
+        let be = LocalBackend::new(dest.to_str().unwrap());
+        assert_eq!(be.target_path("dir/file").unwrap(), dest.join("dir/file"));
+
+        // writing into a symlinked dir
+        assert!(be.create_file("dir-link/new", 10).is_err());
+        assert!(be.create_dir("dir-link/new").is_err());
+        assert!(be.write_at("dir-link/file", 0, b"changed").is_err());
+        assert!(be.get_matching_file("dir-link/file").is_none());
+        assert!(!outside.join("new").exists());
+
+        // writing to a symlinked file
+        assert!(be.create_file("file-link", 10).is_err());
+        assert!(be.write_at("file-link", 0, b"changed").is_err());
+        assert!(be.read_at("file-link", 0, 4).is_err());
+        assert!(be.get_matching_file("file-link").is_none());
+
+        // setting metadata on a symlink doesn't change the target
+        let meta = Metadata {
+            mode: Some(0o777),
+            mtime: Some(std::time::UNIX_EPOCH.into()),
+            ..Default::default()
+        };
+        be.set_permission("file-link", &meta).unwrap();
+        be.set_times("file-link", &meta).unwrap();
+        let target_meta = fs::metadata(&target).unwrap();
+        assert_eq!(target_meta.permissions().mode() & 0o777, 0o600);
+        assert_ne!(
+            FileTime::from_last_modification_time(&target_meta).unix_seconds(),
+            0
+        );
+        assert_eq!(fs::read(&target).unwrap(), b"data");
+
+        // resizing replaces the symlink by a new file
+        be.resize_file("file-link", 2).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"data");
+        assert_eq!(fs::read(dest.join("file-link")).unwrap(), [0, 0]);
+    }
+}
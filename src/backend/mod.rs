@@ -5,29 +5,60 @@ use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use serde::{de::DeserializeOwned, Serialize};
 
+use crate::crypto::hash;
 use crate::id::Id;
 
+pub mod append_only;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod b2;
 pub mod cache;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod choose;
 pub mod decrypt;
 pub mod dry_run;
+pub mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod flags;
 pub mod hotcold;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod ignore;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod local;
 pub mod node;
+pub mod offline;
+#[cfg(feature = "opendal")]
+pub mod opendal;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod rclone;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod rest;
+pub mod stats;
+pub mod throttle;
 
+#[cfg(not(target_arch = "wasm32"))]
 pub use self::ignore::*;
+pub use append_only::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use b2::*;
 pub use cache::*;
+#[cfg(not(target_arch = "wasm32"))]
 pub use choose::*;
 pub use decrypt::*;
 pub use dry_run::*;
+pub use error::*;
 pub use hotcold::*;
+#[cfg(not(target_arch = "wasm32"))]
 pub use local::*;
 use node::Node;
+pub use offline::*;
+#[cfg(feature = "opendal")]
+pub use opendal::*;
+#[cfg(not(target_arch = "wasm32"))]
 pub use rclone::*;
+#[cfg(not(target_arch = "wasm32"))]
 pub use rest::*;
+pub use stats::*;
+pub use throttle::*;
 
 /// All FileTypes which are located in separated directories
 pub const ALL_FILE_TYPES: [FileType; 4] = [
@@ -47,7 +78,7 @@ pub enum FileType {
 }
 
 impl FileType {
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         match &self {
             FileType::Config => "config",
             FileType::Snapshot => "snapshots",
@@ -65,18 +96,128 @@ impl FileType {
     }
 }
 
+/// A validated byte range of a repository file, used for partial reads.
+///
+/// Offsets and lengths usually come from index files or pack headers, so they may be corrupted.
+/// Creating a `FileRange` makes sure that computing the end of the range cannot overflow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileRange {
+    offset: u64,
+    length: u32,
+}
+
+impl FileRange {
+    pub fn new(offset: u64, length: u32) -> BackendResult<Self> {
+        if length == 0 {
+            return Err(BackendError::Corruption(format!(
+                "invalid empty range at offset {offset}"
+            )));
+        }
+        if offset.checked_add(length.into()).is_none() {
+            return Err(BackendError::Corruption(format!(
+                "range at offset {offset} with length {length} exceeds the maximum file size"
+            )));
+        }
+        Ok(Self { offset, length })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// The end of the range (exclusive)
+    pub fn end(&self) -> u64 {
+        self.offset + u64::from(self.length)
+    }
+
+    /// The value of a HTTP `Range` header requesting this range
+    pub fn http_range(&self) -> String {
+        format!("bytes={}-{}", self.offset, self.end() - 1)
+    }
+
+    /// Check that the data read for this range has the expected length
+    pub fn check_read(&self, data: Bytes) -> BackendResult<Bytes> {
+        if data.len() != self.length as usize {
+            return Err(BackendError::Corruption(format!(
+                "read {} bytes at offset {} instead of {}",
+                data.len(),
+                self.offset,
+                self.length
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Check that the range lies within a file of the given size
+    pub fn check_size(&self, size: u64) -> BackendResult<()> {
+        if self.end() > size {
+            return Err(BackendError::Corruption(format!(
+                "range {}..{} exceeds the file size {size}",
+                self.offset,
+                self.end()
+            )));
+        }
+        Ok(())
+    }
+}
+
 pub trait RepoFile: Serialize + DeserializeOwned + Sized + Send + Sync + 'static {
     const TYPE: FileType;
 }
 
+/// Capabilities of a backend, see [`ReadBackend::capabilities`].
+///
+/// Higher layers use them to choose a suitable strategy for the backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Partial reads are efficient, e.g. because they use range requests
+    pub ranged_reads: bool,
+    /// A written file is either completely present or not at all
+    pub atomic_writes: bool,
+    /// Listing files is cheap, e.g. because it doesn't need any request
+    pub cheap_list: bool,
+    /// Files can be copied within the backend without transferring them
+    pub server_side_copy: bool,
+    /// Files can be replaced atomically if their content is unchanged, see
+    /// [`WriteBackend::write_bytes_if_unchanged`]
+    pub compare_and_swap: bool,
+}
+
+/// A backend which allows to read repository files.
+///
+/// All backend methods are blocking. Concurrent requests are issued by calling the backend
+/// from several threads, e.g. the pack uploads of the `Packer` run in a separate writer thread
+/// and restore reads pack data with a thread pool. Hence backends must be cheap to clone and
+/// usable from multiple threads.
 pub trait ReadBackend: Clone + Send + Sync + 'static {
     fn location(&self) -> &str;
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()>;
+    /// The capabilities of the backend. Defaults to the lowest common denominator.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()>;
+
+    /// The local cache used by this backend, if any
+    fn cache(&self) -> Option<&Cache> {
+        None
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>>;
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>>;
+    /// List unexpected files in the directory of the given file type, e.g. files which are not
+    /// named by an id or packs in the wrong subdirectory. Only backends which can detect such
+    /// files return them.
+    fn list_foreign(&self, _tpe: FileType) -> BackendResult<Vec<String>> {
+        Ok(Vec::new())
+    }
 
-    fn list(&self, tpe: FileType) -> Result<Vec<Id>> {
+    fn list(&self, tpe: FileType) -> BackendResult<Vec<Id>> {
         Ok(self
             .list_with_size(tpe)?
             .into_iter()
@@ -84,17 +225,21 @@ pub trait ReadBackend: Clone + Send + Sync + 'static {
             .collect())
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes>;
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes>;
     fn read_partial(
         &self,
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes>;
+    ) -> BackendResult<Bytes>;
 
-    fn find_starts_with(&self, tpe: FileType, vec: &[String]) -> Result<Vec<Result<Id>>> {
+    fn find_starts_with(
+        &self,
+        tpe: FileType,
+        vec: &[String],
+    ) -> BackendResult<Vec<BackendResult<Id>>> {
         #[derive(Clone, Copy, PartialEq, Eq)]
         pub enum MapResult<T> {
             None,
@@ -119,17 +264,20 @@ pub trait ReadBackend: Clone + Send + Sync + 'static {
             .enumerate()
             .map(|(i, id)| match id {
                 MapResult::Some(id) => Ok(id),
-                MapResult::None => Err(anyhow!("no suitable id found for {}", &vec[i])),
-                MapResult::NonUnique => Err(anyhow!("id {} is not unique", &vec[i])),
+                MapResult::None => Err(BackendError::NotFound(format!(
+                    "no suitable id found for {}",
+                    &vec[i]
+                ))),
+                MapResult::NonUnique => Err(anyhow!("id {} is not unique", &vec[i]).into()),
             })
             .collect())
     }
 
-    fn find_id(&self, tpe: FileType, id: &str) -> Result<Id> {
+    fn find_id(&self, tpe: FileType, id: &str) -> BackendResult<Id> {
         Ok(self.find_ids(tpe, &[id.to_string()])?.remove(0))
     }
 
-    fn find_ids(&self, tpe: FileType, ids: &[String]) -> Result<Vec<Id>> {
+    fn find_ids(&self, tpe: FileType, ids: &[String]) -> BackendResult<Vec<Id>> {
         let long_ids: Vec<_> = ids.iter().map(|id| Id::from_hex(id)).collect();
 
         Ok(match long_ids.iter().all(Result::is_ok) {
@@ -138,21 +286,55 @@ pub trait ReadBackend: Clone + Send + Sync + 'static {
             false => self
                 .find_starts_with(tpe, ids)?
                 .into_iter()
-                .collect::<Result<Vec<_>>>()?,
+                .collect::<BackendResult<Vec<_>>>()?,
         })
     }
 }
 
 pub trait WriteBackend: ReadBackend {
-    fn create(&self) -> Result<()>;
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()>;
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()>;
+    fn create(&self) -> BackendResult<()>;
+    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes)
+        -> BackendResult<()>;
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()>;
+
+    /// Copy a file from another backend of the same type without transferring its content.
+    /// Returns `false` if this is not supported; then the file needs to be read and written.
+    fn copy_from(
+        &self,
+        _from: &Self,
+        _tpe: FileType,
+        _id: &Id,
+        _cacheable: bool,
+    ) -> BackendResult<bool> {
+        Ok(false)
+    }
+
+    /// Replace a file by `buf` only if its current content has the hash `expected`
+    /// (compare-and-swap). Returns `false` if the file was changed in the meantime.
+    ///
+    /// By default, the file is read and compared before writing, which narrows but doesn't close
+    /// the window for concurrent changes. Backends with the `compare_and_swap` capability do this
+    /// atomically.
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        if hash(&self.read_full(tpe, id)?) != *expected {
+            return Ok(false);
+        }
+        self.write_bytes(tpe, id, cacheable, buf)?;
+        Ok(true)
+    }
 }
 
-pub trait ReadSource: Iterator<Item = Result<(PathBuf, Node)>> {
-    type Reader: Read;
-    fn read(path: &Path) -> Result<Self::Reader>;
-    fn size(&self) -> Result<u64>;
+pub trait ReadSource: Iterator<Item = BackendResult<(PathBuf, Node)>> {
+    type Reader: Read + 'static;
+    fn read(path: &Path) -> BackendResult<Self::Reader>;
+    fn size(&self) -> BackendResult<u64>;
 }
 
 pub trait WriteSource: Clone {
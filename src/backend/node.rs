@@ -1,15 +1,16 @@
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Write};
+#[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 use std::str::FromStr;
 
-use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Local};
 use derive_getters::Getters;
 use derive_more::{Constructor, IsVariant};
 use serde::{Deserialize, Serialize};
 use serde_aux::prelude::*;
 
+use super::{BackendError, BackendResult};
 use crate::id::Id;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Constructor)]
@@ -45,6 +46,20 @@ pub enum NodeType {
     Socket,
 }
 
+impl NodeType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeType::File => "file",
+            NodeType::Dir => "dir",
+            NodeType::Symlink { .. } => "symlink",
+            NodeType::Dev { .. } => "dev",
+            NodeType::Chardev { .. } => "chardev",
+            NodeType::Fifo => "fifo",
+            NodeType::Socket => "socket",
+        }
+    }
+}
+
 #[serde_with::apply(
     Option => #[serde(default, skip_serializing_if = "Option::is_none")],
     u64 => #[serde(default, skip_serializing_if = "is_default")],
@@ -59,10 +74,60 @@ pub struct Metadata {
     pub gid: Option<u32>,
     pub user: Option<String>,
     pub group: Option<String>,
+    pub flags: Option<u32>,
     pub inode: u64,
     pub device_id: u64,
     pub size: u64,
     pub links: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extended_attributes: Vec<ExtendedAttribute>,
+}
+
+/// Extended attribute of a node. The value is saved base64-encoded as done by restic.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedAttribute {
+    pub name: String,
+    pub value: String,
+}
+
+impl ExtendedAttribute {
+    pub fn new(name: String, value: &[u8]) -> Self {
+        Self {
+            name,
+            value: base64::encode(value),
+        }
+    }
+
+    pub fn value(&self) -> BackendResult<Vec<u8>> {
+        base64::decode(&self.value).map_err(|err| {
+            BackendError::Corruption(format!("invalid value of attribute {}: {err}", self.name))
+        })
+    }
+
+    /// Linux file capabilities (see capabilities(7))
+    pub fn is_capability(&self) -> bool {
+        self.name == "security.capability"
+    }
+
+    /// Check that the value is a valid file capability (`struct vfs_cap_data` of linux/capability.h)
+    /// which can be restored.
+    pub fn is_valid_capability(&self) -> bool {
+        let value = match self.value() {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        let magic = match value.get(0..4) {
+            Some(magic) => u32::from_le_bytes(magic.try_into().unwrap()),
+            None => return false,
+        };
+        let size = match magic & 0xFF00_0000 {
+            0x0100_0000 => 12,
+            0x0200_0000 => 20,
+            0x0300_0000 => 24,
+            _ => return false,
+        };
+        value.len() == size
+    }
 }
 
 fn is_default<T: Default + PartialEq>(t: &T) -> bool {
@@ -117,6 +182,9 @@ impl Node {
 // However, so far there was no specification what Quote really does, so this
 // is some kind of try-and-error and maybe does not cover every case.
 pub fn escape_filename(name: &OsStr) -> String {
+    // non-unix targets like wasm32 only read repositories and don't have non-unicode filenames
+    #[cfg(not(unix))]
+    let name = name.to_string_lossy();
     let mut input = name.as_bytes();
     let mut s = String::with_capacity(name.len());
 
@@ -165,7 +233,9 @@ pub fn escape_filename(name: &OsStr) -> String {
 }
 
 // inspired by the enquote crate
-pub fn unescape_filename(s: &str) -> Result<OsString> {
+pub fn unescape_filename(s: &str) -> BackendResult<OsString> {
+    let invalid =
+        |reason: &str| BackendError::Corruption(format!("invalid filename {s:?}: {reason}"));
     let mut chars = s.chars();
     let mut u = Vec::new();
     loop {
@@ -173,7 +243,7 @@ pub fn unescape_filename(s: &str) -> Result<OsString> {
             None => break,
             Some(c) => match c {
                 '\\' => match chars.next() {
-                    None => bail!("UnexpectedEOF"),
+                    None => return Err(invalid("UnexpectedEOF")),
                     Some(c) => match c {
                         '\\' => u.push(b'\\'),
                         '"' => u.push(b'"'),
@@ -189,26 +259,30 @@ pub fn unescape_filename(s: &str) -> Result<OsString> {
                         // hex
                         'x' => {
                             let hex = take(&mut chars, 2);
-                            u.push(u8::from_str_radix(&hex, 16)?)
+                            u.push(
+                                u8::from_str_radix(&hex, 16).map_err(|_| invalid("invalid hex"))?,
+                            )
                         }
                         // unicode
                         'u' => {
-                            let n = u32::from_str_radix(&take(&mut chars, 4), 16)?;
+                            let n = u32::from_str_radix(&take(&mut chars, 4), 16)
+                                .map_err(|_| invalid("invalid hex"))?;
                             let c =
-                                std::char::from_u32(n).ok_or_else(|| anyhow!("invalid unicode"))?;
+                                std::char::from_u32(n).ok_or_else(|| invalid("invalid unicode"))?;
                             let mut bytes = vec![0u8; c.len_utf8()];
                             c.encode_utf8(&mut bytes);
                             u.extend_from_slice(&bytes);
                         }
                         'U' => {
-                            let n = u32::from_str_radix(&take(&mut chars, 8), 16)?;
+                            let n = u32::from_str_radix(&take(&mut chars, 8), 16)
+                                .map_err(|_| invalid("invalid hex"))?;
                             let c =
-                                std::char::from_u32(n).ok_or_else(|| anyhow!("invalid unicode"))?;
+                                std::char::from_u32(n).ok_or_else(|| invalid("invalid unicode"))?;
                             let mut bytes = vec![0u8; c.len_utf8()];
                             c.encode_utf8(&mut bytes);
                             u.extend_from_slice(&bytes);
                         }
-                        _ => bail!("UnrecognizedEscape"),
+                        _ => return Err(invalid("UnrecognizedEscape")),
                     },
                 },
                 // normal char
@@ -221,7 +295,11 @@ pub fn unescape_filename(s: &str) -> Result<OsString> {
         }
     }
 
-    Ok(OsStr::from_bytes(&u).to_os_string())
+    #[cfg(unix)]
+    let name = OsStr::from_bytes(&u).to_os_string();
+    #[cfg(not(unix))]
+    let name = String::from_utf8_lossy(&u).into_owned().into();
+    Ok(name)
 }
 
 #[inline]
@@ -241,6 +319,24 @@ mod tests {
     use quickcheck_macros::quickcheck;
     use rstest::rstest;
 
+    #[rstest]
+    // cap_net_raw+ep as set for ping
+    #[case(
+        b"\x01\x00\x00\x02\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        true
+    )]
+    #[case(b"\x01\x00\x00\x03\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xe8\x03\x00\x00", true)]
+    #[case(b"\x01\x00\x00\x02\x00\x20\x00\x00", false)]
+    #[case(
+        b"\x01\x00\x00\x07\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        false
+    )]
+    #[case(b"", false)]
+    fn valid_capability(#[case] value: &[u8], #[case] valid: bool) {
+        let attr = ExtendedAttribute::new("security.capability".to_string(), value);
+        assert_eq!(attr.is_valid_capability(), valid);
+    }
+
     #[quickcheck]
     fn escape_unescape_is_identity(bytes: Vec<u8>) -> bool {
         let name = OsStr::from_bytes(&bytes);
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+
+use super::{
+    BackendError, BackendResult, Cache, Capabilities, FileType, Id, ReadBackend, WriteBackend,
+};
+
+/// Backend which serves the repository from the local cache without accessing the repository.
+///
+/// Only files which have been cached while online are available, i.e. the config, keys,
+/// snapshots, index files and tree packs which have been read before. Writing is not possible.
+#[derive(Clone)]
+pub struct OfflineBackend {
+    cache: Cache,
+}
+
+impl OfflineBackend {
+    pub fn new(location: &str, cache_dir: Option<PathBuf>) -> BackendResult<Self> {
+        let cache = Cache::find(location, cache_dir)?.ok_or_else(|| {
+            BackendError::NotFound(format!(
+                "no cache found for repository {location}. Please use it online once before using --offline."
+            ))
+        })?;
+        Ok(Self { cache })
+    }
+
+    fn not_available(tpe: FileType, id: &Id, err: BackendError) -> BackendError {
+        BackendError::NotFound(format!("{tpe:?} file {id} is not available offline: {err}"))
+    }
+}
+
+impl ReadBackend for OfflineBackend {
+    fn location(&self) -> &str {
+        self.cache.location()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ranged_reads: true,
+            atomic_writes: false,
+            cheap_list: true,
+            server_side_copy: false,
+            compare_and_swap: false,
+        }
+    }
+
+    fn set_option(&mut self, _option: &str, _value: &str) -> BackendResult<()> {
+        Ok(())
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        Ok(self.cache.list_with_size(tpe)?.into_iter().collect())
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        self.cache
+            .read_full(tpe, id)
+            .map_err(|err| Self::not_available(tpe, id, err))
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        self.cache
+            .read_partial(tpe, id, offset, length)
+            .map_err(|err| Self::not_available(tpe, id, err))
+    }
+}
+
+impl WriteBackend for OfflineBackend {
+    fn create(&self) -> BackendResult<()> {
+        Err(BackendError::PermissionDenied(
+            "cannot create a repository in offline mode".to_string(),
+        ))
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        _buf: Bytes,
+    ) -> BackendResult<()> {
+        Err(BackendError::PermissionDenied(format!(
+            "cannot write {tpe:?} file {id} in offline mode"
+        )))
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, _cacheable: bool) -> BackendResult<()> {
+        Err(BackendError::PermissionDenied(format!(
+            "cannot remove {tpe:?} file {id} in offline mode"
+        )))
+    }
+}
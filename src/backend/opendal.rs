@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::*;
+use opendal::layers::{BlockingLayer, RetryLayer};
+use opendal::{BlockingOperator, ErrorKind, Metakey, Operator, Scheme};
+use tokio::runtime::Runtime;
+
+use super::{
+    BackendError, BackendResult, Capabilities, FileRange, FileType, Id, ReadBackend, WriteBackend,
+};
+
+/// Backend using an [OpenDAL](https://opendal.apache.org) operator.
+///
+/// The url is given as `opendal:SCHEME` or `opendal:SCHEME:LOCATION`, e.g. `opendal:webdav:https://host/path`.
+/// `LOCATION` is used as `root` for the `fs` scheme and as `endpoint` for all other schemes.
+/// All other options of the service can be given using `set_option`. The operators are built when
+/// they are first used, i.e. after all options have been set.
+///
+/// Options prefixed with a file type directory, e.g. `data.default_storage_class=STANDARD_IA`, only
+/// apply to files of this type. This allows to put pack files into a cheaper storage class while
+/// all other files stay in the default one. Server-side encryption is configured by the options of
+/// the service, e.g. `server_side_encryption=aws:kms` and `server_side_encryption_aws_kms_key_id`
+/// for S3.
+#[derive(Clone)]
+pub struct OpenDALBackend {
+    location: String,
+    scheme: Scheme,
+    options: HashMap<String, String>,
+    type_options: HashMap<&'static str, HashMap<String, String>>,
+    retry: bool,
+    operators: Arc<Mutex<Option<Operators>>>,
+    // services without native blocking support need a tokio runtime
+    runtime: Arc<Runtime>,
+}
+
+/// The operator for all files and the operators for file types which have their own options
+struct Operators {
+    default: BlockingOperator,
+    types: HashMap<&'static str, BlockingOperator>,
+}
+
+impl OpenDALBackend {
+    pub fn new(path: &str) -> Result<Self> {
+        let (scheme, location) = match path.split_once(':') {
+            Some((scheme, location)) => (scheme, Some(location)),
+            None => (path, None),
+        };
+        let scheme = Scheme::from_str(scheme)?;
+
+        let mut options = HashMap::new();
+        if let Some(location) = location {
+            let key = match scheme {
+                Scheme::Fs => "root",
+                _ => "endpoint",
+            };
+            options.insert(key.to_string(), location.to_string());
+        }
+
+        Ok(Self {
+            location: format!("opendal:{path}"),
+            scheme,
+            options,
+            type_options: HashMap::new(),
+            retry: true,
+            operators: Arc::new(Mutex::new(None)),
+            runtime: Arc::new(Runtime::new()?),
+        })
+    }
+
+    fn path(&self, tpe: FileType, id: &Id) -> String {
+        let hex_id = id.to_hex();
+        match tpe {
+            FileType::Config => "config".to_string(),
+            FileType::Pack => format!("data/{}/{hex_id}", &hex_id[0..2]),
+            _ => format!("{}/{hex_id}", tpe.name()),
+        }
+    }
+
+    /// The operator to use for files of the given type. All operators are built on first use.
+    fn operator(&self, tpe: FileType) -> BackendResult<BlockingOperator> {
+        let mut operators = self.operators.lock().unwrap();
+        if operators.is_none() {
+            *operators = Some(self.build_operators()?);
+        }
+        let operators = operators.as_ref().unwrap();
+        Ok(operators
+            .types
+            .get(tpe.name())
+            .unwrap_or(&operators.default)
+            .clone())
+    }
+
+    fn build_operators(&self) -> BackendResult<Operators> {
+        let default = build_operator(self.scheme, &self.options, self.retry, &self.runtime)?;
+        let types = self
+            .type_options
+            .iter()
+            .map(|(tpe, type_options)| {
+                let mut options = self.options.clone();
+                options.extend(type_options.clone());
+                let operator = build_operator(self.scheme, &options, self.retry, &self.runtime)?;
+                Ok((*tpe, operator))
+            })
+            .collect::<BackendResult<HashMap<_, _>>>()?;
+        Ok(Operators { default, types })
+    }
+
+    /// The directory containing the files of the given type
+    fn dir(tpe: FileType) -> String {
+        match tpe {
+            FileType::Pack => "data/".to_string(),
+            _ => format!("{}/", tpe.name()),
+        }
+    }
+}
+
+/// File types whose name can be used as prefix for options only applying to this type
+const TYPE_PREFIXES: [FileType; 5] = [
+    FileType::Config,
+    FileType::Key,
+    FileType::Snapshot,
+    FileType::Index,
+    FileType::Pack,
+];
+
+fn build_operator(
+    scheme: Scheme,
+    options: &HashMap<String, String>,
+    retry: bool,
+    runtime: &Runtime,
+) -> BackendResult<BlockingOperator> {
+    let mut operator = Operator::via_map(scheme, options.clone())?;
+    if retry {
+        operator = operator.layer(RetryLayer::new().with_jitter());
+    }
+    if !operator.info().full_capability().blocking {
+        let _guard = runtime.enter();
+        operator = operator.layer(BlockingLayer::create()?);
+    }
+    Ok(operator.blocking())
+}
+
+impl ReadBackend for OpenDALBackend {
+    fn location(&self) -> &str {
+        &self.location
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ranged_reads: true,
+            atomic_writes: false,
+            cheap_list: self.scheme == Scheme::Fs,
+            server_side_copy: false,
+            compare_and_swap: false,
+        }
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        match option {
+            "retry" => {
+                self.retry = value.parse().map_err(|err| {
+                    BackendError::Config(format!("invalid value {value} for option retry: {err}"))
+                })?;
+            }
+            _ => {
+                let type_option = option.split_once('.').and_then(|(prefix, option)| {
+                    let tpe = TYPE_PREFIXES.iter().find(|tpe| tpe.name() == prefix)?;
+                    Some((tpe.name(), option))
+                });
+                match type_option {
+                    Some((tpe, option)) => {
+                        self.type_options
+                            .entry(tpe)
+                            .or_default()
+                            .insert(option.to_string(), value.to_string());
+                    }
+                    None => {
+                        self.options.insert(option.to_string(), value.to_string());
+                    }
+                }
+            }
+        }
+        // the operators are rebuilt with the changed options when they are used next
+        self.operators = Arc::new(Mutex::new(None));
+        Ok(())
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        if tpe == FileType::Config {
+            return Ok(match self.operator(tpe)?.is_exist("config")? {
+                true => vec![(Id::default(), 0)],
+                false => Vec::new(),
+            });
+        }
+
+        let entries = match self
+            .operator(tpe)?
+            .list_with(&Self::dir(tpe))
+            .recursive(true)
+            .metakey(Metakey::Mode | Metakey::ContentLength)
+            .call()
+        {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.metadata().is_file())
+            .filter_map(|entry| {
+                let id = Id::from_hex(entry.name()).ok()?;
+                let size = entry.metadata().content_length();
+                Some((id, size))
+            })
+            .collect())
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        Ok(self.operator(tpe)?.read(&self.path(tpe, id))?.into())
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        let range = FileRange::new(offset, length)?;
+        range.check_read(
+            self.operator(tpe)?
+                .read_with(&self.path(tpe, id))
+                .range(range.offset()..range.end())
+                .call()?
+                .into(),
+        )
+    }
+}
+
+impl WriteBackend for OpenDALBackend {
+    fn create(&self) -> BackendResult<()> {
+        for tpe in [
+            FileType::Key,
+            FileType::Snapshot,
+            FileType::Index,
+            FileType::Pack,
+        ] {
+            let dir = Self::dir(tpe);
+            self.operator(tpe)?
+                .create_dir(&dir)
+                .map_err(|err| anyhow!("error creating dir {dir}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        _cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
+        trace!("writing tpe: {:?}, id: {}", &tpe, &id);
+        self.operator(tpe)?.write(&self.path(tpe, id), buf)?;
+        Ok(())
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, _cacheable: bool) -> BackendResult<()> {
+        trace!("removing tpe: {:?}, id: {}", &tpe, &id);
+        self.operator(tpe)?.delete(&self.path(tpe, id))?;
+        Ok(())
+    }
+}
@@ -3,13 +3,15 @@ use std::process::{Child, Command, Stdio};
 use std::str;
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::anyhow;
 use bytes::Bytes;
 use log::*;
 use rand::distributions::{Alphanumeric, DistString};
 use rand::thread_rng;
 
-use super::{FileType, Id, ReadBackend, RestBackend, WriteBackend};
+use super::{
+    BackendError, BackendResult, Capabilities, FileType, Id, ReadBackend, RestBackend, WriteBackend,
+};
 
 struct ChildToKill(Child);
 impl Drop for ChildToKill {
@@ -26,19 +28,29 @@ pub struct RcloneBackend {
 }
 
 impl RcloneBackend {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str) -> BackendResult<Self> {
         let rclone_version_output = Command::new("rclone").arg("version").output()?.stdout;
-        let rclone_version = str::from_utf8(&rclone_version_output)?
+        let rclone_version = str::from_utf8(&rclone_version_output)
+            .map_err(|err| BackendError::Other(err.into()))?
             .lines()
             .next()
-            .ok_or_else(|| anyhow!("'rclone version' doesn't give any output"))?
+            .ok_or_else(|| {
+                BackendError::Other(anyhow!("'rclone version' doesn't give any output"))
+            })?
             .strip_prefix("rclone v")
-            .ok_or_else(|| anyhow!("output of 'rclone version' doesn't start with 'rclone v'"))?;
+            .ok_or_else(|| {
+                BackendError::Other(anyhow!(
+                    "output of 'rclone version' doesn't start with 'rclone v'"
+                ))
+            })?;
 
         let versions: Vec<&str> = rclone_version.split(&['.', '-'][..]).collect();
-        let major = versions[0].parse::<i32>()?;
-        let minor = versions[1].parse::<i32>()?;
-        let patch = versions[2].parse::<i32>()?;
+        let version = |i: usize| {
+            versions[i].parse::<i32>().map_err(|err| {
+                BackendError::Other(anyhow!("invalid rclone version {rclone_version}: {err}"))
+            })
+        };
+        let (major, minor, patch) = (version(0)?, version(1)?, version(2)?);
 
         if major
             .cmp(&1)
@@ -71,11 +83,11 @@ impl RcloneBackend {
             child
                 .stderr
                 .take()
-                .ok_or_else(|| anyhow!("cannot get stdout of rclone"))?,
+                .ok_or_else(|| BackendError::Other(anyhow!("cannot get stdout of rclone")))?,
         );
         let url = loop {
             if let Some(status) = child.try_wait()? {
-                bail!("rclone exited with {status}");
+                return Err(BackendError::Other(anyhow!("rclone exited with {status}")));
             }
             let mut line = String::new();
             stderr.read_line(&mut line)?;
@@ -102,13 +114,15 @@ impl RcloneBackend {
         });
 
         if !url.starts_with("http://") {
-            bail!("url must start with http://! url: {url}");
+            return Err(BackendError::Other(anyhow!(
+                "url must start with http://! url: {url}"
+            )));
         }
 
         let url = "http://".to_string() + &user + ":" + &password + "@" + &url[7..];
 
         debug!("using REST backend with url {url}.");
-        let rest = RestBackend::new(&url);
+        let rest = RestBackend::new(&url)?;
         Ok(Self {
             _child_data: Arc::new(ChildToKill(child)),
             rest,
@@ -121,15 +135,19 @@ impl ReadBackend for RcloneBackend {
         self.rest.location()
     }
 
-    fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+    fn capabilities(&self) -> Capabilities {
+        self.rest.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
         self.rest.set_option(option, value)
     }
 
-    fn list_with_size(&self, tpe: FileType) -> Result<Vec<(Id, u32)>> {
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
         self.rest.list_with_size(tpe)
     }
 
-    fn read_full(&self, tpe: FileType, id: &Id) -> Result<Bytes> {
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
         self.rest.read_full(tpe, id)
     }
 
@@ -138,23 +156,29 @@ impl ReadBackend for RcloneBackend {
         tpe: FileType,
         id: &Id,
         cacheable: bool,
-        offset: u32,
+        offset: u64,
         length: u32,
-    ) -> Result<Bytes> {
+    ) -> BackendResult<Bytes> {
         self.rest.read_partial(tpe, id, cacheable, offset, length)
     }
 }
 
 impl WriteBackend for RcloneBackend {
-    fn create(&self) -> Result<()> {
+    fn create(&self) -> BackendResult<()> {
         self.rest.create()
     }
 
-    fn write_bytes(&self, tpe: FileType, id: &Id, cacheable: bool, buf: Bytes) -> Result<()> {
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
         self.rest.write_bytes(tpe, id, cacheable, buf)
     }
 
-    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> Result<()> {
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
         self.rest.remove(tpe, id, cacheable)
     }
 }
@@ -0,0 +1,289 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use bytesize::ByteSize;
+use prettytable::{format, row, Table};
+
+use super::{BackendResult, Capabilities, FileType, Id, ReadBackend, WriteBackend};
+
+/// Number of retried requests of all backends
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Count a retried request; called by backends which retry failed requests
+pub fn count_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Total number of retried requests of all backends
+pub fn retries() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Upper bounds of the buckets of the latency histogram; the last bucket is unbounded
+const LATENCY_BUCKETS: [Duration; 5] = [
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::MAX,
+];
+const LATENCY_NAMES: [&str; 5] = ["<10ms", "<100ms", "<1s", "<10s", ">=10s"];
+
+#[derive(Clone, Copy)]
+enum Operation {
+    List,
+    Read,
+    Write,
+    Remove,
+}
+
+const OPERATIONS: [(Operation, &str); 4] = [
+    (Operation::List, "list"),
+    (Operation::Read, "read"),
+    (Operation::Write, "write"),
+    (Operation::Remove, "remove"),
+];
+
+#[derive(Default)]
+struct OperationStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// Counters of the requests to a backend
+#[derive(Default)]
+pub struct BackendStats {
+    operations: [OperationStats; 4],
+    latencies: [AtomicU64; 5],
+}
+
+impl BackendStats {
+    fn add<T>(
+        &self,
+        op: Operation,
+        start: Instant,
+        result: &BackendResult<T>,
+        bytes: impl FnOnce(&T) -> u64,
+    ) {
+        let duration = start.elapsed();
+        let stats = &self.operations[op as usize];
+        stats.requests.fetch_add(1, Ordering::Relaxed);
+        stats.nanos.fetch_add(
+            duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        match result {
+            Ok(res) => {
+                stats.bytes.fetch_add(bytes(res), Ordering::Relaxed);
+            }
+            Err(_) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| duration < *bound)
+            .unwrap_or(LATENCY_BUCKETS.len() - 1);
+        self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Print the statistics as table to stderr
+    pub fn print(&self, text: &str) {
+        let mut table = Table::new();
+        for (op, name) in OPERATIONS {
+            let stats = &self.operations[op as usize];
+            let requests = stats.requests.load(Ordering::Relaxed);
+            if requests == 0 {
+                continue;
+            }
+            let avg = Duration::from_nanos(stats.nanos.load(Ordering::Relaxed) / requests);
+            table.add_row(row![
+                name,
+                r->requests,
+                r->stats.errors.load(Ordering::Relaxed),
+                r->ByteSize(stats.bytes.load(Ordering::Relaxed)).to_string_as(true),
+                r->format!("{avg:.1?}")
+            ]);
+        }
+        table.set_titles(
+            row![b->"Operation", br->"Requests", br->"Errors", br->"Bytes", br->"Avg. latency"],
+        );
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+        let histogram: Vec<_> = LATENCY_NAMES
+            .iter()
+            .zip(&self.latencies)
+            .map(|(name, count)| format!("{name}: {}", count.load(Ordering::Relaxed)))
+            .collect();
+
+        eprintln!("backend statistics for {text}:");
+        eprintln!();
+        let _ = table.print(&mut std::io::stderr());
+        eprintln!();
+        eprintln!("latencies: {}", histogram.join(", "));
+    }
+}
+
+/// Backend wrapper which counts requests, transferred bytes, errors and latencies of the
+/// wrapped backend. All clones share the same counters.
+#[derive(Clone)]
+pub struct StatsBackend<BE: WriteBackend> {
+    be: BE,
+    stats: Arc<BackendStats>,
+}
+
+impl<BE: WriteBackend> StatsBackend<BE> {
+    pub fn new(be: BE) -> Self {
+        Self {
+            be,
+            stats: Arc::new(BackendStats::default()),
+        }
+    }
+
+    /// The counters of this backend
+    pub fn stats(&self) -> Arc<BackendStats> {
+        self.stats.clone()
+    }
+}
+
+impl<BE: WriteBackend> ReadBackend for StatsBackend<BE> {
+    fn location(&self) -> &str {
+        self.be.location()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        self.be.set_option(option, value)
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        let start = Instant::now();
+        let result = self.be.list_with_size(tpe);
+        self.stats.add(Operation::List, start, &result, |_| 0);
+        result
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        let start = Instant::now();
+        let result = self.be.read_full(tpe, id);
+        self.stats
+            .add(Operation::Read, start, &result, |data| data.len() as u64);
+        result
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        let start = Instant::now();
+        let result = self.be.read_partial(tpe, id, cacheable, offset, length);
+        self.stats
+            .add(Operation::Read, start, &result, |data| data.len() as u64);
+        result
+    }
+}
+
+impl<BE: WriteBackend> WriteBackend for StatsBackend<BE> {
+    fn create(&self) -> BackendResult<()> {
+        self.be.create()
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
+        let len = buf.len() as u64;
+        let start = Instant::now();
+        let result = self.be.write_bytes(tpe, id, cacheable, buf);
+        self.stats.add(Operation::Write, start, &result, |_| len);
+        result
+    }
+
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        let len = buf.len() as u64;
+        let start = Instant::now();
+        let result = self
+            .be
+            .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf);
+        self.stats.add(Operation::Write, start, &result, |_| len);
+        result
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
+        let start = Instant::now();
+        let result = self.be.remove(tpe, id, cacheable);
+        self.stats.add(Operation::Remove, start, &result, |_| 0);
+        result
+    }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+    ) -> BackendResult<bool> {
+        let start = Instant::now();
+        let result = self.be.copy_from(&from.be, tpe, id, cacheable);
+        self.stats.add(Operation::Write, start, &result, |_| 0);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendError;
+
+    #[test]
+    fn backend_stats() {
+        let stats = BackendStats::default();
+        let start = Instant::now();
+        stats.add(Operation::Read, start, &Ok(Bytes::from("abc")), |data| {
+            data.len() as u64
+        });
+        let err: BackendResult<()> = Err(BackendError::NotFound("x".to_string()));
+        stats.add(Operation::Read, start, &err, |_| 0);
+        stats.add(Operation::Write, start, &Ok(()), |_| 5);
+
+        let read = &stats.operations[Operation::Read as usize];
+        assert_eq!(read.requests.load(Ordering::Relaxed), 2);
+        assert_eq!(read.errors.load(Ordering::Relaxed), 1);
+        assert_eq!(read.bytes.load(Ordering::Relaxed), 3);
+        let write = &stats.operations[Operation::Write as usize];
+        assert_eq!(write.bytes.load(Ordering::Relaxed), 5);
+        assert_eq!(
+            stats
+                .latencies
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .sum::<u64>(),
+            3
+        );
+    }
+}
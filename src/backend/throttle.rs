@@ -0,0 +1,154 @@
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use super::{BackendResult, Capabilities, FileType, Id, ReadBackend, WriteBackend};
+
+/// Limits the average transfer rate
+struct RateLimiter {
+    bytes_per_sec: f64,
+    // point in time when the transfers accounted so far are finished w.r.t. the limit
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(kib_per_sec: u32) -> Self {
+        Self {
+            bytes_per_sec: f64::from(kib_per_sec) * 1024.0,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Sleep until the previous transfers are finished w.r.t. the limit and account for the
+    /// transfer of `bytes`.
+    fn throttle(&self, bytes: usize) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            start - now
+        };
+        sleep(wait);
+    }
+}
+
+/// Backend wrapper which limits the upload and download rate of the wrapped backend.
+/// All clones share the same limits.
+#[derive(Clone)]
+pub struct ThrottledBackend<BE: WriteBackend> {
+    be: BE,
+    upload: Option<Arc<RateLimiter>>,
+    download: Option<Arc<RateLimiter>>,
+}
+
+impl<BE: WriteBackend> ThrottledBackend<BE> {
+    /// Create a new throttled backend. The limits are given in KiB/s, `None` or 0 means unlimited.
+    pub fn new(be: BE, limit_upload: Option<u32>, limit_download: Option<u32>) -> Self {
+        let limiter = |limit: Option<u32>| {
+            limit
+                .filter(|limit| *limit > 0)
+                .map(|limit| Arc::new(RateLimiter::new(limit)))
+        };
+        Self {
+            be,
+            upload: limiter(limit_upload),
+            download: limiter(limit_download),
+        }
+    }
+
+    fn throttle_download(&self, data: BackendResult<Bytes>) -> BackendResult<Bytes> {
+        if let (Some(limiter), Ok(data)) = (&self.download, &data) {
+            limiter.throttle(data.len());
+        }
+        data
+    }
+}
+
+impl<BE: WriteBackend> ReadBackend for ThrottledBackend<BE> {
+    fn location(&self) -> &str {
+        self.be.location()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.be.capabilities()
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) -> BackendResult<()> {
+        self.be.set_option(option, value)
+    }
+
+    fn list_foreign(&self, tpe: FileType) -> BackendResult<Vec<String>> {
+        self.be.list_foreign(tpe)
+    }
+
+    fn list_with_size(&self, tpe: FileType) -> BackendResult<Vec<(Id, u64)>> {
+        self.be.list_with_size(tpe)
+    }
+
+    fn read_full(&self, tpe: FileType, id: &Id) -> BackendResult<Bytes> {
+        self.throttle_download(self.be.read_full(tpe, id))
+    }
+
+    fn read_partial(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        offset: u64,
+        length: u32,
+    ) -> BackendResult<Bytes> {
+        self.throttle_download(self.be.read_partial(tpe, id, cacheable, offset, length))
+    }
+}
+
+impl<BE: WriteBackend> WriteBackend for ThrottledBackend<BE> {
+    fn create(&self) -> BackendResult<()> {
+        self.be.create()
+    }
+
+    fn write_bytes(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        buf: Bytes,
+    ) -> BackendResult<()> {
+        if let Some(limiter) = &self.upload {
+            limiter.throttle(buf.len());
+        }
+        self.be.write_bytes(tpe, id, cacheable, buf)
+    }
+
+    fn write_bytes_if_unchanged(
+        &self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+        expected: &Id,
+        buf: Bytes,
+    ) -> BackendResult<bool> {
+        if let Some(limiter) = &self.upload {
+            limiter.throttle(buf.len());
+        }
+        self.be
+            .write_bytes_if_unchanged(tpe, id, cacheable, expected, buf)
+    }
+
+    fn remove(&self, tpe: FileType, id: &Id, cacheable: bool) -> BackendResult<()> {
+        self.be.remove(tpe, id, cacheable)
+    }
+
+    fn copy_from(
+        &self,
+        from: &Self,
+        tpe: FileType,
+        id: &Id,
+        cacheable: bool,
+    ) -> BackendResult<bool> {
+        // server-side copies don't transfer any data, hence no need to throttle
+        self.be.copy_from(&from.be, tpe, id, cacheable)
+    }
+}
@@ -17,15 +17,20 @@ use crate::repo::{ConfigFile, IndexBlob, IndexPack, PackHeaderLength, PackHeader
 
 const KB: u32 = 1024;
 const MB: u32 = 1024 * KB;
-// the absolute maximum size of a pack: including headers it should not exceed 4 GB
-const MAX_SIZE: u32 = 4076 * MB;
+const GB: u64 = 1024 * MB as u64;
+// the absolute maximum size of a pack. Note that packs are built in memory.
+const MAX_SIZE: u64 = 64 * GB;
 const MAX_COUNT: u32 = 10_000;
 const MAX_AGE: Duration = Duration::from_secs(300);
+// number of pack files which are uploaded in parallel
+const UPLOAD_THREADS: usize = 4;
+// number of finished pack files waiting for upload. If the queue is full, adding blobs blocks.
+const UPLOAD_QUEUE_LEN: usize = 1;
 
 pub struct PackSizer {
-    default_size: u32,
+    default_size: u64,
     grow_factor: u32,
-    size_limit: u32,
+    size_limit: u64,
     current_size: u64,
     min_packsize_tolerate_percent: u32,
     max_packsize_tolerate_percent: u32,
@@ -46,29 +51,56 @@ impl PackSizer {
         }
     }
 
-    pub fn pack_size(&self) -> u32 {
-        (self.current_size.integer_sqrt() as u32 * self.grow_factor + self.default_size)
+    pub fn pack_size(&self) -> u64 {
+        (self.current_size.integer_sqrt() * u64::from(self.grow_factor) + self.default_size)
             .min(self.size_limit)
             .min(MAX_SIZE)
     }
 
     // returns whether the given size is not too small or too large
-    pub fn size_ok(&self, size: u32) -> bool {
+    pub fn size_ok(&self, size: u64) -> bool {
         let target_size = self.pack_size();
-        // Note: we cast to u64 so that no overflow can occur in the multiplications
-        size as u64 * 100 >= target_size as u64 * self.min_packsize_tolerate_percent as u64
-            && size as u64 * 100 <= target_size as u64 * self.max_packsize_tolerate_percent as u64
+        // Note: we cast to u128 so that no overflow can occur in the multiplications
+        size as u128 * 100 >= target_size as u128 * self.min_packsize_tolerate_percent as u128
+            && size as u128 * 100
+                <= target_size as u128 * self.max_packsize_tolerate_percent as u128
     }
 
-    fn add_size(&mut self, added: u32) {
-        self.current_size += added as u64;
+    fn add_size(&mut self, added: u64) {
+        self.current_size += added;
     }
 }
+/// A blob which is compressed (if requested) and encrypted, ready to be added to a pack file
+pub struct EncryptedBlob {
+    data: Vec<u8>,
+    uncompressed_length: Option<NonZeroU32>,
+}
+
+impl EncryptedBlob {
+    /// Compress `data` with the given zstd level (if any) and encrypt it. This doesn't need the
+    /// packer, so blobs can be encrypted in parallel and only adding them to the pack is serial.
+    pub fn new(key: &impl CryptoKey, zstd: Option<i32>, data: &[u8]) -> Result<Self> {
+        let data_len: u32 = data.len().try_into()?;
+        let (data, uncompressed_length) = match zstd {
+            None => (key.encrypt_data(data), None),
+            Some(level) => (
+                key.encrypt_data(&encode_all(data, level)?),
+                NonZeroU32::new(data_len),
+            ),
+        };
+        let data = data.map_err(|_| anyhow!("crypto error"))?;
+        Ok(Self {
+            data,
+            uncompressed_length,
+        })
+    }
+}
+
 pub struct Packer<BE: DecryptWriteBackend> {
     be: BE,
     blob_type: BlobType,
     file: BytesMut,
-    size: u32,
+    size: u64,
     count: u32,
     created: SystemTime,
     index: IndexPack,
@@ -93,8 +125,8 @@ impl<BE: DecryptWriteBackend> Packer<BE> {
                 indexer: indexer.clone(),
                 cacheable: blob_type.is_cacheable(),
             },
-            1,
-            1,
+            UPLOAD_QUEUE_LEN,
+            UPLOAD_THREADS,
         );
         let zstd = config.zstd()?;
         let pack_sizer = PackSizer::from_config(config, blob_type, total_size);
@@ -124,10 +156,16 @@ impl<BE: DecryptWriteBackend> Packer<BE> {
         self.hasher.update(data);
         let len = data.len().try_into()?;
         self.file.extend_from_slice(data);
-        self.size += len;
+        self.size += u64::from(len);
         Ok(len)
     }
 
+    /// Increase the pack size as if `size` bytes were already added, e.g. when the size of
+    /// a stream to back up is known in advance.
+    pub fn add_size_hint(&mut self, size: u64) {
+        self.pack_sizer.add_size(size);
+    }
+
     // adds the blob to the packfile; returns the actually added size
     pub fn add(&mut self, data: &[u8], id: &Id) -> Result<u64> {
         // compute size limit based on total size and size bounds
@@ -136,38 +174,49 @@ impl<BE: DecryptWriteBackend> Packer<BE> {
     }
 
     // adds the blob to the packfile; returns the actually added size
-    pub fn add_with_sizelimit(&mut self, data: &[u8], id: &Id, size_limit: u32) -> Result<u64> {
+    pub fn add_with_sizelimit(&mut self, data: &[u8], id: &Id, size_limit: u64) -> Result<u64> {
+        self.add_checked(id, size_limit, |packer| {
+            EncryptedBlob::new(packer.be.key(), packer.zstd, data)
+        })
+    }
+
+    /// The zstd compression level used for the blobs, see `EncryptedBlob::new`
+    pub fn zstd(&self) -> Option<i32> {
+        self.zstd
+    }
+
+    // adds the already encrypted blob to the packfile; returns the actually added size
+    pub fn add_encrypted(&mut self, blob: EncryptedBlob, id: &Id) -> Result<u64> {
+        let size_limit = self.pack_sizer.pack_size();
+        self.add_checked(id, size_limit, |_| Ok(blob))
+    }
+
+    fn add_checked(
+        &mut self,
+        id: &Id,
+        size_limit: u64,
+        blob: impl FnOnce(&Self) -> Result<EncryptedBlob>,
+    ) -> Result<u64> {
         // only add if this blob is not present
         if self.has(id) {
             return Ok(0);
         }
-        {
-            let indexer = self.indexer.read().unwrap();
-            if indexer.has(id) {
-                return Ok(0);
-            }
+        // reserve the blob in the (shared) indexer such that concurrent packers don't add it again
+        if !self.indexer.write().unwrap().reserve(id) {
+            return Ok(0);
         }
+        // release the reservation if the blob could not be added, so that it is packed later
+        let res = blob(self).and_then(|blob| self.add_reserved(&blob, id, size_limit));
+        if res.is_err() {
+            self.indexer.write().unwrap().release(id);
+        }
+        res
+    }
 
-        // compress if requested
-        let data_len: u32 = data.len().try_into()?;
-        let key = self.be.key();
-
-        let (data, uncompressed_length) = match self.zstd {
-            None => (
-                key.encrypt_data(data)
-                    .map_err(|_| anyhow!("crypto error"))?,
-                None,
-            ),
-            Some(level) => (
-                key.encrypt_data(&encode_all(data, level)?)
-                    .map_err(|_| anyhow!("crypto error"))?,
-                NonZeroU32::new(data_len),
-            ),
-        };
-
+    fn add_reserved(&mut self, blob: &EncryptedBlob, id: &Id, size_limit: u64) -> Result<u64> {
         // add using current total_size as repo_size
-        self.add_raw(&data, id, uncompressed_length, size_limit)?;
-        Ok(data.len().try_into()?)
+        self.add_raw(&blob.data, id, blob.uncompressed_length, size_limit)?;
+        Ok(blob.data.len().try_into()?)
     }
 
     // adds the already compressed/encrypted blob to the packfile without any check
@@ -176,7 +225,7 @@ impl<BE: DecryptWriteBackend> Packer<BE> {
         data: &[u8],
         id: &Id,
         uncompressed_length: Option<NonZeroU32>,
-        size_limit: u32,
+        size_limit: u64,
     ) -> Result<()> {
         let offset = self.size;
         let len = self.write_data(data)?;
@@ -254,6 +303,8 @@ impl<BE: DecryptWriteBackend> ActorHandle<(Bytes, Id, IndexPack)> for FileWriter
         let (file, id, mut index) = load;
         self.be
             .write_bytes(FileType::Pack, &id, self.cacheable, file)?;
+        // only add the pack to the index after it has been written, so an index file never
+        // references a pack which is not (yet) present
         index.time = Some(Local::now());
         self.indexer.write().unwrap().add(index)?;
         Ok(())
@@ -267,6 +318,7 @@ pub trait ActorHandle<T>: Clone + Send + 'static {
 pub struct Actor<T> {
     sender: Sender<T>,
     finish: Receiver<Result<()>>,
+    par: usize,
 }
 
 impl<T: Send + Sync + 'static> Actor<T> {
@@ -292,6 +344,7 @@ impl<T: Send + Sync + 'static> Actor<T> {
         Self {
             sender: tx,
             finish: finish_rx,
+            par,
         }
     }
 
@@ -303,15 +356,22 @@ impl<T: Send + Sync + 'static> Actor<T> {
     pub fn finalize(self) -> Result<()> {
         // cancel channel
         drop(self.sender);
-        // wait for items in channel to be processed
-        self.finish.recv().unwrap()
+        // wait for items in channel to be processed by all threads
+        let mut status = Ok(());
+        for _ in 0..self.par {
+            let result = self.finish.recv().unwrap();
+            if status.is_ok() {
+                status = result;
+            }
+        }
+        status
     }
 }
 
 pub struct Repacker<BE: DecryptFullBackend> {
     be: BE,
     packer: Packer<BE>,
-    size_limit: u32,
+    size_limit: u64,
 }
 
 impl<BE: DecryptFullBackend> Repacker<BE> {
@@ -362,3 +422,115 @@ impl<BE: DecryptFullBackend> Repacker<BE> {
         self.packer.finalize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::bail;
+
+    use super::*;
+    use crate::backend::{DecryptBackend, DecryptReadBackend, LocalBackend, WriteBackend};
+    use crate::chunker::random_poly;
+    use crate::crypto::{hash, Key};
+    use crate::index::Indexer;
+
+    #[derive(Clone)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl ActorHandle<usize> for Counter {
+        fn process(&self, load: usize) -> Result<()> {
+            if load == 13 {
+                bail!("failed");
+            }
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pack_size_limit() {
+        let mut config = ConfigFile::default();
+        let sizer = PackSizer::from_config(&config, BlobType::Data, 1 << 60);
+        assert_eq!(sizer.pack_size(), u64::from(u32::MAX));
+
+        config.datapack_size_limit = Some(16 * GB);
+        let sizer = PackSizer::from_config(&config, BlobType::Data, 1 << 60);
+        assert_eq!(sizer.pack_size(), 16 * GB);
+        assert!(sizer.size_ok(16 * GB));
+
+        config.datapack_size_limit = Some(u64::MAX);
+        let sizer = PackSizer::from_config(&config, BlobType::Data, u64::MAX);
+        assert_eq!(sizer.pack_size(), MAX_SIZE);
+    }
+
+    #[test]
+    fn actor_finalize_waits_for_all_threads() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let actor = Actor::new(Counter(count.clone()), 1, 4);
+        for i in 0..10 {
+            actor.send(i).unwrap();
+        }
+        actor.finalize().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+
+        let actor = Actor::new(Counter(count), 1, 4);
+        actor.send(13).unwrap();
+        assert!(actor.finalize().is_err());
+    }
+
+    #[test]
+    fn failed_add_releases_blob() {
+        let tmp = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(tmp.path().to_str().unwrap());
+        be.create().unwrap();
+        let be = DecryptBackend::new(&be, Key::new());
+        let indexer = Indexer::new(be.clone()).into_shared();
+        let config = ConfigFile::new(2, Id::random(), random_poly().unwrap());
+        let mut packer = Packer::new(be, BlobType::Data, indexer.clone(), &config, 0).unwrap();
+
+        // make adding the blob fail after it has been reserved
+        packer.created = SystemTime::now() + Duration::from_secs(3600);
+        let id = Id::random();
+        assert!(packer.add(b"data", &id).is_err());
+        assert!(indexer.write().unwrap().reserve(&id));
+        indexer.write().unwrap().release(&id);
+
+        packer.created = SystemTime::now();
+        assert!(packer.add(b"data", &Id::random()).unwrap() > 0);
+        packer.finalize().unwrap();
+    }
+
+    #[test]
+    fn add_encrypted_blob() {
+        let tmp = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(tmp.path().to_str().unwrap());
+        be.create().unwrap();
+        let be = DecryptBackend::new(&be, Key::new());
+        let indexer = Indexer::new(be.clone()).into_shared();
+        let config = ConfigFile::new(2, Id::random(), random_poly().unwrap());
+        let mut packer = Packer::new(be.clone(), BlobType::Data, indexer, &config, 0).unwrap();
+        assert!(packer.zstd().is_some());
+
+        let data = b"data data data data data data data data";
+        let id = hash(data);
+        let blob = EncryptedBlob::new(be.key(), packer.zstd(), data).unwrap();
+        let uncompressed_length = blob.uncompressed_length;
+        let encrypted = blob.data.clone();
+        assert_eq!(
+            packer.add_encrypted(blob, &id).unwrap(),
+            encrypted.len() as u64
+        );
+        assert_eq!(
+            be.decrypt_blob(&encrypted, uncompressed_length).unwrap(),
+            &data[..]
+        );
+
+        // a blob which is already added is not added again
+        let blob = EncryptedBlob::new(be.key(), packer.zstd(), data).unwrap();
+        assert_eq!(packer.add_encrypted(blob, &id).unwrap(), 0);
+        packer.finalize().unwrap();
+    }
+}
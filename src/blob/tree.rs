@@ -1,10 +1,13 @@
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
 
-use anyhow::{anyhow, Result};
-use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use anyhow::{anyhow, bail, Result};
+use crossbeam_channel::{bounded, never, unbounded, Receiver, Sender};
 use derive_getters::Getters;
+use ignore::overrides::Override;
 use indicatif::ProgressBar;
 use serde::{Deserialize, Deserializer, Serialize};
 
@@ -12,9 +15,9 @@ use crate::crypto::hash;
 use crate::id::Id;
 use crate::index::IndexedBackend;
 
-use super::Node;
+use super::{Metadata, Node, NodeType};
 
-#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Getters)]
 pub struct Tree {
     #[serde(deserialize_with = "deserialize_null_default")]
     nodes: Vec<Node>,
@@ -51,7 +54,30 @@ impl Tree {
             .ok_or_else(|| anyhow!("blob {} not found in index", id.to_hex()))?
             .read_data(be.be())?;
 
-        Ok(serde_json::from_slice(&data)?)
+        let tree: Self = serde_json::from_slice(&data)?;
+        tree.validate()
+            .map_err(|err| anyhow!("tree {} is invalid: {err}", id.to_hex()))?;
+        Ok(tree)
+    }
+
+    /// Check that all node names can be used as path component and are unique.
+    /// Trees read from a (possibly tampered) repository are used to build paths, so names like
+    /// ".." or "/etc" must never be accepted.
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for node in &self.nodes {
+            let name = node.name();
+            if name.is_empty() || name == "." || name == ".." {
+                bail!("invalid node name {name:?}");
+            }
+            if name.to_string_lossy().contains('/') {
+                bail!("node name {name:?} contains a '/'");
+            }
+            if !names.insert(name) {
+                bail!("duplicate node name {:?}", node.name());
+            }
+        }
+        Ok(())
     }
 
     pub fn subtree_id(be: &impl IndexedBackend, mut id: Id, path: &Path) -> Result<Id> {
@@ -71,6 +97,27 @@ impl Tree {
         }
         Ok(id)
     }
+
+    /// Get the node at `path` within the tree `id`. For an empty path or "/", a dir node with
+    /// an empty name and `id` as subtree is returned.
+    pub fn node_from_path(be: &impl IndexedBackend, id: Id, path: &Path) -> Result<Node> {
+        let mut node = Node::new_node(OsStr::new(""), NodeType::Dir, Metadata::default());
+        node.set_subtree(id);
+        for p in path.iter() {
+            if p == "/" {
+                continue;
+            }
+            let id = node
+                .subtree()
+                .ok_or_else(|| anyhow!("{:?} is no dir", node.name()))?;
+            node = Tree::from_backend(be, id)?
+                .nodes
+                .into_iter()
+                .find(|node| node.name() == p)
+                .ok_or_else(|| anyhow!("{:?} not found", p))?;
+        }
+        Ok(node)
+    }
 }
 
 impl IntoIterator for Tree {
@@ -91,6 +138,7 @@ where
     inner: std::vec::IntoIter<Node>,
     path: PathBuf,
     be: BE,
+    overrides: Option<Override>,
 }
 
 impl<BE> NodeStreamer<BE>
@@ -104,8 +152,17 @@ where
             open_iterators: Vec::new(),
             path: PathBuf::new(),
             be,
+            overrides: None,
         })
     }
+
+    /// Create a NodeStreamer which skips all nodes which are ignored by the given glob overrides.
+    /// Subtrees of ignored directories are not read at all.
+    pub fn new_with_glob(be: BE, id: Id, overrides: Override) -> Result<Self> {
+        let mut streamer = Self::new(be, id)?;
+        streamer.overrides = Some(overrides);
+        Ok(streamer)
+    }
 }
 
 type NodeStreamItem = Result<(PathBuf, Node)>;
@@ -122,6 +179,11 @@ where
             match self.inner.next() {
                 Some(node) => {
                     let path = self.path.join(node.name());
+                    if let Some(overrides) = &self.overrides {
+                        if overrides.matched(&path, node.is_dir()).is_ignore() {
+                            continue;
+                        }
+                    }
                     if let Some(id) = node.subtree() {
                         self.path.push(node.name());
                         let be = self.be.clone();
@@ -155,6 +217,7 @@ pub struct TreeStreamerOnce {
     p: ProgressBar,
     counter: Vec<usize>,
     finished_ids: usize,
+    handles: Vec<JoinHandle<()>>,
 }
 
 const MAX_TREE_LOADER: usize = 4;
@@ -166,18 +229,22 @@ impl TreeStreamerOnce {
         let (out_tx, out_rx) = bounded(MAX_TREE_LOADER);
         let (in_tx, in_rx) = unbounded();
 
-        for _ in 0..MAX_TREE_LOADER {
-            let be = be.clone();
-            let in_rx = in_rx.clone();
-            let out_tx = out_tx.clone();
-            std::thread::spawn(move || {
-                for (path, id, count) in in_rx {
-                    out_tx
-                        .send(Tree::from_backend(&be, id).map(|tree| (path, tree, count)))
-                        .unwrap();
-                }
-            });
-        }
+        let handles = (0..MAX_TREE_LOADER)
+            .map(|_| {
+                let be = be.clone();
+                let in_rx = in_rx.clone();
+                let out_tx = out_tx.clone();
+                std::thread::spawn(move || {
+                    for (path, id, count) in in_rx {
+                        let tree = Tree::from_backend(&be, id).map(|tree| (path, tree, count));
+                        if out_tx.send(tree).is_err() {
+                            // the streamer has been dropped
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
 
         let counter = vec![0; ids.len()];
         let mut streamer = Self {
@@ -187,6 +254,7 @@ impl TreeStreamerOnce {
             p,
             counter,
             finished_ids: 0,
+            handles,
         };
 
         for (count, id) in ids.into_iter().enumerate() {
@@ -245,3 +313,76 @@ impl Iterator for TreeStreamerOnce {
         Some(Ok((path, tree)))
     }
 }
+
+impl Drop for TreeStreamerOnce {
+    // Wait for the loader threads such that they don't hold the backend (and e.g. the index)
+    // after the streamer is gone.
+    fn drop(&mut self) {
+        drop(self.queue_in.take());
+        drop(mem::replace(&mut self.queue_out, never()));
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fuzz;
+    use quickcheck_macros::quickcheck;
+    use rstest::rstest;
+
+    const TREE: &[u8] = br#"{"nodes":[{"name":"file","type":"file","mode":420,"mtime":"2022-07-27T16:59:42.398957497+02:00","uid":1000,"gid":1000,"size":20,"content":["3ec79977ef0cf5de7b08cd12b874cd0f62bbaf7f07f3497a5b1bbcc8cb39b1ce"]},{"name":"dir","type":"dir","mode":2147484141,"subtree":"9ccb846e60d90d4eb915848add7aa7ea1e4bbabfc60e573db9f7bfb2789afbae"},{"name":"link","type":"symlink","linktarget":"file"},{"name":"dev","type":"chardev","device":1025}]}"#;
+
+    #[test]
+    fn parse_tree() {
+        assert!(serde_json::from_slice::<Tree>(TREE).is_ok());
+    }
+
+    // corrupted or arbitrary tree files must not panic, only return an error
+    #[quickcheck]
+    fn parse_corrupted_tree(changes: Vec<(usize, u8)>, len: usize) -> bool {
+        fuzz::parse_tree(&fuzz::corrupt(TREE, changes, len));
+        true
+    }
+
+    #[quickcheck]
+    fn parse_arbitrary_tree(data: Vec<u8>) -> bool {
+        fuzz::parse_tree(&data);
+        true
+    }
+
+    // arbitrary node names must not panic when they are unescaped and the tree is validated
+    #[quickcheck]
+    fn parse_tree_arbitrary_names(names: Vec<String>) -> bool {
+        let nodes: Vec<_> = names
+            .iter()
+            .map(|name| serde_json::json!({"name": name, "type": "file"}))
+            .collect();
+        let data = serde_json::to_vec(&serde_json::json!({ "nodes": nodes })).unwrap();
+        fuzz::parse_tree(&data);
+        true
+    }
+
+    #[test]
+    fn validate_tree() {
+        let tree: Tree = serde_json::from_slice(TREE).unwrap();
+        assert!(tree.validate().is_ok());
+    }
+
+    #[rstest]
+    #[case(r#"[{"name":"","type":"file"}]"#)]
+    #[case(r#"[{"name":".","type":"dir"}]"#)]
+    #[case(r#"[{"name":"..","type":"dir"}]"#)]
+    #[case(r#"[{"name":"/etc","type":"dir"}]"#)]
+    #[case(r#"[{"name":"../../etc","type":"file"}]"#)]
+    // escaped name which is unescaped to ".."
+    #[case(r#"[{"name":"\\x2e\\x2e","type":"file"}]"#)]
+    #[case(r#"[{"name":"file","type":"file"},{"name":"file","type":"symlink","linktarget":"/"}]"#)]
+    fn validate_invalid_tree(#[case] nodes: &str) {
+        let tree: Tree = serde_json::from_str(&format!(r#"{{"nodes":{nodes}}}"#)).unwrap();
+        assert!(tree.validate().is_err());
+    }
+}
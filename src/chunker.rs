@@ -29,6 +29,8 @@ pub struct ChunkIter<R: Read> {
 }
 
 impl<R: Read> ChunkIter<R> {
+    /// Create a new chunk iterator. `size_hint` is only used to optimize memory allocation and
+    /// should be an upper bound of the size to read; it may be 0 if the size is unknown, e.g. for stdin.
     pub fn new(reader: R, size_hint: usize, poly: &Polynom64) -> Self {
         Self {
             buf: Vec::with_capacity(4 * KB),
@@ -36,7 +38,7 @@ impl<R: Read> ChunkIter<R> {
             reader,
             predicate: default_predicate,
             rabin: Rabin64::new_with_polynom(6, poly),
-            size_hint, // size hint is used to optimize memory allocation; this should be an upper bound on the size
+            size_hint,
             min_size: MIN_SIZE,
             max_size: MAX_SIZE,
             finished: false,
@@ -117,7 +119,7 @@ impl<R: Read> Iterator for ChunkIter<R> {
             self.pos += 1;
             self.rabin.slide(&byte);
         }
-        self.size_hint -= vec.len();
+        self.size_hint = self.size_hint.saturating_sub(vec.len());
         Some(Ok(vec))
     }
 }
@@ -232,11 +234,16 @@ fn qp(p: i32, g: &Polynom64) -> Polynom64 {
     res.add(&2).modulo(g)
 }
 
+#[cfg(test)]
+mod gorand;
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::{repeat, Cursor};
 
+    use crate::crypto::hash;
+
     #[test]
     fn chunk_empty() {
         let empty: Vec<u8> = vec![];
@@ -271,4 +278,160 @@ mod tests {
         let chunk = chunker.next().unwrap().unwrap();
         assert_eq!(MIN_SIZE, chunk.len());
     }
+
+    #[test]
+    fn chunk_unknown_size() {
+        // e.g. stdin: the size hint is 0, but much more data is read
+        let data = gorand::random_data(42, 3 * MAX_SIZE);
+        let poly = random_poly().unwrap();
+        let chunks: Vec<_> = ChunkIter::new(&data[..], 0, &poly)
+            .map(|chunk| chunk.unwrap())
+            .collect();
+        assert!(chunks.len() > 1);
+        assert_eq!(data, chunks.concat());
+    }
+
+    fn chunks(data: &[u8], poly: u64) -> Vec<(usize, String)> {
+        ChunkIter::new(data, data.len(), &poly)
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                (chunk.len(), hash(&chunk).to_hex())
+            })
+            .collect()
+    }
+
+    fn chunk_lengths(data: &[u8], poly: u64) -> Vec<usize> {
+        chunks(data, poly).into_iter().map(|(len, _)| len).collect()
+    }
+
+    // Test vectors `chunks1` of restic's chunker (github.com/restic/chunker, chunker_test.go):
+    // length and SHA-256 digest of the chunks of 32 MiB random data (`getRandom(23, 32*MiB)`)
+    // using the polynomial 0x3DA3358B4DC173. They must never change as this would break the
+    // deduplication with existing repositories.
+    const CHUNKS1: [(usize, &str); 23] = [
+        (
+            2163460,
+            "4b94cb2cf293855ea43bf766731c74969b91aa6bf3c078719aabdd19860d590d",
+        ),
+        (
+            643703,
+            "5727a63c0964f365ab8ed2ccf604912f2ea7be29759a2b53ede4d6841e397407",
+        ),
+        (
+            1528956,
+            "a73759636a1e7a2758767791c69e81b69fb49236c6929e5d1b654e06e37674ba",
+        ),
+        (
+            1955808,
+            "c955fb059409b25f07e5ae09defbbc2aadf117c97a3724e06ad4abd2787e6824",
+        ),
+        (
+            2222372,
+            "6ba5e9f7e1b310722be3627716cf469be941f7f3e39a4c3bcefea492ec31ee56",
+        ),
+        (
+            2538687,
+            "8687937412f654b5cfe4a82b08f28393a0c040f77c6f95e26742c2fc4254bfde",
+        ),
+        (
+            609606,
+            "5da820742ff5feb3369112938d3095785487456f65a8efc4b96dac4be7ebb259",
+        ),
+        (
+            1205738,
+            "cc70d8fad5472beb031b1aca356bcab86c7368f40faa24fe5f8922c6c268c299",
+        ),
+        (
+            959742,
+            "4065bdd778f95676c92b38ac265d361f81bff17d76e5d9452cf985a2ea5a4e39",
+        ),
+        (
+            4036109,
+            "b9cf166e75200eb4993fc9b6e22300a6790c75e6b0fc8f3f29b68a752d42f275",
+        ),
+        (
+            1525894,
+            "2f238180e4ca1f7520a05f3d6059233926341090f9236ce677690c1823eccab3",
+        ),
+        (
+            1352720,
+            "afd12f13286a3901430de816e62b85cc62468c059295ce5888b76b3af9028d84",
+        ),
+        (
+            811884,
+            "42d0cdb1ee7c48e552705d18e061abb70ae7957027db8ae8db37ec756472a70a",
+        ),
+        (
+            1282314,
+            "819721c2457426eb4f4c7565050c44c32076a56fa9b4515a1c7796441730eb58",
+        ),
+        (
+            1318021,
+            "842eb53543db55bacac5e25cb91e43cc2e310fe5f9acc1aee86bdf5e91389374",
+        ),
+        (
+            948640,
+            "b8e36bf7019bb96ac3fb7867659d2167d9d3b3148c09fe0de45850b8fe577185",
+        ),
+        (
+            645464,
+            "5584bd27982191c3329f01ed846bfd266e96548dfa87018f745c33cfc240211d",
+        ),
+        (
+            533758,
+            "4da778a25b72a9a0d53529eccfe2e5865a789116cb1800f470d8df685a8ab05d",
+        ),
+        (
+            1128303,
+            "08c6b0b38095b348d80300f0be4c5184d2744a17147c2cba5cc4315abf4c048f",
+        ),
+        (
+            800374,
+            "820284d2c8fd243429674c996d8eb8d3450cbc32421f43113e980f516282c7bf",
+        ),
+        (
+            2453512,
+            "5fa870ed107c67704258e5e50abe67509fb73562caf77caa843b5f243425d853",
+        ),
+        (
+            2651975,
+            "181347d2bbec32bef77ad5e9001e6af80f6abcf3576549384d334ee00c1988d8",
+        ),
+        (
+            237392,
+            "fcd567f5d866357a8e299fd5b2359bb2c8157c30395229c4e9b0a353944a7978",
+        ),
+    ];
+
+    #[test]
+    fn chunk_vectors() {
+        let data = gorand::random_data(23, 32 * MB);
+        let expected: Vec<_> = CHUNKS1
+            .iter()
+            .map(|(len, digest)| (*len, digest.to_string()))
+            .collect();
+        assert_eq!(chunks(&data, 0x3DA3358B4DC173), expected);
+    }
+
+    // Test vectors `chunks2` of restic's chunker: 32 MiB of zeros are split into chunks of
+    // the minimum size.
+    #[test]
+    fn chunk_vectors_zeros() {
+        let data = vec![0u8; 32 * MB];
+        let digest = "07854d2fef297a06ba81685e660c332de36d5d18d546927d30daad6d7fda1541";
+        assert_eq!(
+            chunks(&data, 0x3DA3358B4DC173),
+            vec![(MIN_SIZE, digest.to_string()); 64]
+        );
+    }
+
+    #[test]
+    fn chunk_max_size() {
+        // no 64 byte window of this data is a chunk boundary
+        let data: Vec<_> = (0..20 * MB).map(|i| i as u8).collect();
+        assert_eq!(
+            chunk_lengths(&data, 0x3DA3358B4DC173),
+            [MAX_SIZE, MAX_SIZE, 4 * MB]
+        );
+    }
 }
@@ -1,24 +1,36 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use anyhow::{anyhow, Result};
-use chrono::{Duration, Local};
-use clap::{AppSettings, Parser};
+use anyhow::{anyhow, bail, Result};
+use bytesize::ByteSize;
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone};
+use clap::{AppSettings, Parser, ValueEnum};
 use gethostname::gethostname;
 use log::*;
 use merge::Merge;
+use nix::unistd::{Group, User};
 use path_dedot::ParseDot;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
 
-use super::{bytes, progress_bytes, progress_counter, RusticConfig};
-use crate::archiver::{Archiver, Parent};
+use super::hooks::{HookContext, HookOpts};
+use super::{
+    bytes, index_files, progress_bytes, progress_bytes_stream, progress_counter, status,
+    BackupProgress, RusticConfig,
+};
+use crate::archiver::{Archiver, ArchiverBuilder, Parent, SharedPackers};
 use crate::backend::{
     DecryptFullBackend, DecryptWriteBackend, DryRunBackend, LocalSource, LocalSourceOptions,
     ReadSource,
 };
 use crate::blob::{Metadata, Node, NodeType};
-use crate::index::IndexBackend;
-use crate::repo::{ConfigFile, DeleteOption, SnapshotFile, SnapshotSummary, StringList};
+use crate::index::{IndexBackend, IndexedBackend};
+use crate::repo::{
+    BackupReport, ConfigFile, DeleteOption, SnapshotFile, SnapshotGroupCriterion, SnapshotSummary,
+    SourceReport, StringList,
+};
 
 #[serde_as]
 #[derive(Clone, Default, Parser, Deserialize, Merge)]
@@ -26,50 +38,159 @@ use crate::repo::{ConfigFile, DeleteOption, SnapshotFile, SnapshotSummary, Strin
 #[serde(default, rename_all = "kebab-case")]
 pub(super) struct Opts {
     /// Do not upload or write any data, just show what would be done
-    #[clap(long, short = 'n')]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    dry_run: bool,
+    #[clap(
+        long,
+        short = 'n',
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    dry_run: Option<bool>,
 
     /// Snapshot to use as parent
     #[clap(long, value_name = "SNAPSHOT", conflicts_with = "force")]
     parent: Option<String>,
 
     /// Use no parent, read all files
-    #[clap(long, short, conflicts_with = "parent")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    force: bool,
+    #[clap(
+        long,
+        short,
+        conflicts_with = "parent",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    force: Option<bool>,
+
+    /// Group snapshots by any combination of host,paths,tags to find the parent snapshot; the latest
+    /// snapshot of the group of the new snapshot is used as parent [default: host,paths]
+    #[clap(long, value_name = "CRITERION", conflicts_with = "parent")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    group_by: Option<SnapshotGroupCriterion>,
+
+    /// If no parent is found in the group, use the latest snapshot containing the backup path,
+    /// preferring snapshots from the same host and then snapshots with the same tags
+    #[clap(
+        long,
+        conflicts_with_all = &["parent", "force"],
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    parent_fallback: Option<bool>,
 
     /// Ignore ctime changes when checking for modified files
-    #[clap(long, conflicts_with = "force")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    ignore_ctime: bool,
+    #[clap(
+        long,
+        conflicts_with = "force",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    ignore_ctime: Option<bool>,
 
     /// Ignore inode number changes when checking for modified files
-    #[clap(long, conflicts_with = "force")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    ignore_inode: bool,
-
-    /// Tags to add to backup (can be specified multiple times)
+    #[clap(
+        long,
+        conflicts_with = "force",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    ignore_inode: Option<bool>,
+
+    /// Detect renamed or moved directories by their inode and compare their contents with the
+    /// directory in the parent snapshot, so unchanged files are not read again.
+    /// This reads all trees of the parent snapshot once a directory is not found in the parent.
+    #[clap(
+        long,
+        conflicts_with_all = &["force", "ignore-inode"],
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    detect_renames: Option<bool>,
+
+    /// Don't save the snapshot if its tree is identical to the tree of the parent snapshot,
+    /// i.e. nothing has changed (e.g. to avoid identical snapshots from frequent backups)
+    #[clap(
+        long,
+        conflicts_with = "force",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    skip_if_unchanged: Option<bool>,
+
+    /// Tags to add to backup (can be specified multiple times). The variables {hostname}, {date}
+    /// (YYYY-MM-DD), {weekday} (e.g. Monday) and {source} are replaced by their values at backup
+    /// time; use {{ and }} for literal braces
     #[clap(long, value_name = "TAG[,TAG,..]")]
     #[serde_as(as = "Vec<DisplayFromStr>")]
     #[merge(strategy = merge::vec::overwrite_empty)]
     tag: Vec<StringList>,
 
     /// Mark snapshot as uneraseable
-    #[clap(long, conflicts_with = "delete-after")]
-    #[merge(strategy = merge::bool::overwrite_false)]
-    delete_never: bool,
+    #[clap(
+        long,
+        conflicts_with = "delete-after",
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    delete_never: Option<bool>,
 
     /// Mark snapshot to be deleted after given duration (e.g. 10d)
     #[clap(long, value_name = "DURATION")]
     #[serde_as(as = "Option<DisplayFromStr>")]
     delete_after: Option<humantime::Duration>,
 
+    /// What to do with files which cannot be opened, e.g. as they are locked or busy:
+    /// skip them (default) or retry opening them using exponential backoff
+    #[clap(long, value_enum, value_name = "POLICY")]
+    open_file_policy: Option<OpenFilePolicy>,
+
+    /// Maximum duration (e.g. 30s) to retry opening a file when using --open-file-policy retry [default: 1m]
+    #[clap(long, value_name = "DURATION")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    open_file_retry_time: Option<humantime::Duration>,
+
     /// Set filename to be used when backing up from stdin
     #[clap(long, value_name = "FILENAME", default_value = "stdin")]
     #[merge(skip)]
     stdin_filename: String,
 
+    /// Expected size of the data read from stdin (e.g. 2TiB). This is only used as a hint for the
+    /// pack size and to show the progress, the actual size may differ.
+    #[clap(long, value_name = "SIZE")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    stdin_size_hint: Option<ByteSize>,
+
+    /// Set the permissions of the file saved when backing up from stdin, given in octal (e.g. 0600)
+    #[clap(long, value_name = "MODE")]
+    stdin_mode: Option<String>,
+
+    /// Set the modification time of the file saved when backing up from stdin [default: now].
+    /// The time is given as "now", RFC 3339 (e.g. 2022-07-27T16:59:42+02:00) or local time (e.g. 2022-07-27 16:59:42)
+    #[clap(long, value_name = "TIME")]
+    stdin_mtime: Option<String>,
+
+    /// Set the owner of the file saved when backing up from stdin
+    #[clap(long, value_name = "USER")]
+    stdin_user: Option<String>,
+
+    /// Set the group of the file saved when backing up from stdin
+    #[clap(long, value_name = "GROUP")]
+    stdin_group: Option<String>,
+
     /// Manually set backup path in snapshot
     #[clap(long, value_name = "PATH")]
     as_path: Option<PathBuf>,
@@ -78,10 +199,49 @@ pub(super) struct Opts {
     #[clap(long, value_name = "NAME")]
     host: Option<String>,
 
+    /// Set the time of the snapshot [default: now].
+    /// The time is given as "now", RFC 3339 (e.g. 2022-07-27T16:59:42+02:00) or local time (e.g. 2022-07-27 16:59:42)
+    #[clap(long, value_name = "TIME")]
+    time: Option<String>,
+
+    /// Create reproducible trees: atime, ctime, inode and device ID are not saved and extended
+    /// attributes are sorted, so backing up identical content always gives identical tree ids.
+    /// Use together with --time to also fix the snapshot time and the mtime of stdin backups
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    deterministic: Option<bool>,
+
+    /// Number of sources to back up in parallel [default: 1]
+    #[clap(long, value_name = "N")]
+    parallel_sources: Option<usize>,
+
+    /// Save a report of the backup run in the repository and reference it from the snapshots
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    save_report: Option<bool>,
+
+    /// Add the new snapshot to this SQLite database, see the index-files command
+    #[clap(long, value_name = "FILE")]
+    index_files_db: Option<PathBuf>,
+
     #[clap(flatten)]
     #[serde(flatten)]
     ignore_opts: LocalSourceOptions,
 
+    #[clap(flatten)]
+    #[serde(flatten)]
+    hook_opts: HookOpts,
+
     /// Backup source (can be specified multiple times), use - for stdin. If no source is given, uses all
     /// sources defined in the config file
     #[clap(value_name = "SOURCE")]
@@ -95,6 +255,19 @@ pub(super) struct Opts {
     source: String,
 }
 
+impl Opts {
+    fn dry_run(&self) -> bool {
+        self.dry_run.unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum OpenFilePolicy {
+    Skip,
+    Retry,
+}
+
 pub(super) fn execute(
     be: &impl DecryptFullBackend,
     opts: Opts,
@@ -102,7 +275,7 @@ pub(super) fn execute(
     config_file: RusticConfig,
     command: String,
 ) -> Result<()> {
-    let time = Local::now();
+    let now = Local::now();
 
     let zstd = config.zstd()?;
 
@@ -120,182 +293,608 @@ pub(super) fn execute(
         }
     };
 
-    let index = IndexBackend::only_full_trees(&be.clone(), progress_counter(""))?;
-
+    let mut sources_opts = Vec::new();
     for source in sources {
-        let mut opts = opts.clone();
+        let source_opts = merge_source_opts(&opts, &source, &mut config_opts, &config_file)?;
+        sources_opts.push((source, source_opts));
+    }
+
+    let (parallel_sources, save_report, time) = match sources_opts.first() {
+        Some((_, opts)) => (
+            opts.parallel_sources.unwrap_or(1),
+            opts.save_report.unwrap_or(false),
+            parse_time(opts.time.as_deref().unwrap_or("now"), now)?,
+        ),
+        None => (1, false, now),
+    };
+
+    let index = IndexBackend::only_full_trees(&be.clone(), progress_counter(""))?;
 
-        // merge Options from config file, if given
-        if let Some(idx) = config_opts.iter().position(|opt| opt.source == *source) {
-            info!("merging source=\"{source}\" section from config file");
-            opts.merge(config_opts.remove(idx));
+    // share the packers between all sources such that not every source writes its own partially filled packs.
+    // This is only possible if all sources use the same dry-run setting.
+    // Shared packers are also used to save the report, as the snapshots must be saved after the report.
+    let packers = match sources_opts.split_first() {
+        Some(((_, first), others))
+            if (!others.is_empty() || save_report)
+                && others
+                    .iter()
+                    .all(|(_, opts)| opts.dry_run() == first.dry_run()) =>
+        {
+            let mut be = DryRunBackend::new(be.clone(), first.dry_run());
+            be.set_zstd(zstd);
+            Some(SharedPackers::new(be, &index, &config)?)
         }
-        // merge Options from config file using as_path, if given
-        if let Some(path) = &opts.as_path {
-            if let Some(path) = path.as_os_str().to_str() {
-                if let Some(idx) = config_opts.iter().position(|opt| opt.source == path) {
-                    info!("merging source=\"{path}\" section from config file");
-                    opts.merge(config_opts.remove(idx));
+        _ => None,
+    };
+    if save_report && packers.is_none() {
+        warn!("not saving a report as the sources use different dry-run settings.");
+    }
+
+    let backup = |(source, opts): (String, Opts)| {
+        let result = hostname(&opts)
+            .and_then(|hostname| {
+                opts.hook_opts.run_before(&HookContext {
+                    hook: "before",
+                    source: &source,
+                    hostname: &hostname,
+                    time,
+                    dry_run: opts.dry_run(),
+                    success: None,
+                    error: None,
+                    snapshot: None,
+                })
+            })
+            .and_then(|_| {
+                backup_source(
+                    be,
+                    &index,
+                    &config,
+                    zstd,
+                    &source,
+                    &opts,
+                    time,
+                    &command,
+                    packers.clone(),
+                )
+            })
+            .and_then(|(snap, unchanged)| {
+                if unchanged {
+                    println!("snapshot is unchanged compared to its parent, not saving it.");
+                } else if packers.is_none() {
+                    snapshot_saved(be, &snap, &opts)?;
                 }
+                info!("backup of \"{source}\" done.");
+                Ok((snap, unchanged))
+            });
+        (source, opts, result)
+    };
+
+    let results: Vec<_> = if parallel_sources > 1 && sources_opts.len() > 1 {
+        info!("backing up up to {parallel_sources} sources in parallel...");
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(parallel_sources)
+            .build()?;
+        pool.install(|| sources_opts.into_par_iter().map(backup).collect())
+    } else {
+        sources_opts.into_iter().map(backup).collect()
+    };
+
+    let mut errors = 0;
+    let mut report = BackupReport {
+        time,
+        command,
+        sources: Vec::new(),
+    };
+    // the results of the sources as needed for the after hooks: the snapshot (if saved) or the error
+    let mut finished = Vec::new();
+    for (source, opts, result) in results {
+        match result {
+            Ok((snap, unchanged)) => {
+                report.sources.push(SourceReport {
+                    source: source.clone(),
+                    summary: snap.summary.clone(),
+                    error: None,
+                });
+                finished.push((source, opts, Ok((!unchanged).then_some(snap))));
+            }
+            Err(err) => {
+                error!("{err}");
+                report.sources.push(SourceReport {
+                    source: source.clone(),
+                    summary: None,
+                    error: Some(err.to_string()),
+                });
+                errors += 1;
+                finished.push((source, opts, Err(err.to_string())));
             }
         }
-        // merge "backup" section from config file, if given
-        config_file.merge_into("backup", &mut opts)?;
-
-        let mut be = DryRunBackend::new(be.clone(), opts.dry_run);
-        be.set_zstd(zstd);
-        info!("starting to backup \"{source}\"...");
-        let index = index.clone();
-        let backup_stdin = source == "-";
-        let backup_path = if backup_stdin {
-            PathBuf::from(&opts.stdin_filename)
-        } else {
-            PathBuf::from(&source).parse_dot()?.to_path_buf()
-        };
-        let as_path = match opts.as_path {
-            None => None,
-            Some(p) => Some(p.parse_dot()?.to_path_buf()),
+    }
+
+    if let Some(packers) = packers {
+        let report_id = match save_report {
+            true => Some(packers.add_data(&index, &serde_json::to_vec(&report)?)?),
+            false => None,
         };
-        let backup_path_str = as_path.as_ref().unwrap_or(&backup_path);
-        let backup_path_str = backup_path_str
-            .to_str()
-            .ok_or_else(|| anyhow!("non-unicode path {:?}", backup_path_str))?
-            .to_string();
-
-        let hostname = match opts.host {
-            Some(host) => host,
-            None => {
-                let hostname = gethostname();
-                hostname
-                    .to_str()
-                    .ok_or_else(|| anyhow!("non-unicode hostname {:?}", hostname))?
-                    .to_string()
+        packers.finalize()?;
+        for (_, opts, result) in &mut finished {
+            if let Ok(Some(snap)) = result {
+                let mut be = DryRunBackend::new(be.clone(), opts.dry_run());
+                be.set_zstd(zstd);
+                snap.report = report_id;
+                snap.id = be.save_file(snap)?;
+                snapshot_saved(&be, snap, opts)?;
             }
-        };
+        }
+        if let Some(id) = report_id {
+            info!("backup report saved as data blob {id}.");
+        }
+    }
 
-        let parent = match (backup_stdin, opts.force, opts.parent.clone()) {
-            (true, _, _) | (false, true, _) => None,
-            (false, false, None) => SnapshotFile::latest(
-                &be,
-                |snap| snap.hostname == hostname && snap.paths.contains(&backup_path_str),
-                progress_counter(""),
-            )
-            .ok(),
-            (false, false, Some(parent)) => SnapshotFile::from_id(&be, &parent).ok(),
+    for (source, opts, result) in &finished {
+        let context = HookContext {
+            hook: "after",
+            source,
+            hostname: &hostname(opts)?,
+            time,
+            dry_run: opts.dry_run(),
+            success: Some(result.is_ok()),
+            error: result.as_ref().err().map(String::as_str),
+            snapshot: result
+                .as_ref()
+                .ok()
+                .and_then(|snap| snap.as_ref().map(|snap| snap.id)),
         };
+        if let Err(err) = opts.hook_opts.run_after(&context) {
+            error!("{err}");
+            // failed sources are already counted
+            if result.is_ok() {
+                errors += 1;
+            }
+        }
+    }
+
+    if errors > 0 {
+        bail!("backup of {errors} sources failed.");
+    }
+
+    Ok(())
+}
 
-        let parent_tree = match &parent {
-            Some(snap) => {
-                info!("using parent {}", snap.id);
-                Some(snap.tree)
+/// Combine the options for a source. The precedence is:
+/// command line > matching `[[backup.sources]]` entry > `[backup]` section > defaults
+fn merge_source_opts(
+    opts: &Opts,
+    source: &str,
+    config_opts: &mut Vec<Opts>,
+    config_file: &RusticConfig,
+) -> Result<Opts> {
+    let mut opts = opts.clone();
+
+    // merge Options from config file, if given
+    if let Some(idx) = config_opts.iter().position(|opt| opt.source == source) {
+        info!("merging source=\"{source}\" section from config file");
+        opts.merge(config_opts.remove(idx));
+    }
+    // merge Options from config file using as_path, if given
+    if let Some(path) = &opts.as_path {
+        if let Some(path) = path.as_os_str().to_str() {
+            if let Some(idx) = config_opts.iter().position(|opt| opt.source == path) {
+                info!("merging source=\"{path}\" section from config file");
+                opts.merge(config_opts.remove(idx));
             }
-            None => {
-                info!("using no parent");
-                None
+        }
+    }
+    // merge "backup" section from config file, if given
+    config_file.merge_into("backup", &mut opts)?;
+    Ok(opts)
+}
+
+/// Parse a time given as "now", RFC 3339 or local time in the format "YYYY-MM-DD HH:MM:SS"
+fn parse_time(s: &str, now: DateTime<Local>) -> Result<DateTime<Local>> {
+    if s == "now" {
+        return Ok(now);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).single())
+        .ok_or_else(|| anyhow!("invalid time \"{s}\""))
+}
+
+/// The host name given by --host or the name of this host
+fn hostname(opts: &Opts) -> Result<String> {
+    Ok(match &opts.host {
+        Some(host) => host.clone(),
+        None => {
+            let hostname = gethostname();
+            hostname
+                .to_str()
+                .ok_or_else(|| anyhow!("non-unicode hostname {:?}", hostname))?
+                .to_string()
+        }
+    })
+}
+
+/// Metadata of the file saved when backing up from stdin, as given by the --stdin-* options
+fn stdin_metadata(opts: &Opts, time: DateTime<Local>) -> Result<Metadata> {
+    let mode = opts
+        .stdin_mode
+        .as_ref()
+        .map(|mode| match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => Err(anyhow!(
+                "invalid mode \"{mode}\", expected octal permissions like 0600"
+            )),
+        })
+        .transpose()?;
+    let mtime = parse_time(opts.stdin_mtime.as_deref().unwrap_or("now"), time)?;
+    let uid = match &opts.stdin_user {
+        Some(user) => User::from_name(user)?.map(|u| u.uid.as_raw()),
+        None => None,
+    };
+    let gid = match &opts.stdin_group {
+        Some(group) => Group::from_name(group)?.map(|g| g.gid.as_raw()),
+        None => None,
+    };
+
+    Ok(Metadata {
+        mode,
+        mtime: Some(mtime),
+        uid,
+        gid,
+        user: opts.stdin_user.clone(),
+        group: opts.stdin_group.clone(),
+        ..Default::default()
+    })
+}
+
+/// Replace the variables {hostname}, {date}, {weekday} and {source} in `template`, e.g. a tag or
+/// a hook command
+pub(super) fn expand_variables(
+    template: &str,
+    hostname: &str,
+    time: DateTime<Local>,
+    source: &str,
+) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                result.push('{');
             }
-        };
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let (var, rest) = chars
+                    .as_str()
+                    .split_once('}')
+                    .ok_or_else(|| anyhow!("\"{template}\": missing }} after {{"))?;
+                match var {
+                    "hostname" => result.push_str(hostname),
+                    "date" => result.push_str(&time.format("%Y-%m-%d").to_string()),
+                    "weekday" => result.push_str(&time.format("%A").to_string()),
+                    "source" => result.push_str(source),
+                    _ => bail!("\"{template}\": unknown variable {{{var}}}"),
+                }
+                chars = rest.chars();
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
 
-        let delete = match (opts.delete_never, opts.delete_after) {
-            (true, _) => DeleteOption::Never,
-            (_, Some(d)) => DeleteOption::After(time + Duration::from_std(*d)?),
-            (false, None) => DeleteOption::NotSet,
-        };
+#[allow(clippy::too_many_arguments)]
+fn backup_source<BE: DecryptFullBackend>(
+    be: &BE,
+    index: &impl IndexedBackend,
+    config: &ConfigFile,
+    zstd: Option<i32>,
+    source: &str,
+    opts: &Opts,
+    time: DateTime<Local>,
+    command: &str,
+    packers: Option<SharedPackers<DryRunBackend<BE>>>,
+) -> Result<(SnapshotFile, bool)> {
+    let mut be = DryRunBackend::new(be.clone(), opts.dry_run());
+    be.set_zstd(zstd);
+    info!("starting to backup \"{source}\"...");
+    let index = index.clone();
+    let backup_stdin = source == "-";
+    let backup_path = if backup_stdin {
+        PathBuf::from(&opts.stdin_filename)
+    } else {
+        PathBuf::from(&source).parse_dot()?.to_path_buf()
+    };
+    let as_path = match &opts.as_path {
+        None => None,
+        Some(p) => Some(p.parse_dot()?.to_path_buf()),
+    };
+    let backup_path_str = as_path.as_ref().unwrap_or(&backup_path);
+    let backup_path_str = backup_path_str
+        .to_str()
+        .ok_or_else(|| anyhow!("non-unicode path {:?}", backup_path_str))?
+        .to_string();
+
+    let hostname = hostname(opts)?;
+
+    let delete = match (opts.delete_never.unwrap_or(false), opts.delete_after) {
+        (true, _) => DeleteOption::Never,
+        (_, Some(d)) => DeleteOption::After(time + Duration::from_std(*d)?),
+        (false, None) => DeleteOption::NotSet,
+    };
 
-        let mut snap = SnapshotFile {
-            time,
-            parent: parent.map(|sn| sn.id),
-            hostname,
-            delete,
-            summary: Some(SnapshotSummary {
-                command: command.clone(),
-                ..Default::default()
-            }),
+    let tags = opts
+        .tag
+        .iter()
+        .map(|tags| tags.try_map(|tag| expand_variables(tag, &hostname, time, &backup_path_str)))
+        .collect::<Result<_>>()?;
+
+    let mut snap = SnapshotFile {
+        time,
+        hostname,
+        delete,
+        summary: Some(SnapshotSummary {
+            command: command.to_string(),
             ..Default::default()
-        };
-        snap.paths.add(backup_path_str.clone());
-        snap.set_tags(opts.tag.clone());
-
-        let parent = Parent::new(&index, parent_tree, opts.ignore_ctime, opts.ignore_inode);
-
-        let snap = if backup_stdin {
-            let mut archiver = Archiver::new(be, index, &config, parent, snap)?;
-            let p = progress_bytes("starting backup from stdin...");
-            archiver.backup_reader(
-                std::io::stdin(),
-                Node::new(
-                    backup_path_str,
-                    NodeType::File,
-                    Metadata::default(),
-                    None,
-                    None,
-                ),
-                p.clone(),
-            )?;
-
-            let snap = archiver.finalize_snapshot()?;
-            p.finish_with_message("done");
-            snap
-        } else {
-            let src = LocalSource::new(opts.ignore_opts.clone(), backup_path.clone())?;
-
-            let p = progress_bytes("determining size...");
-            if !p.is_hidden() {
-                let size = src.size()?;
+        }),
+        ..Default::default()
+    };
+    snap.paths.add(backup_path_str.clone());
+    snap.set_tags(tags);
+
+    let parent = match (
+        backup_stdin,
+        opts.force.unwrap_or(false),
+        opts.parent.clone(),
+    ) {
+        (true, _, _) | (false, true, _) => None,
+        (false, false, None) => {
+            let group_by = opts
+                .group_by
+                .unwrap_or_else(|| SnapshotGroupCriterion::from_str("host,paths").unwrap());
+            SnapshotFile::latest_parent(
+                &be,
+                &snap,
+                &backup_path_str,
+                &group_by,
+                opts.parent_fallback.unwrap_or(false),
+                progress_counter(""),
+            )
+            .ok()
+        }
+        (false, false, Some(parent)) => SnapshotFile::from_id(&be, &parent).ok(),
+    };
+
+    let parent_tree = match &parent {
+        Some(snap) => {
+            info!("using parent {}", snap.id);
+            Some(snap.tree)
+        }
+        None => {
+            info!("using no parent");
+            None
+        }
+    };
+
+    snap.parent = parent.map(|sn| sn.id);
+
+    // deterministic backups don't save ctime and inode, so they can't be compared to the parent
+    let deterministic = opts.deterministic.unwrap_or(false);
+    let parent = Parent::new(
+        &index,
+        parent_tree,
+        deterministic || opts.ignore_ctime.unwrap_or(false),
+        deterministic || opts.ignore_inode.unwrap_or(false),
+        opts.detect_renames.unwrap_or(false),
+    );
+
+    let mut builder = ArchiverBuilder::new(be.clone(), index, snap).parent(parent);
+    if let Some(packers) = packers.clone() {
+        builder = builder.packers(packers);
+    }
+    if let Some(as_path) = &as_path {
+        builder = builder.backup_as(backup_path.clone(), as_path.clone());
+    }
+    if let Some(OpenFilePolicy::Retry) = opts.open_file_policy {
+        let retry_time = opts
+            .open_file_retry_time
+            .map_or(std::time::Duration::from_secs(60), |d| *d);
+        builder = builder.open_retry(retry_time);
+    }
+    let mut archiver = builder.build(config)?;
+    let finish = |archiver: Archiver<_, _>| match packers {
+        Some(_) => archiver.finish_snapshot(),
+        None => archiver.finalize(),
+    };
+
+    let mut snap = if backup_stdin {
+        let size_hint = opts.stdin_size_hint.map(|size| size.as_u64());
+        let p = match size_hint {
+            Some(size) => {
+                let p = progress_bytes("backing up stdin...");
                 p.set_length(size);
-            };
-            p.set_prefix("backing up...");
-            let mut archiver = Archiver::new(be, index.clone(), &config, parent, snap)?;
-            for item in src {
-                match item {
-                    Err(e) => {
-                        warn!("ignoring error {}\n", e)
-                    }
-                    Ok((path, node)) => {
-                        let snapshot_path = if let Some(as_path) = &as_path {
-                            as_path
-                                .clone()
-                                .join(path.strip_prefix(&backup_path).unwrap())
-                        } else {
-                            path.clone()
-                        };
-                        if let Err(e) = archiver.add_entry(&snapshot_path, &path, node, p.clone()) {
-                            warn!("ignoring error {} for {:?}\n", e, path);
-                        }
-                    }
-                }
+                p
             }
-            let snap = archiver.finalize_snapshot()?;
-            p.finish_with_message("done");
-            snap
+            None => progress_bytes_stream("backing up stdin..."),
+        };
+        status::report_progress(&p, "backing up stdin".to_string());
+        let meta = Metadata {
+            size: size_hint.unwrap_or(0),
+            ..stdin_metadata(opts, time)?
+        };
+        archiver.backup_reader(
+            std::io::stdin(),
+            Node::new(backup_path_str, NodeType::File, meta, None, None),
+            p.clone(),
+        )?;
+
+        let snap = finish(archiver)?;
+        p.finish_with_message("done");
+        snap
+    } else {
+        let mut src = LocalSource::new(opts.ignore_opts.clone(), backup_path.clone())?;
+        src.set_deterministic(deterministic);
+
+        let p = progress_bytes("determining size...");
+        if !p.is_hidden() {
+            let size = src.size()?;
+            p.set_length(size);
         };
+        p.set_prefix("backing up...");
+        status::report_progress(&p, format!("backing up {source}"));
+        archiver.backup_source(src, &BackupProgress::new(p.clone()))?;
+        let snap = finish(archiver)?;
+        p.finish_with_message("done");
+        snap
+    };
 
-        let summary = snap.summary.unwrap();
+    let summary = snap.summary.as_ref().unwrap();
 
-        println!(
-            "Files:       {} new, {} changed, {} unchanged",
-            summary.files_new, summary.files_changed, summary.files_unmodified
-        );
-        println!(
-            "Dirs:        {} new, {} changed, {} unchanged",
-            summary.dirs_new, summary.dirs_changed, summary.dirs_unmodified
-        );
-        debug!("Data Blobs:  {} new", summary.data_blobs);
-        debug!("Tree Blobs:  {} new", summary.tree_blobs);
-        println!(
-            "Added to the repo: {} (raw: {})",
-            bytes(summary.data_added_packed),
-            bytes(summary.data_added)
+    println!(
+        "Files:       {} new, {} changed, {} unchanged",
+        summary.files_new, summary.files_changed, summary.files_unmodified
+    );
+    if summary.files_skipped > 0 {
+        warn!(
+            "{} files could not be opened and have been skipped",
+            summary.files_skipped
         );
+    }
+    println!(
+        "Dirs:        {} new, {} changed, {} unchanged",
+        summary.dirs_new, summary.dirs_changed, summary.dirs_unmodified
+    );
+    debug!("Data Blobs:  {} new", summary.data_blobs);
+    debug!("Tree Blobs:  {} new", summary.tree_blobs);
+    println!(
+        "Added to the repo: {} (raw: {})",
+        bytes(summary.data_added_packed),
+        bytes(summary.data_added)
+    );
+
+    println!(
+        "processed {} files, {}",
+        summary.total_files_processed,
+        bytes(summary.total_bytes_processed)
+    );
+
+    let unchanged = opts.skip_if_unchanged.unwrap_or(false) && parent_tree == Some(snap.tree);
+    if packers.is_none() && !unchanged {
+        snap.id = be.save_file(&snap)?;
+    }
 
-        println!(
-            "processed {} files, {}",
-            summary.total_files_processed,
-            bytes(summary.total_bytes_processed)
-        );
-        println!("snapshot {} successfully saved.", snap.id);
+    Ok((snap, unchanged))
+}
 
-        info!("backup of \"{source}\" done.");
-    }
+fn snapshot_saved(be: &impl DecryptFullBackend, snap: &SnapshotFile, opts: &Opts) -> Result<()> {
+    println!("snapshot {} successfully saved.", snap.id);
 
+    if let (Some(db), false) = (&opts.index_files_db, opts.dry_run()) {
+        info!("adding snapshot to {db:?}...");
+        // the index must be re-read to contain the newly saved trees
+        let index = IndexBackend::only_full_trees(be, progress_counter(""))?;
+        let mut conn = index_files::open_db(db)?;
+        index_files::add_snapshot(&mut conn, &index, snap)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const CONFIG: &str = r#"
+        [backup]
+        dry-run = true
+        force = true
+
+        [[backup.sources]]
+        source = "/source"
+        force = false
+    "#;
+
+    #[rstest]
+    #[case(&[], Some(true), Some(false))]
+    #[case(&["--dry-run=false"], Some(false), Some(false))]
+    #[case(&["-n", "--force"], Some(true), Some(true))]
+    #[case(&["--force=true"], Some(true), Some(true))]
+    fn merge_precedence(
+        #[case] args: &[&str],
+        #[case] dry_run: Option<bool>,
+        #[case] force: Option<bool>,
+    ) {
+        let config_file = RusticConfig::from_data(CONFIG.to_string()).unwrap();
+        let mut config_opts: Vec<Opts> = config_file.get("backup.sources").unwrap();
+        let opts = Opts::parse_from(["backup"].iter().chain(args));
+
+        let opts = merge_source_opts(&opts, "/source", &mut config_opts, &config_file).unwrap();
+        assert_eq!(opts.dry_run, dry_run);
+        assert_eq!(opts.force, force);
+    }
+
+    #[test]
+    fn merge_defaults() {
+        let config_file = RusticConfig::from_data(String::new()).unwrap();
+        let opts = Opts::parse_from(["backup"]);
+
+        let opts = merge_source_opts(&opts, "/source", &mut Vec::new(), &config_file).unwrap();
+        assert!(!opts.dry_run());
+        assert_eq!(opts.force, None);
+    }
+
+    #[rstest]
+    #[case("now", true)]
+    #[case("2022-07-27T16:59:42+02:00", true)]
+    #[case("2022-07-27 16:59:42", true)]
+    #[case("2022-07-27", false)]
+    #[case("yesterday", false)]
+    fn parse_time(#[case] s: &str, #[case] ok: bool) {
+        let now = Local::now();
+        assert_eq!(super::parse_time(s, now).is_ok(), ok);
+        if s == "now" {
+            assert_eq!(super::parse_time(s, now).unwrap(), now);
+        }
+    }
+
+    #[rstest]
+    #[case("daily", "daily")]
+    #[case("{hostname}-{weekday}", "host-Wednesday")]
+    #[case("{date}:{source}", "2022-07-27:/home")]
+    #[case("{{date}}", "{date}")]
+    fn expand_variables(#[case] tag: &str, #[case] expected: &str) {
+        let time = super::parse_time("2022-07-27 16:59:42", Local::now()).unwrap();
+        let tag = super::expand_variables(tag, "host", time, "/home").unwrap();
+        assert_eq!(tag, expected);
+    }
+
+    #[rstest]
+    #[case("{unknown}")]
+    #[case("{date")]
+    fn expand_variables_fails(#[case] tag: &str) {
+        assert!(super::expand_variables(tag, "host", Local::now(), "/home").is_err());
+    }
+
+    #[test]
+    fn stdin_metadata() {
+        let now = Local::now();
+        let opts = Opts::parse_from(["backup", "--stdin-mode", "0640", "--stdin-user", "root"]);
+        let meta = super::stdin_metadata(&opts, now).unwrap();
+        assert_eq!(meta.mode, Some(0o640));
+        assert_eq!(meta.mtime, Some(now));
+        assert_eq!(meta.user.as_deref(), Some("root"));
+        assert_eq!(meta.uid, Some(0));
+
+        let opts = Opts::parse_from(["backup", "--stdin-mode", "rw-r--r--"]);
+        assert!(super::stdin_metadata(&opts, now).is_err());
+        let opts = Opts::parse_from(["backup", "--stdin-mode", "01777"]);
+        assert!(super::stdin_metadata(&opts, now).is_err());
+    }
+}
@@ -1,6 +1,7 @@
+use std::io::{self, Write};
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use indicatif::ProgressBar;
 
@@ -8,8 +9,8 @@ use super::progress_counter;
 use crate::backend::{DecryptReadBackend, FileType};
 use crate::blob::{BlobType, Tree};
 use crate::id::Id;
-use crate::index::{IndexBackend, IndexedBackend};
-use crate::repo::SnapshotFile;
+use crate::index::{IndexBackend, IndexedBackend, ReadIndex};
+use crate::repo::{BackupReport, PackHeader, SnapshotFile};
 
 #[derive(Parser)]
 pub(super) struct Opts {
@@ -20,17 +21,25 @@ pub(super) struct Opts {
 #[derive(Subcommand)]
 enum Command {
     /// Display a tree blob
-    TreeBlob(IdOpt),
+    TreeBlob(BlobOpts),
     /// Display a data blob
-    DataBlob(IdOpt),
+    DataBlob(BlobOpts),
+    /// Display a blob which may be a tree or data blob
+    Blob(BlobOpts),
     /// Display the config file
     Config,
     /// Display an index file
     Index(IdOpt),
     /// Display a snapshot file
     Snapshot(IdOpt),
+    /// Display a key file
+    Key(IdOpt),
+    /// Display the blobs contained in a pack file as given by its header
+    Pack(IdOpt),
     /// Display a tree within a snapshot
     Tree(TreeOpts),
+    /// Display the backup report of a snapshot
+    Report(SnapOpt),
 }
 
 #[derive(Default, Parser)]
@@ -39,6 +48,23 @@ struct IdOpt {
     id: String,
 }
 
+#[derive(Parser)]
+struct BlobOpts {
+    /// Id to display
+    id: String,
+
+    /// Only show the location (pack, offset, length, compression) of the blob as JSON
+    #[clap(long)]
+    locate: bool,
+}
+
+#[derive(Parser)]
+struct SnapOpt {
+    /// Snapshot to use
+    #[clap(value_name = "SNAPSHOT")]
+    snap: String,
+}
+
 #[derive(Parser)]
 struct TreeOpts {
     /// Snapshot/path of the tree to display
@@ -51,11 +77,15 @@ pub(super) fn execute(be: &impl DecryptReadBackend, opts: Opts) -> Result<()> {
         Command::Config => cat_file(be, FileType::Config, IdOpt::default()),
         Command::Index(opt) => cat_file(be, FileType::Index, opt),
         Command::Snapshot(opt) => cat_file(be, FileType::Snapshot, opt),
+        Command::Key(opt) => cat_key(be, opt),
+        Command::Pack(opt) => cat_pack(be, opt),
         // special treatment for catingg blobs: read the index and use it to locate the blob
-        Command::TreeBlob(opt) => cat_blob(be, BlobType::Tree, opt),
-        Command::DataBlob(opt) => cat_blob(be, BlobType::Data, opt),
+        Command::TreeBlob(opt) => cat_blob(be, Some(BlobType::Tree), opt),
+        Command::DataBlob(opt) => cat_blob(be, Some(BlobType::Data), opt),
+        Command::Blob(opt) => cat_blob(be, None, opt),
         // special treatment for cating a tree within a snapshot
         Command::Tree(opts) => cat_tree(be, opts),
+        Command::Report(opts) => cat_report(be, opts),
     }
 }
 
@@ -67,10 +97,51 @@ fn cat_file(be: &impl DecryptReadBackend, tpe: FileType, opt: IdOpt) -> Result<(
     Ok(())
 }
 
-fn cat_blob(be: &impl DecryptReadBackend, tpe: BlobType, opt: IdOpt) -> Result<()> {
+/// Key files are not encrypted, so they are shown as they are saved
+fn cat_key(be: &impl DecryptReadBackend, opt: IdOpt) -> Result<()> {
+    let id = be.find_id(FileType::Key, &opt.id)?;
+    let data = be.read_full(FileType::Key, &id)?;
+    println!("{}", String::from_utf8(data.to_vec())?);
+
+    Ok(())
+}
+
+fn cat_pack(be: &impl DecryptReadBackend, opt: IdOpt) -> Result<()> {
+    let id = be.find_id(FileType::Pack, &opt.id)?;
+    let size = be
+        .list_with_size(FileType::Pack)?
+        .into_iter()
+        .find_map(|(pack, size)| (pack == id).then_some(size))
+        .ok_or_else(|| anyhow!("pack {id} not found"))?;
+    let blobs = PackHeader::from_file(be, id, None, size)?.into_blobs();
+    serde_json::to_writer_pretty(io::stdout(), &blobs)?;
+    println!();
+
+    Ok(())
+}
+
+/// Print the blob with the given id. If no blob type is given, the type is taken from the index.
+fn cat_blob(be: &impl DecryptReadBackend, tpe: Option<BlobType>, opt: BlobOpts) -> Result<()> {
     let id = Id::from_hex(&opt.id)?;
-    let data = IndexBackend::new(be, ProgressBar::hidden())?.blob_from_backend(&tpe, &id)?;
-    print!("{}", String::from_utf8(data.to_vec())?);
+    let index = IndexBackend::new(be, ProgressBar::hidden())?;
+    let tpe = match tpe {
+        Some(tpe) => tpe,
+        None => [BlobType::Tree, BlobType::Data]
+            .into_iter()
+            .find(|tpe| index.has(tpe, &id))
+            .ok_or_else(|| anyhow!("blob {id} not found in index"))?,
+    };
+    if opt.locate {
+        let packs = index.locate(&tpe, &[id])?;
+        serde_json::to_writer_pretty(io::stdout(), &packs)?;
+        println!();
+        return Ok(());
+    }
+    // data blobs may contain binary data, so write them as they are
+    let data = index.blob_from_backend(&tpe, &id)?;
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&data)?;
+    stdout.flush()?;
 
     Ok(())
 }
@@ -85,3 +156,17 @@ fn cat_tree(be: &impl DecryptReadBackend, opts: TreeOpts) -> Result<()> {
 
     Ok(())
 }
+
+fn cat_report(be: &impl DecryptReadBackend, opts: SnapOpt) -> Result<()> {
+    let snap = SnapshotFile::from_str(be, &opts.snap, |_| true, progress_counter(""))?;
+    let id = snap
+        .report
+        .ok_or_else(|| anyhow!("snapshot {} has no backup report", snap.id))?;
+    let index = IndexBackend::new(be, progress_counter(""))?;
+    let data = index.blob_from_backend(&BlobType::Data, &id)?;
+    let report: BackupReport = serde_json::from_slice(&data)?;
+    serde_json::to_writer_pretty(io::stdout(), &report)?;
+    println!();
+
+    Ok(())
+}
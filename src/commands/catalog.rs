@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use clap::Parser;
+use log::*;
+use serde::Serialize;
+
+use super::{progress_counter, RusticConfig};
+use crate::backend::DecryptReadBackend;
+use crate::blob::NodeStreamer;
+use crate::id::Id;
+use crate::index::IndexBackend;
+use crate::repo::{SnapshotFile, SnapshotFilter, StringList};
+
+const SENSITIVE_NOTE: &str = "This catalog contains unencrypted file names and metadata of \
+    the repository. Treat it as sensitive data!";
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
+    filter: SnapshotFilter,
+
+    /// Write the catalog to this file instead of stdout
+    #[clap(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Snapshots to export. If none is given, all snapshots matching the filter are exported.
+    #[clap(value_name = "ID")]
+    ids: Vec<String>,
+}
+
+/// A line of the JSONL catalog
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "lowercase")]
+enum CatalogRecord<'a> {
+    Header {
+        sensitive: bool,
+        note: &'a str,
+    },
+    Snapshot {
+        id: Id,
+        time: DateTime<Local>,
+        hostname: &'a str,
+        paths: &'a StringList,
+        tags: &'a StringList,
+    },
+    Node {
+        snapshot: Id,
+        path: &'a str,
+        #[serde(rename = "type")]
+        node_type: &'static str,
+        size: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mtime: Option<DateTime<Local>>,
+    },
+}
+
+pub(super) fn execute(
+    be: &(impl DecryptReadBackend + Unpin),
+    mut opts: Opts,
+    config_file: RusticConfig,
+) -> Result<()> {
+    config_file.merge_into("snapshot-filter", &mut opts.filter)?;
+
+    let mut snapshots = match opts.ids.is_empty() {
+        true => SnapshotFile::all_from_backend(be, &opts.filter)?,
+        false => SnapshotFile::from_ids(be, &opts.ids)?,
+    };
+    snapshots.sort_unstable();
+
+    let index = IndexBackend::only_full_trees(be, progress_counter(""))?;
+
+    warn!("{SENSITIVE_NOTE}");
+    let mut out: Box<dyn Write> = match &opts.output {
+        Some(file) => Box::new(BufWriter::new(File::create(file)?)),
+        None => Box::new(BufWriter::new(stdout())),
+    };
+
+    let mut write = |record: CatalogRecord| -> Result<()> {
+        serde_json::to_writer(&mut out, &record)?;
+        writeln!(out)?;
+        Ok(())
+    };
+
+    write(CatalogRecord::Header {
+        sensitive: true,
+        note: SENSITIVE_NOTE,
+    })?;
+
+    let p = progress_counter("exporting snapshots...");
+    p.set_length(snapshots.len() as u64);
+    for snap in snapshots {
+        write(CatalogRecord::Snapshot {
+            id: snap.id,
+            time: snap.time,
+            hostname: &snap.hostname,
+            paths: &snap.paths,
+            tags: &snap.tags,
+        })?;
+
+        for item in NodeStreamer::new(index.clone(), snap.tree)? {
+            let (path, node) = item?;
+            write(CatalogRecord::Node {
+                snapshot: snap.id,
+                path: &path.to_string_lossy(),
+                node_type: node.node_type().name(),
+                size: node.meta.size,
+                mtime: node.meta.mtime,
+            })?;
+        }
+        p.inc(1);
+    }
+    p.finish();
+
+    out.flush()?;
+    Ok(())
+}
@@ -1,14 +1,18 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bytes::Bytes;
 use clap::Parser;
 use indicatif::ProgressBar;
 use log::*;
+use rand::{seq::SliceRandom, thread_rng};
 use rayon::prelude::*;
+use serde::Serialize;
 use zstd::stream::decode_all;
 
-use super::{progress_bytes, progress_counter};
+use super::{progress_bytes, progress_counter, wait, warm_up, warm_up_command};
 use crate::backend::{Cache, DecryptReadBackend, FileType, ReadBackend};
 use crate::blob::{BlobType, NodeType, TreeStreamerOnce};
 use crate::commands::helpers::progress_spinner;
@@ -26,8 +30,164 @@ pub(super) struct Opts {
     trust_cache: bool,
 
     /// Read all data blobs
-    #[clap(long)]
+    #[clap(long, group = "read")]
     read_data: bool,
+
+    /// Read the data blobs of a subset of the pack files: n/m reads the n-th of m buckets of packs,
+    /// x% a random selection of x percent of the packs
+    #[clap(long, value_name = "n/m|x%", group = "read")]
+    read_data_subset: Option<ReadSubset>,
+
+    /// Check that the parents of all snapshots exist, i.e. that no snapshot chain is broken
+    #[clap(long)]
+    check_chains: bool,
+
+    /// Read the headers of all pack files and check that they match the index
+    #[clap(long, conflicts_with = "read")]
+    read_pack_headers: bool,
+
+    /// Print a report of all findings as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Warm up all data pack files by only requesting them before reading them
+    #[clap(long, requires = "read")]
+    warm_up: bool,
+
+    /// Warm up all data pack files by running the command with %id replaced by pack id
+    #[clap(long, conflicts_with = "warm-up", requires = "read")]
+    warm_up_command: Option<String>,
+
+    /// Duration (e.g. 10m) to wait after warm up before reading the data
+    #[clap(long, value_name = "DURATION", requires = "read")]
+    warm_up_wait: Option<humantime::Duration>,
+}
+
+/// Subset of the pack files to read
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadSubset {
+    /// Bucket n of m buckets, chosen by the pack id
+    Bucket(u32, u32),
+    /// Random selection of the given percentage of pack files
+    Percentage(f64),
+}
+
+impl FromStr for ReadSubset {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(p) = s.strip_suffix('%') {
+            let p: f64 = p.parse()?;
+            if !(0.0..=100.0).contains(&p) {
+                bail!("percentage must be between 0% and 100%");
+            }
+            Ok(Self::Percentage(p))
+        } else if let Some((n, m)) = s.split_once('/') {
+            let (n, m): (u32, u32) = (n.parse()?, m.parse()?);
+            if n == 0 || n > m {
+                bail!("n/m must satisfy 1 <= n <= m");
+            }
+            Ok(Self::Bucket(n, m))
+        } else {
+            bail!("invalid subset {s}, use n/m or x%")
+        }
+    }
+}
+
+impl ReadSubset {
+    fn apply(self, packs: &mut Vec<IndexPack>) {
+        match self {
+            Self::Bucket(n, m) => packs.retain(|pack| bucket(&pack.id, m) == n - 1),
+            Self::Percentage(p) => {
+                packs.shuffle(&mut thread_rng());
+                packs.truncate((packs.len() as f64 * p / 100.0).ceil() as usize);
+            }
+        }
+    }
+}
+
+/// The bucket (0..m) of the pack with the given id
+fn bucket(id: &Id, m: u32) -> u32 {
+    let bytes = id.as_bytes();
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % m
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Serialize)]
+struct Finding {
+    severity: Severity,
+    object: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Id>,
+    message: String,
+}
+
+/// Collects all findings of the check. Findings are also logged when they are added.
+#[derive(Default)]
+struct Report(Mutex<Vec<Finding>>);
+
+impl Report {
+    fn add(&self, severity: Severity, object: &'static str, id: Option<Id>, message: String) {
+        match severity {
+            Severity::Error => error!("{message}"),
+            Severity::Warning => warn!("{message}"),
+            Severity::Info => info!("{message}"),
+        }
+        self.0.lock().unwrap().push(Finding {
+            severity,
+            object,
+            id,
+            message,
+        });
+    }
+
+    fn error(&self, object: &'static str, id: impl Into<Option<Id>>, message: String) {
+        self.add(Severity::Error, object, id.into(), message);
+    }
+
+    fn warn(&self, object: &'static str, id: impl Into<Option<Id>>, message: String) {
+        self.add(Severity::Warning, object, id.into(), message);
+    }
+
+    fn info(&self, object: &'static str, id: impl Into<Option<Id>>, message: String) {
+        self.add(Severity::Info, object, id.into(), message);
+    }
+
+    fn print_json(self) -> Result<()> {
+        #[derive(Serialize)]
+        struct JsonReport {
+            errors: usize,
+            warnings: usize,
+            findings: Vec<Finding>,
+        }
+
+        let findings = self.0.into_inner().unwrap();
+        let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+        let report = JsonReport {
+            errors: count(Severity::Error),
+            warnings: count(Severity::Warning),
+            findings,
+        };
+        serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+        println!();
+        Ok(())
+    }
+}
+
+fn object_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Config => "config",
+        FileType::Snapshot => "snapshot",
+        FileType::Index => "index",
+        FileType::Key => "key",
+        FileType::Pack => "pack",
+    }
 }
 
 pub(super) fn execute(
@@ -35,8 +195,18 @@ pub(super) fn execute(
     cache: &Option<Cache>,
     hot_be: &Option<impl ReadBackend>,
     raw_be: &impl ReadBackend,
+    cold_be: &impl ReadBackend,
     opts: Opts,
 ) -> Result<()> {
+    let report = Report::default();
+
+    if let Some(command) = &opts.warm_up_command {
+        if !command.contains("%id") {
+            bail!("warm-up command must contain %id!")
+        }
+        info!("using warm-up command {command}")
+    }
+
     if !opts.trust_cache {
         if let Some(cache) = &cache {
             for file_type in [FileType::Snapshot, FileType::Index] {
@@ -48,50 +218,116 @@ pub(super) fn execute(
 
                 let p = progress_bytes(format!("checking {} in cache...", file_type.name()));
                 // TODO: Make concurrency (20) customizable
-                check_cache_files(20, cache, raw_be, file_type, p)?;
+                check_cache_files(20, cache, raw_be, file_type, p, &report)?;
             }
         }
     }
 
     if let Some(hot_be) = hot_be {
         for file_type in [FileType::Snapshot, FileType::Index] {
-            check_hot_files(raw_be, hot_be, file_type)?;
+            check_hot_files(cold_be, hot_be, file_type, &report)?;
         }
     }
 
-    let index_collector = check_packs(be, hot_be, opts.read_data)?;
+    check_foreign_files(cold_be, "repo", &report)?;
+    if let Some(hot_be) = hot_be {
+        check_foreign_files(hot_be, "hot repo", &report)?;
+    }
+
+    let read_data = opts.read_data || opts.read_data_subset.is_some();
+    let index_collector = check_packs(be, hot_be, read_data || opts.read_pack_headers, &report)?;
 
     if !opts.trust_cache {
         if let Some(cache) = &cache {
             let p = progress_bytes("checking packs in cache...");
             // TODO: Make concurrency (5) customizable
-            check_cache_files(5, cache, raw_be, FileType::Pack, p)?;
+            check_cache_files(5, cache, raw_be, FileType::Pack, p, &report)?;
         }
     }
 
     let index_be = IndexBackend::new_from_index(be, index_collector.into_index());
 
-    check_snapshots(&index_be)?;
+    check_snapshots(&index_be, opts.check_chains, &report)?;
 
-    if opts.read_data {
-        let p = progress_counter("reading pack data...");
+    if opts.read_pack_headers {
+        let packs: Vec<_> = index_be.into_index().into_iter().collect();
+        let p = progress_counter("reading pack headers...");
+        p.set_length(packs.len() as u64);
+        packs
+            .into_par_iter()
+            .for_each_with((be.clone(), p.clone()), |(be, p), pack| {
+                let id = pack.id;
+                if let Err(err) = check_pack_header(be, pack, &report) {
+                    report.error(
+                        "pack",
+                        id,
+                        format!("Error reading header of pack {id} : {err}"),
+                    );
+                }
+                p.inc(1);
+            });
+        p.finish();
+    } else if read_data {
+        let mut packs: Vec<_> = index_be.into_index().into_iter().collect();
+        if let Some(subset) = opts.read_data_subset {
+            let total = packs.len();
+            subset.apply(&mut packs);
+            info!("reading {} of {total} pack files", packs.len());
+        }
+        if opts.warm_up {
+            warm_up(be, packs.iter().map(|pack| pack.id))?;
+        } else if opts.warm_up_command.is_some() {
+            warm_up_command(
+                packs.iter().map(|pack| pack.id),
+                opts.warm_up_command.as_ref().unwrap(),
+            )?;
+        }
+        wait(opts.warm_up_wait);
 
-        index_be
-            .into_index()
-            .into_iter()
-            .par_bridge()
+        let p = progress_counter("reading pack data...");
+        packs
+            .into_par_iter()
             .for_each_with((be.clone(), p.clone()), |(be, p), pack| {
                 let id = pack.id;
-                let data = be.read_full(FileType::Pack, &id).unwrap();
-                match check_pack(be, pack, data) {
+                match be
+                    .read_full(FileType::Pack, &id)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|data| check_pack(be, pack, data, &report))
+                {
                     Ok(()) => {}
-                    Err(err) => error!("Error reading pack {id} : {err}",),
+                    Err(err) => {
+                        report.error("pack", id, format!("Error reading pack {id} : {err}"))
+                    }
                 }
                 p.inc(1);
             });
         p.finish();
     }
 
+    if opts.json {
+        report.print_json()?;
+    }
+
+    Ok(())
+}
+
+/// Report files which don't belong to the repository; they may hint at corruption or at another
+/// tool using the repository directory
+fn check_foreign_files(be: &impl ReadBackend, repo: &str, report: &Report) -> Result<()> {
+    for file_type in [
+        FileType::Key,
+        FileType::Snapshot,
+        FileType::Index,
+        FileType::Pack,
+    ] {
+        for name in be.list_foreign(file_type)? {
+            report.warn(
+                object_name(file_type),
+                None,
+                format!("unexpected file {name} in {repo}"),
+            );
+        }
+    }
     Ok(())
 }
 
@@ -99,7 +335,9 @@ fn check_hot_files(
     be: &impl ReadBackend,
     be_hot: &impl ReadBackend,
     file_type: FileType,
+    report: &Report,
 ) -> Result<()> {
+    let object = object_name(file_type);
     let p = progress_spinner(format!("checking {} in hot repo...", file_type.name()));
     let mut files = be
         .list_with_size(file_type)?
@@ -110,16 +348,26 @@ fn check_hot_files(
 
     for (id, size_hot) in files_hot {
         match files.remove(&id) {
-            None => error!("hot file Type: {file_type:?}, Id: {id} does not exist in repo",),
-            Some(size) if size != size_hot => {
-                error!("Type: {file_type:?}, Id: {id}: hot size: {size_hot}, actual size: {size}",)
-            }
+            None => report.error(
+                object,
+                id,
+                format!("hot file Type: {file_type:?}, Id: {id} does not exist in repo"),
+            ),
+            Some(size) if size != size_hot => report.error(
+                object,
+                id,
+                format!("Type: {file_type:?}, Id: {id}: hot size: {size_hot}, actual size: {size}"),
+            ),
             _ => {} //everything ok
         }
     }
 
     for (id, _) in files {
-        error!("hot file Type: {file_type:?}, Id: {id} is missing!",);
+        report.error(
+            object,
+            id,
+            format!("hot file Type: {file_type:?}, Id: {id} is missing!"),
+        );
     }
     p.finish();
 
@@ -132,35 +380,48 @@ fn check_cache_files(
     be: &impl ReadBackend,
     file_type: FileType,
     p: ProgressBar,
+    report: &Report,
 ) -> Result<()> {
+    let object = object_name(file_type);
     let files = cache.list_with_size(file_type)?;
 
     if files.is_empty() {
         return Ok(());
     }
 
-    let total_size = files.iter().map(|(_, size)| *size as u64).sum();
+    let total_size = files.values().sum();
     p.set_length(total_size);
 
-    files.into_par_iter()
-    .for_each_with((cache,be,p.clone()), |(cache, be, p),(id, size)|  {
-        // Read file from cache and from backend and compare
-        match (
-            cache.read_full(file_type, &id),
-            be.read_full(file_type, &id),
-        ) {
-            (Err(err), _) => {
-                error!("Error reading cached file Type: {file_type:?}, Id: {id} : {err}",)
+    files
+        .into_par_iter()
+        .for_each_with((cache, be, p.clone()), |(cache, be, p), (id, size)| {
+            // Read file from cache and from backend and compare
+            match (
+                cache.read_full(file_type, &id),
+                be.read_full(file_type, &id),
+            ) {
+                (Err(err), _) => report.warn(
+                    object,
+                    id,
+                    format!("Error reading cached file Type: {file_type:?}, Id: {id} : {err}"),
+                ),
+                (_, Err(err)) => report.error(
+                    object,
+                    id,
+                    format!("Error reading file Type: {file_type:?}, Id: {id} : {err}"),
+                ),
+                (Ok(data_cached), Ok(data)) if data_cached != data => report.warn(
+                    object,
+                    id,
+                    format!(
+                        "Cached file Type: {file_type:?}, Id: {id} is not identical to backend!"
+                    ),
+                ),
+                (Ok(_), Ok(_)) => {} // everything ok
             }
-            (_, Err(err)) => error!("Error reading file Type: {file_type:?}, Id: {id} : {err}",),
-            (Ok(data_cached), Ok(data)) if data_cached != data => {
-                error!("Cached file Type: {file_type:?}, Id: {id} is not identical to backend!",)
-            }
-            (Ok(_), Ok(_)) => {} // everything ok
-        }
 
-        p.inc(size as u64);
-    });
+            p.inc(size);
+        });
 
     p.finish();
     Ok(())
@@ -170,11 +431,12 @@ fn check_cache_files(
 fn check_packs(
     be: &impl DecryptReadBackend,
     hot_be: &Option<impl ReadBackend>,
-    read_data: bool,
+    full_index: bool,
+    report: &Report,
 ) -> Result<IndexCollector> {
     let mut packs = HashMap::new();
     let mut tree_packs = HashMap::new();
-    let mut index_collector = IndexCollector::new(if read_data {
+    let mut index_collector = IndexCollector::new(if full_index {
         IndexType::Full
     } else {
         IndexType::FullTrees
@@ -183,30 +445,47 @@ fn check_packs(
     let mut process_pack = |p: IndexPack| {
         let blob_type = p.blob_type();
         let pack_size = p.pack_size();
-        packs.insert(p.id, pack_size);
+        if packs.insert(p.id, pack_size).is_some() {
+            report.warn(
+                "pack",
+                p.id,
+                format!(
+                    "pack {} is contained in several index files. To repair: 'rustic prune'.",
+                    p.id
+                ),
+            );
+        }
         if hot_be.is_some() && blob_type == BlobType::Tree {
             tree_packs.insert(p.id, pack_size);
         }
 
         // check offsests in index
-        let mut expected_offset: u32 = 0;
+        let mut expected_offset: u64 = 0;
         let mut blobs = p.blobs;
         blobs.sort_unstable();
         for blob in blobs {
             if blob.tpe != blob_type {
-                error!(
-                    "pack {}: blob {} blob type does not match: type: {:?}, expected: {:?}",
-                    p.id, blob.id, blob.tpe, blob_type
+                report.error(
+                    "pack",
+                    p.id,
+                    format!(
+                        "pack {}: blob {} blob type does not match: type: {:?}, expected: {:?}",
+                        p.id, blob.id, blob.tpe, blob_type
+                    ),
                 );
             }
 
             if blob.offset != expected_offset {
-                error!(
-                    "pack {}: blob {} offset in index: {}, expected: {}",
-                    p.id, blob.id, blob.offset, expected_offset
+                report.error(
+                    "pack",
+                    p.id,
+                    format!(
+                        "pack {}: blob {} offset in index: {}, expected: {}",
+                        p.id, blob.id, blob.offset, expected_offset
+                    ),
                 );
             }
-            expected_offset += blob.length;
+            expected_offset += u64::from(blob.length);
         }
     };
 
@@ -225,62 +504,116 @@ fn check_packs(
 
     if let Some(hot_be) = hot_be {
         let p = progress_spinner("listing packs in hot repo...");
-        check_packs_list(hot_be, tree_packs)?;
+        check_packs_list(hot_be, tree_packs, report)?;
         p.finish();
     }
 
     let p = progress_spinner("listing packs...");
-    check_packs_list(be, packs)?;
+    check_packs_list(be, packs, report)?;
     p.finish();
 
     Ok(index_collector)
 }
 
-fn check_packs_list(be: &impl ReadBackend, mut packs: HashMap<Id, u32>) -> Result<()> {
+fn check_packs_list(
+    be: &impl ReadBackend,
+    mut packs: HashMap<Id, u64>,
+    report: &Report,
+) -> Result<()> {
     for (id, size) in be.list_with_size(FileType::Pack)? {
         match packs.remove(&id) {
-            None => warn!("pack {id} not referenced in index. Can be a parallel backup job. To repair: 'rustic repair index'."),
+            None => report.warn("pack", id, format!("pack {id} not referenced in index. Can be a parallel backup job. To repair: 'rustic repair index'.")),
             Some(index_size) if index_size != size => {
-                error!("pack {id}: size computed by index: {index_size}, actual size: {size}. To repair: 'rustic repair index'.")
+                report.error("pack", id, format!("pack {id}: size computed by index: {index_size}, actual size: {size}. To repair: 'rustic repair index'."))
             }
             _ => {} //everything ok
         }
     }
 
     for (id, _) in packs {
-        error!("pack {id} is referenced by the index but not present! To repair: 'rustic repair index'.",);
+        report.error("pack", id, format!("pack {id} is referenced by the index but not present! To repair: 'rustic repair index'."));
     }
     Ok(())
 }
 
 // check if all snapshots and contained trees can be loaded and contents exist in the index
-fn check_snapshots(index: &(impl IndexedBackend + Unpin)) -> Result<()> {
+fn check_snapshots(
+    index: &(impl IndexedBackend + Unpin),
+    check_chains: bool,
+    report: &Report,
+) -> Result<()> {
     let p = progress_counter("reading snapshots...");
-    let snap_trees: Vec<_> = index
+    let snaps: Vec<_> = index
         .be()
         .stream_all::<SnapshotFile>(p.clone())?
-        .iter()
-        .map(|(_, snap)| snap.tree)
+        .into_iter()
+        .map(SnapshotFile::set_id)
         .collect();
+    let snap_trees: Vec<_> = snaps.iter().map(|snap| snap.tree).collect();
     p.finish();
+    report.info(
+        "snapshot",
+        None,
+        format!("checking {} snapshots", snap_trees.len()),
+    );
+
+    if check_chains {
+        for snap in SnapshotFile::broken_chains(&snaps) {
+            report.warn(
+                "snapshot",
+                snap.id,
+                format!(
+                    "parent {} of snapshot {} does not exist, the snapshot chain is broken.",
+                    snap.parent.unwrap(),
+                    snap.id
+                ),
+            );
+        }
+    }
 
     let p = progress_counter("checking trees...");
     let mut tree_streamer = TreeStreamerOnce::new(index.clone(), snap_trees, p)?;
+    let mut capabilities = 0;
     while let Some(item) = tree_streamer.next().transpose()? {
         let (path, tree) = item;
         for node in tree.nodes() {
             match node.node_type() {
                 NodeType::File => {
+                    for attr in &node.meta().extended_attributes {
+                        if !attr.is_capability() {
+                            continue;
+                        }
+                        capabilities += 1;
+                        if !attr.is_valid_capability() {
+                            report.warn(
+                                "tree",
+                                None,
+                                format!(
+                                    "file {:?} has invalid file capabilities which cannot be restored",
+                                    path.join(node.name())
+                                ),
+                            );
+                        }
+                    }
+
                     for (i, id) in node.content().iter().enumerate() {
                         if id.is_null() {
-                            error!("file {:?} blob {} has null ID", path.join(node.name()), i);
+                            report.error(
+                                "blob",
+                                None,
+                                format!("file {:?} blob {} has null ID", path.join(node.name()), i),
+                            );
                         }
 
                         if !index.has_data(id) {
-                            error!(
-                                "file {:?} blob {} is missing in index",
-                                path.join(node.name()),
-                                id
+                            report.error(
+                                "blob",
+                                *id,
+                                format!(
+                                    "file {:?} blob {} is missing in index",
+                                    path.join(node.name()),
+                                    id
+                                ),
                             );
                         }
                     }
@@ -288,12 +621,16 @@ fn check_snapshots(index: &(impl IndexedBackend + Unpin)) -> Result<()> {
 
                 NodeType::Dir => {
                     match node.subtree() {
-                        None => {
-                            error!("dir {:?} subtree does not exist", path.join(node.name()))
-                        }
-                        Some(tree) if tree.is_null() => {
-                            error!("dir {:?} subtree has null ID", path.join(node.name()))
-                        }
+                        None => report.error(
+                            "tree",
+                            None,
+                            format!("dir {:?} subtree does not exist", path.join(node.name())),
+                        ),
+                        Some(tree) if tree.is_null() => report.error(
+                            "tree",
+                            None,
+                            format!("dir {:?} subtree has null ID", path.join(node.name())),
+                        ),
                         _ => {} // subtree is ok
                     }
                 }
@@ -303,23 +640,68 @@ fn check_snapshots(index: &(impl IndexedBackend + Unpin)) -> Result<()> {
         }
     }
 
+    if capabilities > 0 {
+        report.info(
+            "tree",
+            None,
+            format!("{capabilities} files have file capabilities; these are lost if they are restored without root privileges or to a filesystem not supporting them"),
+        );
+    }
+
     Ok(())
 }
 
-fn check_pack(be: &impl DecryptReadBackend, index_pack: IndexPack, mut data: Bytes) -> Result<()> {
+// check that the header of the pack file matches the index without reading the whole pack
+fn check_pack_header(
+    be: &impl DecryptReadBackend,
+    index_pack: IndexPack,
+    report: &Report,
+) -> Result<()> {
+    let id = index_pack.id;
+    let header_len = PackHeaderRef::from_index_pack(&index_pack).size();
+    let pack_blobs =
+        PackHeader::from_file(be, id, Some(header_len), index_pack.pack_size())?.into_blobs();
+    let mut blobs = index_pack.blobs;
+    blobs.sort_unstable_by_key(|b| b.offset);
+    if pack_blobs != blobs {
+        report.error(
+            "pack",
+            id,
+            format!("pack {id}: Header from pack file does not match the index"),
+        );
+        debug!("pack file header: {pack_blobs:?}");
+        debug!("index: {:?}", blobs);
+    }
+    Ok(())
+}
+
+fn check_pack(
+    be: &impl DecryptReadBackend,
+    index_pack: IndexPack,
+    mut data: Bytes,
+    report: &Report,
+) -> Result<()> {
     let id = index_pack.id;
     let size = index_pack.pack_size();
     if data.len() != size as usize {
-        error!(
-            "pack {id}: data size does not match expected size. Read: {} bytes, expected: {size} bytes",
-            data.len()
+        report.error(
+            "pack",
+            id,
+            format!(
+                "pack {id}: data size does not match expected size. Read: {} bytes, expected: {size} bytes",
+                data.len()
+            ),
         );
         return Ok(());
     }
 
     let comp_id = hash(&data);
     if id != comp_id {
-        error!("pack {id}: Hash mismatch. Computed hash: {comp_id}");
+        report.error(
+            "pack",
+            id,
+            format!("pack {id}: Hash mismatch. Computed hash: {comp_id}"),
+        );
         return Ok(());
     }
 
@@ -327,7 +709,7 @@ fn check_pack(be: &impl DecryptReadBackend, index_pack: IndexPack, mut data: Byt
     let header_len = PackHeaderRef::from_index_pack(&index_pack).size();
     let pack_header_len = PackHeaderLength::from_binary(&data.split_off(data.len() - 4))?.to_u32();
     if pack_header_len != header_len {
-        error!("pack {id}: Header length in pack file doesn't match index. In pack: {pack_header_len}, calculated: {header_len}");
+        report.error("pack", id, format!("pack {id}: Header length in pack file doesn't match index. In pack: {pack_header_len}, calculated: {header_len}"));
         return Ok(());
     }
 
@@ -338,7 +720,11 @@ fn check_pack(be: &impl DecryptReadBackend, index_pack: IndexPack, mut data: Byt
     let mut blobs = index_pack.blobs;
     blobs.sort_unstable_by_key(|b| b.offset);
     if pack_blobs != blobs {
-        error!("pack {id}: Header from pack file does not match the index");
+        report.error(
+            "pack",
+            id,
+            format!("pack {id}: Header from pack file does not match the index"),
+        );
         debug!("pack file header: {pack_blobs:?}");
         debug!("index: {:?}", blobs);
         return Ok(());
@@ -353,17 +739,60 @@ fn check_pack(be: &impl DecryptReadBackend, index_pack: IndexPack, mut data: Byt
         if let Some(length) = blob.uncompressed_length {
             blob_data = decode_all(&*blob_data).unwrap();
             if blob_data.len() != length.get() as usize {
-                error!("pack {id}, blob {blob_id}: Actual uncompressed length does not fit saved uncompressed length");
+                report.error("blob", blob_id, format!("pack {id}, blob {blob_id}: Actual uncompressed length does not fit saved uncompressed length"));
                 return Ok(());
             }
         }
 
         let comp_id = hash(&blob_data);
         if blob.id != comp_id {
-            error!("pack {id}, blob {blob_id}: Hash mismatch. Computed hash: {comp_id}");
+            report.error(
+                "blob",
+                blob_id,
+                format!("pack {id}, blob {blob_id}: Hash mismatch. Computed hash: {comp_id}"),
+            );
             return Ok(());
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_subset() {
+        assert_eq!(
+            ReadSubset::from_str("2/5").unwrap(),
+            ReadSubset::Bucket(2, 5)
+        );
+        assert_eq!(
+            ReadSubset::from_str("12.5%").unwrap(),
+            ReadSubset::Percentage(12.5)
+        );
+        for s in ["0/5", "6/5", "101%", "5"] {
+            assert!(ReadSubset::from_str(s).is_err());
+        }
+
+        // all buckets together contain every pack exactly once
+        let packs: Vec<_> = (0..100)
+            .map(|_| IndexPack {
+                id: Id::random(),
+                ..Default::default()
+            })
+            .collect();
+        let mut count = 0;
+        for n in 1..=3 {
+            let mut subset = packs.clone();
+            ReadSubset::Bucket(n, 3).apply(&mut subset);
+            count += subset.len();
+        }
+        assert_eq!(count, packs.len());
+
+        let mut subset = packs;
+        ReadSubset::Percentage(10.0).apply(&mut subset);
+        assert_eq!(subset.len(), 10);
+    }
+}
@@ -27,7 +27,8 @@ fn generate_completion<G: Generator>(shell: G) {
     generate(
         shell,
         &mut command,
-        env!("CARGO_BIN_NAME"),
+        // CARGO_BIN_NAME is not available as the commands are part of the library
+        "rustic",
         &mut std::io::stdout(),
     )
 }
@@ -1,18 +1,69 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use bytesize::ByteSize;
-use clap::{AppSettings, Parser};
+use clap::{AppSettings, Parser, Subcommand};
+use log::*;
 
-use crate::backend::{DecryptBackend, DecryptFullBackend, DecryptWriteBackend, WriteBackend};
-use crate::repo::ConfigFile;
+use super::rustic_config::{check_key, SectionCheck};
+use super::{backup, forget, progress_counter, GlobalOpts, RusticConfig};
+use crate::backend::{DecryptBackend, DecryptFullBackend, FileType, WriteBackend};
+use crate::crypto::hash;
+use crate::id::Id;
+use crate::repo::{ConfigFile, SnapshotFile, SnapshotFilter};
 
 #[derive(Parser)]
 pub(super) struct Opts {
+    #[clap(subcommand)]
+    command: Option<ConfigCommand>,
+
     #[clap(flatten)]
     config_opts: ConfigOpts,
+
+    /// Rewrite uncompressed index and snapshot files compressed, e.g. after upgrading to repository version 2
+    #[clap(long)]
+    compress_files: bool,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Check the rustic config file (profile) for unknown keys and invalid values.
+    /// This doesn't access the repository.
+    Check,
+}
+
+impl Opts {
+    pub(super) fn is_check(&self) -> bool {
+        matches!(self.command, Some(ConfigCommand::Check))
+    }
+}
+
+/// Known sections of the rustic config file together with the check of their keys
+pub(super) fn config_sections() -> Vec<(&'static str, SectionCheck)> {
+    vec![
+        ("global", check_key::<GlobalOpts>),
+        ("snapshot-filter", check_key::<SnapshotFilter>),
+        ("backup", check_key::<backup::Opts>),
+        ("backup.sources", check_key::<backup::Opts>),
+        ("forget", check_key::<forget::ConfigOpts>),
+    ]
+}
+
+/// Check the rustic config file for unknown keys and values which cannot be parsed
+pub(super) fn check_config_file(config_file: &RusticConfig) -> Result<()> {
+    let errors = config_file.check(&config_sections());
+
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{error}");
+        }
+        bail!("config file contains {} errors", errors.len());
+    }
+    println!("config file is ok");
+    Ok(())
 }
 
 pub(super) fn execute(
     be: &impl DecryptFullBackend,
+    cold_be: &impl WriteBackend,
     hot_be: &Option<impl WriteBackend>,
     opts: Opts,
     config: ConfigFile,
@@ -20,15 +71,17 @@ pub(super) fn execute(
     let mut new_config = config.clone();
     opts.config_opts.apply(&mut new_config)?;
     if new_config != config {
+        // the config is saved to the cold repo, not through the hot/cold backend, as the hot repo
+        // has its own config file.
+        let dbe = DecryptBackend::new(cold_be, be.key().clone());
         new_config.is_hot = None;
-        // for hot/cold backend, this only saves the config to the cold repo.
-        be.save_file(&new_config)?;
+        save_config(&dbe, &config, &new_config)?;
 
         if let Some(hot_be) = hot_be {
             // save config to hot repo
             let dbe = DecryptBackend::new(hot_be, be.key().clone());
             new_config.is_hot = Some(true);
-            dbe.save_file(&new_config)?;
+            save_config(&dbe, &config, &new_config)?;
         }
 
         println!("saved new config");
@@ -36,6 +89,64 @@ pub(super) fn execute(
         println!("config is unchanged");
     }
 
+    if opts.compress_files {
+        let zstd = new_config.zstd()?.ok_or_else(|| {
+            anyhow!("compression is not used, it needs repository version 2 and a compression level other than 0")
+        })?;
+        let mut be = be.clone();
+        be.set_zstd(Some(zstd));
+        compress_files(&be)?;
+    }
+
+    Ok(())
+}
+
+/// Save the config only if the config file is still the one which was read when starting
+/// (compare-and-swap). This prevents lost updates if several clients change the config at the
+/// same time; the comparison ignores whether the file belongs to a hot repo.
+fn save_config(be: &impl DecryptFullBackend, old: &ConfigFile, new: &ConfigFile) -> Result<()> {
+    if !be.capabilities().compare_and_swap {
+        warn!("backend cannot replace the config atomically, concurrent changes may get lost");
+    }
+    let data = be.read_full(FileType::Config, &Id::default())?;
+    let mut current: ConfigFile = serde_json::from_slice(&be.decrypt(&data)?)?;
+    current.is_hot = old.is_hot;
+    if current != *old || !be.save_file_if_unchanged(new, &hash(&data))? {
+        bail!("the config was changed by another client in the meantime. Please retry.");
+    }
+    Ok(())
+}
+
+/// Rewrite all uncompressed index and snapshot files using the compression of the backend
+fn compress_files(be: &impl DecryptFullBackend) -> Result<()> {
+    for (tpe, name) in [(FileType::Index, "index"), (FileType::Snapshot, "snapshot")] {
+        let ids = be.list(tpe)?;
+        let p = progress_counter(format!("compressing {name} files..."));
+        p.set_length(ids.len() as u64);
+        let mut count = 0;
+        for id in ids {
+            let data = be.decrypt(&be.read_full(tpe, &id)?)?;
+            // compressed files start with a version byte instead of JSON
+            if matches!(data.first(), Some(b'{' | b'[')) {
+                match tpe {
+                    FileType::Snapshot => {
+                        // `original` keeps the old id such that the snapshot can still be found as parent
+                        let mut snap = SnapshotFile::set_id((id, serde_json::from_slice(&data)?));
+                        snap.id = Id::default();
+                        be.save_file(&snap)?;
+                    }
+                    _ => {
+                        be.hash_write_full(tpe, &data)?;
+                    }
+                }
+                be.remove(tpe, &id, true)?;
+                count += 1;
+            }
+            p.inc(1);
+        }
+        p.finish();
+        println!("compressed {count} {name} files");
+    }
     Ok(())
 }
 
@@ -59,7 +170,8 @@ pub(super) struct ConfigOpts {
 
     /// Set upper limit for default packsize for tree packs.
     /// Note that packs actually can get up to some MiBs larger.
-    /// If not set, pack sizes can grow up to approximately 4 GiB.
+    /// If not set, pack sizes can grow up to approximately 4 GiB. The maximum is 64 GiB; note that
+    /// packs are built in memory and larger packs may not be readable by other restic implementations.
     #[clap(long, value_name = "SIZE")]
     pub set_treepack_size_limit: Option<ByteSize>,
 
@@ -85,7 +197,8 @@ pub(super) struct ConfigOpts {
 
     /// Set upper limit for default packsize for tree packs.
     /// Note that packs actually can get up to some MiBs larger.
-    /// If not set, pack sizes can grow up to approximately 4 GiB.
+    /// If not set, pack sizes can grow up to approximately 4 GiB. The maximum is 64 GiB; note that
+    /// packs are built in memory and larger packs may not be readable by other restic implementations.
     #[clap(long, value_name = "SIZE")]
     pub set_datapack_size_limit: Option<ByteSize>,
 
@@ -135,23 +248,23 @@ impl ConfigOpts {
         }
 
         if let Some(size) = self.set_treepack_size {
-            config.treepack_size = Some(size.as_u64().try_into()?);
+            config.treepack_size = Some(size.as_u64());
         }
         if let Some(factor) = self.set_treepack_growfactor {
             config.treepack_growfactor = Some(factor);
         }
         if let Some(size) = self.set_treepack_size_limit {
-            config.treepack_size_limit = Some(size.as_u64().try_into()?);
+            config.treepack_size_limit = Some(size.as_u64());
         }
 
         if let Some(size) = self.set_datapack_size {
-            config.datapack_size = Some(size.as_u64().try_into()?);
+            config.datapack_size = Some(size.as_u64());
         }
         if let Some(factor) = self.set_datapack_growfactor {
             config.datapack_growfactor = Some(factor);
         }
         if let Some(size) = self.set_datapack_size_limit {
-            config.datapack_size_limit = Some(size.as_u64().try_into()?);
+            config.datapack_size_limit = Some(size.as_u64());
         }
 
         if let Some(percent) = self.set_min_packsize_tolerate_percent {
@@ -171,3 +284,49 @@ impl ConfigOpts {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(data: &str) -> Vec<String> {
+        let config_file = RusticConfig::from_data(data.to_string()).unwrap();
+        config_file.check(&config_sections())
+    }
+
+    #[test]
+    fn config_keys_are_serde_names() {
+        let data = r#"
+[global]
+option = ["username=me"]
+repo-hot = "/tmp/hot"
+
+[backup]
+git-ignore = true
+hook-before = ["true"]
+
+[[backup.sources]]
+source = "/home"
+glob = ["!/home/*/.cache"]
+
+[forget]
+keep-daily = 7
+filter-host = ["host"]
+"#;
+        assert!(check(data).is_empty(), "{:?}", check(data));
+    }
+
+    #[test]
+    fn config_errors_have_lines() {
+        let data = "[global]\noptions = [\"username=me\"]\n\n[backup]\ngit-ignore = \"yes\"\n";
+        let errors = check(data);
+        // sections are checked in alphabetical order
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(
+            errors[0].starts_with("invalid value of \"git-ignore\" in [backup] (line 5): "),
+            "{}",
+            errors[0]
+        );
+        assert_eq!(errors[1], "unknown key \"options\" in [global] (line 2)");
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use clap::{AppSettings, Parser};
+use log::*;
+use prettytable::{format, row, Table};
+use rayon::prelude::*;
+
+use super::password::{FromFile, Prompt};
+use super::rustic_config::RusticConfig;
+use super::{get_repo_key, progress_counter};
+use crate::backend::{
+    DecryptBackend, DecryptFullBackend, DecryptReadBackend, DecryptWriteBackend, FileType,
+    WriteBackend,
+};
+use crate::blob::{BlobType, NodeType, Packer, TreeStreamerOnce};
+use crate::crypto::RepoKey;
+use crate::id::Id;
+use crate::index::{IndexBackend, IndexedBackend, Indexer, ReadIndex};
+use crate::repo::{ConfigFile, IndexFile, SnapshotFile, SnapshotFilter};
+
+#[derive(Parser)]
+#[clap(global_setting(AppSettings::DeriveDisplayOrder))]
+pub(super) struct Opts {
+    /// Repository to copy the snapshots to
+    #[clap(long, value_name = "REPOSITORY")]
+    pub(super) target: String,
+
+    /// Set a backend option for the target repository (can be specified multiple times), e.g. --target-option copy-mode=hardlink
+    #[clap(long = "target-option", value_name = "KEY=VALUE")]
+    pub(super) target_options: Vec<String>,
+
+    /// File to read the password of the target repository from.
+    /// Not needed if the target repository uses the same master key.
+    #[clap(long, value_name = "FILE")]
+    target_password_file: Option<PathBuf>,
+
+    /// Don't copy anything, only show what would be done
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
+    filter: SnapshotFilter,
+
+    /// Snapshots to copy. If none is given, use filter options to filter from all snapshots
+    #[clap(value_name = "ID")]
+    ids: Vec<String>,
+}
+
+pub(super) fn execute<BE: WriteBackend>(
+    be: &BE,
+    dbe: &impl DecryptFullBackend,
+    target: &BE,
+    key: &RepoKey,
+    allow_unencrypted: bool,
+    mut opts: Opts,
+    config_file: RusticConfig,
+) -> Result<()> {
+    config_file.merge_into("snapshot-filter", &mut opts.filter)?;
+
+    let config_ids = target.list(FileType::Config)?;
+    if config_ids.len() != 1 {
+        bail!("target repository has no or more than one config file. Aborting.");
+    }
+
+    // If the target repository uses the same master key, pack files can be copied as they are.
+    let same_key = DecryptBackend::new(target, key.clone())
+        .get_file::<ConfigFile>(&config_ids[0])
+        .is_ok();
+    let target_key = match (same_key, &opts.target_password_file) {
+        (true, _) => key.clone(),
+        (false, Some(file)) => get_repo_key(target, &FromFile(file.clone()), allow_unencrypted)?.1,
+        (false, None) => {
+            let password = Prompt("enter password of target repository: ");
+            get_repo_key(target, &password, allow_unencrypted)?.1
+        }
+    };
+    let mut target_dbe = DecryptBackend::new(target, target_key);
+    let target_config: ConfigFile = target_dbe.get_file(&config_ids[0])?;
+    target_dbe.set_zstd(target_config.zstd()?);
+
+    let mut snapshots = match opts.ids.is_empty() {
+        true => SnapshotFile::all_from_backend(dbe, &opts.filter)?,
+        false => SnapshotFile::from_ids(dbe, &opts.ids)?,
+    };
+    // don't copy snapshots which already have been copied to the target
+    let existing: HashSet<_> =
+        SnapshotFile::all_from_backend(&target_dbe, &SnapshotFilter::default())?
+            .into_iter()
+            .map(|snap| snap.original.unwrap_or(snap.id))
+            .collect();
+    snapshots.retain(|snap| !existing.contains(&snap.original.unwrap_or(snap.id)));
+
+    if snapshots.is_empty() {
+        println!("nothing to copy.");
+        return Ok(());
+    }
+    let mut table: Table = snapshots
+        .iter()
+        .map(|snap| {
+            let time = snap.time.format("%Y-%m-%d %H:%M:%S");
+            row![snap.id, time, snap.hostname, snap.paths.formatln()]
+        })
+        .collect();
+    table.set_titles(row![b->"ID", b->"Time", b->"Host", b->"Paths"]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    println!("snapshots to copy:");
+    table.printstd();
+    println!();
+
+    let index = IndexBackend::new(dbe, progress_counter("reading index..."))?;
+    let target_index = IndexBackend::new(&target_dbe, progress_counter("reading target index..."))?;
+
+    // find all blobs of the snapshots which are not present in the target
+    let mut trees: HashSet<_> = snapshots.iter().map(|snap| snap.tree).collect();
+    let mut data: HashSet<_> = snapshots.iter().filter_map(|snap| snap.report).collect();
+    let p = progress_counter("finding needed blobs...");
+    let mut tree_streamer =
+        TreeStreamerOnce::new(index.clone(), trees.iter().copied().collect(), p)?;
+    while let Some(item) = tree_streamer.next().transpose()? {
+        let (_, tree) = item;
+        for node in tree.nodes() {
+            match node.node_type() {
+                NodeType::File => data.extend(node.content()),
+                NodeType::Dir => {
+                    trees.insert(node.subtree().unwrap());
+                }
+                _ => {}
+            }
+        }
+    }
+    trees.retain(|id| !target_index.has_tree(id));
+    data.retain(|id| !target_index.has_data(id));
+    info!(
+        "{} tree blobs and {} data blobs need to be copied.",
+        trees.len(),
+        data.len()
+    );
+
+    if opts.dry_run {
+        println!("would have copied {} snapshots.", snapshots.len());
+        return Ok(());
+    }
+
+    if same_key {
+        info!("target repository uses the same key, copying pack files.");
+        let packs = trees
+            .iter()
+            .map(|id| index.get_tree(id))
+            .chain(data.iter().map(|id| index.get_data(id)))
+            .map(|ie| ie.map(|ie| *ie.pack()))
+            .collect::<Option<HashSet<_>>>();
+        let packs = match packs {
+            Some(packs) => packs,
+            None => bail!("index of source repository is incomplete. Please run repair index."),
+        };
+        copy_packs(be, dbe, target, &target_dbe, packs)?;
+    } else {
+        copy_blobs(
+            &index,
+            &target_dbe,
+            &target_config,
+            &target_index,
+            trees,
+            data,
+        )?;
+    }
+
+    for mut snap in snapshots {
+        let snap_id = snap.id;
+        if snap.original.is_none() {
+            snap.original = Some(snap.id);
+        }
+        let new_id = target_dbe.save_file(&snap)?;
+        info!("copied snapshot {snap_id} to {new_id}.");
+    }
+
+    Ok(())
+}
+
+/// Copy the given pack files. This only works if both repositories use the same master key.
+/// Pack files are copied server-side if the backend supports it.
+fn copy_packs<BE: WriteBackend>(
+    be: &BE,
+    dbe: &impl DecryptReadBackend,
+    target: &BE,
+    target_dbe: &impl DecryptWriteBackend,
+    mut packs: HashSet<Id>,
+) -> Result<()> {
+    let p = progress_counter("reading index files...");
+    let mut index_packs = Vec::new();
+    for (_, index) in dbe.stream_all::<IndexFile>(p.clone())? {
+        for pack in index.packs {
+            if packs.remove(&pack.id) {
+                index_packs.push(pack);
+            }
+        }
+    }
+    p.finish();
+
+    let indexer = Indexer::new(target_dbe.clone()).into_shared();
+    let p = progress_counter("copying pack files...");
+    p.set_length(index_packs.len() as u64);
+    index_packs
+        .into_par_iter()
+        .try_for_each(|pack| -> Result<()> {
+            let cacheable = pack.blob_type().is_cacheable();
+            if !target.copy_from(be, FileType::Pack, &pack.id, cacheable)? {
+                let data = be.read_full(FileType::Pack, &pack.id)?;
+                target.write_bytes(FileType::Pack, &pack.id, cacheable, data)?;
+            }
+            indexer.write().unwrap().add(pack)?;
+            p.inc(1);
+            Ok(())
+        })?;
+    p.finish();
+
+    let indexer = indexer.write().unwrap();
+    indexer.finalize()?;
+    Ok(())
+}
+
+/// Copy the given blobs by reading them from the source and packing them into the target.
+fn copy_blobs(
+    index: &impl IndexedBackend,
+    target_dbe: &impl DecryptFullBackend,
+    target_config: &ConfigFile,
+    target_index: &impl ReadIndex,
+    trees: HashSet<Id>,
+    data: HashSet<Id>,
+) -> Result<()> {
+    let indexer = Indexer::new(target_dbe.clone()).into_shared();
+    let p = progress_counter("copying blobs...");
+    p.set_length((trees.len() + data.len()) as u64);
+    for (blob_type, ids) in [(BlobType::Tree, trees), (BlobType::Data, data)] {
+        let mut packer = Packer::new(
+            target_dbe.clone(),
+            blob_type,
+            indexer.clone(),
+            target_config,
+            target_index.total_size(&blob_type),
+        )?;
+        for id in ids {
+            let data = index.blob_from_backend(&blob_type, &id)?;
+            packer.add(&data, &id)?;
+            p.inc(1);
+        }
+        packer.finalize()?;
+    }
+    p.finish();
+
+    let indexer = indexer.write().unwrap();
+    indexer.finalize()?;
+    Ok(())
+}
@@ -3,7 +3,7 @@ use std::path::{Path, PathBuf};
 use anyhow::{bail, Result};
 use clap::Parser;
 
-use super::progress_counter;
+use super::{bytes, progress_counter};
 use crate::backend::{DecryptReadBackend, LocalSource, LocalSourceOptions};
 use crate::blob::{Node, NodeStreamer, NodeType, Tree};
 use crate::commands::helpers::progress_spinner;
@@ -22,6 +22,11 @@ pub(super) struct Opts {
     /// New snapshot/path or local path [default for PATH2: PATH1]
     #[clap(value_name = "SNAPSHOT2[:PATH2]|PATH2")]
     snap2: String,
+
+    /// Also show changes of permissions, owner, group and modification time (marked with U if
+    /// the content is unchanged)
+    #[clap(long)]
+    metadata: bool,
 }
 
 pub(super) fn execute(be: &impl DecryptReadBackend, opts: Opts) -> Result<()> {
@@ -46,6 +51,7 @@ pub(super) fn execute(be: &impl DecryptReadBackend, opts: Opts) -> Result<()> {
                 NodeStreamer::new(index.clone(), id1)?,
                 NodeStreamer::new(index, id2)?,
                 true,
+                opts.metadata,
             )
         }
         (Some(id1), None) => {
@@ -62,7 +68,7 @@ pub(super) fn execute(be: &impl DecryptReadBackend, opts: Opts) -> Result<()> {
                 Ok((path.strip_prefix(&path2)?.to_path_buf(), node))
             });
 
-            diff(NodeStreamer::new(index, id1)?, src, false)
+            diff(NodeStreamer::new(index, id1)?, src, false, opts.metadata)
         }
         (None, _) => bail!("cannot use local path as first argument"),
     }
@@ -81,56 +87,110 @@ fn arg_to_snap_path<'a>(arg: &'a str, default_path: &'a str) -> (Option<&'a str>
     }
 }
 
+/// Statistics of a diff run
+#[derive(Default)]
+struct DiffStatistics {
+    added: usize,
+    removed: usize,
+    modified: usize,
+    metadata_changed: usize,
+    added_bytes: u64,
+    removed_bytes: u64,
+}
+
+impl DiffStatistics {
+    fn print(&self, with_metadata: bool) {
+        println!();
+        print!(
+            "{} added, {} removed, {} modified",
+            self.added, self.removed, self.modified
+        );
+        if with_metadata {
+            print!(", {} with changed metadata", self.metadata_changed);
+        }
+        println!();
+        println!(
+            "changed bytes: +{} -{}",
+            bytes(self.added_bytes),
+            bytes(self.removed_bytes)
+        );
+    }
+}
+
 fn diff(
     mut tree_streamer1: impl Iterator<Item = Result<(PathBuf, Node)>>,
     mut tree_streamer2: impl Iterator<Item = Result<(PathBuf, Node)>>,
     check_content: bool,
+    with_metadata: bool,
 ) -> Result<()> {
     let mut item1 = tree_streamer1.next().transpose()?;
     let mut item2 = tree_streamer2.next().transpose()?;
+    let mut stats = DiffStatistics::default();
 
     loop {
         match (&item1, &item2) {
             (None, None) => break,
             (Some(i1), None) => {
                 println!("-    {:?}", i1.0);
+                stats.removed += 1;
+                stats.removed_bytes += i1.1.meta().size;
                 item1 = tree_streamer1.next().transpose()?;
             }
             (None, Some(i2)) => {
                 println!("+    {:?}", i2.0);
+                stats.added += 1;
+                stats.added_bytes += i2.1.meta().size;
                 item2 = tree_streamer2.next().transpose()?;
             }
             (Some(i1), Some(i2)) if i1.0 < i2.0 => {
                 println!("-    {:?}", i1.0);
+                stats.removed += 1;
+                stats.removed_bytes += i1.1.meta().size;
                 item1 = tree_streamer1.next().transpose()?;
             }
             (Some(i1), Some(i2)) if i1.0 > i2.0 => {
                 println!("+    {:?}", i2.0);
+                stats.added += 1;
+                stats.added_bytes += i2.1.meta().size;
                 item2 = tree_streamer2.next().transpose()?;
             }
             (Some(i1), Some(i2)) => {
                 let path = &i1.0;
                 let node1 = &i1.1;
                 let node2 = &i2.1;
-                match node1.node_type() {
-                    tpe if tpe != node2.node_type() => println!("M    {:?}", path), // type was changed
-                    NodeType::File if node1.meta() != node2.meta() => {
-                        println!("M    {:?}", path)
-                    }
-                    NodeType::File if check_content && node1.content() != node2.content() => {
-                        println!("M    {:?}", path)
+                let modified = match (node1.node_type(), node2.node_type()) {
+                    (tpe1, tpe2) if tpe1 != tpe2 => true, // type was changed
+                    (NodeType::File, _) if check_content => node1.content() != node2.content(),
+                    // without content, the file is modified if size or mtime changed
+                    (NodeType::File, _) => {
+                        node1.meta().size != node2.meta().size
+                            || node1.meta().mtime != node2.meta().mtime
                     }
-                    NodeType::Symlink { linktarget } => {
-                        if let NodeType::Symlink {
+                    (
+                        NodeType::Symlink { linktarget },
+                        NodeType::Symlink {
                             linktarget: linktarget2,
-                        } = node2.node_type()
-                        {
-                            if *linktarget != *linktarget2 {
-                                println!("M    {:?}", path)
-                            }
-                        }
-                    }
-                    _ => {} // no difference to show
+                        },
+                    ) => linktarget != linktarget2,
+                    _ => false, // no difference to show
+                };
+                let changes = match with_metadata {
+                    true => metadata_changes(node1, node2),
+                    false => Vec::new(),
+                };
+                if modified {
+                    stats.modified += 1;
+                    stats.removed_bytes += node1.meta().size;
+                    stats.added_bytes += node2.meta().size;
+                }
+                if !changes.is_empty() {
+                    stats.metadata_changed += 1;
+                }
+                match (modified, changes.is_empty()) {
+                    (true, true) => println!("M    {:?}", path),
+                    (true, false) => println!("M    {:?} ({})", path, changes.join(", ")),
+                    (false, false) => println!("U    {:?} ({})", path, changes.join(", ")),
+                    (false, true) => {}
                 }
                 item1 = tree_streamer1.next().transpose()?;
                 item2 = tree_streamer2.next().transpose()?;
@@ -138,5 +198,52 @@ fn diff(
         }
     }
 
+    stats.print(with_metadata);
     Ok(())
 }
+
+/// Returns the kinds of metadata which differ between both nodes: permissions, owner and times
+fn metadata_changes(node1: &Node, node2: &Node) -> Vec<&'static str> {
+    let (meta1, meta2) = (node1.meta(), node2.meta());
+    let mut changes = Vec::new();
+    if meta1.mode != meta2.mode {
+        changes.push("permissions");
+    }
+    if meta1.uid != meta2.uid || meta1.user != meta2.user {
+        changes.push("owner");
+    }
+    if meta1.gid != meta2.gid || meta1.group != meta2.group {
+        changes.push("group");
+    }
+    if meta1.mtime != meta2.mtime {
+        changes.push("mtime");
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::Metadata;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn metadata_changes_are_detected() {
+        let meta = Metadata {
+            mode: Some(0o644),
+            uid: Some(1000),
+            user: Some("user".to_string()),
+            ..Default::default()
+        };
+        let node1 = Node::new_node(OsStr::new("file"), NodeType::File, meta.clone());
+        assert!(metadata_changes(&node1, &node1).is_empty());
+
+        let meta2 = Metadata {
+            mode: Some(0o600),
+            user: Some("other".to_string()),
+            ..meta
+        };
+        let node2 = Node::new_node(OsStr::new("file"), NodeType::File, meta2);
+        assert_eq!(metadata_changes(&node1, &node2), ["permissions", "owner"]);
+    }
+}
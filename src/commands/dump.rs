@@ -0,0 +1,210 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes};
+use clap::Parser;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::*;
+use tar::{Builder, EntryType, Header};
+
+use super::progress_counter;
+use crate::backend::{map_mode_from_go, DecryptReadBackend};
+use crate::blob::{BlobType, Node, NodeStreamer, NodeType, Tree};
+use crate::id::Id;
+use crate::index::{IndexBackend, IndexedBackend};
+use crate::repo::SnapshotFile;
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    /// Snapshot to dump from
+    #[clap(value_name = "SNAPSHOT")]
+    snap: String,
+
+    /// File or directory within the snapshot to dump
+    #[clap(value_name = "PATH")]
+    path: PathBuf,
+
+    /// Archive format which is used if PATH is a directory
+    #[clap(long, value_name = "FORMAT", possible_values = ["tar", "tar.gz"], default_value = "tar")]
+    archive: String,
+}
+
+pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Result<()> {
+    let snap = SnapshotFile::from_str(be, &opts.snap, |_| true, progress_counter(""))?;
+    let index = IndexBackend::new(be, progress_counter(""))?;
+    let node = Tree::node_from_path(&index, snap.tree, &opts.path)?;
+
+    let stdout = io::stdout().lock();
+    match (node.node_type(), opts.archive.as_str()) {
+        (NodeType::File, _) => {
+            let mut stdout = stdout;
+            io::copy(&mut ContentReader::new(&index, node.content()), &mut stdout)?;
+            stdout.flush()?;
+        }
+        (NodeType::Dir, "tar.gz") => {
+            let gz = GzEncoder::new(stdout, Compression::default());
+            dump_tar(&index, &node, gz)?.finish()?.flush()?;
+        }
+        (NodeType::Dir, _) => {
+            dump_tar(&index, &node, stdout)?.flush()?;
+        }
+        (tpe, _) => return Err(anyhow!("{:?} is a {}", opts.path, tpe.name())),
+    }
+
+    Ok(())
+}
+
+/// Write the dir `node` and all its contents as tar archive. Paths within the archive start
+/// with the name of `node`. Returns the writer after the archive has been finished.
+fn dump_tar<W: Write>(index: &impl IndexedBackend, node: &Node, w: W) -> Result<W> {
+    let mut builder = Builder::new(w);
+    let base = PathBuf::from(node.name());
+    if !base.as_os_str().is_empty() {
+        append_node(&mut builder, index, &base, node)?;
+    }
+
+    let id = node
+        .subtree()
+        .ok_or_else(|| anyhow!("dir {base:?} has no subtree"))?;
+    for item in NodeStreamer::new(index.clone(), id)? {
+        let (path, node) = item?;
+        append_node(&mut builder, index, &base.join(path), &node)?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+fn append_node<W: Write>(
+    builder: &mut Builder<W>,
+    index: &impl IndexedBackend,
+    path: &Path,
+    node: &Node,
+) -> Result<()> {
+    let meta = node.meta();
+    let mut header = Header::new_gnu();
+    let default_mode = if node.is_dir() { 0o755 } else { 0o644 };
+    header.set_mode(
+        meta.mode
+            .map_or(default_mode, |mode| map_mode_from_go(mode) & 0o7777),
+    );
+    header.set_mtime(meta.mtime.map_or(0, |t| t.timestamp().max(0) as u64));
+    header.set_uid(meta.uid.unwrap_or_default().into());
+    header.set_gid(meta.gid.unwrap_or_default().into());
+    // names which don't fit into the header are left out; the numerical ids are still saved
+    if let Some(user) = &meta.user {
+        let _ = header.set_username(user);
+    }
+    if let Some(group) = &meta.group {
+        let _ = header.set_groupname(group);
+    }
+    header.set_size(0);
+
+    match node.node_type() {
+        NodeType::Dir => {
+            header.set_entry_type(EntryType::Directory);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        NodeType::File => {
+            // take the size from the index as it must exactly match the content
+            let size = node
+                .content()
+                .iter()
+                .map(|id| {
+                    index
+                        .get_data(id)
+                        .map(|ie| u64::from(ie.data_length()))
+                        .ok_or_else(|| anyhow!("blob {id} of file {path:?} not found in index"))
+                })
+                .sum::<Result<u64>>()?;
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(size);
+            builder.append_data(&mut header, path, ContentReader::new(index, node.content()))?;
+        }
+        NodeType::Symlink { linktarget } => {
+            header.set_entry_type(EntryType::Symlink);
+            builder.append_link(&mut header, path, linktarget)?;
+        }
+        NodeType::Dev { device } => {
+            set_device(&mut header, EntryType::Block, *device)?;
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        NodeType::Chardev { device } => {
+            set_device(&mut header, EntryType::Char, *device)?;
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        NodeType::Fifo => {
+            header.set_entry_type(EntryType::Fifo);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        NodeType::Socket => warn!("{path:?}: sockets can't be saved in tar archives, skipping"),
+    }
+    Ok(())
+}
+
+fn set_device(header: &mut Header, tpe: EntryType, device: u64) -> Result<()> {
+    let (major, minor) = major_minor(device);
+    header.set_entry_type(tpe);
+    header.set_device_major(major)?;
+    header.set_device_minor(minor)?;
+    Ok(())
+}
+
+/// Split a device number into major and minor using the encoding of Linux
+fn major_minor(device: u64) -> (u32, u32) {
+    let major = ((device >> 32) & 0xffff_f000) | ((device >> 8) & 0x0000_0fff);
+    let minor = ((device >> 12) & 0xffff_ff00) | (device & 0x0000_00ff);
+    (major as u32, minor as u32)
+}
+
+/// Reader for the contents of a file which reads the data blobs one after another
+struct ContentReader<'a, I: IndexedBackend> {
+    index: &'a I,
+    ids: std::slice::Iter<'a, Id>,
+    data: Bytes,
+}
+
+impl<'a, I: IndexedBackend> ContentReader<'a, I> {
+    fn new(index: &'a I, ids: &'a [Id]) -> Self {
+        Self {
+            index,
+            ids: ids.iter(),
+            data: Bytes::new(),
+        }
+    }
+}
+
+impl<I: IndexedBackend> Read for ContentReader<'_, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.data.is_empty() {
+            match self.ids.next() {
+                None => return Ok(0),
+                Some(id) => {
+                    self.data = self
+                        .index
+                        .blob_from_backend(&BlobType::Data, id)
+                        .map_err(io::Error::other)?;
+                }
+            }
+        }
+        let n = buf.len().min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data.advance(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_numbers() {
+        // /dev/sda1 and /dev/null
+        assert_eq!(major_minor(0x801), (8, 1));
+        assert_eq!(major_minor(0x103), (1, 3));
+        // large minor numbers
+        assert_eq!(major_minor(0x0100_0103), (1, 0x1003));
+    }
+}
@@ -9,8 +9,9 @@ use prettytable::{format, row, Table};
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
 
-use super::{progress_counter, prune, RusticConfig};
+use super::{confirm, progress_counter, prune, RusticConfig};
 use crate::backend::{Cache, DecryptFullBackend, FileType};
+use crate::id::Id;
 use crate::repo::{
     ConfigFile, SnapshotFile, SnapshotFilter, SnapshotGroup, SnapshotGroupCriterion, StringList,
 };
@@ -40,12 +41,23 @@ pub(super) struct Opts {
 #[derive(Default, Parser, Deserialize, Merge)]
 #[clap(global_setting(AppSettings::DeriveDisplayOrder))]
 #[serde(default, rename_all = "kebab-case")]
-struct ConfigOpts {
+pub(super) struct ConfigOpts {
     /// Group snapshots by any combination of host,paths,tags (default: "host,paths")
     #[clap(long, short = 'g', value_name = "CRITERION")]
     #[serde_as(as = "Option<DisplayFromStr>")]
     group_by: Option<SnapshotGroupCriterion>,
 
+    /// Re-parent snapshots whose parent is removed to the nearest kept ancestor to keep the
+    /// snapshot chains intact
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    reparent: Option<bool>,
+
     #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
     #[serde(flatten)]
     filter: SnapshotFilter,
@@ -138,15 +150,44 @@ pub(super) fn execute(
         println!();
     }
 
+    let mut reparent_snaps = match (forget_snaps.is_empty(), opts.config.reparent) {
+        (false, Some(true)) => {
+            let snaps = SnapshotFile::all_from_backend(be, &SnapshotFilter::default())?;
+            SnapshotFile::reparent(&snaps, &forget_snaps)
+        }
+        _ => Vec::new(),
+    };
+
     match (forget_snaps.is_empty(), opts.dry_run) {
         (true, _) => println!("nothing to remove"),
-        (false, true) => println!(
-            "would have removed the following snapshots:\n {:?}",
-            forget_snaps
-        ),
+        (false, true) => {
+            println!(
+                "would have removed the following snapshots:\n {:?}",
+                forget_snaps
+            );
+            if !reparent_snaps.is_empty() {
+                let ids: Vec<_> = reparent_snaps.iter().map(|sn| sn.id).collect();
+                println!("would have re-parented the following snapshots:\n {ids:?}");
+            }
+        }
         (false, false) => {
+            let question = format!("Do you want to remove {} snapshots?", forget_snaps.len());
+            if !confirm(&question, opts.prune_opts.yes)? {
+                println!("aborted.");
+                return Ok(());
+            }
+            let mut delete_snaps = forget_snaps.clone();
+            if !reparent_snaps.is_empty() {
+                delete_snaps.extend(reparent_snaps.iter().map(|sn| sn.id));
+                // remove old ids from snapshots
+                for snap in &mut reparent_snaps {
+                    snap.id = Id::default();
+                }
+                let p = progress_counter("saving re-parented snapshots...");
+                be.save_list(reparent_snaps, p)?;
+            }
             let p = progress_counter("removing snapshots...");
-            be.delete_list(FileType::Snapshot, true, forget_snaps.clone(), p)?;
+            be.delete_list(FileType::Snapshot, true, delete_snaps, p)?;
         }
     }
 
@@ -1,20 +1,27 @@
 use std::borrow::Cow;
 use std::fmt::Write;
+use std::io::{self, Write as _};
+use std::path::Path;
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{bail, Result};
 use bytesize::ByteSize;
 use indicatif::HumanDuration;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
 use log::*;
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::unistd::isatty;
 use rayon::ThreadPoolBuilder;
-use rpassword::prompt_password;
 
+use super::password::PasswordProvider;
+use crate::archiver::Progress;
 use crate::backend::{DecryptReadBackend, FileType, ReadBackend};
-use crate::crypto::Key;
-use crate::repo::{find_key_in_backend, Id};
+use crate::crypto::{Key, RepoKey};
+use crate::repo::{find_key_id_in_backend, Id};
 
 const MAX_PASSWORD_RETRIES: usize = 5;
 
@@ -22,24 +29,97 @@ pub fn bytes(b: u64) -> String {
     ByteSize(b).to_string_as(true)
 }
 
-pub fn get_key(be: &impl ReadBackend, password: Option<String>) -> Result<Key> {
+/// Ask the user to confirm a destructive action.
+/// Returns `true` without asking if `yes` is set or if stdin is no terminal, so scripts are not blocked.
+pub fn confirm(question: &str, yes: bool) -> Result<bool> {
+    if yes || !matches!(isatty(0), Ok(true)) {
+        return Ok(true);
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Get the key fitting to the password together with the id of the used key file.
+/// The provider is only asked again after a wrong password if it supports retrying.
+pub fn get_key(be: &impl ReadBackend, password: &dyn PasswordProvider) -> Result<(Id, Key)> {
     for _ in 0..MAX_PASSWORD_RETRIES {
-        match &password {
-            // if password is given, directly return the result of find_key_in_backend and don't retry
-            Some(pass) => return find_key_in_backend(be, pass, None),
-            None => {
-                // TODO: Differentiate between wrong password and other error!
-                if let Ok(key) =
-                    find_key_in_backend(be, &prompt_password("enter repository password: ")?, None)
-                {
-                    return Ok(key);
-                }
-            }
+        match find_key_id_in_backend(be, &password.password()?, None) {
+            Ok(key) => return Ok(key),
+            // TODO: Differentiate between wrong password and other error!
+            Err(_) if password.can_retry() => {}
+            Err(err) => return Err(err),
         }
     }
     bail!("incorrect password!");
 }
 
+/// Get the key of the repository and the id of the used key file. Repositories without any key
+/// file are unencrypted; in this case no password is needed, but they are only used if
+/// `allow_unencrypted` is set. Otherwise an attacker could remove the keys and make rustic save
+/// new data unencrypted.
+pub fn get_repo_key(
+    be: &impl ReadBackend,
+    password: &dyn PasswordProvider,
+    allow_unencrypted: bool,
+) -> Result<(Option<Id>, RepoKey)> {
+    if be.list(FileType::Key)?.is_empty() {
+        if !allow_unencrypted {
+            bail!("repository has no keys, i.e. it is unencrypted. Use --allow-unencrypted if this is intended.");
+        }
+        warn!("repository has no keys, using it UNENCRYPTED.");
+        return Ok((None, RepoKey::Unencrypted));
+    }
+    let (id, key) = get_key(be, password)?;
+    Ok((Some(id), RepoKey::Encrypted(key)))
+}
+
+/// Print the resources used by this process: peak memory, CPU and wall time and threads
+pub fn print_resources(wall_time: Duration) {
+    let usage = match getrusage(UsageWho::RUSAGE_SELF) {
+        Ok(usage) => usage,
+        Err(err) => {
+            warn!("error getting resource usage: {err}");
+            return;
+        }
+    };
+    let duration = |t: TimeVal| Duration::from_micros(t.num_microseconds().max(0) as u64);
+    let (user, system) = (duration(usage.user_time()), duration(usage.system_time()));
+    // max_rss is given in bytes on macOS and in KiB on other platforms
+    let max_rss = usage.max_rss().max(0) as u64;
+    let max_rss = if cfg!(target_os = "macos") {
+        max_rss
+    } else {
+        max_rss * 1024
+    };
+
+    eprintln!("resources used:");
+    eprintln!("  wall time:   {wall_time:.2?}");
+    eprintln!(
+        "  CPU time:    {:.2?} (user: {user:.2?}, system: {system:.2?})",
+        user + system
+    );
+    eprintln!("  peak memory: {}", bytes(max_rss));
+    match thread_count() {
+        Some(threads) => eprintln!(
+            "  threads:     {threads} (thread pool: {})",
+            rayon::current_num_threads()
+        ),
+        None => eprintln!("  thread pool: {}", rayon::current_num_threads()),
+    }
+}
+
+/// Number of threads of this process; only available on Linux
+fn thread_count() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|threads| threads.trim().parse().ok())
+}
+
 fn progress_intervall() -> Duration {
     let env_name = "RUSTIC_PROGRESS_INTERVAL";
     std::env::var(env_name)
@@ -83,7 +163,7 @@ pub fn progress_bytes(prefix: impl Into<Cow<'static, str>>) -> ProgressBar {
             .with_key("my_eta", |s: &ProgressState, w: &mut dyn Write| 
                  match (s.pos(), s.len()){
                     (0, _) => write!(w,"-"),
-                    (pos,Some(len)) => write!(w,"{:#}", HumanDuration(Duration::from_secs(s.elapsed().as_secs() * (len-pos)/pos))),
+                    (pos,Some(len)) if len >= pos => write!(w,"{:#}", HumanDuration(Duration::from_secs(s.elapsed().as_secs() * (len-pos)/pos))),
                     (_, _) => write!(w,"-"),
                 }.unwrap())
             .template("[{elapsed_precise}] {prefix:30} {bar:40.cyan/blue} {bytes:>10}/{total_bytes:10} {bytes_per_sec:12} (ETA {my_eta})")
@@ -94,6 +174,81 @@ pub fn progress_bytes(prefix: impl Into<Cow<'static, str>>) -> ProgressBar {
     p
 }
 
+/// Progress bar for a stream of bytes whose total size is unknown, e.g. stdin
+pub fn progress_bytes_stream(prefix: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let p = ProgressBar::new(0).with_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {prefix:30} {spinner} {bytes:>10} {bytes_per_sec:12}")
+            .unwrap(),
+    );
+    p.set_prefix(prefix);
+    p.enable_steady_tick(progress_intervall());
+    p
+}
+
+/// Files of at least this size get an own progress bar during backup
+const FILE_PROGRESS_THRESHOLD: u64 = 1 << 30;
+
+/// Progress of a backup. Besides the total progress, the progress of the file currently read
+/// is shown if this file is large, so that reading it doesn't look like a hang.
+#[derive(Clone)]
+pub struct BackupProgress {
+    multi: Option<MultiProgress>,
+    total: ProgressBar,
+    file: Arc<Mutex<Option<ProgressBar>>>,
+}
+
+impl BackupProgress {
+    pub fn new(total: ProgressBar) -> Self {
+        // adding a hidden progress bar to a MultiProgress would make it visible
+        let multi = (!total.is_hidden()).then(MultiProgress::new);
+        let total = match &multi {
+            Some(multi) => multi.add(total),
+            None => total,
+        };
+        Self {
+            multi,
+            total,
+            file: Arc::default(),
+        }
+    }
+}
+
+impl Progress for BackupProgress {
+    fn inc(&self, bytes: u64) {
+        self.total.inc(bytes);
+        if let Some(p) = self.file.lock().unwrap().as_ref() {
+            p.inc(bytes);
+        }
+    }
+
+    // unchanged files are not read, so they are removed from the bytes to process instead of
+    // being counted as processed; this keeps the ETA meaningful for incremental backups
+    fn inc_unchanged(&self, bytes: u64) {
+        if let Some(len) = self.total.length() {
+            self.total.set_length(len.saturating_sub(bytes));
+        }
+    }
+
+    fn start_file(&self, path: &Path, size: u64) {
+        if let Some(multi) = &self.multi {
+            if size >= FILE_PROGRESS_THRESHOLD {
+                let name = path.file_name().unwrap_or(path.as_os_str());
+                let p = multi.add(progress_bytes(name.to_string_lossy().to_string()));
+                p.set_length(size);
+                *self.file.lock().unwrap() = Some(p);
+            }
+        }
+    }
+
+    fn finish_file(&self) {
+        if let (Some(multi), Some(p)) = (&self.multi, self.file.lock().unwrap().take()) {
+            p.finish_and_clear();
+            multi.remove(&p);
+        }
+    }
+}
+
 pub fn warm_up_command(packs: impl ExactSizeIterator<Item = Id>, command: &str) -> Result<()> {
     let p = progress_counter("warming up packs...");
     p.set_length(packs.len() as u64);
@@ -149,3 +304,24 @@ pub fn wait(d: Option<humantime::Duration>) {
         p.finish();
     }
 }
+
+/// Show a desktop notification using notify-send (Linux/BSD) or osascript (macOS).
+/// Failures are only reported as warnings.
+pub fn notify_desktop(message: &str) {
+    let result = if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification {:?} with title \"rustic\"",
+            message.replace('"', "'")
+        );
+        Command::new("osascript").args(["-e", &script]).status()
+    } else {
+        Command::new("notify-send")
+            .args(["rustic", message])
+            .status()
+    };
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("desktop notification was not successful: {status}"),
+        Err(err) => warn!("error showing desktop notification: {err}"),
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local};
+use clap::{Parser, ValueEnum};
+use log::*;
+use merge::Merge;
+use serde::{Deserialize, Serialize};
+
+use super::backup::expand_variables;
+use crate::id::Id;
+
+/// What to do if a hook command fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum HookFailurePolicy {
+    Abort,
+    Warn,
+    Retry,
+}
+
+#[derive(Clone, Default, Parser, Deserialize, Merge)]
+#[serde(default, rename_all = "kebab-case")]
+pub(super) struct HookOpts {
+    /// Command to run with 'sh -c' before backing up a source (can be specified multiple times).
+    /// The variables {hostname}, {date}, {weekday} and {source} are replaced; the context is
+    /// given as JSON on stdin and in RUSTIC_HOOK_* environment variables
+    #[clap(long, value_name = "COMMAND", help_heading = "HOOK OPTIONS")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    hook_before: Vec<String>,
+
+    /// Command to run with 'sh -c' after backing up a source, also if the backup failed (can be
+    /// specified multiple times). The variables and the context are the same as for --hook-before
+    #[clap(long, value_name = "COMMAND", help_heading = "HOOK OPTIONS")]
+    #[merge(strategy = merge::vec::overwrite_empty)]
+    hook_after: Vec<String>,
+
+    /// What to do if a hook fails: abort the backup of the source and skip the remaining hooks
+    /// (default), warn and continue, or retry the hook and abort if it still fails
+    #[clap(long, value_enum, value_name = "POLICY", help_heading = "HOOK OPTIONS")]
+    hook_failure: Option<HookFailurePolicy>,
+
+    /// Number of retries for --hook-failure=retry [default: 3]
+    #[clap(long, value_name = "N", help_heading = "HOOK OPTIONS")]
+    hook_retries: Option<usize>,
+}
+
+/// Context of a hook, passed to the hook command as JSON on stdin and in environment variables
+#[derive(Serialize)]
+pub(super) struct HookContext<'a> {
+    /// "before" or "after"
+    pub hook: &'static str,
+    pub source: &'a str,
+    pub hostname: &'a str,
+    pub time: DateTime<Local>,
+    pub dry_run: bool,
+    /// whether the backup succeeded; only set for after hooks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    /// the saved snapshot; not set if the backup failed or the snapshot was unchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Id>,
+}
+
+impl HookContext<'_> {
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("RUSTIC_HOOK", self.hook.to_string()),
+            ("RUSTIC_HOOK_SOURCE", self.source.to_string()),
+            ("RUSTIC_HOOK_HOSTNAME", self.hostname.to_string()),
+            ("RUSTIC_HOOK_TIME", self.time.to_rfc3339()),
+            ("RUSTIC_HOOK_DRY_RUN", self.dry_run.to_string()),
+        ];
+        if let Some(success) = self.success {
+            env.push(("RUSTIC_HOOK_SUCCESS", success.to_string()));
+        }
+        if let Some(error) = self.error {
+            env.push(("RUSTIC_HOOK_ERROR", error.to_string()));
+        }
+        if let Some(id) = self.snapshot {
+            env.push(("RUSTIC_HOOK_SNAPSHOT", id.to_hex()));
+        }
+        env
+    }
+}
+
+impl HookOpts {
+    pub(super) fn run_before(&self, context: &HookContext<'_>) -> Result<()> {
+        self.run(&self.hook_before, context)
+    }
+
+    pub(super) fn run_after(&self, context: &HookContext<'_>) -> Result<()> {
+        self.run(&self.hook_after, context)
+    }
+
+    /// Run the hook commands one after another. Depending on the failure policy, a failed command
+    /// stops the chain and returns an error.
+    fn run(&self, commands: &[String], context: &HookContext<'_>) -> Result<()> {
+        let policy = self.hook_failure.unwrap_or(HookFailurePolicy::Abort);
+        let retries = match policy {
+            HookFailurePolicy::Retry => self.hook_retries.unwrap_or(3),
+            _ => 0,
+        };
+
+        for command in commands {
+            let command =
+                expand_variables(command, context.hostname, context.time, context.source)?;
+            let mut result = run_command(&command, context);
+            for retry in 1..=retries {
+                let err = match &result {
+                    Ok(()) => break,
+                    Err(err) => err,
+                };
+                let wait = Duration::from_secs(1 << (retry - 1).min(6));
+                warn!("{err}; retrying in {wait:?} ({retry}/{retries})...");
+                sleep(wait);
+                result = run_command(&command, context);
+            }
+            match (result, policy) {
+                (Ok(()), _) => {}
+                (Err(err), HookFailurePolicy::Warn) => warn!("{err}"),
+                (Err(err), _) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run_command(command: &str, context: &HookContext<'_>) -> Result<()> {
+    info!("running {} hook \"{command}\"...", context.hook);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(context.env())
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("{} hook \"{command}\": {err}", context.hook))?;
+
+    // the hook may not read the context, so errors like a broken pipe are ignored
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = serde_json::to_writer(&mut stdin, context);
+        let _ = stdin.write_all(b"\n");
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("{} hook \"{command}\" failed: {status}", context.hook);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> HookContext<'static> {
+        HookContext {
+            hook: "before",
+            source: "/source",
+            hostname: "host",
+            time: Local::now(),
+            dry_run: false,
+            success: None,
+            error: None,
+            snapshot: None,
+        }
+    }
+
+    #[test]
+    fn hook_failure_policies() {
+        let opts = |policy| HookOpts {
+            hook_before: vec![
+                "false".to_string(),
+                "test $RUSTIC_HOOK = before".to_string(),
+            ],
+            hook_failure: Some(policy),
+            hook_retries: Some(0),
+            ..Default::default()
+        };
+        assert!(opts(HookFailurePolicy::Abort)
+            .run_before(&context())
+            .is_err());
+        assert!(opts(HookFailurePolicy::Retry)
+            .run_before(&context())
+            .is_err());
+        assert!(opts(HookFailurePolicy::Warn).run_before(&context()).is_ok());
+
+        // the context is given on stdin and variables are replaced
+        let opts = HookOpts {
+            hook_before: vec![r#"grep -q '"source":"{source}"'"#.to_string()],
+            ..Default::default()
+        };
+        assert!(opts.run_before(&context()).is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use prettytable::{format, row, Table};
+
+use super::{bytes, confirm, progress_counter};
+use crate::backend::{DecryptFullBackend, FileType};
+use crate::id::Id;
+use crate::repo::IndexFile;
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List all index files and show which are superseded by other index files
+    List,
+    /// Remove superseded index files, i.e. index files whose packs are all contained in other index files
+    Gc(GcOpts),
+}
+
+#[derive(Parser)]
+struct GcOpts {
+    /// Don't remove anything, only show what would be done
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    /// Don't ask for confirmation before removing index files
+    #[clap(long, alias = "force")]
+    yes: bool,
+}
+
+pub(super) fn execute(be: &impl DecryptFullBackend, opts: Opts) -> Result<()> {
+    let p = progress_counter("reading index...");
+    let mut index_files: Vec<_> = be.stream_all::<IndexFile>(p.clone())?.iter().collect();
+    p.finish();
+    index_files.sort_unstable_by_key(|(id, _)| *id);
+
+    let superseded = superseded(&index_files);
+    match opts.command {
+        Command::List => {
+            list(&index_files, &superseded);
+            Ok(())
+        }
+        Command::Gc(opts) => gc(be, superseded, opts),
+    }
+}
+
+/// Returns the index files which are not needed as all their packs are contained in other index
+/// files which are kept. Empty index files are always superseded.
+fn superseded(index_files: &[(Id, IndexFile)]) -> HashSet<Id> {
+    // packs to delete are counted separately as removing the only index file which contains
+    // a pack not marked for deletion would mark the pack for deletion
+    let keys = |index: &IndexFile| {
+        let packs = index.packs.iter().map(|pack| (pack.id, false));
+        let packs_to_delete = index.packs_to_delete.iter().map(|pack| (pack.id, true));
+        packs.chain(packs_to_delete).collect::<Vec<_>>()
+    };
+
+    let mut counts = HashMap::new();
+    for (_, index) in index_files {
+        for key in keys(index) {
+            *counts.entry(key).or_insert(0_usize) += 1;
+        }
+    }
+
+    // check small index files first such that larger ones are kept
+    let mut sorted: Vec<_> = index_files.iter().collect();
+    sorted.sort_by_key(|(_, index)| index.packs.len() + index.packs_to_delete.len());
+
+    let mut superseded = HashSet::new();
+    for (id, index) in sorted {
+        let keys = keys(index);
+        if keys.iter().all(|key| counts[key] > 1) {
+            for key in keys {
+                *counts.get_mut(&key).unwrap() -= 1;
+            }
+            superseded.insert(*id);
+        }
+    }
+    superseded
+}
+
+fn list(index_files: &[(Id, IndexFile)], superseded: &HashSet<Id>) {
+    let mut table = Table::new();
+    for (id, index) in index_files {
+        let blobs = index
+            .packs
+            .iter()
+            .map(|pack| pack.blobs.len())
+            .sum::<usize>();
+        let size = index
+            .packs
+            .iter()
+            .flat_map(|pack| &pack.blobs)
+            .map(|blob| u64::from(blob.length))
+            .sum();
+        let superseded = if superseded.contains(id) { "yes" } else { "" };
+        table.add_row(row![
+            id,
+            r->index.packs.len(),
+            r->index.packs_to_delete.len(),
+            r->blobs,
+            r->bytes(size),
+            superseded
+        ]);
+    }
+    table.set_titles(row![
+        b->"ID",
+        br->"Packs",
+        br->"Packs to delete",
+        br->"Blobs",
+        br->"Size",
+        b->"Superseded"
+    ]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+    println!();
+    table.printstd();
+    println!();
+    println!(
+        "{} index files, {} superseded",
+        index_files.len(),
+        superseded.len()
+    );
+}
+
+fn gc(be: &impl DecryptFullBackend, superseded: HashSet<Id>, opts: GcOpts) -> Result<()> {
+    let ids: Vec<_> = superseded.into_iter().collect();
+    match (ids.is_empty(), opts.dry_run) {
+        (true, _) => println!("no superseded index files."),
+        (false, true) => println!("would have removed the following index files:\n {ids:?}"),
+        (false, false) => {
+            let question = format!(
+                "Do you want to remove {} superseded index files?",
+                ids.len()
+            );
+            if !confirm(&question, opts.yes)? {
+                println!("aborted.");
+                return Ok(());
+            }
+            let p = progress_counter("removing superseded index files...");
+            be.delete_list(FileType::Index, true, ids, p)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::IndexPack;
+
+    fn index_file(packs: &[Id], packs_to_delete: &[Id]) -> IndexFile {
+        let pack = |id: &Id| IndexPack {
+            id: *id,
+            ..Default::default()
+        };
+        IndexFile {
+            supersedes: None,
+            packs: packs.iter().map(pack).collect(),
+            packs_to_delete: packs_to_delete.iter().map(pack).collect(),
+        }
+    }
+
+    #[test]
+    fn superseded_index_files() {
+        let packs: Vec<_> = (0..3).map(|_| Id::random()).collect();
+        let ids: Vec<_> = (0..5).map(|_| Id::random()).collect();
+        let index_files = vec![
+            (ids[0], index_file(&packs, &[])),
+            // contained in ids[0]
+            (ids[1], index_file(&packs[..2], &[])),
+            // empty
+            (ids[2], index_file(&[], &[])),
+            // pack is marked for deletion only here
+            (ids[3], index_file(&[], &packs[..1])),
+            // duplicate of ids[3]
+            (ids[4], index_file(&[], &packs[..1])),
+        ];
+        let superseded = superseded(&index_files);
+        assert!(superseded.contains(&ids[1]));
+        assert!(superseded.contains(&ids[2]));
+        assert!(!superseded.contains(&ids[0]));
+        // exactly one of the duplicates is kept
+        assert!(superseded.contains(&ids[3]) ^ superseded.contains(&ids[4]));
+        assert_eq!(superseded.len(), 3);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::Parser;
+use log::*;
+use rusqlite::{params, Connection};
+
+use super::{progress_counter, RusticConfig};
+use crate::backend::DecryptReadBackend;
+use crate::blob::NodeStreamer;
+use crate::index::{IndexBackend, IndexedBackend};
+use crate::repo::{SnapshotFile, SnapshotFilter};
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
+    filter: SnapshotFilter,
+
+    /// SQLite database file to create or update
+    #[clap(long, value_name = "FILE")]
+    db: PathBuf,
+
+    /// Snapshots to index. If none is given, all snapshots matching the filter are indexed.
+    /// Snapshots in the database which are not selected are removed from the database.
+    #[clap(value_name = "ID")]
+    ids: Vec<String>,
+}
+
+pub(super) fn execute(
+    be: &(impl DecryptReadBackend + Unpin),
+    mut opts: Opts,
+    config_file: RusticConfig,
+) -> Result<()> {
+    config_file.merge_into("snapshot-filter", &mut opts.filter)?;
+
+    let snapshots = match opts.ids.is_empty() {
+        true => SnapshotFile::all_from_backend(be, &opts.filter)?,
+        false => SnapshotFile::from_ids(be, &opts.ids)?,
+    };
+
+    let mut conn = open_db(&opts.db)?;
+    warn!("The database contains unencrypted file names and metadata. Treat it as sensitive data!");
+
+    // remove snapshots which are no longer selected
+    let selected: HashSet<_> = snapshots.iter().map(|snap| snap.id.to_hex()).collect();
+    let indexed = indexed_snapshots(&conn)?;
+    let to_remove: Vec<_> = indexed.difference(&selected).collect();
+    if !to_remove.is_empty() {
+        info!("removing {} snapshots from database...", to_remove.len());
+        let tx = conn.transaction()?;
+        for id in to_remove {
+            tx.execute("DELETE FROM files WHERE snapshot = ?1", params![id])?;
+            tx.execute("DELETE FROM snapshots WHERE id = ?1", params![id])?;
+        }
+        tx.commit()?;
+    }
+
+    let to_add: Vec<_> = snapshots
+        .into_iter()
+        .filter(|snap| !indexed.contains(&snap.id.to_hex()))
+        .collect();
+    if to_add.is_empty() {
+        info!("database is up to date.");
+        return Ok(());
+    }
+
+    let index = IndexBackend::only_full_trees(be, progress_counter(""))?;
+    let p = progress_counter("indexing snapshots...");
+    p.set_length(to_add.len() as u64);
+    for snap in to_add {
+        add_snapshot(&mut conn, &index, &snap)?;
+        p.inc(1);
+    }
+    p.finish();
+
+    Ok(())
+}
+
+/// Open the database and create the tables if needed
+pub(super) fn open_db(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS snapshots (
+            id TEXT PRIMARY KEY,
+            time TEXT NOT NULL,
+            hostname TEXT NOT NULL,
+            paths TEXT NOT NULL,
+            tags TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS files (
+            snapshot TEXT NOT NULL,
+            path TEXT NOT NULL,
+            type TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime TEXT
+        );
+        CREATE INDEX IF NOT EXISTS files_snapshot ON files(snapshot);
+        CREATE INDEX IF NOT EXISTS files_path ON files(path);
+        CREATE VIEW IF NOT EXISTS entries AS
+            SELECT f.snapshot, s.time, s.hostname, f.path, f.type, f.size, f.mtime
+            FROM files f JOIN snapshots s ON f.snapshot = s.id;",
+    )?;
+    Ok(conn)
+}
+
+fn indexed_snapshots(conn: &Connection) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare("SELECT id FROM snapshots")?;
+    let ids = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
+/// Add all entries of the snapshot to the database
+pub(super) fn add_snapshot(
+    conn: &mut Connection,
+    index: &impl IndexedBackend,
+    snap: &SnapshotFile,
+) -> Result<()> {
+    let id = snap.id.to_hex();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO snapshots (id, time, hostname, paths, tags) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            id,
+            snap.time.to_rfc3339(),
+            snap.hostname,
+            snap.paths.to_string(),
+            snap.tags.to_string()
+        ],
+    )?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO files (snapshot, path, type, size, mtime) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for item in NodeStreamer::new(index.clone(), snap.tree)? {
+            let (path, node) = item?;
+            stmt.execute(params![
+                id,
+                path.to_string_lossy(),
+                node.node_type().name(),
+                node.meta.size,
+                node.meta.mtime.map(|t| t.to_rfc3339())
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
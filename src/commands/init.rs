@@ -1,18 +1,24 @@
 use anyhow::{bail, Result};
 use bytes::Bytes;
 use clap::Parser;
-use rpassword::prompt_password;
+use log::*;
 
 use super::config::ConfigOpts;
 use super::key::KeyOpts;
+use super::password::{PasswordProvider, Prompt};
 use crate::backend::{DecryptBackend, DecryptWriteBackend, FileType, WriteBackend};
 use crate::chunker;
-use crate::crypto::{hash, Key};
+use crate::crypto::{hash, Key, RepoKey};
 use crate::id::Id;
 use crate::repo::{ConfigFile, KeyFile};
 
 #[derive(Parser)]
 pub(super) struct Opts {
+    /// Create an unencrypted repository. WARNING: Everyone with access to the repository storage
+    /// can read all saved data! Only use this for non-sensitive or already encrypted data.
+    #[clap(long)]
+    no_encryption: bool,
+
     #[clap(flatten, help_heading = "KEY OPTIONS")]
     key_opts: KeyOpts,
 
@@ -24,7 +30,7 @@ pub(super) fn execute(
     be: &impl WriteBackend,
     hot_be: &Option<impl WriteBackend>,
     opts: Opts,
-    password: Option<String>,
+    password: Option<Box<dyn PasswordProvider>>,
     config_ids: Vec<Id>,
 ) -> Result<()> {
     if !config_ids.is_empty() {
@@ -41,15 +47,46 @@ pub(super) fn execute(
     let mut config = ConfigFile::new(version, repo_id, chunker_poly);
     opts.config_opts.apply(&mut config)?;
 
-    // generate key
+    let key = if opts.no_encryption {
+        warn!("creating an UNENCRYPTED repository! Everyone with access to the repository storage can read all saved data.");
+        be.create()?;
+        if let Some(hot_be) = hot_be {
+            hot_be.create()?;
+        }
+        config.unencrypted = Some(true);
+        RepoKey::Unencrypted
+    } else {
+        RepoKey::Encrypted(add_key(be, hot_be, opts.key_opts, password)?)
+    };
+
+    // save config
+    let dbe = DecryptBackend::new(be, key.clone());
+    dbe.save_file(&config)?;
+
+    if let Some(hot_be) = hot_be {
+        let dbe = DecryptBackend::new(hot_be, key);
+        config.is_hot = Some(true);
+        dbe.save_file(&config)?;
+    }
+    println!("repository {} successfully created.", repo_id);
+
+    Ok(())
+}
+
+/// Generate a new master key and save it in a key file protected by the given password
+fn add_key(
+    be: &impl WriteBackend,
+    hot_be: &Option<impl WriteBackend>,
+    key_opts: KeyOpts,
+    password: Option<Box<dyn PasswordProvider>>,
+) -> Result<Key> {
     let key = Key::new();
 
     let pass = match password {
-        Some(pass) => pass,
-        None => prompt_password("enter password for new key: ")?,
+        Some(password) => password.password()?,
+        None => Prompt("enter password for new key: ").password()?,
     };
 
-    let key_opts = opts.key_opts;
     let keyfile = KeyFile::generate(
         key.clone(),
         &pass,
@@ -67,17 +104,5 @@ pub(super) fn execute(
         hot_be.write_bytes(FileType::Key, &id, false, data)?;
     }
     println!("key {} successfully added.", id);
-
-    // save config
-    let dbe = DecryptBackend::new(be, key.clone());
-    dbe.save_file(&config)?;
-
-    if let Some(hot_be) = hot_be {
-        let dbe = DecryptBackend::new(hot_be, key);
-        config.is_hot = Some(true);
-        dbe.save_file(&config)?;
-    }
-    println!("repository {} successfully created.", repo_id);
-
-    Ok(())
+    Ok(key)
 }
@@ -1,13 +1,23 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use clap::{AppSettings, Parser, Subcommand};
-use rpassword::{prompt_password, read_password_from_bufread};
+use prettytable::{format, row, Table};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
-use crate::backend::{FileType, WriteBackend};
-use crate::crypto::{hash, Key};
-use crate::repo::KeyFile;
+use super::password::{FromFile, PasswordProvider, Prompt};
+use super::{confirm, progress_bytes, progress_counter};
+use crate::backend::{
+    DecryptBackend, DecryptFullBackend, DecryptReadBackend, DecryptWriteBackend, FileType,
+    ReadBackend, WriteBackend,
+};
+use crate::blob::BlobType;
+use crate::crypto::{hash, CryptoKey, Key, RepoKey};
+use crate::id::Id;
+use crate::repo::{
+    ConfigFile, IndexFile, KeyFile, PackHeader, PackHeaderLength, SnapshotFile, LENGTH_LEN,
+};
 
 #[derive(Parser)]
 pub(super) struct Opts {
@@ -17,7 +27,23 @@ pub(super) struct Opts {
 
 #[derive(Subcommand)]
 enum Command {
+    /// List all keys of the repository; the key used to access the repository is marked with *
+    List,
+
+    /// Add a new key to the repository
     Add(AddOpts),
+
+    /// Remove a key from the repository. The last key and the key used to access the repository
+    /// cannot be removed.
+    Remove(RemoveOpts),
+
+    /// Change the password of the used key. This only replaces the key file; the master key is
+    /// kept and no repository data is re-encrypted.
+    Passwd(AddOpts),
+
+    /// Generate a new master key and re-encrypt all repository files with it. All existing keys
+    /// are removed and replaced by one key for the new master key.
+    RotateMaster(RotateOpts),
 }
 
 #[derive(Parser)]
@@ -31,6 +57,31 @@ pub(crate) struct AddOpts {
     pub key_opts: KeyOpts,
 }
 
+#[derive(Parser)]
+pub(crate) struct RemoveOpts {
+    /// Key to remove
+    #[clap(value_name = "ID")]
+    id: String,
+
+    /// Don't ask for confirmation before removing the key
+    #[clap(long, alias = "force")]
+    yes: bool,
+}
+
+#[derive(Parser)]
+#[clap(global_setting(AppSettings::DeriveDisplayOrder))]
+pub(crate) struct RotateOpts {
+    /// Rewrite config, index and snapshot files under the new master key. As the pack files are
+    /// encrypted with the master key, too, they are also rewritten. This is as expensive as
+    /// copying the whole repository and must not be interrupted or run concurrently to other
+    /// commands.
+    #[clap(long)]
+    reencrypt_metadata: bool,
+
+    #[clap(flatten)]
+    add_opts: AddOpts,
+}
+
 #[derive(Parser)]
 #[clap(global_setting(AppSettings::DeriveDisplayOrder))]
 pub(crate) struct KeyOpts {
@@ -47,19 +98,86 @@ pub(crate) struct KeyOpts {
     pub(crate) with_created: bool,
 }
 
-pub(super) fn execute(be: &impl WriteBackend, key: Key, opts: Opts) -> Result<()> {
+pub(super) fn execute(
+    be: &impl DecryptFullBackend,
+    hot_be: &Option<impl WriteBackend>,
+    key_id: Option<Id>,
+    key: RepoKey,
+    config: ConfigFile,
+    opts: Opts,
+) -> Result<()> {
+    let (key_id, key) = match (key_id, key) {
+        (Some(id), RepoKey::Encrypted(key)) => (id, key),
+        _ => bail!("repository is unencrypted and doesn't use keys."),
+    };
     match opts.command {
-        Command::Add(opt) => add_key(be, key, opt),
+        Command::List => list(be, key_id),
+        Command::Add(opt) => add_key(be, key, opt).map(|_| ()),
+        Command::Remove(opt) => remove(be, key_id, opt),
+        Command::Passwd(opt) => passwd(be, key_id, key, opt),
+        Command::RotateMaster(opt) => rotate_master(be, hot_be, config, opt),
     }
 }
 
-fn add_key(be: &impl WriteBackend, key: Key, opts: AddOpts) -> Result<()> {
+fn list(be: &impl ReadBackend, key_id: Id) -> Result<()> {
+    let mut table = Table::new();
+    let mut ids = be.list(FileType::Key)?;
+    ids.sort_unstable();
+    for id in ids {
+        let current = if id == key_id { "*" } else { "" };
+        let row = match KeyFile::from_backend(be, &id) {
+            Ok(keyfile) => {
+                let created = keyfile
+                    .created
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                row![
+                    current,
+                    id,
+                    keyfile.username.unwrap_or_default(),
+                    keyfile.hostname.unwrap_or_default(),
+                    created
+                ]
+            }
+            Err(err) => row![
+                current,
+                id,
+                format!("error reading key file: {err}"),
+                "",
+                ""
+            ],
+        };
+        table.add_row(row);
+    }
+    table.set_titles(row![b->"", b->"ID", b->"User", b->"Host", b->"Created"]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+    println!();
+    table.printstd();
+    Ok(())
+}
+
+fn remove(be: &impl WriteBackend, key_id: Id, opts: RemoveOpts) -> Result<()> {
+    let id = be.find_id(FileType::Key, &opts.id)?;
+    if be.list(FileType::Key)?.len() <= 1 {
+        bail!("key {id} is the last key of the repository and cannot be removed.");
+    }
+    if id == key_id {
+        bail!("key {id} is used to access the repository and cannot be removed. Use 'key passwd' to replace it.");
+    }
+    if !confirm(&format!("Do you want to remove key {id}?"), opts.yes)? {
+        println!("aborted.");
+        return Ok(());
+    }
+    be.remove(FileType::Key, &id, false)?;
+    println!("key {id} removed.");
+    Ok(())
+}
+
+fn add_key(be: &impl WriteBackend, key: Key, opts: AddOpts) -> Result<Id> {
     let pass = match opts.new_password_file {
-        Some(file) => {
-            let mut file = BufReader::new(File::open(file)?);
-            read_password_from_bufread(&mut file)?
-        }
-        None => prompt_password("enter password for new key: ")?,
+        Some(file) => FromFile(file.into()).password()?,
+        None => Prompt("enter password for new key: ").password()?,
     };
     let ko = opts.key_opts;
     let keyfile = KeyFile::generate(key, &pass, ko.hostname, ko.username, ko.with_created)?;
@@ -68,5 +186,205 @@ fn add_key(be: &impl WriteBackend, key: Key, opts: AddOpts) -> Result<()> {
     be.write_bytes(FileType::Key, &id, false, data.into())?;
 
     println!("key {} successfully added.", id);
+    Ok(id)
+}
+
+fn passwd(be: &impl WriteBackend, key_id: Id, key: Key, opts: AddOpts) -> Result<()> {
+    // save the new key file first such that the repository is always accessible
+    add_key(be, key, opts)?;
+    be.remove(FileType::Key, &key_id, false)?;
+    println!("key {key_id} removed.");
+    println!("password changed; the master key is unchanged and no data was re-encrypted.");
     Ok(())
 }
+
+/// Maximum number of packs which are re-encrypted in parallel by rotate-master
+const MAX_PARALLEL_PACKS: usize = 4;
+
+fn rotate_master(
+    be: &impl DecryptFullBackend,
+    hot_be: &Option<impl WriteBackend>,
+    config: ConfigFile,
+    opts: RotateOpts,
+) -> Result<()> {
+    if !opts.reencrypt_metadata {
+        bail!("rotating the master key rewrites all repository files. Please confirm with --reencrypt-metadata.");
+    }
+    let old_keys = be.list(FileType::Key)?;
+    let new_key = Key::new();
+    let mut new_be = DecryptBackend::new(be, RepoKey::Encrypted(new_key.clone()));
+    new_be.set_zstd(config.zstd()?);
+
+    // re-encrypt all pack files. Each thread holds a complete re-encrypted pack in memory, so only
+    // a few packs are processed in parallel.
+    let packs = be.list_with_size(FileType::Pack)?;
+    let p = progress_bytes("re-encrypting pack files...");
+    p.set_length(packs.iter().map(|(_, size)| size).sum());
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(MAX_PARALLEL_PACKS)
+        .build()?;
+    // maps the old pack ids to the new ids and whether the pack is cacheable
+    let pack_ids = pool.install(|| {
+        packs
+            .par_iter()
+            .map(|(id, size)| -> Result<(Id, (Id, bool))> {
+                let (data, cacheable) = reencrypt_pack(be, new_be.key(), id, *size)
+                    .map_err(|err| anyhow!("pack {id}: {err}"))?;
+                let new_id = hash(&data);
+                new_be.write_bytes(FileType::Pack, &new_id, cacheable, data.into())?;
+                p.inc(*size);
+                Ok((*id, (new_id, cacheable)))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+    })?;
+    p.finish();
+
+    // rewrite index files using the new pack ids
+    let p = progress_counter("re-encrypting index files...");
+    let mut index_ids = Vec::new();
+    for (id, mut index) in be.stream_all::<IndexFile>(p.clone())? {
+        for pack in index
+            .packs
+            .iter_mut()
+            .chain(index.packs_to_delete.iter_mut())
+        {
+            pack.id = pack_ids
+                .get(&pack.id)
+                .ok_or_else(|| anyhow!("pack {} is referenced by the index, but missing", pack.id))?
+                .0;
+        }
+        new_be.save_file(&index)?;
+        index_ids.push(id);
+    }
+    p.finish();
+
+    // rewrite snapshot files; `original` keeps the old id such that they can still be found as parent
+    let p = progress_counter("re-encrypting snapshot files...");
+    let mut snapshot_ids = Vec::new();
+    for (id, snap) in be.stream_all::<SnapshotFile>(p.clone())? {
+        let mut snap = SnapshotFile::set_id((id, snap));
+        snap.id = Id::default();
+        new_be.save_file(&snap)?;
+        snapshot_ids.push(id);
+    }
+    p.finish();
+
+    // write the new key and config; from here on the repository uses the new master key
+    add_key(&new_be, new_key.clone(), opts.add_opts)?;
+    let mut config = config;
+    config.is_hot = None;
+    new_be.save_file(&config)?;
+    if let Some(hot_be) = hot_be {
+        let dbe = DecryptBackend::new(hot_be, RepoKey::Encrypted(new_key));
+        config.is_hot = Some(true);
+        dbe.save_file(&config)?;
+    }
+
+    // remove everything which uses the old master key
+    let p = progress_counter("removing old files...");
+    be.delete_list(FileType::Key, false, old_keys, p.clone())?;
+    be.delete_list(FileType::Snapshot, true, snapshot_ids, p.clone())?;
+    be.delete_list(FileType::Index, true, index_ids, p.clone())?;
+    for cacheable in [true, false] {
+        let ids = pack_ids
+            .iter()
+            .filter(|(_, (_, c))| *c == cacheable)
+            .map(|(id, _)| *id)
+            .collect();
+        be.delete_list(FileType::Pack, cacheable, ids, p.clone())?;
+    }
+
+    println!("master key rotated, all repository files have been re-encrypted.");
+    Ok(())
+}
+
+/// Re-encrypt all blobs and the header of the pack `id` which has the given size. The blobs are
+/// read one by one using ranged reads. As the encryption overhead doesn't change, all blobs keep
+/// their offsets. Also returns if the pack contains tree blobs.
+fn reencrypt_pack(
+    be: &impl DecryptReadBackend,
+    new: &impl CryptoKey,
+    id: &Id,
+    size: u64,
+) -> Result<(Vec<u8>, bool)> {
+    let len_start = size
+        .checked_sub(LENGTH_LEN.into())
+        .ok_or_else(|| anyhow!("pack is too small"))?;
+    let length = be.read_partial(FileType::Pack, id, false, len_start, LENGTH_LEN)?;
+    let header_len = PackHeaderLength::from_binary(&length)?.to_u32();
+    let header_start = len_start
+        .checked_sub(header_len.into())
+        .ok_or_else(|| anyhow!("header length {header_len} exceeds the pack size"))?;
+    let header = be.read_partial(FileType::Pack, id, false, header_start, header_len)?;
+    let header = be.decrypt(&header)?;
+    let blobs = PackHeader::from_binary(&header)?.into_blobs();
+
+    let mut result = Vec::with_capacity(usize::try_from(size)?);
+    for blob in &blobs {
+        if blob.offset + u64::from(blob.length) > header_start {
+            bail!("blob {} exceeds the pack data", blob.id);
+        }
+        let encrypted = be.read_partial(FileType::Pack, id, false, blob.offset, blob.length)?;
+        result.extend(new.encrypt_data(&be.decrypt(&encrypted)?)?);
+    }
+    if result.len() as u64 != header_start {
+        bail!("pack contains data which is not referenced by the header");
+    }
+    result.extend(new.encrypt_data(&header)?);
+    result.extend_from_slice(&length);
+
+    let cacheable = blobs.iter().any(|blob| blob.tpe == BlobType::Tree);
+    Ok((result, cacheable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+    use crate::repo::{IndexBlob, IndexPack, PackHeaderRef};
+
+    #[test]
+    fn reencrypt_pack_keeps_offsets() {
+        let (old, new) = (RepoKey::Unencrypted, RepoKey::Encrypted(Key::new()));
+        let mut pack = IndexPack::default();
+        let mut data = Vec::new();
+        for content in [&b"hello"[..], b"world!"] {
+            let blob = old.encrypt_data(content).unwrap();
+            pack.blobs.push(IndexBlob {
+                id: hash(content),
+                tpe: BlobType::Data,
+                offset: data.len() as u64,
+                length: blob.len() as u32,
+                uncompressed_length: None,
+            });
+            data.extend(blob);
+        }
+        let header = PackHeaderRef::from_index_pack(&pack).to_binary().unwrap();
+        let header = old.encrypt_data(&header).unwrap();
+        let header_len = PackHeaderLength::from_u32(header.len() as u32);
+        data.extend(header);
+        data.extend(header_len.to_binary().unwrap());
+
+        let dir = tempfile::tempdir().unwrap();
+        let be = LocalBackend::new(dir.path().to_str().unwrap());
+        be.create().unwrap();
+        let be = DecryptBackend::new(&be, old);
+        let id = hash(&data);
+        be.write_bytes(FileType::Pack, &id, false, data.clone().into())
+            .unwrap();
+
+        let (result, cacheable) = reencrypt_pack(&be, &new, &id, data.len() as u64).unwrap();
+        assert!(!cacheable);
+        assert_eq!(result.len(), data.len());
+        let blob = &pack.blobs[1];
+        let start = blob.offset as usize;
+        let encrypted = &result[start..start + blob.length as usize];
+        assert_eq!(new.decrypt_data(encrypted).unwrap(), b"world!");
+
+        // the old key can't read the re-encrypted pack any more
+        let new_id = hash(&result);
+        be.write_bytes(FileType::Pack, &new_id, false, result.into())
+            .unwrap();
+        assert!(reencrypt_pack(&be, &new, &new_id, data.len() as u64).is_err());
+    }
+}
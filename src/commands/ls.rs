@@ -1,30 +1,187 @@
 use anyhow::Result;
-use clap::Parser;
-use std::path::Path;
+use clap::{AppSettings, Parser};
+use ignore::overrides::{Override, OverrideBuilder};
+use std::path::{Path, PathBuf};
 
 use super::progress_counter;
-use crate::backend::DecryptReadBackend;
-use crate::blob::{NodeStreamer, Tree};
+use crate::backend::ignore::add_pattern;
+use crate::backend::{map_mode_from_go, DecryptReadBackend};
+use crate::blob::{Node, NodeStreamer, NodeType, Tree};
 use crate::index::IndexBackend;
 use crate::repo::SnapshotFile;
 
 #[derive(Parser)]
+#[clap(global_setting(AppSettings::DeriveDisplayOrder))]
 pub(super) struct Opts {
     /// Snapshot/path to list
     #[clap(value_name = "SNAPSHOT[:PATH]")]
     snap: String,
+
+    /// Path within the snapshot to list. Alternatively, use SNAPSHOT:PATH
+    #[clap(value_name = "PATH")]
+    path: Option<String>,
+
+    /// List the contents of directories recursively (default if no path is given)
+    #[clap(long, short = 'R')]
+    recursive: bool,
+
+    /// Use a long listing format showing mode, user, group, size and modification time like 'ls -l'
+    #[clap(long, short = 'l')]
+    long: bool,
+
+    /// Only list entries matching this glob pattern; exclude entries with !PATTERN (can be specified multiple times).
+    /// Subtrees of excluded directories are not read at all.
+    #[clap(long, value_name = "GLOB")]
+    glob: Vec<String>,
+
+    /// Same as --glob but ignores the casing of filenames
+    #[clap(long, value_name = "GLOB")]
+    iglob: Vec<String>,
+}
+
+impl Opts {
+    fn filter(&self) -> Result<Override> {
+        let mut builder = OverrideBuilder::new("/");
+        for glob in &self.glob {
+            add_pattern(&mut builder, glob)?;
+        }
+        builder.case_insensitive(true)?;
+        for glob in &self.iglob {
+            add_pattern(&mut builder, glob)?;
+        }
+        Ok(builder.build()?)
+    }
 }
 
 pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Result<()> {
     let (id, path) = opts.snap.split_once(':').unwrap_or((&opts.snap, ""));
+    let path = opts.path.as_deref().unwrap_or(path);
+    let recursive = opts.recursive || path.is_empty();
+    let filter = opts.filter()?;
+
     let snap = SnapshotFile::from_str(be, id, |_| true, progress_counter(""))?;
     let index = IndexBackend::new(be, progress_counter(""))?;
-    let tree = Tree::subtree_id(&index, snap.tree, Path::new(path))?;
+    let node = Tree::node_from_path(&index, snap.tree, Path::new(path))?;
 
-    for item in NodeStreamer::new(index, tree)? {
-        let (path, _) = item?;
-        println!("{:?} ", path);
+    let print = |path: &Path, node: &Node| match opts.long {
+        true => println!("{}", long_format(path, node)),
+        false => println!("{:?} ", path),
+    };
+
+    match node.subtree() {
+        // a single file
+        None => print(Path::new(path), &node),
+        Some(tree) if recursive => {
+            for item in NodeStreamer::new_with_glob(index, *tree, filter)? {
+                let (path, node) = item?;
+                print(&path, &node);
+            }
+        }
+        Some(tree) => {
+            for node in Tree::from_backend(&index, *tree)? {
+                let path = PathBuf::from(node.name());
+                if !filter.matched(&path, node.is_dir()).is_ignore() {
+                    print(&path, &node);
+                }
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Format a node like 'ls -l' does: mode, user, group, size, modification time and path
+fn long_format(path: &Path, node: &Node) -> String {
+    let meta = node.meta();
+    let user = meta
+        .user
+        .clone()
+        .unwrap_or_else(|| meta.uid.unwrap_or_default().to_string());
+    let group = meta
+        .group
+        .clone()
+        .unwrap_or_else(|| meta.gid.unwrap_or_default().to_string());
+    let mtime = meta
+        .mtime
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "?".repeat(19));
+    let mut line = format!(
+        "{} {user:>8} {group:>8} {:>12} {mtime} {}",
+        mode_string(node),
+        meta.size,
+        path.display()
+    );
+    if let NodeType::Symlink { linktarget } = node.node_type() {
+        line.push_str(" -> ");
+        line.push_str(linktarget);
+    }
+    line
+}
+
+/// Get the mode of a node as string like 'drwxr-xr-x'
+fn mode_string(node: &Node) -> String {
+    let tpe = match node.node_type() {
+        NodeType::File => '-',
+        NodeType::Dir => 'd',
+        NodeType::Symlink { .. } => 'l',
+        NodeType::Dev { .. } => 'b',
+        NodeType::Chardev { .. } => 'c',
+        NodeType::Fifo => 'p',
+        NodeType::Socket => 's',
+    };
+    let mode = match node.meta().mode {
+        Some(mode) => map_mode_from_go(mode),
+        None => return format!("{tpe}?????????"),
+    };
+
+    let mut s = String::with_capacity(10);
+    s.push(tpe);
+    // special bit and its char for user, group and others
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    for (i, (bit, c)) in special.into_iter().enumerate() {
+        let perm = (mode >> (6 - 3 * i)) & 0o7;
+        s.push(if perm & 0o4 > 0 { 'r' } else { '-' });
+        s.push(if perm & 0o2 > 0 { 'w' } else { '-' });
+        s.push(match (perm & 0o1 > 0, mode & bit > 0) {
+            (true, true) => c,
+            (false, true) => c.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::Metadata;
+    use std::ffi::OsStr;
+
+    fn node(node_type: NodeType, mode: u32) -> Node {
+        let meta = Metadata {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        Node::new_node(OsStr::new("test"), node_type, meta)
+    }
+
+    #[test]
+    fn mode_strings() {
+        // go uses 1<<31 for directories and 1<<20 for the sticky bit
+        assert_eq!(
+            mode_string(&node(NodeType::Dir, 0o755 | 1 << 31)),
+            "drwxr-xr-x"
+        );
+        assert_eq!(mode_string(&node(NodeType::File, 0o640)), "-rw-r-----");
+        assert_eq!(
+            mode_string(&node(NodeType::Dir, 0o777 | 1 << 31 | 1 << 20)),
+            "drwxrwxrwt"
+        );
+        // setuid (go: 1<<23) without execute permission
+        assert_eq!(
+            mode_string(&node(NodeType::File, 0o644 | 1 << 23)),
+            "-rwSr--r--"
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use std::path::PathBuf;
+
+#[cfg(not(feature = "mount"))]
+use anyhow::bail;
+use anyhow::Result;
+use clap::Parser;
+
+use super::rustic_config::RusticConfig;
+use crate::backend::DecryptReadBackend;
+use crate::repo::SnapshotFilter;
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    /// Directory to mount the repository at
+    #[clap(value_name = "MOUNTPOINT")]
+    mountpoint: PathBuf,
+
+    #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
+    filter: SnapshotFilter,
+}
+
+#[cfg(feature = "mount")]
+pub(super) fn execute(
+    be: &(impl DecryptReadBackend + Unpin),
+    mut opts: Opts,
+    config_file: RusticConfig,
+) -> Result<()> {
+    use fuser::MountOption;
+
+    use super::progress_counter;
+    use crate::index::IndexBackend;
+    use crate::repo::SnapshotFile;
+
+    config_file.merge_into("snapshot-filter", &mut opts.filter)?;
+
+    let snapshots = SnapshotFile::all_from_backend(be, &opts.filter)?;
+    let index = IndexBackend::new(be, progress_counter(""))?;
+    let fs = fs::RusticFs::new(index, snapshots);
+
+    println!(
+        "mounting repository at {:?}, use 'umount' or 'fusermount -u' to unmount.",
+        opts.mountpoint
+    );
+    let options = [MountOption::RO, MountOption::FSName("rustic".to_string())];
+    fuser::mount2(fs, &opts.mountpoint, &options)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "mount"))]
+pub(super) fn execute(
+    _be: &(impl DecryptReadBackend + Unpin),
+    _opts: Opts,
+    _config_file: RusticConfig,
+) -> Result<()> {
+    bail!("rustic was compiled without mount support.");
+}
+
+#[cfg(feature = "mount")]
+mod fs {
+    use std::collections::{BTreeMap, HashMap};
+    use std::ffi::{OsStr, OsString};
+    use std::time::{Duration, SystemTime};
+
+    use bytes::Bytes;
+    use fuser::{
+        FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    };
+    use log::*;
+    use nix::libc::{c_int, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR};
+
+    use crate::backend::map_mode_from_go;
+    use crate::blob::{BlobType, Metadata, Node, NodeType, Tree};
+    use crate::id::Id;
+    use crate::index::IndexedBackend;
+    use crate::repo::SnapshotFile;
+
+    /// The repository contents never change while mounted, so the kernel may cache everything
+    const TTL: Duration = Duration::from_secs(3600);
+    const ROOT: u64 = 1;
+
+    enum Kind {
+        /// Dir which only exists in the mounted filesystem, like /ids or /hosts/<host>
+        Dir(BTreeMap<OsString, u64>),
+        /// Symlink which only exists in the mounted filesystem, like /latest
+        Link(String),
+        /// Node within a snapshot. The children of dirs and the offsets of the content blobs
+        /// of files are only read when they are needed.
+        Node {
+            node: Box<Node>,
+            children: Option<BTreeMap<OsString, u64>>,
+            offsets: Option<Vec<u64>>,
+        },
+    }
+
+    struct Inode {
+        parent: u64,
+        kind: Kind,
+        time: SystemTime,
+    }
+
+    /// Read-only filesystem showing the snapshots of a repository
+    ///
+    /// Layout:
+    /// - `/ids/<id>`: contents of each snapshot
+    /// - `/hosts/<host>/<time>`: links to the snapshots of each host; `latest` links to the latest one
+    /// - `/latest`: link to the latest snapshot
+    pub(super) struct RusticFs<I: IndexedBackend> {
+        index: I,
+        /// inode `ino` is saved at position `ino - 1`
+        inodes: Vec<Inode>,
+        /// last read data blob as files are usually read sequentially in small pieces
+        cache: Option<(Id, Bytes)>,
+    }
+
+    impl<I: IndexedBackend> RusticFs<I> {
+        pub(super) fn new(index: I, mut snapshots: Vec<SnapshotFile>) -> Self {
+            let mut fs = Self {
+                index,
+                inodes: Vec::new(),
+                cache: None,
+            };
+            let now = SystemTime::now();
+            fs.inodes.push(Inode {
+                parent: ROOT,
+                kind: Kind::Dir(BTreeMap::new()),
+                time: now,
+            });
+            let ids = fs.add_dir(ROOT, "ids", now);
+            let hosts = fs.add_dir(ROOT, "hosts", now);
+
+            snapshots.sort_unstable();
+            let mut latest = HashMap::new();
+            for snap in &snapshots {
+                let time = SystemTime::from(snap.time);
+                let id = snap.id.to_hex();
+                let meta = Metadata {
+                    mtime: Some(snap.time),
+                    ..Default::default()
+                };
+                let mut node = Node::new_node(OsStr::new(&id), NodeType::Dir, meta);
+                node.set_subtree(snap.tree);
+                let kind = Kind::Node {
+                    node: Box::new(node),
+                    children: None,
+                    offsets: None,
+                };
+                fs.add(ids, OsString::from(&id), kind, time);
+
+                let host = match snap.hostname.as_str() {
+                    "" => "unknown".to_string(),
+                    host => host.replace('/', "_"),
+                };
+                let host = match fs.child(hosts, OsStr::new(&host)) {
+                    Some(ino) => ino,
+                    None => fs.add_dir(hosts, &host, time),
+                };
+                let name = snap.time.format("%Y-%m-%dT%H:%M:%S").to_string();
+                fs.add_link(host, &name, format!("../../ids/{id}"), time);
+                // snapshots are sorted by time, so the last one wins
+                latest.insert(host, (id, time));
+            }
+
+            for (host, (id, time)) in latest {
+                fs.add_link(host, "latest", format!("../../ids/{id}"), time);
+            }
+            if let Some(snap) = snapshots.last() {
+                let time = SystemTime::from(snap.time);
+                fs.add_link(ROOT, "latest", format!("ids/{}", snap.id.to_hex()), time);
+            }
+            fs
+        }
+
+        fn inode(&self, ino: u64) -> Option<&Inode> {
+            ino.checked_sub(1)
+                .and_then(|i| self.inodes.get(usize::try_from(i).ok()?))
+        }
+
+        fn inode_mut(&mut self, ino: u64) -> Option<&mut Inode> {
+            ino.checked_sub(1)
+                .and_then(|i| self.inodes.get_mut(usize::try_from(i).ok()?))
+        }
+
+        fn add_dir(&mut self, parent: u64, name: &str, time: SystemTime) -> u64 {
+            self.add(parent, name.into(), Kind::Dir(BTreeMap::new()), time)
+        }
+
+        fn add_link(&mut self, parent: u64, name: &str, target: String, time: SystemTime) -> u64 {
+            self.add(parent, name.into(), Kind::Link(target), time)
+        }
+
+        /// Add a new inode as child of `parent`. If the name is already used, a suffix is added.
+        fn add(&mut self, parent: u64, name: OsString, kind: Kind, time: SystemTime) -> u64 {
+            self.inodes.push(Inode { parent, kind, time });
+            let ino = self.inodes.len() as u64;
+            if let Some(children) = self.children_mut(parent) {
+                let mut unique = name.clone();
+                let mut i = 1;
+                while children.contains_key(&unique) {
+                    unique = name.clone();
+                    unique.push(format!("-{i}"));
+                    i += 1;
+                }
+                children.insert(unique, ino);
+            }
+            ino
+        }
+
+        fn children_mut(&mut self, ino: u64) -> Option<&mut BTreeMap<OsString, u64>> {
+            match &mut self.inode_mut(ino)?.kind {
+                Kind::Dir(children) => Some(children),
+                Kind::Node { children, .. } => children.as_mut(),
+                Kind::Link(_) => None,
+            }
+        }
+
+        fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+            match &self.inode(parent)?.kind {
+                Kind::Dir(children) => children.get(name).copied(),
+                Kind::Node {
+                    children: Some(children),
+                    ..
+                } => children.get(name).copied(),
+                _ => None,
+            }
+        }
+
+        /// Return the children of the dir `ino`; they are read from the repository if needed
+        fn children(&mut self, ino: u64) -> Result<&BTreeMap<OsString, u64>, c_int> {
+            let subtree = match &self.inode(ino).ok_or(ENOENT)?.kind {
+                Kind::Node {
+                    node,
+                    children: None,
+                    ..
+                } if node.is_dir() => Some((*node.subtree()).ok_or(EIO)?),
+                Kind::Dir(_)
+                | Kind::Node {
+                    children: Some(_), ..
+                } => None,
+                _ => return Err(ENOTDIR),
+            };
+
+            if let Some(id) = subtree {
+                let tree = Tree::from_backend(&self.index, id).map_err(|err| {
+                    warn!("error reading tree {id}: {err}");
+                    EIO
+                })?;
+                if let Some(Kind::Node { children, .. }) = self.inode_mut(ino).map(|i| &mut i.kind)
+                {
+                    *children = Some(BTreeMap::new());
+                }
+                for node in tree {
+                    let name = node.name();
+                    let time = node.meta().mtime.map_or(SystemTime::UNIX_EPOCH, Into::into);
+                    let kind = Kind::Node {
+                        node: Box::new(node),
+                        children: None,
+                        offsets: None,
+                    };
+                    self.add(ino, name, kind, time);
+                }
+            }
+
+            match &self.inode(ino).ok_or(ENOENT)?.kind {
+                Kind::Dir(children)
+                | Kind::Node {
+                    children: Some(children),
+                    ..
+                } => Ok(children),
+                _ => Err(ENOTDIR),
+            }
+        }
+
+        fn attr(&self, ino: u64) -> Option<FileAttr> {
+            let inode = self.inode(ino)?;
+            let mut attr = FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: inode.time,
+                mtime: inode.time,
+                ctime: inode.time,
+                crtime: inode.time,
+                kind: FileType::Directory,
+                perm: 0o555,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: 4096,
+                flags: 0,
+            };
+
+            match &inode.kind {
+                Kind::Dir(_) => {}
+                Kind::Link(target) => {
+                    attr.kind = FileType::Symlink;
+                    attr.perm = 0o777;
+                    attr.size = target.len() as u64;
+                }
+                Kind::Node { node, .. } => {
+                    let meta = node.meta();
+                    attr.kind = file_type(node.node_type());
+                    let default_perm = if node.is_dir() { 0o755 } else { 0o644 };
+                    attr.perm = meta.mode.map_or(default_perm, |mode| {
+                        (map_mode_from_go(mode) & 0o7777) as u16
+                    });
+                    attr.size = match node.node_type() {
+                        NodeType::File => meta.size,
+                        NodeType::Symlink { linktarget } => linktarget.len() as u64,
+                        _ => 0,
+                    };
+                    if let NodeType::Dev { device } | NodeType::Chardev { device } =
+                        node.node_type()
+                    {
+                        attr.rdev = *device as u32;
+                    }
+                    attr.mtime = meta.mtime.map_or(inode.time, Into::into);
+                    attr.atime = meta.atime.map_or(attr.mtime, Into::into);
+                    attr.ctime = meta.ctime.map_or(attr.mtime, Into::into);
+                    attr.uid = meta.uid.unwrap_or_default();
+                    attr.gid = meta.gid.unwrap_or_default();
+                    attr.nlink = meta.links.max(1) as u32;
+                }
+            }
+            attr.blocks = attr.size.div_ceil(512);
+            Some(attr)
+        }
+
+        /// Read `size` bytes starting at `offset` from the file `ino`
+        fn read_file(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, c_int> {
+            let index = self.index.clone();
+            let end = offset + size;
+            // the content blobs which contain data within offset..end together with their start
+            let blobs: Vec<(Id, u64)> = match &mut self.inode_mut(ino).ok_or(ENOENT)?.kind {
+                Kind::Node { node, offsets, .. } if node.node_type() == &NodeType::File => {
+                    if offsets.is_none() {
+                        let mut start = 0;
+                        let mut result = Vec::with_capacity(node.content().len());
+                        for id in node.content() {
+                            result.push(start);
+                            start += u64::from(index.get_data(id).ok_or(EIO)?.data_length());
+                        }
+                        *offsets = Some(result);
+                    }
+                    let offsets = offsets.as_deref().unwrap_or_default();
+                    let first = offsets.partition_point(|&start| start <= offset).max(1) - 1;
+                    node.content()
+                        .iter()
+                        .copied()
+                        .zip(offsets.iter().copied())
+                        .skip(first)
+                        .take_while(|(_, start)| *start < end)
+                        .collect()
+                }
+                Kind::Node { node, .. } if node.is_dir() => return Err(EISDIR),
+                Kind::Dir(_) => return Err(EISDIR),
+                _ => return Err(EINVAL),
+            };
+
+            let mut data = Vec::new();
+            for (id, start) in blobs {
+                let blob = self.blob(&id)?;
+                let from = offset.saturating_sub(start).min(blob.len() as u64) as usize;
+                let to = (end - start).min(blob.len() as u64) as usize;
+                data.extend_from_slice(&blob[from..to]);
+            }
+            Ok(data)
+        }
+
+        fn blob(&mut self, id: &Id) -> Result<Bytes, c_int> {
+            match &self.cache {
+                Some((cached, data)) if cached == id => Ok(data.clone()),
+                _ => {
+                    let data =
+                        self.index
+                            .blob_from_backend(&BlobType::Data, id)
+                            .map_err(|err| {
+                                warn!("error reading blob {id}: {err}");
+                                EIO
+                            })?;
+                    self.cache = Some((*id, data.clone()));
+                    Ok(data)
+                }
+            }
+        }
+    }
+
+    fn file_type(tpe: &NodeType) -> FileType {
+        match tpe {
+            NodeType::File => FileType::RegularFile,
+            NodeType::Dir => FileType::Directory,
+            NodeType::Symlink { .. } => FileType::Symlink,
+            NodeType::Dev { .. } => FileType::BlockDevice,
+            NodeType::Chardev { .. } => FileType::CharDevice,
+            NodeType::Fifo => FileType::NamedPipe,
+            NodeType::Socket => FileType::Socket,
+        }
+    }
+
+    impl<I: IndexedBackend> Filesystem for RusticFs<I> {
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            let ino = match self.children(parent) {
+                Ok(children) => children.get(name).copied(),
+                Err(err) => return reply.error(err),
+            };
+            match ino.and_then(|ino| self.attr(ino)) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match self.attr(ino) {
+                Some(attr) => reply.attr(&TTL, &attr),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            match self.inode(ino).map(|inode| &inode.kind) {
+                Some(Kind::Link(target)) => reply.data(target.as_bytes()),
+                Some(Kind::Node { node, .. }) => match node.node_type() {
+                    NodeType::Symlink { linktarget } => reply.data(linktarget.as_bytes()),
+                    _ => reply.error(EINVAL),
+                },
+                Some(Kind::Dir(_)) => reply.error(EINVAL),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            let offset = match u64::try_from(offset) {
+                Ok(offset) => offset,
+                Err(_) => return reply.error(EINVAL),
+            };
+            match self.read_file(ino, offset, size.into()) {
+                Ok(data) => reply.data(&data),
+                Err(err) => reply.error(err),
+            }
+        }
+
+        fn readdir(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            mut reply: ReplyDirectory,
+        ) {
+            let parent = match self.inode(ino) {
+                Some(inode) => inode.parent,
+                None => return reply.error(ENOENT),
+            };
+            let children: Vec<_> = match self.children(ino) {
+                Ok(children) => children
+                    .iter()
+                    .map(|(name, ino)| (*ino, name.clone()))
+                    .collect(),
+                Err(err) => return reply.error(err),
+            };
+
+            let entries = [(ino, OsString::from(".")), (parent, OsString::from(".."))]
+                .into_iter()
+                .chain(children);
+            let skip = usize::try_from(offset).unwrap_or_default();
+            for (i, (ino, name)) in entries.enumerate().skip(skip) {
+                let kind = self
+                    .attr(ino)
+                    .map_or(FileType::RegularFile, |attr| attr.kind);
+                // the offset passed is the one of the next entry
+                if reply.add(ino, (i + 1) as i64, kind, &name) {
+                    break;
+                }
+            }
+            reply.ok();
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use rpassword::{prompt_password, read_password_from_bufread};
+
+/// A source for a password
+pub(crate) trait PasswordProvider {
+    /// Get the password
+    fn password(&self) -> Result<String>;
+
+    /// Whether the provider may return a different password when asked again, e.g. after a
+    /// wrong password has been given
+    fn can_retry(&self) -> bool {
+        false
+    }
+}
+
+/// The password given directly, e.g. by --password
+pub(crate) struct Direct(pub(crate) String);
+
+impl PasswordProvider for Direct {
+    fn password(&self) -> Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Read the password from the first line of a file
+pub(super) struct FromFile(pub(super) PathBuf);
+
+impl PasswordProvider for FromFile {
+    fn password(&self) -> Result<String> {
+        let file = File::open(&self.0)
+            .map_err(|err| anyhow!("error opening password file {:?}: {err}", self.0))?;
+        Ok(read_password_from_bufread(&mut BufReader::new(file))?)
+    }
+}
+
+/// Read the password from the output of a command
+pub(super) struct FromCommand(pub(super) String);
+
+impl PasswordProvider for FromCommand {
+    fn password(&self) -> Result<String> {
+        let commands: Vec<_> = self.0.split(' ').collect();
+        let output = Command::new(commands[0]).args(&commands[1..]).output()?;
+        if !output.status.success() {
+            bail!("password command {} failed: {}", self.0, output.status);
+        }
+        Ok(read_password_from_bufread(&mut BufReader::new(
+            &*output.stdout,
+        ))?)
+    }
+}
+
+/// Read the password from the given environment variable
+pub(super) struct FromEnv(pub(super) String);
+
+impl PasswordProvider for FromEnv {
+    fn password(&self) -> Result<String> {
+        std::env::var(&self.0)
+            .map_err(|err| anyhow!("error reading password from env variable {}: {err}", self.0))
+    }
+}
+
+/// Read the password from the system keyring. The entry is given as SERVICE[:USER]
+pub(super) struct FromKeyring(pub(super) String);
+
+impl PasswordProvider for FromKeyring {
+    #[cfg(feature = "keyring")]
+    fn password(&self) -> Result<String> {
+        let (service, user) = self.0.split_once(':').unwrap_or((&self.0, "rustic"));
+        Ok(keyring::Entry::new(service, user)?.get_password()?)
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn password(&self) -> Result<String> {
+        bail!(
+            "cannot read keyring entry {}: rustic was compiled without keyring support.",
+            self.0
+        );
+    }
+}
+
+/// Ask the user for the password
+pub(super) struct Prompt(pub(super) &'static str);
+
+impl PasswordProvider for Prompt {
+    fn password(&self) -> Result<String> {
+        Ok(prompt_password(self.0)?)
+    }
+
+    fn can_retry(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_env_and_command() {
+        assert_eq!(Direct("secret".to_string()).password().unwrap(), "secret");
+
+        std::env::set_var("RUSTIC_TEST_PASSWORD_ENV", "from-env");
+        let env = FromEnv("RUSTIC_TEST_PASSWORD_ENV".to_string());
+        assert_eq!(env.password().unwrap(), "from-env");
+        assert!(FromEnv("RUSTIC_TEST_PASSWORD_UNSET".to_string())
+            .password()
+            .is_err());
+
+        let command = FromCommand("echo from-command".to_string());
+        assert_eq!(command.password().unwrap(), "from-command");
+        assert!(!command.can_retry());
+        assert!(FromCommand("false".to_string()).password().is_err());
+    }
+}
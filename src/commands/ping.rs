@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use bytesize::ByteSize;
+use clap::Parser;
+use log::*;
+use prettytable::{format, row, Table};
+use rand::{thread_rng, RngCore};
+
+use crate::backend::{FileType, WriteBackend};
+use crate::id::Id;
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    /// Only check listing and reading, don't write and remove a test file (e.g. for append-only repositories)
+    #[clap(long)]
+    no_write: bool,
+
+    /// Size of the test file which is written, read and removed
+    #[clap(long, value_name = "SIZE", default_value = "1kiB")]
+    size: ByteSize,
+
+    /// Number of times each check is repeated
+    #[clap(long, short = 'c', value_name = "N", default_value = "3")]
+    count: usize,
+}
+
+pub(super) fn execute(
+    be: &impl WriteBackend,
+    hot_be: &Option<impl WriteBackend>,
+    opts: Opts,
+) -> Result<()> {
+    if opts.count == 0 {
+        bail!("--count must be at least 1");
+    }
+
+    let mut ok = ping("repository", be, &opts);
+    if let Some(hot_be) = hot_be {
+        println!();
+        ok &= ping("hot repository", hot_be, &opts);
+    }
+
+    if !ok {
+        bail!("backend check failed!");
+    }
+    Ok(())
+}
+
+/// Latencies of one operation
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    /// Run the operation and save the time it needed if it succeeded
+    fn measure<T>(&mut self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = f()?;
+        self.0.push(start.elapsed());
+        Ok(result)
+    }
+
+    fn row(&self, name: &str, error: Option<&anyhow::Error>) -> prettytable::Row {
+        match (error, self.0.iter().min(), self.0.iter().max()) {
+            (Some(err), ..) => row![name, "failed", format!("{err}")],
+            (None, Some(min), Some(max)) => {
+                let avg = self.0.iter().sum::<Duration>() / self.0.len() as u32;
+                row![name, "ok", format!("{min:.1?} / {avg:.1?} / {max:.1?}")]
+            }
+            (None, ..) => row![name, "skipped", ""],
+        }
+    }
+}
+
+/// Check the given backend and print the results. Returns `true` if all checks succeeded.
+fn ping(text: &str, be: &impl WriteBackend, opts: &Opts) -> bool {
+    info!("checking {text} at {}...", be.location());
+
+    let ops = ["list", "read", "write", "read back", "remove"];
+    let mut latencies: Vec<_> = ops.iter().map(|_| Latencies::default()).collect();
+    let mut errors: Vec<Option<anyhow::Error>> = ops.iter().map(|_| None).collect();
+
+    let mut data = vec![0; opts.size.as_u64().try_into().unwrap_or(usize::MAX)];
+    thread_rng().fill_bytes(&mut data);
+    let data = Bytes::from(data);
+
+    for _ in 0..opts.count {
+        // list and read the config file which exists in every repository
+        let config = latencies[0].measure(|| Ok(be.list(FileType::Config)?));
+        let config = match config {
+            Ok(ids) if !ids.is_empty() => ids[0],
+            Ok(_) => {
+                errors[0] = Some(anyhow!("no config file found"));
+                break;
+            }
+            Err(err) => {
+                errors[0] = Some(err);
+                break;
+            }
+        };
+        if let Err(err) = latencies[1].measure(|| Ok(be.read_full(FileType::Config, &config)?)) {
+            errors[1] = Some(err);
+            break;
+        }
+
+        if opts.no_write {
+            continue;
+        }
+
+        // write a test file at a random pack location as packs are never cached and
+        // a left-over file is removed by prune
+        let id = Id::random();
+        if let Err(err) =
+            latencies[2].measure(|| Ok(be.write_bytes(FileType::Pack, &id, false, data.clone())?))
+        {
+            errors[2] = Some(err);
+            break;
+        }
+        let read_back = latencies[3].measure(|| {
+            let read = be.read_full(FileType::Pack, &id)?;
+            if read != data {
+                bail!("read data differs from written data");
+            }
+            Ok(())
+        });
+        if let Err(err) = read_back {
+            errors[3] = Some(err);
+        }
+        if let Err(err) = latencies[4].measure(|| Ok(be.remove(FileType::Pack, &id, false)?)) {
+            warn!(
+                "test file data/{} could not be removed, it will be removed by prune.",
+                id.to_hex()
+            );
+            errors[4] = Some(err);
+        }
+        if errors.iter().any(Option::is_some) {
+            break;
+        }
+    }
+
+    let mut table = Table::new();
+    for ((op, latencies), error) in ops.iter().zip(&latencies).zip(&errors) {
+        table.add_row(latencies.row(op, error.as_ref()));
+    }
+    table.set_titles(row![b->"Operation", b->"Result", b->"Latency (min / avg / max)"]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    println!("{text}");
+    println!();
+    table.printstd();
+
+    errors.iter().all(Option::is_none)
+}
@@ -8,8 +8,11 @@ use chrono::{DateTime, Duration, Local};
 use clap::{AppSettings, Parser};
 use derive_more::Add;
 use log::*;
+use prettytable::{format, row, Table};
 
-use super::{bytes, no_progress, progress_bytes, progress_counter, wait, warm_up, warm_up_command};
+use super::{
+    bytes, confirm, no_progress, progress_bytes, progress_counter, wait, warm_up, warm_up_command,
+};
 use crate::backend::{Cache, DecryptFullBackend, DecryptReadBackend, FileType, ReadBackend};
 use crate::blob::{
     BlobType, BlobTypeMap, Initialize, NodeType, PackSizer, Repacker, Sum, TreeStreamerOnce,
@@ -22,10 +25,14 @@ use crate::repo::{ConfigFile, HeaderEntry, IndexBlob, IndexFile, IndexPack, Snap
 #[derive(Parser)]
 #[clap(global_setting(AppSettings::DeriveDisplayOrder))]
 pub(super) struct Opts {
-    /// Don't remove anything, only show what would be done
+    /// Don't remove anything, only show what would be done including the space accounting per blob type
     #[clap(long, short = 'n')]
     pub(crate) dry_run: bool,
 
+    /// Don't ask for confirmation before removing data
+    #[clap(long, alias = "force")]
+    pub(crate) yes: bool,
+
     /// Define maximum data to repack in % of reposize or as size (e.g. '5b', '2 kB', '3M', '4TiB') or 'unlimited'
     #[clap(long, value_name = "LIMIT", default_value = "unlimited")]
     max_repack: LimitOption,
@@ -70,6 +77,11 @@ pub(super) struct Opts {
     #[clap(long)]
     no_resize: bool,
 
+    /// Always repack packs smaller than this size (e.g. '1MiB') if there are at least two of them.
+    /// This avoids accumulating the small final packs of frequent small backups.
+    #[clap(long, value_name = "SIZE", conflicts_with = "no-resize")]
+    repack_tiny: Option<ByteSize>,
+
     /// Warm up needed data pack files by only requesting them without processing
     #[clap(long)]
     warm_up: bool,
@@ -136,19 +148,26 @@ pub(super) fn execute(
     let existing_packs: HashMap<_, _> = be.list_with_size(FileType::Pack)?.into_iter().collect();
     p.finish();
 
-    let mut pruner = Pruner::new(used_ids, existing_packs, index_files);
+    let keep_delete = if opts.instant_delete {
+        Duration::zero()
+    } else {
+        Duration::from_std(*opts.keep_delete)?
+    };
+    let mut pruner = Pruner::new(used_ids, existing_packs, index_files, keep_delete);
     pruner.count_used_blobs();
     pruner.check()?;
     let repack_cacheable_only = opts
         .repack_cacheable_only
         .unwrap_or_else(|| config.is_hot == Some(true));
     let pack_sizer = total_size.map(|tpe, size| PackSizer::from_config(&config, tpe, size));
+    let tiny_size = opts.repack_tiny.map_or(0, |size| size.as_u64());
     pruner.decide_packs(
         Duration::from_std(*opts.keep_pack)?,
         Duration::from_std(*opts.keep_delete)?,
         repack_cacheable_only,
         opts.repack_uncompressed,
         &pack_sizer,
+        tiny_size,
     )?;
     pruner.decide_repack(
         &opts.max_repack,
@@ -156,10 +175,24 @@ pub(super) fn execute(
         opts.repack_uncompressed,
         opts.no_resize,
         &pack_sizer,
+        tiny_size,
     );
     pruner.check_existing_packs()?;
     pruner.filter_index_files(opts.instant_delete);
     pruner.print_stats();
+    if opts.dry_run {
+        pruner.print_detailed_stats();
+    }
+
+    if !opts.dry_run
+        && !confirm(
+            "Do you want to prune the repository as shown above?",
+            opts.yes,
+        )?
+    {
+        println!("aborted.");
+        return Ok(());
+    }
 
     if opts.warm_up {
         warm_up(be, pruner.repack_packs().into_iter())?;
@@ -254,6 +287,8 @@ struct PruneStats {
 struct PruneIndex {
     id: Id,
     modified: bool,
+    // contains duplicate packs which are too recent to remove them from this index file
+    protected: bool,
     packs: Vec<PrunePack>,
 }
 
@@ -278,7 +313,7 @@ enum PackToDo {
 struct PrunePack {
     id: Id,
     blob_type: BlobType,
-    size: u32,
+    size: u64,
     delete_mark: bool,
     to_do: PackToDo,
     time: Option<DateTime<Local>>,
@@ -331,39 +366,39 @@ impl PrunePack {
             PackToDo::Keep => {
                 stats.blobs[tpe].used += pi.used_blobs as u64;
                 stats.blobs[tpe].unused += pi.unused_blobs as u64;
-                stats.size[tpe].used += pi.used_size as u64;
-                stats.size[tpe].unused += pi.unused_size as u64;
+                stats.size[tpe].used += pi.used_size;
+                stats.size[tpe].unused += pi.unused_size;
                 stats.packs.keep += 1;
             }
             PackToDo::Repack => {
                 stats.blobs[tpe].used += pi.used_blobs as u64;
                 stats.blobs[tpe].unused += pi.unused_blobs as u64;
-                stats.size[tpe].used += pi.used_size as u64;
-                stats.size[tpe].unused += pi.unused_size as u64;
+                stats.size[tpe].used += pi.used_size;
+                stats.size[tpe].unused += pi.unused_size;
                 stats.packs.repack += 1;
                 stats.blobs[tpe].repack += (pi.unused_blobs + pi.used_blobs) as u64;
                 stats.blobs[tpe].repackrm += pi.unused_blobs as u64;
-                stats.size[tpe].repack += (pi.unused_size + pi.used_size) as u64;
-                stats.size[tpe].repackrm += pi.unused_size as u64;
+                stats.size[tpe].repack += pi.unused_size + pi.used_size;
+                stats.size[tpe].repackrm += pi.unused_size;
             }
 
             PackToDo::MarkDelete => {
                 stats.blobs[tpe].unused += pi.unused_blobs as u64;
-                stats.size[tpe].unused += pi.unused_size as u64;
+                stats.size[tpe].unused += pi.unused_size;
                 stats.blobs[tpe].remove += pi.unused_blobs as u64;
-                stats.size[tpe].remove += pi.unused_size as u64;
+                stats.size[tpe].remove += pi.unused_size;
             }
             PackToDo::Recover => {
                 stats.packs_to_delete.recover += 1;
-                stats.size_to_delete.recover += self.size as u64;
+                stats.size_to_delete.recover += self.size;
             }
             PackToDo::Delete => {
                 stats.packs_to_delete.remove += 1;
-                stats.size_to_delete.remove += self.size as u64;
+                stats.size_to_delete.remove += self.size;
             }
             PackToDo::KeepMarked => {
                 stats.packs_to_delete.keep += 1;
-                stats.size_to_delete.keep += self.size as u64;
+                stats.size_to_delete.keep += self.size;
             }
         }
         self.to_do = todo;
@@ -387,7 +422,7 @@ use RepackReason::*;
 struct Pruner {
     time: DateTime<Local>,
     used_ids: HashMap<Id, u8>,
-    existing_packs: HashMap<Id, u32>,
+    existing_packs: HashMap<Id, u64>,
     repack_candidates: Vec<(PackInfo, RepackReason, usize, usize)>,
     index_files: Vec<PruneIndex>,
     stats: PruneStats,
@@ -396,22 +431,33 @@ struct Pruner {
 impl Pruner {
     fn new(
         used_ids: HashMap<Id, u8>,
-        existing_packs: HashMap<Id, u32>,
+        existing_packs: HashMap<Id, u64>,
         index_files: Vec<(Id, IndexFile)>,
+        keep_delete: Duration,
     ) -> Self {
+        let time = Local::now();
+        // Duplicate packs may come from index files written by a still running process. Index
+        // files are only rewritten to remove duplicates once the safety window has passed.
+        let expired = |p: &IndexPack| !matches!(p.time, Some(t) if time - t < keep_delete);
+
         let mut processed_packs = HashSet::new();
         let mut processed_packs_delete = HashSet::new();
         let mut index_files: Vec<_> = index_files
             .into_iter()
             .map(|(id, index)| {
                 let mut modified = false;
+                let mut protected = false;
                 let mut packs: Vec<_> = index
                     .packs
                     .into_iter()
                     // filter out duplicate packs
                     .filter(|p| {
                         let no_duplicate = processed_packs.insert(p.id);
-                        modified |= !no_duplicate;
+                        if !no_duplicate {
+                            let expired = expired(p);
+                            modified |= expired;
+                            protected |= !expired;
+                        }
                         no_duplicate
                     })
                     .map(PrunePack::from_index_pack_unmarked)
@@ -423,7 +469,11 @@ impl Pruner {
                         // filter out duplicate packs
                         .filter(|p| {
                             let no_duplicate = processed_packs_delete.insert(p.id);
-                            modified |= !no_duplicate;
+                            if !no_duplicate {
+                                let expired = expired(p);
+                                modified |= expired;
+                                protected |= !expired;
+                            }
                             no_duplicate
                         })
                         .map(PrunePack::from_index_pack_marked),
@@ -432,6 +482,7 @@ impl Pruner {
                 PruneIndex {
                     id,
                     modified,
+                    protected,
                     packs,
                 }
             })
@@ -452,7 +503,7 @@ impl Pruner {
         }
 
         Self {
-            time: Local::now(),
+            time,
             used_ids,
             existing_packs,
             repack_candidates: Vec::new(),
@@ -495,6 +546,7 @@ impl Pruner {
         repack_cacheable_only: bool,
         repack_uncompressed: bool,
         pack_sizer: &BlobTypeMap<PackSizer>,
+        tiny_size: u64,
     ) -> Result<()> {
         // first process all marked packs then the unmarked ones:
         // - first processed packs are more likely to have all blobs seen as unused
@@ -515,7 +567,8 @@ impl Pruner {
                     let keep_uncacheable = repack_cacheable_only && !pack.blob_type.is_cacheable();
 
                     let to_compress = repack_uncompressed && !pack.is_compressed();
-                    let size_mismatch = !pack_sizer[pack.blob_type].size_ok(pack.size);
+                    let size_mismatch =
+                        !pack_sizer[pack.blob_type].size_ok(pack.size) || pack.size < tiny_size;
 
                     match (pack.delete_mark, pi.used_blobs, pi.unused_blobs) {
                         (false, 0, _) => {
@@ -589,6 +642,7 @@ impl Pruner {
         repack_uncompressed: bool,
         no_resize: bool,
         pack_sizer: &BlobTypeMap<PackSizer>,
+        tiny_size: u64,
     ) {
         let max_unused = match (repack_uncompressed, max_unused) {
             (true, _) => 0,
@@ -610,6 +664,7 @@ impl Pruner {
         let mut resize_packs: BlobTypeMap<Vec<_>> = Default::default();
         let mut do_repack: BlobTypeMap<bool> = Default::default();
         let mut repack_size: BlobTypeMap<u64> = Default::default();
+        let mut tiny_packs: BlobTypeMap<usize> = Default::default();
 
         for (pi, repack_reason, index_num, pack_num) in std::mem::take(&mut self.repack_candidates)
         {
@@ -617,7 +672,7 @@ impl Pruner {
             let blob_type = pi.blob_type;
 
             let total_repack_size: u64 = repack_size.into_values().sum();
-            if total_repack_size + pi.used_size as u64 >= max_repack
+            if total_repack_size + pi.used_size >= max_repack
                 || (self.stats.size.sum().unused_after_prune() < max_unused
                     && repack_reason == PartlyUsed
                     && blob_type == BlobType::Data)
@@ -625,19 +680,23 @@ impl Pruner {
             {
                 pack.set_todo(PackToDo::Keep, &pi, &mut self.stats);
             } else if repack_reason == SizeMismatch {
+                if pi.used_size + pi.unused_size < tiny_size {
+                    tiny_packs[blob_type] += 1;
+                }
                 resize_packs[blob_type].push((pi, index_num, pack_num));
-                repack_size[blob_type] += pi.used_size as u64;
+                repack_size[blob_type] += pi.used_size;
             } else {
                 pack.set_todo(PackToDo::Repack, &pi, &mut self.stats);
-                repack_size[blob_type] += pi.used_size as u64;
+                repack_size[blob_type] += pi.used_size;
                 do_repack[blob_type] = true;
             }
         }
         for (blob_type, resize_packs) in resize_packs {
-            // packs in resize_packs are only repacked if we anyway repack this blob type or
-            // if the target pack size is reached for the blob type.
+            // packs in resize_packs are only repacked if we anyway repack this blob type,
+            // if the target pack size is reached for the blob type or if there are several tiny packs.
             let todo = if do_repack[blob_type]
-                || repack_size[blob_type] > pack_sizer[blob_type].pack_size() as u64
+                || repack_size[blob_type] > pack_sizer[blob_type].pack_size()
+                || tiny_packs[blob_type] > 1
             {
                 PackToDo::Repack
             } else {
@@ -690,7 +749,7 @@ impl Pruner {
 
         // all remaining packs in existing_packs are unreferenced packs
         for size in self.existing_packs.values() {
-            self.stats.size_unref += *size as u64;
+            self.stats.size_unref += *size;
         }
 
         Ok(())
@@ -712,8 +771,9 @@ impl Pruner {
 
             any_must_modify |= must_modify;
 
-            // also process index files which are too small (i.e. rebuild them)
-            must_modify || index.len() < MIN_INDEX_LEN
+            // also process index files which are too small (i.e. rebuild them) unless
+            // they contain recent duplicates
+            must_modify || (!index.protected && index.len() < MIN_INDEX_LEN)
         });
 
         if !any_must_modify && self.index_files.len() == 1 {
@@ -818,6 +878,56 @@ impl Pruner {
         );
     }
 
+    /// Print the space accounting per blob type and the pack statistics
+    fn print_detailed_stats(&self) {
+        let cell = |blobs: u64, size: u64| format!("{blobs} blobs, {}", bytes(size));
+        let mut table = Table::new();
+        let types = self
+            .stats
+            .blobs
+            .iter()
+            .zip(self.stats.size.values())
+            .map(|((tpe, blobs), size)| (format!("{tpe:?}"), *blobs, *size));
+        let total = (
+            "Total".to_string(),
+            self.stats.blobs.sum(),
+            self.stats.size.sum(),
+        );
+        for (name, blobs, size) in types.chain(std::iter::once(total)) {
+            table.add_row(row![
+                name,
+                r->cell(blobs.used, size.used),
+                r->cell(blobs.unused, size.unused),
+                r->cell(blobs.repackrm, size.repackrm),
+                r->cell(blobs.remove, size.remove),
+                r->cell(blobs.total_after_prune(), size.total_after_prune()),
+            ]);
+        }
+        table.set_titles(row![
+            b->"Blob type",
+            br->"Used",
+            br->"Unused",
+            br->"Removed by repack",
+            br->"Removed by delete",
+            br->"Remaining"
+        ]);
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+        println!();
+        table.printstd();
+        println!();
+
+        let pack_stat = &self.stats.packs;
+        println!(
+            "packs: {} used, {} partly used, {} unused, {} to repack, {} to keep",
+            pack_stat.used,
+            pack_stat.partly_used,
+            pack_stat.unused,
+            pack_stat.repack,
+            pack_stat.keep
+        );
+    }
+
     fn repack_packs(&self) -> Vec<Id> {
         self.index_files
             .iter()
@@ -1010,8 +1120,8 @@ struct PackInfo {
     blob_type: BlobType,
     used_blobs: u16,
     unused_blobs: u16,
-    used_size: u32,
-    unused_size: u32,
+    used_size: u64,
+    unused_size: u64,
 }
 
 impl PartialOrd<PackInfo> for PackInfo {
@@ -1027,8 +1137,9 @@ impl Ord for PackInfo {
             // then order such that packs with highest
             // ratio unused/used space are picked first.
             // This is equivalent to ordering by unused / total space.
-            (other.unused_size as u64 * self.used_size as u64)
-                .cmp(&(self.unused_size as u64 * other.used_size as u64)),
+            // Note: we cast to u128 so that no overflow can occur in the multiplications
+            (other.unused_size as u128 * self.used_size as u128)
+                .cmp(&(self.unused_size as u128 * other.used_size as u128)),
         )
     }
 }
@@ -1053,17 +1164,17 @@ impl PackInfo {
             let count = used_ids.get_mut(&blob.id);
             match count {
                 None | Some(0) => {
-                    pi.unused_size += blob.length;
+                    pi.unused_size += u64::from(blob.length);
                     pi.unused_blobs += 1;
                 }
                 Some(count) if needed_pack => {
-                    pi.used_size += blob.length;
+                    pi.used_size += u64::from(blob.length);
                     pi.used_blobs += 1;
                     *count = 0;
                 }
                 Some(count) => {
                     // mark as unused and decrease counter
-                    pi.unused_size += blob.length;
+                    pi.unused_size += u64::from(blob.length);
                     pi.unused_blobs += 1;
                     *count -= 1;
                 }
@@ -1088,15 +1199,23 @@ fn find_used_blobs(
         .into_iter()
         .filter(|id| !ignore_snaps.contains(id))
         .collect();
-    let snap_trees: Vec<_> = index
+    let snaps: Vec<_> = index
         .be()
         .stream_list::<SnapshotFile>(list, p.clone())?
         .into_iter()
-        .map(|(_, snap)| snap.tree)
+        .map(|(_, snap)| snap)
         .collect();
     p.finish();
+    let snap_trees: Vec<_> = snaps.iter().map(|snap| snap.tree).collect();
 
     let mut ids: HashMap<_, _> = snap_trees.iter().map(|id| (*id, 0)).collect();
+    // backup reports are data blobs which are referenced by the snapshot
+    ids.extend(
+        snaps
+            .iter()
+            .filter_map(|snap| snap.report)
+            .map(|id| (id, 0)),
+    );
     let p = progress_counter("finding used blobs...");
 
     let mut tree_streamer = TreeStreamerOnce::new(index.clone(), snap_trees, p)?;
@@ -1115,3 +1234,55 @@ fn find_used_blobs(
 
     Ok(ids)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_file(pack: Id, time: DateTime<Local>) -> IndexFile {
+        IndexFile {
+            supersedes: None,
+            packs: vec![IndexPack {
+                id: pack,
+                time: Some(time),
+                ..Default::default()
+            }],
+            packs_to_delete: Vec::new(),
+        }
+    }
+
+    fn pruner(time: DateTime<Local>) -> Pruner {
+        let pack = Id::random();
+        let index_files = vec![
+            (Id::random(), index_file(pack, time)),
+            // superseded by the first index file
+            (Id::random(), index_file(pack, time)),
+        ];
+        let mut pruner = Pruner::new(
+            HashMap::new(),
+            HashMap::new(),
+            index_files,
+            Duration::hours(23),
+        );
+        for pack in pruner.index_files.iter_mut().flat_map(|i| &mut i.packs) {
+            pack.to_do = PackToDo::Keep;
+        }
+        pruner.filter_index_files(false);
+        pruner
+    }
+
+    #[test]
+    fn superseded_index_file_removed_after_keep_delete() {
+        let pruner = pruner(Local::now() - Duration::days(1));
+        // both index files are rebuilt; the superseded one no longer contains any pack
+        assert_eq!(pruner.index_files.len(), 2);
+        assert!(pruner.index_files[1].packs.is_empty());
+    }
+
+    #[test]
+    fn superseded_index_file_kept_within_keep_delete() {
+        let pruner = pruner(Local::now());
+        // the first index file alone is not rebuilt and the superseded one is protected
+        assert!(pruner.index_files.is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use rusqlite::{Connection, OpenFlags};
+
+use super::bytes;
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    /// SQLite database file created by the index-files command
+    #[clap(long, value_name = "FILE")]
+    db: PathBuf,
+
+    /// Maximum number of entries to show
+    #[clap(long, value_name = "N")]
+    limit: Option<u64>,
+
+    /// SQL predicate using the columns snapshot, time, hostname, path, type, size and mtime,
+    /// e.g. "path LIKE '%invoices_2021.xlsx' AND size > 1000"
+    #[clap(value_name = "PREDICATE")]
+    predicate: Option<String>,
+}
+
+pub(super) fn execute(opts: Opts) -> Result<()> {
+    let conn = Connection::open_with_flags(&opts.db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut sql =
+        "SELECT snapshot, time, hostname, path, type, size, mtime FROM entries".to_string();
+    if let Some(predicate) = &opts.predicate {
+        sql.push_str(&format!(" WHERE {predicate}"));
+    }
+    sql.push_str(" ORDER BY time, path");
+    if let Some(limit) = opts.limit {
+        sql.push_str(&format!(" LIMIT {limit}"));
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let snapshot: String = row.get(0)?;
+        let time: String = row.get(1)?;
+        let hostname: String = row.get(2)?;
+        let path: String = row.get(3)?;
+        let tpe: String = row.get(4)?;
+        let size: u64 = row.get(5)?;
+        println!(
+            "{} {time} {hostname} {tpe:7} {:>10} {path}",
+            &snapshot[0..8],
+            bytes(size)
+        );
+    }
+
+    Ok(())
+}
@@ -22,6 +22,7 @@ pub(super) fn execute(
     if let Some(hot_be) = hot_be {
         fileinfo("hot repository files", hot_be)?;
     }
+    capabilities(be);
 
     #[derive(Default, Clone, Copy, Add)]
     struct Info {
@@ -43,7 +44,7 @@ pub(super) fn execute(
 
         fn add_pack(&mut self, ip: &IndexPack) {
             self.pack_count += 1;
-            let size = ip.pack_size() as u64;
+            let size = ip.pack_size();
             self.total_pack_size += size;
             self.min_pack_size = self.min_pack_size.min(size);
             self.max_pack_size = self.max_pack_size.max(size);
@@ -106,6 +107,24 @@ pub(super) fn execute(
     Ok(())
 }
 
+fn capabilities(be: &impl ReadBackend) {
+    let capabilities = be.capabilities();
+    let yes_no = |b| if b { "yes" } else { "no" };
+    println!("backend capabilities");
+    println!("ranged reads:     {}", yes_no(capabilities.ranged_reads));
+    println!("atomic writes:    {}", yes_no(capabilities.atomic_writes));
+    println!("cheap list:       {}", yes_no(capabilities.cheap_list));
+    println!(
+        "server-side copy: {}",
+        yes_no(capabilities.server_side_copy)
+    );
+    println!(
+        "compare-and-swap: {}",
+        yes_no(capabilities.compare_and_swap)
+    );
+    println!();
+}
+
 fn fileinfo(text: &str, be: &impl ReadBackend) -> Result<()> {
     info!("scanning files...");
 
@@ -115,7 +134,7 @@ fn fileinfo(text: &str, be: &impl ReadBackend) -> Result<()> {
     for tpe in ALL_FILE_TYPES {
         let list = be.list_with_size(tpe)?;
         let count = list.len();
-        let size = list.iter().map(|f| f.1 as u64).sum();
+        let size = list.iter().map(|f| f.1).sum();
         table.add_row(row![format!("{:?}", tpe), r->count, r->bytes(size)]);
         total_count += count;
         total_size += size;
@@ -1,23 +1,33 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::num::NonZeroU32;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Instant;
 
 use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use bytesize::ByteSize;
 use clap::{AppSettings, Parser};
 use derive_getters::Dissolve;
+use ignore::overrides::{Override, OverrideBuilder};
 use ignore::{DirEntry, WalkBuilder};
 use log::*;
 use rayon::ThreadPoolBuilder;
 
-use super::{bytes, progress_bytes, progress_counter, wait, warm_up, warm_up_command};
-use crate::backend::{DecryptReadBackend, FileType, LocalBackend};
+use super::{bytes, progress_bytes, progress_counter, status, wait, warm_up, warm_up_command};
+use crate::backend::ignore::add_pattern;
+use crate::backend::{
+    BackendResult, BlobCache, Cache, DecryptReadBackend, FileRange, FileType, IdMap, LocalBackend,
+};
 use crate::blob::{Node, NodeStreamer, NodeType, Tree};
 use crate::commands::helpers::progress_spinner;
 use crate::crypto::hash;
 use crate::id::Id;
-use crate::index::{IndexBackend, IndexedBackend};
+use crate::index::{IndexBackend, IndexEntry, IndexedBackend};
 use crate::repo::SnapshotFile;
 
 #[derive(Parser)]
@@ -27,7 +37,27 @@ pub(super) struct Opts {
     #[clap(long, short = 'n')]
     dry_run: bool,
 
-    /// Remove all files/dirs in destination which are not contained in snapshot.
+    /// Read and verify all needed data blobs without writing anything. Only allowed with --dry-run.
+    /// This proves that the data is retrievable and measures how long reading the data takes.
+    #[clap(long, requires = "dry-run")]
+    read_data: bool,
+
+    /// Only show an estimate of the data to download, the number of requests and the cost
+    /// (if prices are given); implies --dry-run
+    #[clap(long)]
+    estimate: bool,
+
+    /// Price per GB of downloaded data, used by --estimate
+    #[clap(long, value_name = "PRICE", requires = "estimate")]
+    cost_per_gb: Option<f64>,
+
+    /// Price per request to the backend, used by --estimate
+    #[clap(long, value_name = "PRICE", requires = "estimate")]
+    cost_per_request: Option<f64>,
+
+    /// Remove all files/dirs in destination which are not contained in snapshot and replace
+    /// existing entries of another type, so that the destination mirrors the snapshot.
+    /// Files/dirs which are not restored due to --include or --exclude are kept.
     /// WARNING: Use with care, maybe first try this first with --dry-run?
     #[clap(long)]
     delete: bool,
@@ -36,6 +66,45 @@ pub(super) struct Opts {
     #[clap(long)]
     numeric_id: bool,
 
+    /// Map users when restoring ownership, given as comma-separated FROM:TO pairs of uids or user names
+    /// (e.g. "1000:2000,alice:bob")
+    #[clap(long, value_name = "FROM:TO[,FROM:TO,..]")]
+    owner_map: Option<IdMap>,
+
+    /// Map groups when restoring ownership, given as comma-separated FROM:TO pairs of gids or group names
+    #[clap(long, value_name = "FROM:TO[,FROM:TO,..]")]
+    group_map: Option<IdMap>,
+
+    /// Restore file attributes like immutable or append-only (see chattr(1)).
+    /// They are set after all contents and other metadata have been restored.
+    #[clap(long)]
+    restore_flags: bool,
+
+    /// Don't restore modification and access times of directories. Use this for filesystems
+    /// which don't support setting them or change them while the directory content is restored.
+    #[clap(long)]
+    no_fix_dir_times: bool,
+
+    /// Strip the given number of leading path components when restoring.
+    /// Entries with less or equal path components are not restored.
+    #[clap(long, value_name = "N", default_value = "0")]
+    prefix_strip: usize,
+
+    /// Rewrite paths starting with FROM to start with TO (after --prefix-strip is applied).
+    /// Can be specified multiple times, the first matching rule is used.
+    #[clap(long, value_name = "FROM=TO")]
+    rewrite_path: Vec<RewritePath>,
+
+    /// Read neighboring needed blobs of a pack with a single request if they make up at least this
+    /// fraction of the range to read. Use a value greater than 1 to always read blobs individually.
+    #[clap(long, value_name = "FRACTION", default_value = "0.8")]
+    coalesce_threshold: f64,
+
+    /// Cache downloaded data blobs locally, limiting the blob cache to the given size (e.g. 10GiB).
+    /// Repeated restores of the same contents then don't need to access the backend.
+    #[clap(long, value_name = "SIZE")]
+    blob_cache_size: Option<ByteSize>,
+
     /// Warm up needed data pack files by only requesting them without processing
     #[clap(long)]
     warm_up: bool,
@@ -48,6 +117,24 @@ pub(super) struct Opts {
     #[clap(long, value_name = "DURATION", conflicts_with = "dry-run")]
     warm_up_wait: Option<humantime::Duration>,
 
+    /// Only restore files matching this glob pattern (can be specified multiple times).
+    /// Directories are only restored if they contain restored files.
+    #[clap(long, value_name = "GLOB", help_heading = "FILTER OPTIONS")]
+    include: Vec<String>,
+
+    /// Don't restore files and directories matching this glob pattern (can be specified multiple times).
+    /// Excludes take precedence over includes.
+    #[clap(long, value_name = "GLOB", help_heading = "FILTER OPTIONS")]
+    exclude: Vec<String>,
+
+    /// Same as --include but ignores the casing of filenames
+    #[clap(long, value_name = "GLOB", help_heading = "FILTER OPTIONS")]
+    iinclude: Vec<String>,
+
+    /// Same as --exclude but ignores the casing of filenames
+    #[clap(long, value_name = "GLOB", help_heading = "FILTER OPTIONS")]
+    iexclude: Vec<String>,
+
     /// Snapshot/path to restore
     #[clap(value_name = "SNAPSHOT[:PATH]")]
     snap: String,
@@ -57,7 +144,18 @@ pub(super) struct Opts {
     dest: String,
 }
 
-pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Result<()> {
+pub(super) fn execute(
+    be: &(impl DecryptReadBackend + Unpin),
+    cache: &Option<Cache>,
+    mut opts: Opts,
+) -> Result<()> {
+    if opts.estimate {
+        opts.dry_run = true;
+    }
+    if !be.capabilities().ranged_reads {
+        // partial reads are expensive: read all needed blobs of a pack with a single request
+        opts.coalesce_threshold = 0.0;
+    }
     if let Some(command) = &opts.warm_up_command {
         if !command.contains("%id") {
             bail!("warm-up command must contain %id!")
@@ -74,7 +172,7 @@ pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Res
     let dest = LocalBackend::new(&opts.dest);
 
     let p = progress_spinner("collecting file information...");
-    let file_infos = allocate_and_collect(&dest, index.clone(), tree, &opts)?;
+    let (file_infos, specials) = allocate_and_collect(&dest, index.clone(), tree, &opts)?;
     p.finish();
     info!("total restore size: {}", bytes(file_infos.total_size));
     if file_infos.matched_size > 0 {
@@ -84,7 +182,12 @@ pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Res
         );
     }
 
-    if file_infos.total_size == file_infos.matched_size {
+    if opts.estimate {
+        print_estimate(&file_infos, &opts);
+        return Ok(());
+    }
+
+    if file_infos.total_size == file_infos.matched_size && !opts.read_data {
         info!("all file contents are fine.");
     } else {
         if opts.warm_up {
@@ -96,12 +199,35 @@ pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Res
             )?;
         }
         wait(opts.warm_up_wait);
-        if !opts.dry_run {
-            restore_contents(be, &dest, file_infos)?;
+        if opts.read_data {
+            verify_contents(be, &file_infos)?;
+        } else if !opts.dry_run {
+            let blob_cache = match (cache, opts.blob_cache_size) {
+                (Some(cache), Some(size)) => Some(cache.blob_cache(size.as_u64())?),
+                (None, Some(_)) => {
+                    warn!("no cache available, --blob-cache-size is ignored.");
+                    None
+                }
+                (_, None) => None,
+            };
+            restore_contents(
+                be,
+                &dest,
+                file_infos,
+                opts.coalesce_threshold,
+                blob_cache.as_ref(),
+            )?;
+            if let Some(blob_cache) = blob_cache {
+                blob_cache.trim()?;
+            }
         }
     }
 
     if !opts.dry_run {
+        let p = progress_spinner("creating special files...");
+        restore_special(&dest, &specials);
+        p.finish();
+
         let p = progress_spinner("setting metadata...");
         restore_metadata(&dest, index, tree, &opts)?;
         p.finish();
@@ -111,21 +237,160 @@ pub(super) fn execute(be: &(impl DecryptReadBackend + Unpin), opts: Opts) -> Res
     Ok(())
 }
 
-/// collect restore information, scan existing files and allocate non-existing files
+#[derive(Clone, Debug)]
+struct RewritePath {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl FromStr for RewritePath {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid path rewrite \"{s}\", expected FROM=TO"))?;
+        Ok(Self {
+            from: from.trim_start_matches('/').into(),
+            to: to.trim_start_matches('/').into(),
+        })
+    }
+}
+
+impl Opts {
+    /// Build the filter given by --include, --exclude, --iinclude and --iexclude
+    fn filter(&self) -> Result<Override> {
+        let mut builder = OverrideBuilder::new("/");
+        // later patterns take precedence, so excludes are added last
+        for glob in &self.include {
+            add_pattern(&mut builder, glob)?;
+        }
+        builder.case_insensitive(true)?;
+        for glob in &self.iinclude {
+            add_pattern(&mut builder, glob)?;
+        }
+        builder.case_insensitive(false)?;
+        for glob in &self.exclude {
+            add_pattern(&mut builder, &format!("!{glob}"))?;
+        }
+        builder.case_insensitive(true)?;
+        for glob in &self.iexclude {
+            add_pattern(&mut builder, &format!("!{glob}"))?;
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Apply --prefix-strip and --rewrite-path to the given path.
+    /// Returns `None` if the path should not be restored.
+    fn rewrite(&self, path: &Path) -> Option<PathBuf> {
+        let mut components = path.components();
+        for _ in 0..self.prefix_strip {
+            components.next();
+        }
+        let path = components.as_path();
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+
+        Some(
+            self.rewrite_path
+                .iter()
+                .find_map(|rw| Some(rw.to.join(path.strip_prefix(&rw.from).ok()?)))
+                .unwrap_or_else(|| path.to_path_buf()),
+        )
+    }
+}
+
+type NodeIterator<'a> = Box<dyn Iterator<Item = Result<(PathBuf, Node)>> + 'a>;
+
+/// Check that a path to restore stays within the destination. Snapshots may be untrusted
+/// and contain node names like ".." or names including a '/'.
+fn is_valid_path(path: &Path) -> bool {
+    let valid = path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        warn!("path {path:?} is not a valid relative path, not restoring it.");
+    }
+    valid
+}
+
+/// Check if `path` or one of its parent dirs is not restored due to the given filter
+fn is_filtered(filter: &Override, path: &Path, is_dir: bool) -> bool {
+    filter.matched(path, is_dir).is_ignore()
+        || path
+            .ancestors()
+            .skip(1)
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| filter.matched(path, true).is_ignore())
+}
+
+/// Stream all nodes to restore. If paths are rewritten, the rewritten paths are sorted to
+/// allow comparing them with the existing entries in the destination.
+fn restore_nodes<'a>(
+    index: impl IndexedBackend + Unpin + 'a,
+    tree: Id,
+    opts: &Opts,
+) -> Result<NodeIterator<'a>> {
+    let filter = opts.filter()?;
+    let has_includes = filter.num_whitelists() > 0;
+    let node_streamer = NodeStreamer::new_with_glob(index, tree, filter.clone())?;
+    if opts.prefix_strip == 0 && opts.rewrite_path.is_empty() && !has_includes {
+        return Ok(Box::new(node_streamer.filter(|item| match item {
+            Ok((path, _)) => is_valid_path(path),
+            Err(_) => true,
+        })));
+    }
+
+    let mut nodes = node_streamer.collect::<Result<Vec<_>>>()?;
+    if has_includes {
+        // only restore directories which are needed for the included files
+        let needed: HashSet<_> = nodes
+            .iter()
+            .filter(|(_, node)| !node.is_dir())
+            .flat_map(|(path, _)| path.ancestors().skip(1).map(Path::to_path_buf))
+            .collect();
+        nodes.retain(|(path, node)| {
+            !node.is_dir() || needed.contains(path) || filter.matched(path, true).is_whitelist()
+        });
+    }
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .filter_map(|(path, node)| {
+            opts.rewrite(&path)
+                .filter(|path| is_valid_path(path))
+                .map(|path| (path, node))
+        })
+        .collect();
+    // stable sort: for duplicate paths the first node in the snapshot is kept
+    nodes.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+    nodes.dedup_by(|(p1, _), (p2, _)| {
+        let dup = p1 == p2;
+        if dup {
+            warn!(
+                "path {p1:?} is contained multiple times after rewriting, only restoring it once."
+            );
+        }
+        dup
+    });
+    Ok(Box::new(nodes.into_iter().map(Ok)))
+}
+
+/// collect restore information, scan existing files and allocate non-existing files.
+/// Returns the file contents to restore and the special files which need to be created.
 fn allocate_and_collect(
     dest: &LocalBackend,
     index: impl IndexedBackend + Unpin,
     tree: Id,
     opts: &Opts,
-) -> Result<FileInfos> {
+) -> Result<(FileInfos, Vec<(PathBuf, Node)>)> {
     let dest_path = Path::new(&opts.dest);
 
     let mut file_infos = FileInfos::new();
+    let mut specials = Vec::new();
     let mut additional_existing = false;
     // Dir stack is needed to process removal of dirs AFTER the content has been processed.
     // This is the same logic as in restore_metadata -> TODO: consollidate!
     let mut dir_stack = Vec::new();
 
+    let filter = opts.filter()?;
     let mut process_existing = |entry: &DirEntry| -> Result<_> {
         if entry.depth() == 0 {
             // don't process the root dir which should be existing
@@ -133,12 +398,15 @@ fn allocate_and_collect(
         }
 
         debug!("additional {:?}", entry.path());
+        let is_dir = entry.file_type().unwrap().is_dir();
+        if opts.delete && is_filtered(&filter, entry.path().strip_prefix(dest_path)?, is_dir) {
+            // entries which are not restored due to the filter are kept, and so are their parents
+            debug!("keeping filtered {:?}", entry.path());
+            dir_stack.retain(|dir| !entry.path().starts_with(dir));
+            return Ok(());
+        }
 
-        match (
-            opts.delete,
-            opts.dry_run,
-            entry.file_type().unwrap().is_dir(),
-        ) {
+        match (opts.delete, opts.dry_run, is_dir) {
             (true, true, true) => {
                 println!("would have removed the existing dir: {:?}", entry.path())
             }
@@ -200,14 +468,21 @@ fn allocate_and_collect(
                     (true, (Some(size), _)) => {
                         debug!("to modify: {path:?} (exists with different size)");
                         if !opts.dry_run {
-                            // remove file and re-create as it doesn't exist with right size
-                            dest.remove_file(dest_path.join(path))?;
-                            dest.create_file(path, size)?;
+                            // keep the existing contents which are re-used, only change the size
+                            dest.resize_file(path, size)?;
                         }
                     }
                 }
             }
-            _ => {} // nothing to do for symlink, device, etc.
+            _ => {
+                // symlink, device, etc. are created after all file contents are restored
+                if exists {
+                    trace!("existing special file {path:?}");
+                } else {
+                    debug!("to restore: {path:?}");
+                    specials.push((path.clone(), node.clone()));
+                }
+            }
         }
         Ok(())
     };
@@ -221,7 +496,7 @@ fn allocate_and_collect(
         .filter_map(Result::ok); // TODO: print out the ignored error
     let mut next_dst = dst_iter.next();
 
-    let mut node_streamer = NodeStreamer::new(index.clone(), tree)?;
+    let mut node_streamer = restore_nodes(index.clone(), tree, opts)?;
     let mut next_node = node_streamer.next().transpose()?;
 
     loop {
@@ -239,9 +514,46 @@ fn allocate_and_collect(
                 }
                 Ordering::Equal => {
                     // process existing node
-                    // TODO: This fails or behaves wrong if the type of the existing node
-                    // does not match the type of the node in the snapshot!
-                    process_node(path, node, true)?;
+                    // Existing symlinks are never followed, so they are replaced by dirs or files.
+                    let replace = dst.path_is_symlink()
+                        && matches!(node.node_type(), NodeType::Dir | NodeType::File);
+                    if replace {
+                        debug!("to replace existing symlink: {path:?}");
+                        if !opts.dry_run {
+                            dest.remove_file(dst.path())?;
+                        }
+                    } else if !is_same_type(dst, node) {
+                        if opts.delete {
+                            // mirror the snapshot: replace the existing entry, including its contents
+                            let dst_path = dst.path().to_path_buf();
+                            let is_dir = matches!(dst.file_type(), Some(t) if t.is_dir());
+                            match (opts.dry_run, is_dir) {
+                                (true, _) => println!(
+                                    "would have replaced the existing {}: {dst_path:?}",
+                                    if is_dir { "dir" } else { "file" }
+                                ),
+                                (false, true) => dest.remove_dir_all(&dst_path)?,
+                                (false, false) => dest.remove_file(&dst_path)?,
+                            }
+                            // skip the contents of a replaced dir
+                            next_dst = dst_iter.next();
+                            while matches!(&next_dst, Some(dst) if dst.path().starts_with(&dst_path))
+                            {
+                                next_dst = dst_iter.next();
+                            }
+                            process_node(path, node, false)?;
+                            next_node = node_streamer.next().transpose()?;
+                            continue;
+                        }
+                        if matches!(node.node_type(), NodeType::Dir | NodeType::File) {
+                            bail!(
+                                "existing {path:?} is not a {}, use --delete to replace it.",
+                                node.node_type().name()
+                            );
+                        }
+                        warn!("existing {path:?} differs from the snapshot, use --delete to replace it.");
+                    }
+                    process_node(path, node, !replace)?;
                     next_dst = dst_iter.next();
                     next_node = node_streamer.next().transpose()?;
                 }
@@ -266,57 +578,150 @@ fn allocate_and_collect(
         dest.remove_dir(path)?;
     }
 
-    Ok(file_infos)
+    Ok((file_infos, specials))
+}
+
+/// Check if the existing entry in the destination has the type of the node.
+/// For symlinks, the link target must also be identical.
+fn is_same_type(dst: &DirEntry, node: &Node) -> bool {
+    let file_type = match dst.file_type() {
+        Some(file_type) => file_type,
+        None => return false,
+    };
+    match node.node_type() {
+        NodeType::File => file_type.is_file(),
+        NodeType::Dir => file_type.is_dir(),
+        NodeType::Symlink { linktarget } => {
+            file_type.is_symlink()
+                && matches!(fs::read_link(dst.path()), Ok(target) if target == Path::new(linktarget))
+        }
+        NodeType::Dev { .. } => file_type.is_block_device(),
+        NodeType::Chardev { .. } => file_type.is_char_device(),
+        NodeType::Fifo => file_type.is_fifo(),
+        NodeType::Socket => file_type.is_socket(),
+    }
 }
 
 /// restore_contents restores all files contents as described by file_infos
 /// using the ReadBackend be and writing them into the LocalBackend dest.
+///
+/// All needed blobs are planned per pack first; the blobs which must be read from the backend
+/// are then fetched with as few ranged reads as possible, which are run in parallel.
+/// Each blob is written to all file positions where it is needed.
 fn restore_contents(
     be: &impl DecryptReadBackend,
     dest: &LocalBackend,
     file_infos: FileInfos,
+    coalesce_threshold: f64,
+    blob_cache: Option<&BlobCache>,
 ) -> Result<()> {
     let (filenames, restore_info, total_size, matched_size) = file_infos.dissolve();
+    let is_cached = |bl: &BlobLocation| matches!(blob_cache, Some(c) if c.contains(&bl.id));
 
     let p = progress_bytes("restoring file contents...");
     p.set_length(total_size - matched_size);
+    status::report_progress(&p, "restoring".to_string());
+    let errors = AtomicU64::new(0);
+
+    let name_dests = |fls: &[FileLocation]| -> Vec<_> {
+        fls.iter()
+            .filter(|fl| !fl.matches)
+            .map(|fl| (filenames[fl.file_idx].clone(), fl.file_start))
+            .collect()
+    };
 
     const MAX_READER: usize = 20;
     let pool = ThreadPoolBuilder::new().num_threads(MAX_READER).build()?;
     pool.in_place_scope(|s| {
         for (pack, blob) in restore_info {
+            // blobs which must be read from the backend
+            let (mut remote, blob): (Vec<_>, Vec<_>) = blob
+                .into_iter()
+                .partition(|(bl, fls)| fls.iter().all(|fl| !fl.matches) && !is_cached(bl));
+            remote.sort_unstable_by_key(|(bl, _)| bl.range.offset());
+            let ranges = coalesce_ranges(remote.iter().map(|(bl, _)| bl), coalesce_threshold);
+            let mut remote = remote.into_iter().map(|(bl, fls)| (bl, name_dests(&fls)));
+
+            for (offset, length, count) in ranges {
+                let remote: Vec<_> = remote.by_ref().take(count).collect();
+                let p = &p;
+                let errors = &errors;
+
+                s.spawn(move |s1| {
+                    // read all blobs of this range with a single request
+                    let data = match be.read_partial(FileType::Pack, &pack, false, offset, length) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("pack {pack}: error reading data: {err}");
+                            errors.fetch_add(count as u64, Relaxed);
+                            return;
+                        }
+                    };
+                    for (bl, name_dests) in remote {
+                        let start = (bl.range.offset() - offset) as usize;
+                        let end = start + bl.range.length() as usize;
+                        let encrypted = &data[start..end];
+                        let data = match be.decrypt_blob(encrypted, bl.uncompressed_length) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                error!("pack {pack}, blob {}: error decrypting data: {err}", bl.id);
+                                errors.fetch_add(1, Relaxed);
+                                continue;
+                            }
+                        };
+                        if let Some(blob_cache) = blob_cache {
+                            let _ = blob_cache.put(&bl.id, encrypted);
+                        }
+                        let size = bl.data_length();
+
+                        // save into needed files in parallel
+                        for (name, start) in name_dests {
+                            let data = data.clone();
+                            s1.spawn(move |_| {
+                                if let Err(err) = dest.write_at(&name, start, &data) {
+                                    error!("error writing {name:?}: {err}");
+                                    errors.fetch_add(1, Relaxed);
+                                }
+                                p.inc(size);
+                            });
+                        }
+                    }
+                });
+            }
+
             for (bl, fls) in blob {
                 let from_file = fls
                     .iter()
                     .find(|fl| fl.matches)
                     .map(|fl| (filenames[fl.file_idx].clone(), fl.file_start));
 
-                let name_dests: Vec<_> = fls
-                    .iter()
-                    .filter(|fl| !fl.matches)
-                    .map(|fl| (filenames[fl.file_idx].clone(), fl.file_start))
-                    .collect();
+                let name_dests = name_dests(&fls);
                 let p = &p;
+                let errors = &errors;
 
                 if !name_dests.is_empty() {
-                    // TODO: error handling!
                     s.spawn(move |s1| {
                         let data = match from_file {
                             Some((filename, start)) => {
                                 // read from existing file
-                                dest.read_at(filename, start, bl.data_length()).unwrap()
+                                dest.read_at(filename, start, bl.data_length())
                             }
-                            None => {
-                                // read pack at blob_offset with length blob_length
-                                be.read_encrypted_partial(
-                                    FileType::Pack,
-                                    &pack,
-                                    false,
-                                    bl.offset,
-                                    bl.length,
-                                    bl.uncompressed_length,
-                                )
-                                .unwrap()
+                            None => match blob_cache
+                                .and_then(|c| c.get(be, &bl.id, bl.uncompressed_length))
+                            {
+                                Some(data) => Ok(data),
+                                None => {
+                                    // read pack at blob_offset with length blob_length
+                                    read_blob(be, &pack, &bl, blob_cache)
+                                }
+                            },
+                        };
+                        let data = match data {
+                            Ok(data) => data,
+                            Err(err) => {
+                                error!("pack {pack}, blob {}: error reading data: {err}", bl.id);
+                                errors.fetch_add(1, Relaxed);
+                                return;
                             }
                         };
                         let size = bl.data_length();
@@ -325,7 +730,10 @@ fn restore_contents(
                         for (name, start) in name_dests {
                             let data = data.clone();
                             s1.spawn(move |_| {
-                                dest.write_at(&name, start, &data).unwrap();
+                                if let Err(err) = dest.write_at(&name, start, &data) {
+                                    error!("error writing {name:?}: {err}");
+                                    errors.fetch_add(1, Relaxed);
+                                }
                                 p.inc(size);
                             });
                         }
@@ -337,9 +745,170 @@ fn restore_contents(
 
     p.finish();
 
-    Ok(())
+    match errors.into_inner() {
+        0 => Ok(()),
+        n => bail!("{n} errors occurred while restoring file contents!"),
+    }
 }
 
+/// Read and decrypt a blob from the pack; the encrypted blob is saved in the blob cache
+fn read_blob(
+    be: &impl DecryptReadBackend,
+    pack: &Id,
+    bl: &BlobLocation,
+    blob_cache: Option<&BlobCache>,
+) -> BackendResult<Bytes> {
+    let encrypted = be.read_partial(
+        FileType::Pack,
+        pack,
+        false,
+        bl.range.offset(),
+        bl.range.length(),
+    )?;
+    let data = be.decrypt_blob(&encrypted, bl.uncompressed_length)?;
+    if let Some(blob_cache) = blob_cache {
+        let _ = blob_cache.put(&bl.id, &encrypted);
+    }
+    Ok(data)
+}
+
+/// Maximum size of a coalesced read from a pack
+const MAX_COALESCE_SIZE: u32 = 128 * 1024 * 1024;
+
+/// Plan the ranged reads needed to get the given blobs of a pack, which must be sorted by offset.
+/// Neighboring blobs are read using a single request as long as the needed data makes up at
+/// least the fraction `threshold` of the range to read.
+/// Returns the ranges as (offset, length, number of blobs).
+fn coalesce_ranges<'a>(
+    blobs: impl IntoIterator<Item = &'a BlobLocation>,
+    threshold: f64,
+) -> Vec<(u64, u32, usize)> {
+    let mut ranges: Vec<(u64, u32, usize)> = Vec::new();
+    let mut needed = 0;
+    for bl in blobs {
+        let length = bl.range.length();
+        if let Some((start, range_length, count)) = ranges.last_mut() {
+            let end = bl.range.end().max(*start + u64::from(*range_length));
+            match u32::try_from(end - *start) {
+                Ok(new_length)
+                    if new_length <= MAX_COALESCE_SIZE
+                        && (needed + u64::from(length)) as f64
+                            >= threshold * f64::from(new_length) =>
+                {
+                    *range_length = new_length;
+                    *count += 1;
+                    needed += u64::from(length);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        ranges.push((bl.range.offset(), length, 1));
+        needed = u64::from(length);
+    }
+    ranges
+}
+
+/// print the estimated download size, number of requests and cost of the restore
+fn print_estimate(file_infos: &FileInfos, opts: &Opts) {
+    let (requests, size) = file_infos.remote_reads(opts.coalesce_threshold);
+    let packs = file_infos.to_packs().len();
+
+    println!("Data to download: {} from {} packs", bytes(size), packs);
+    println!("Requests:         {}", requests);
+
+    let cost_size = opts
+        .cost_per_gb
+        .map(|price| price * size as f64 / 1_000_000_000.0);
+    let cost_requests = opts.cost_per_request.map(|price| price * requests as f64);
+    match (cost_size, cost_requests) {
+        (None, None) => {}
+        (cost_size, cost_requests) => {
+            let cost_size = cost_size.unwrap_or_default();
+            let cost_requests = cost_requests.unwrap_or_default();
+            println!(
+                "Estimated cost:   {:.2} (data: {:.2}, requests: {:.2})",
+                cost_size + cost_requests,
+                cost_size,
+                cost_requests
+            );
+        }
+    }
+}
+
+/// verify_contents reads all blobs needed to restore the files described by file_infos
+/// and checks their hash without writing anything.
+fn verify_contents(be: &impl DecryptReadBackend, file_infos: &FileInfos) -> Result<()> {
+    let size = file_infos
+        .r
+        .values()
+        .flat_map(|blobs| blobs.keys())
+        .map(BlobLocation::data_length)
+        .sum();
+
+    let p = progress_bytes("reading and verifying data...");
+    p.set_length(size);
+    let start = Instant::now();
+    let errors = AtomicU64::new(0);
+
+    const MAX_READER: usize = 20;
+    let pool = ThreadPoolBuilder::new().num_threads(MAX_READER).build()?;
+    pool.in_place_scope(|s| {
+        for (pack, blobs) in &file_infos.r {
+            for bl in blobs.keys() {
+                let p = &p;
+                let errors = &errors;
+                s.spawn(move |_| {
+                    match be.read_encrypted_partial(
+                        FileType::Pack,
+                        pack,
+                        false,
+                        bl.range.offset(),
+                        bl.range.length(),
+                        bl.uncompressed_length,
+                    ) {
+                        Ok(data) if hash(&data) == bl.id => {}
+                        Ok(_) => {
+                            error!("pack {pack}, blob {}: hash mismatch", bl.id);
+                            errors.fetch_add(1, Relaxed);
+                        }
+                        Err(err) => {
+                            error!("pack {pack}, blob {}: error reading data: {err}", bl.id);
+                            errors.fetch_add(1, Relaxed);
+                        }
+                    }
+                    p.inc(bl.data_length());
+                });
+            }
+        }
+    });
+    p.finish();
+
+    let duration = start.elapsed();
+    info!(
+        "read and verified {} in {:.1?} ({}/s)",
+        bytes(size),
+        duration,
+        bytes((size as f64 / duration.as_secs_f64().max(0.001)) as u64)
+    );
+
+    match errors.into_inner() {
+        0 => Ok(()),
+        n => bail!("{n} blobs could not be read or verified!"),
+    }
+}
+
+/// create symlinks, devices, fifos and sockets
+fn restore_special(dest: &LocalBackend, specials: &[(PathBuf, Node)]) {
+    for (path, node) in specials {
+        debug!("creating special file {:?}", path);
+        dest.create_special(path, node)
+            .unwrap_or_else(|_| warn!("restore {:?}: creating special file failed.", path));
+    }
+}
+
+/// set metadata for all restored entries. Directories are processed after their content
+/// (i.e. bottom-up) such that their permissions and times are not changed afterwards.
 fn restore_metadata(
     dest: &LocalBackend,
     index: impl IndexedBackend + Unpin,
@@ -347,7 +916,7 @@ fn restore_metadata(
     opts: &Opts,
 ) -> Result<()> {
     // walk over tree in repository and compare with tree in dest
-    let mut node_streamer = NodeStreamer::new(index, tree)?;
+    let mut node_streamer = restore_nodes(index, tree, opts)?;
     let mut dir_stack = Vec::new();
     while let Some((path, node)) = node_streamer.next().transpose()? {
         match node.node_type() {
@@ -356,7 +925,7 @@ fn restore_metadata(
                 while let Some((stackpath, _)) = dir_stack.last() {
                     if !path.starts_with(stackpath) {
                         let (path, node) = dir_stack.pop().unwrap();
-                        set_metadata(dest, &path, &node, opts);
+                        set_metadata(dest, &path, &node, opts, !opts.no_fix_dir_times);
                     } else {
                         break;
                     }
@@ -364,33 +933,45 @@ fn restore_metadata(
                 // push current path to the stack
                 dir_stack.push((path, node));
             }
-            _ => set_metadata(dest, &path, &node, opts),
+            _ => set_metadata(dest, &path, &node, opts, true),
         }
     }
 
     // empty dir stack and set metadata
     for (path, node) in dir_stack.into_iter().rev() {
-        set_metadata(dest, &path, &node, opts);
+        set_metadata(dest, &path, &node, opts, !opts.no_fix_dir_times);
     }
 
     Ok(())
 }
 
-fn set_metadata(dest: &LocalBackend, path: &PathBuf, node: &Node, opts: &Opts) {
+fn set_metadata(dest: &LocalBackend, path: &PathBuf, node: &Node, opts: &Opts, times: bool) {
     debug!("setting metadata for {:?}", path);
-    dest.create_special(path, node)
-        .unwrap_or_else(|_| warn!("restore {:?}: creating special file failed.", path));
+    let no_map = IdMap::default();
+    let owner_map = opts.owner_map.as_ref().unwrap_or(&no_map);
+    let group_map = opts.group_map.as_ref().unwrap_or(&no_map);
     if opts.numeric_id {
-        dest.set_uid_gid(path, node.meta())
+        dest.set_uid_gid(path, node.meta(), owner_map, group_map)
             .unwrap_or_else(|_| warn!("restore {:?}: setting UID/GID failed.", path));
     } else {
-        dest.set_user_group(path, node.meta())
+        dest.set_user_group(path, node.meta(), owner_map, group_map)
             .unwrap_or_else(|_| warn!("restore {:?}: setting User/Group failed.", path));
     }
+    // Note: extended attributes must be set after chown as changing the owner removes file capabilities
+    // and before chmod as setting them needs write permission
+    dest.set_extended_attributes(path, node.meta())
+        .unwrap_or_else(|_| warn!("restore {:?}: setting extended attributes failed.", path));
     dest.set_permission(path, node.meta())
         .unwrap_or_else(|_| warn!("restore {:?}: chmod failed.", path));
-    dest.set_times(path, node.meta())
-        .unwrap_or_else(|_| warn!("restore {:?}: setting file times failed.", path));
+    if times {
+        dest.set_times(path, node.meta())
+            .unwrap_or_else(|_| warn!("restore {:?}: setting file times failed.", path));
+    }
+    // Note: flags must be set at last as e.g. the immutable flag prevents further changes
+    if opts.restore_flags {
+        dest.set_flags(path, node.meta())
+            .unwrap_or_else(|_| warn!("restore {:?}: setting file attributes failed.", path));
+    }
 }
 
 /// struct that contains information of file contents grouped by
@@ -410,15 +991,32 @@ type Filenames = Vec<PathBuf>;
 
 #[derive(Debug, Hash, PartialEq, Eq)]
 struct BlobLocation {
-    offset: u32,
-    length: u32,
+    id: Id,
+    range: FileRange,
     uncompressed_length: Option<NonZeroU32>,
 }
 
 impl BlobLocation {
+    /// Get the location of the blob from its index entry; corrupted entries are rejected
+    fn new(id: Id, ie: &IndexEntry) -> Result<Self> {
+        let range = FileRange::new(*ie.offset(), *ie.length())
+            .map_err(|err| anyhow!("index entry of blob {id}: {err}"))?;
+        if ie.uncompressed_length().is_none() && range.length() < 32 {
+            bail!(
+                "index entry of blob {id}: length {} is smaller than the crypto overhead",
+                range.length()
+            );
+        }
+        Ok(Self {
+            id,
+            range,
+            uncompressed_length: *ie.uncompressed_length(),
+        })
+    }
+
     fn data_length(&self) -> u64 {
         match self.uncompressed_length {
-            None => self.length - 32, // crypto overhead
+            None => self.range.length() - 32, // crypto overhead
             Some(length) => length.get(),
         }
         .into()
@@ -443,7 +1041,10 @@ impl FileInfos {
     }
 
     /// Add the file to FilesInfos using index to get blob information.
-    /// Returns the computed length of the file
+    /// Blobs which are already contained at the right position in an existing file in the
+    /// destination are marked as matching and need not be restored.
+    /// Returns the computed length of the file if it must be created or resized and whether
+    /// there are blobs which are not matching.
     fn add_file(
         &mut self,
         dest: &LocalBackend,
@@ -451,29 +1052,24 @@ impl FileInfos {
         name: PathBuf,
         index: &impl IndexedBackend,
     ) -> Result<(Option<u64>, bool)> {
-        let mut open_file = dest.get_matching_file(&name, *file.meta().size());
+        let open_file = dest.get_matching_file(&name);
         let mut file_pos = 0;
         let mut has_unmatched = false;
         if !file.content().is_empty() {
             let file_idx = self.names.len();
             self.names.push(name);
-            for id in file.content().iter() {
-                let ie = index
-                    .get_data(id)
-                    .ok_or_else(|| anyhow!("did not find id {} in index", id))?;
-                let bl = BlobLocation {
-                    offset: *ie.offset(),
-                    length: *ie.length(),
-                    uncompressed_length: *ie.uncompressed_length(),
-                };
+            let entries = index.get_data_ids(file.content());
+            for (id, ie) in file.content().iter().zip(entries) {
+                let ie = ie.ok_or_else(|| anyhow!("did not find id {} in index", id))?;
+                let bl = BlobLocation::new(*id, &ie)?;
 
-                let matches = match &mut open_file {
-                    Some(file) => {
+                let matches = match &open_file {
+                    Some((file, size)) if file_pos + bl.data_length() <= *size => {
                         // Existing file content; check if SHA256 matches
-                        let mut vec = vec![0; ie.data_length() as usize];
-                        file.read_exact(&mut vec).is_ok() && id == &hash(&vec)
+                        let mut vec = vec![0; bl.data_length() as usize];
+                        file.read_exact_at(&mut vec, file_pos).is_ok() && id == &hash(&vec)
                     }
-                    None => false,
+                    _ => false,
                 };
                 let length = bl.data_length();
                 self.total_size += length;
@@ -491,12 +1087,37 @@ impl FileInfos {
                     matches,
                 });
 
-                file_pos += ie.data_length() as u64;
+                file_pos += length;
             }
         }
 
         // Tell to allocate the size only if the file does NOT exist with matching size
-        Ok((open_file.is_none().then_some(file_pos), has_unmatched))
+        let size_matches = matches!(open_file, Some((_, size)) if size == file_pos);
+        Ok(((!size_matches).then_some(file_pos), has_unmatched))
+    }
+
+    /// Returns the number of reads from the backend needed to restore and the number of bytes read.
+    /// Blobs which are contained in an existing file in the destination are not read from the backend.
+    fn remote_reads(&self, coalesce_threshold: f64) -> (u64, u64) {
+        self.r
+            .values()
+            .map(|blobs| {
+                let mut remote: Vec<_> = blobs
+                    .iter()
+                    .filter(|(_, fls)| fls.iter().all(|fl| !fl.matches))
+                    .map(|(bl, _)| bl)
+                    .collect();
+                remote.sort_unstable_by_key(|bl| bl.range.offset());
+                let ranges = coalesce_ranges(remote, coalesce_threshold);
+                (
+                    ranges.len() as u64,
+                    ranges
+                        .iter()
+                        .map(|(_, length, _)| u64::from(*length))
+                        .sum::<u64>(),
+                )
+            })
+            .fold((0, 0), |(count, size), (c, s)| (count + c, size + s))
     }
 
     fn to_packs(&self) -> Vec<Id> {
@@ -508,3 +1129,27 @@ impl FileInfos {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_blobs() {
+        let blobs: Vec<_> = [(0, 100), (100, 100), (250, 50), (10_000, 100)]
+            .into_iter()
+            .map(|(offset, length)| BlobLocation {
+                id: Id::random(),
+                range: FileRange::new(offset, length).unwrap(),
+                uncompressed_length: None,
+            })
+            .collect();
+
+        assert_eq!(
+            coalesce_ranges(&blobs, 0.8),
+            vec![(0, 300, 3), (10_000, 100, 1)]
+        );
+        assert_eq!(coalesce_ranges(&blobs, 0.0), vec![(0, 10_100, 4)]);
+        assert_eq!(coalesce_ranges(&blobs, 1.1).len(), 4);
+    }
+}
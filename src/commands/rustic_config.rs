@@ -1,12 +1,56 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use directories::ProjectDirs;
 use merge::Merge;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use toml::Value;
 
-pub struct RusticConfig(Value);
+use crate::crypto::hash;
+
+/// A file included by the `include` key of a config file: either a location (path or https URL)
+/// or a table `{ location = "...", sha256 = "..." }` giving the expected SHA256 checksum.
+/// The checksum is required for URLs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Include {
+    Location(String),
+    Verified { location: String, sha256: String },
+}
+
+/// Result of checking a key of a config section, see [`check_key`]
+pub enum KeyCheck {
+    Ok,
+    Unknown,
+    Invalid(String),
+}
+
+/// Check a key of a config section given as option struct, see [`RusticConfig::check`]
+pub type SectionCheck = fn(key: &str, value: &Value) -> KeyCheck;
+
+/// Check whether `key` is an option of `T` and whether `value` is valid for it.
+/// The allowed keys are given by the serde names of the fields of `T`.
+pub fn check_key<T: DeserializeOwned>(key: &str, value: &Value) -> KeyCheck {
+    let table = |value| Value::Table([(key.to_string(), value)].into_iter().collect());
+    // no option accepts this value, so it is only accepted if serde ignores the key
+    let invalid = Value::Array(vec![Value::Array(Vec::new())]);
+    if table(invalid).try_into::<T>().is_ok() {
+        return KeyCheck::Unknown;
+    }
+    match table(value.clone()).try_into::<T>() {
+        Ok(_) => KeyCheck::Ok,
+        Err(err) => KeyCheck::Invalid(err.to_string()),
+    }
+}
+
+pub struct RusticConfig {
+    config: Value,
+    // the original content of the config file, used to find line numbers
+    data: String,
+}
 
 impl RusticConfig {
     pub fn new(profile: &str) -> Result<Self> {
@@ -19,24 +63,152 @@ impl RusticConfig {
         };
         let path = path.join(profile.to_string() + ".toml");
 
-        let config = if path.exists() {
+        let data = if path.exists() {
             // TODO: This should be log::info! - however, the logging config
             // can be stored in the config file and is needed to initialize the logger
             eprintln!("using config {}", path.display());
-            let data = std::fs::read_to_string(path)?;
-            toml::from_str(&data)?
+            fs::read_to_string(&path)?
         } else {
-            toml::from_str("")?
+            String::new()
+        };
+        let mut config =
+            Self::from_data(data).map_err(|err| anyhow!("error in {}: {err}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        config
+            .resolve_includes(base)
+            .map_err(|err| anyhow!("error in {}: {err}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Merge the files given by the top-level `include` key into the config. Included files are
+    /// merged in the given order; values of later files and of the config file itself take
+    /// precedence. Relative paths are relative to `base`.
+    fn resolve_includes(&mut self, base: &Path) -> Result<()> {
+        let includes: Vec<Include> = match &mut self.config {
+            Value::Table(table) => match table.remove("include") {
+                Some(value) => value.try_into()?,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        let mut merged = Value::Table(toml::value::Table::new());
+        for include in includes {
+            let (location, sha256) = match include {
+                Include::Location(location) => (location, None),
+                Include::Verified { location, sha256 } => (location, Some(sha256)),
+            };
+            let data = read_include(&location, sha256.as_deref(), base)
+                .map_err(|err| anyhow!("include \"{location}\": {err}"))?;
+            let value: Value =
+                toml::from_str(&data).map_err(|err| anyhow!("include \"{location}\": {err}"))?;
+            if value.get("include").is_some() {
+                bail!("include \"{location}\": nested includes are not supported");
+            }
+            merge_values(&mut merged, value);
+        }
+        merge_values(
+            &mut merged,
+            std::mem::replace(&mut self.config, Value::Boolean(false)),
+        );
+        self.config = merged;
+        Ok(())
+    }
+
+    /// Parse the given content of a config file
+    pub fn from_data(data: String) -> Result<Self, toml::de::Error> {
+        let config = toml::from_str(&data)?;
+        Ok(RusticConfig { config, data })
+    }
+
+    /// Check the config file for unknown sections and keys and for invalid values.
+    /// `sections` contains all known sections together with the check of their keys.
+    /// Returns a description of each error; the line is given if it can be found in the
+    /// config file.
+    pub fn check(&self, sections: &[(&str, SectionCheck)]) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Value::Table(table) = &self.config {
+            for (section, value) in table {
+                self.check_section(section, value, sections, &mut errors);
+            }
+        }
+        errors
+    }
+
+    fn check_section(
+        &self,
+        section: &str,
+        value: &Value,
+        sections: &[(&str, SectionCheck)],
+        errors: &mut Vec<String>,
+    ) {
+        let check = match sections.iter().find(|(name, _)| *name == section) {
+            Some((_, check)) => check,
+            None => {
+                errors.push(format!("unknown section [{section}]"));
+                return;
+            }
         };
+        let table = match value {
+            Value::Table(table) => table,
+            _ => {
+                errors.push(format!("[{section}] must be a section"));
+                return;
+            }
+        };
+        for (key, value) in table {
+            let subsection = format!("{section}.{key}");
+            if sections.iter().any(|(name, _)| *name == subsection) {
+                match value {
+                    Value::Array(values) => {
+                        for value in values {
+                            self.check_section(&subsection, value, sections, errors);
+                        }
+                    }
+                    value => self.check_section(&subsection, value, sections, errors),
+                }
+                continue;
+            }
+            let line = match self.line_of(key) {
+                Some(line) => format!(" (line {line})"),
+                None => String::new(),
+            };
+            match check(key, value) {
+                KeyCheck::Ok => {}
+                KeyCheck::Unknown => {
+                    errors.push(format!("unknown key \"{key}\" in [{section}]{line}"));
+                }
+                KeyCheck::Invalid(err) => {
+                    errors.push(format!(
+                        "invalid value of \"{key}\" in [{section}]{line}: {err}"
+                    ));
+                }
+            }
+        }
+    }
 
-        Ok(RusticConfig(config))
+    // find the first line where the given key is set
+    fn line_of(&self, key: &str) -> Option<usize> {
+        self.data
+            .lines()
+            .position(|line| {
+                let line = line.trim_start().trim_start_matches(['"', '\'']);
+                match line.strip_prefix(key) {
+                    Some(rest) => rest
+                        .trim_start_matches(['"', '\''])
+                        .trim_start()
+                        .starts_with('='),
+                    None => false,
+                }
+            })
+            .map(|idx| idx + 1)
     }
 
     fn get_value(&self, section: &str) -> Option<&Value> {
         // loop over subsections separated by '.'
-        section
-            .split('.')
-            .fold(Some(&self.0), |acc, x| acc.and_then(|value| value.get(x)))
+        section.split('.').fold(Some(&self.config), |acc, x| {
+            acc.and_then(|value| value.get(x))
+        })
     }
 
     pub fn merge_into<'de, Opts>(&self, section: &str, opts: &mut Opts) -> Result<()>
@@ -60,3 +232,120 @@ impl RusticConfig {
         }
     }
 }
+
+/// Deep-merge `value` into `base`; tables are merged key by key, other values of `value` replace
+/// those of `base`
+fn merge_values(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Table(base), Value::Table(table)) => {
+            for (key, value) in table {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_values(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// Read an included file from a local path or from a https URL.
+///
+/// As included files can set commands which are run by rustic, e.g. password-command or hooks,
+/// remote files must be given with their sha256 checksum. Downloaded files are cached and the cached
+/// version is used if it matches the checksum.
+fn read_include(location: &str, sha256: Option<&str>, base: &Path) -> Result<String> {
+    let verify = |data: &str, sha256: &str| {
+        if !hash(data.as_bytes()).to_hex().eq_ignore_ascii_case(sha256) {
+            bail!("checksum mismatch, expected sha256 {sha256}");
+        }
+        Ok(())
+    };
+
+    if location.starts_with("http://") {
+        bail!("remote includes must use https");
+    }
+    if !location.starts_with("https://") {
+        let data = fs::read_to_string(base.join(location))?;
+        if let Some(sha256) = sha256 {
+            verify(&data, sha256)?;
+        }
+        return Ok(data);
+    }
+
+    let sha256 =
+        sha256.ok_or_else(|| anyhow!("remote includes need a sha256 checksum to be verified"))?;
+    let cache_file = include_cache_dir().map(|dir| dir.join(hash(location.as_bytes()).to_hex()));
+    if let Some(data) = cache_file
+        .as_ref()
+        .and_then(|file| fs::read_to_string(file).ok())
+    {
+        if verify(&data, sha256).is_ok() {
+            return Ok(data);
+        }
+    }
+
+    let data = download(location)?;
+    verify(&data, sha256)?;
+    if let Some(file) = cache_file {
+        if let Err(err) =
+            fs::create_dir_all(file.parent().unwrap()).and_then(|_| fs::write(&file, &data))
+        {
+            eprintln!("could not cache {location}: {err}");
+        }
+    }
+    Ok(data)
+}
+
+fn include_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("rustic").join("config-includes"))
+}
+
+fn download(url: &str) -> Result<String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    Ok(client.get(url).send()?.error_for_status()?.text()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_are_merged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let include = "[backup]\nglob = [\"!/tmp\"]\ngit-ignore = true\n";
+        fs::write(dir.join("excludes.toml"), include).unwrap();
+        let sha256 = hash(include.as_bytes()).to_hex();
+
+        let data = format!(
+            "include = [{{ location = \"excludes.toml\", sha256 = \"{sha256}\" }}]\n[backup]\ngit-ignore = false\n"
+        );
+        let mut config = RusticConfig::from_data(data).unwrap();
+        config.resolve_includes(dir).unwrap();
+        assert_eq!(
+            config.get_value("backup.git-ignore"),
+            Some(&Value::Boolean(false))
+        );
+        assert!(config.get_value("backup.glob").is_some());
+        assert!(config.get_value("include").is_none());
+
+        let data = "include = [{ location = \"excludes.toml\", sha256 = \"00\" }]".to_string();
+        let mut config = RusticConfig::from_data(data).unwrap();
+        assert!(config.resolve_includes(dir).is_err());
+
+        // remote includes need https and a checksum; these fail before downloading anything
+        for include in [
+            "\"https://example.com/rustic.toml\"",
+            "{ location = \"http://example.com/rustic.toml\", sha256 = \"00\" }",
+        ] {
+            let mut config = RusticConfig::from_data(format!("include = [{include}]")).unwrap();
+            let err = config.resolve_includes(dir).unwrap_err().to_string();
+            assert!(err.contains("sha256") || err.contains("https"), "{err}");
+        }
+    }
+}
@@ -38,6 +38,14 @@ pub(super) struct Opts {
     #[clap(long, conflicts_with_all = &["long", "json"])]
     all: bool,
 
+    /// Only show the given number of snapshots per group, starting with the latest one
+    #[clap(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Skip the given number of latest snapshots per group
+    #[clap(long, value_name = "N", default_value = "0")]
+    offset: usize,
+
     /// Snapshots to show
     #[clap(value_name = "ID")]
     ids: Vec<String>,
@@ -70,17 +78,30 @@ pub(super) fn execute(
         )],
     };
 
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, snaps)| {
+            let count = snaps.len();
+            (group, opts.paginate(snaps), count)
+        })
+        // don't show groups where all snapshots are skipped by --offset
+        .filter(|(_, snaps, _)| !snaps.is_empty())
+        .collect();
+
     if opts.json {
+        let groups: Vec<_> = groups
+            .into_iter()
+            .map(|(group, snaps, _)| (group, snaps))
+            .collect();
         let mut stdout = std::io::stdout();
         serde_json::to_writer_pretty(&mut stdout, &groups)?;
         return Ok(());
     }
 
-    for (group, mut snapshots) in groups {
+    for (group, snapshots, total) in groups {
         if !group.is_empty() {
             println!("\nsnapshots for {group}");
         }
-        snapshots.sort_unstable();
         let count = snapshots.len();
 
         if opts.long {
@@ -120,12 +141,29 @@ pub(super) fn execute(
             table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
             table.printstd();
         }
-        println!("{} snapshot(s)", count);
+        if count == total {
+            println!("{count} snapshot(s)");
+        } else {
+            println!("{count} of {total} snapshot(s)");
+        }
     }
 
     Ok(())
 }
 
+impl Opts {
+    /// Sort the snapshots by time and select the ones given by --offset and --limit,
+    /// counted from the latest snapshot
+    fn paginate(&self, mut snaps: Vec<SnapshotFile>) -> Vec<SnapshotFile> {
+        snaps.sort_unstable();
+        let end = snaps.len().saturating_sub(self.offset);
+        let start = self.limit.map_or(0, |limit| end.saturating_sub(limit));
+        snaps.truncate(end);
+        snaps.drain(..start);
+        snaps
+    }
+}
+
 fn display_snap(sn: SnapshotFile) {
     let mut table = Table::new();
 
@@ -137,6 +175,9 @@ fn display_snap(sn: SnapshotFile) {
     table.add_row(row![b->"Time", sn.time.format("%Y-%m-%d %H:%M:%S")]);
     table.add_row(row![b->"Host", sn.hostname]);
     table.add_row(row![b->"Tags", sn.tags.formatln()]);
+    if let Some(description) = &sn.description {
+        table.add_row(row![b->"Description", description]);
+    }
     let delete = match sn.delete {
         DeleteOption::NotSet => "not set".to_string(),
         DeleteOption::Never => "never".to_string(),
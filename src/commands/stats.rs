@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use clap::{Parser, ValueEnum};
+use log::*;
+use prettytable::{format, row, Table};
+use serde::Serialize;
+
+use super::{bytes, progress_counter, RusticConfig};
+use crate::backend::DecryptReadBackend;
+use crate::blob::{BlobType, NodeStreamer, NodeType};
+use crate::id::Id;
+use crate::index::{IndexBackend, ReadIndex};
+use crate::repo::{SnapshotFile, SnapshotFilter};
+
+#[derive(Parser)]
+pub(super) struct Opts {
+    #[clap(flatten, help_heading = "SNAPSHOT FILTER OPTIONS")]
+    filter: SnapshotFilter,
+
+    /// Show one data point per snapshot instead of the totals
+    #[clap(long)]
+    timeseries: bool,
+
+    /// Compute the statistics by reading the trees of the snapshots instead of using the snapshot
+    /// summaries: restore-size is the size of all files when restoring each snapshot,
+    /// files-by-contents counts files with identical contents only once, raw-data shows the size
+    /// of all blobs used by the snapshots, and blobs-per-file counts blobs once per unique file
+    #[clap(long, value_enum, value_name = "MODE", conflicts_with = "timeseries")]
+    mode: Option<Mode>,
+
+    /// Show statistics in json format
+    #[clap(long)]
+    json: bool,
+
+    /// Snapshots to show statistics for [default: all snapshots matching the filter]
+    #[clap(value_name = "ID")]
+    ids: Vec<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    RestoreSize,
+    FilesByContents,
+    RawData,
+    BlobsPerFile,
+}
+
+/// Statistics of a single snapshot, taken from the snapshot summary
+#[derive(Serialize)]
+struct Point {
+    time: DateTime<Local>,
+    snapshot: Id,
+    hostname: String,
+    /// bytes added to the repository (after compression)
+    added_bytes: u64,
+    /// sum of added bytes of all snapshots up to this one. Pruned data is not taken into account.
+    total_size_after: u64,
+    /// size of all files within the snapshot
+    snapshot_size: u64,
+    /// duration of the backup in seconds
+    duration: f64,
+}
+
+#[derive(Serialize)]
+struct Totals {
+    snapshots: usize,
+    snapshots_without_summary: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last: Option<DateTime<Local>>,
+    added_bytes: u64,
+    duration: f64,
+}
+
+pub(super) fn execute(
+    be: &impl DecryptReadBackend,
+    mut opts: Opts,
+    config_file: RusticConfig,
+) -> Result<()> {
+    config_file.merge_into("snapshot-filter", &mut opts.filter)?;
+
+    let mut snapshots = match opts.ids.is_empty() {
+        true => SnapshotFile::all_from_backend(be, &opts.filter)?,
+        false => SnapshotFile::from_ids(be, &opts.ids)?,
+    };
+    snapshots.sort_unstable();
+    let count = snapshots.len();
+
+    if let Some(mode) = opts.mode {
+        let stats = mode_stats(be, mode, &snapshots)?;
+        if opts.json {
+            serde_json::to_writer_pretty(std::io::stdout(), &stats)?;
+            println!();
+        } else {
+            stats.print();
+        }
+        return Ok(());
+    }
+
+    let mut total_size = 0;
+    let points: Vec<_> = snapshots
+        .into_iter()
+        .filter_map(|snap| {
+            let summary = snap.summary?;
+            total_size += summary.data_added_packed;
+            Some(Point {
+                time: snap.time,
+                snapshot: snap.id,
+                hostname: snap.hostname,
+                added_bytes: summary.data_added_packed,
+                total_size_after: total_size,
+                snapshot_size: summary.total_bytes_processed,
+                duration: summary.backup_duration,
+            })
+        })
+        .collect();
+
+    if points.len() < count {
+        warn!(
+            "{} snapshots have no summary and are not included.",
+            count - points.len()
+        );
+    }
+
+    match (opts.timeseries, opts.json) {
+        (true, true) => {
+            serde_json::to_writer_pretty(std::io::stdout(), &points)?;
+            println!();
+        }
+        (true, false) => {
+            let mut table: Table = points
+                .iter()
+                .map(|p| {
+                    row![
+                        p.snapshot,
+                        p.time.format("%Y-%m-%d %H:%M:%S"),
+                        p.hostname,
+                        r->bytes(p.added_bytes),
+                        r->bytes(p.total_size_after),
+                        r->bytes(p.snapshot_size),
+                        r->format!("{:.1}s", p.duration)
+                    ]
+                })
+                .collect();
+            table.set_titles(
+                row![b->"ID", b->"Time", b->"Host", br->"Added", br->"Total", br->"Size", br->"Duration"],
+            );
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.printstd();
+        }
+        (false, json) => {
+            let totals = Totals {
+                snapshots: count,
+                snapshots_without_summary: count - points.len(),
+                first: points.first().map(|p| p.time),
+                last: points.last().map(|p| p.time),
+                added_bytes: total_size,
+                duration: points.iter().map(|p| p.duration).sum(),
+            };
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout(), &totals)?;
+                println!();
+            } else {
+                println!("snapshots:   {}", totals.snapshots);
+                if let (Some(first), Some(last)) = (totals.first, totals.last) {
+                    println!("first:       {}", first.format("%Y-%m-%d %H:%M:%S"));
+                    println!("last:        {}", last.format("%Y-%m-%d %H:%M:%S"));
+                }
+                println!("added:       {}", bytes(totals.added_bytes));
+                println!("duration:    {:.1}s", totals.duration);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Statistics computed from the trees of the snapshots
+#[derive(Serialize)]
+struct ModeStats {
+    mode: Mode,
+    snapshots: usize,
+    /// number of files; unique files for all modes except restore-size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<u64>,
+    /// number of blobs; blobs-per-file counts blobs once per unique file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blobs: Option<u64>,
+    /// total size of the files or blobs (before compression)
+    total_size: u64,
+    /// size of the blobs within the pack files (after compression)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packed_size: Option<u64>,
+}
+
+impl ModeStats {
+    fn print(&self) {
+        println!("snapshots:   {}", self.snapshots);
+        if let Some(files) = self.files {
+            println!("files:       {files}");
+        }
+        if let Some(blobs) = self.blobs {
+            println!("blobs:       {blobs}");
+        }
+        println!("total size:  {}", bytes(self.total_size));
+        if let Some(packed_size) = self.packed_size {
+            println!("packed size: {}", bytes(packed_size));
+            if packed_size > 0 {
+                let ratio = self.total_size as f64 / packed_size as f64;
+                println!("compression: {ratio:.2}");
+            }
+        }
+    }
+}
+
+fn mode_stats(
+    be: &impl DecryptReadBackend,
+    mode: Mode,
+    snapshots: &[SnapshotFile],
+) -> Result<ModeStats> {
+    let index = IndexBackend::new(be, progress_counter(""))?;
+    let mut stats = ModeStats {
+        mode,
+        snapshots: snapshots.len(),
+        files: Some(0),
+        blobs: None,
+        total_size: 0,
+        packed_size: None,
+    };
+    let mut files = HashSet::new();
+    let mut blobs = HashSet::new();
+
+    let p = progress_counter("reading snapshot trees...");
+    p.set_length(snapshots.len() as u64);
+    for snap in snapshots {
+        blobs.insert((BlobType::Tree, snap.tree));
+        for item in NodeStreamer::new(index.clone(), snap.tree)? {
+            let (_, node) = item?;
+            match (mode, node.node_type()) {
+                (Mode::RestoreSize, NodeType::File) => {
+                    *stats.files.get_or_insert(0) += 1;
+                    stats.total_size += node.meta().size;
+                }
+                (Mode::FilesByContents | Mode::BlobsPerFile, NodeType::File)
+                    if files.insert(node.content().clone()) =>
+                {
+                    stats.total_size += node.meta().size;
+                }
+                (Mode::RawData, NodeType::File) => {
+                    files.insert(node.content().clone());
+                    let ids = node.content().iter().map(|id| (BlobType::Data, *id));
+                    blobs.extend(ids);
+                }
+                (Mode::RawData, NodeType::Dir) => {
+                    if let Some(id) = node.subtree() {
+                        blobs.insert((BlobType::Tree, *id));
+                    }
+                }
+                _ => {}
+            }
+        }
+        p.inc(1);
+    }
+    p.finish();
+
+    match mode {
+        Mode::RestoreSize => {}
+        Mode::FilesByContents => stats.files = Some(files.len() as u64),
+        Mode::RawData => {
+            stats.files = Some(files.len() as u64);
+            stats.blobs = Some(blobs.len() as u64);
+            stats.total_size = 0;
+            let mut packed_size = 0;
+            for (tpe, id) in &blobs {
+                let ie = index
+                    .get_id(tpe, id)
+                    .ok_or_else(|| anyhow!("did not find id {id} in index"))?;
+                stats.total_size += u64::from(ie.data_length());
+                packed_size += u64::from(*ie.length());
+            }
+            stats.packed_size = Some(packed_size);
+        }
+        Mode::BlobsPerFile => {
+            stats.files = Some(files.len() as u64);
+            stats.blobs = Some(files.iter().map(|content| content.len() as u64).sum());
+            // blobs are counted once per file, the size is the sum of the blob sizes
+            stats.total_size = 0;
+            for content in &files {
+                for id in content {
+                    let ie = index
+                        .get_data(id)
+                        .ok_or_else(|| anyhow!("did not find id {id} in index"))?;
+                    stats.total_size += u64::from(ie.data_length());
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,77 @@
+use std::env;
+use std::io::{stderr, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use log::*;
+use nix::unistd::isatty;
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+// whether the terminal title has been changed (and the original one has been saved)
+static TITLE_SET: AtomicBool = AtomicBool::new(false);
+
+/// Send a status message to systemd (if running as a notify service) and
+/// show it in the terminal title (if stderr is a terminal).
+pub fn set_status(status: &str) {
+    notify_systemd(status);
+
+    if matches!(isatty(2), Ok(true)) {
+        // save the original title on the title stack of the terminal before changing it
+        if !TITLE_SET.swap(true, Ordering::SeqCst) {
+            _ = write!(stderr(), "\x1b[22;0t");
+        }
+        _ = write!(stderr(), "\x1b]0;rustic: {status}\x07");
+    }
+}
+
+/// Restore the terminal title which was saved by [`set_status`].
+pub fn reset_title() {
+    if TITLE_SET.swap(false, Ordering::SeqCst) {
+        _ = write!(stderr(), "\x1b[23;0t");
+    }
+}
+
+fn notify_systemd(status: &str) {
+    if let Some(socket) = env::var_os("NOTIFY_SOCKET") {
+        let socket = socket.to_string_lossy();
+        // abstract socket addresses are not supported
+        if !socket.starts_with('@') {
+            if let Err(err) = UnixDatagram::unbound()
+                .and_then(|sock| sock.send_to(format!("STATUS={status}").as_bytes(), &*socket))
+            {
+                debug!("error notifying systemd: {err}");
+            }
+        }
+    }
+}
+
+fn status_wanted() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some() || matches!(isatty(2), Ok(true))
+}
+
+/// Periodically report the progress of `p` as status until it is finished or dropped.
+/// This also works for hidden progress bars, e.g. if stderr is no terminal when running as service.
+pub fn report_progress(p: &ProgressBar, label: String) {
+    if !status_wanted() {
+        return;
+    }
+    // don't keep the progress bar alive, so that the thread stops if it is dropped unfinished
+    let p = p.downgrade();
+    thread::spawn(move || {
+        while let Some(p) = p.upgrade().filter(|p| !p.is_finished()) {
+            let status = match p.length() {
+                Some(len) if len > 0 => format!("{label} {}%", p.position() * 100 / len),
+                _ => label.clone(),
+            };
+            set_status(&status);
+            drop(p);
+            thread::sleep(STATUS_INTERVAL);
+        }
+        notify_systemd(&format!("{label} done"));
+        reset_title();
+    });
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use chrono::{Duration, Local};
 use clap::{AppSettings, Parser};
@@ -62,6 +64,28 @@ pub(super) struct Opts {
     #[clap(long, value_name = "DURATION", help_heading = "DELETE MARK OPTIONS")]
     set_delete_after: Option<humantime::Duration>,
 
+    /// Set the description of the snapshots
+    #[clap(
+        long,
+        value_name = "DESCRIPTION",
+        conflicts_with_all = &["set-description-from", "remove-description"],
+        help_heading = "DESCRIPTION OPTIONS"
+    )]
+    set_description: Option<String>,
+
+    /// Set the description of the snapshots to the content of the given file
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with = "remove-description",
+        help_heading = "DESCRIPTION OPTIONS"
+    )]
+    set_description_from: Option<PathBuf>,
+
+    /// Remove the description of the snapshots
+    #[clap(long, help_heading = "DESCRIPTION OPTIONS")]
+    remove_description: bool,
+
     /// Snapshots to change tags. If none is given, use filter to filter from all
     /// snapshots.
     #[clap(value_name = "ID")]
@@ -91,9 +115,20 @@ pub(super) fn execute(
         (false, false, None) => None,
     };
 
+    let description = match (
+        opts.remove_description,
+        &opts.set_description,
+        &opts.set_description_from,
+    ) {
+        (true, _, _) => Some(None),
+        (_, Some(description), _) => Some(Some(description.clone())),
+        (_, _, Some(path)) => Some(Some(std::fs::read_to_string(path)?.trim_end().to_string())),
+        (false, None, None) => None,
+    };
+
     let mut snapshots: Vec<_> = snapshots
         .into_iter()
-        .filter_map(|sn| modify_sn(sn, &opts, &delete))
+        .filter_map(|sn| modify_sn(sn, &opts, &delete, &description))
         .collect();
     let old_snap_ids: Vec<_> = snapshots.iter().map(|sn| sn.id).collect();
     // remove old ids from snapshots
@@ -122,6 +157,7 @@ fn modify_sn(
     mut sn: SnapshotFile,
     opts: &Opts,
     delete: &Option<DeleteOption>,
+    description: &Option<Option<String>>,
 ) -> Option<SnapshotFile> {
     let mut changed = false;
 
@@ -133,7 +169,14 @@ fn modify_sn(
 
     if let Some(delete) = delete {
         if &sn.delete != delete {
-            sn.delete = delete.clone();
+            sn.delete = *delete;
+            changed = true;
+        }
+    }
+
+    if let Some(description) = description {
+        if &sn.description != description {
+            sn.description = description.clone();
             changed = true;
         }
     }
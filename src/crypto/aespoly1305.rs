@@ -1,3 +1,5 @@
+use std::fmt;
+
 use aes256ctr_poly1305aes::{
     aead::{self, Aead, AeadInPlace, NewAead},
     Aes256CtrPoly1305Aes,
@@ -10,15 +12,24 @@ use super::CryptoKey;
 type Nonce = aead::Nonce<Aes256CtrPoly1305Aes>;
 type AeadKey = aead::Key<Aes256CtrPoly1305Aes>;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Copy)]
 pub enum KeyError {
     #[error("crypto error")]
     CryptoError,
 }
 
+// the key is not `Copy` to avoid implicit copies of the key material
+#[allow(missing_copy_implementations)]
 #[derive(Clone, Default)]
 pub struct Key(AeadKey);
 
+// don't print the key material
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key")
+    }
+}
+
 impl Key {
     pub fn new() -> Self {
         let mut key = AeadKey::default();
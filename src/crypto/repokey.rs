@@ -0,0 +1,71 @@
+use super::{CryptoKey, Key, KeyError};
+
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 16;
+
+/// The key used to access a repository.
+///
+/// Repositories created with `init --no-encryption` have no key; their data is saved in plain
+/// text. To keep the repository format (and all length computations) the same, the plain text
+/// is framed by a zero nonce and a zero MAC instead of the random nonce and the real MAC.
+#[derive(Clone)]
+pub enum RepoKey {
+    Encrypted(Key),
+    Unencrypted,
+}
+
+impl CryptoKey for RepoKey {
+    type CryptoError = KeyError;
+
+    fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, Self::CryptoError> {
+        match self {
+            Self::Encrypted(key) => key.decrypt_data(data),
+            Self::Unencrypted => {
+                if data.len() < NONCE_LEN + MAC_LEN {
+                    return Err(KeyError::CryptoError);
+                }
+                let (nonce, rest) = data.split_at(NONCE_LEN);
+                let (data, mac) = rest.split_at(rest.len() - MAC_LEN);
+                // encrypted data is detected by the random nonce
+                if nonce.iter().chain(mac).any(|b| *b != 0) {
+                    return Err(KeyError::CryptoError);
+                }
+                Ok(data.to_vec())
+            }
+        }
+    }
+
+    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, Self::CryptoError> {
+        match self {
+            Self::Encrypted(key) => key.encrypt_data(data),
+            Self::Unencrypted => {
+                let mut res = Vec::with_capacity(data.len() + NONCE_LEN + MAC_LEN);
+                res.extend_from_slice(&[0; NONCE_LEN]);
+                res.extend_from_slice(data);
+                res.extend_from_slice(&[0; MAC_LEN]);
+                Ok(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unencrypted() {
+        let key = RepoKey::Unencrypted;
+        let data = b"Hello!".to_vec();
+        let enc = key.encrypt_data(&data).unwrap();
+        assert_eq!(enc.len(), data.len() + 32);
+        assert_eq!(&enc[16..22], &data);
+        assert_eq!(key.decrypt_data(&enc).unwrap(), data);
+
+        // encrypted data is not accepted
+        let mut enc = enc;
+        enc[0] = 1;
+        assert!(key.decrypt_data(&enc).is_err());
+        assert!(key.decrypt_data(&[]).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+//! C API to drive rustic in-process, e.g. from Python using `python/rustic.py`.
+//!
+//! The declarations are in `include/rustic.h`. All functions returning `int` return 0 on success
+//! and -1 on error; functions returning pointers return NULL on error. The error message of the
+//! last failed call in the current thread can be retrieved using `rustic_last_error`.
+//! Panics are caught and reported as errors as they must not unwind into the calling code.
+#![allow(unsafe_code)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, OsString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use anyhow::{anyhow, Result};
+
+use crate::backend::{ChooseBackend, DecryptBackend};
+use crate::commands::execute_args;
+use crate::commands::helpers::get_repo_key;
+use crate::commands::password::Direct;
+use crate::crypto::RepoKey;
+use crate::repo::{SnapshotFile, SnapshotFilter};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: &anyhow::Error) {
+    let msg =
+        CString::new(format!("{err:#}").replace('\0', "")).expect("nul bytes have been removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Run `f` and convert a panic into an error
+fn catch<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(msg), _) => msg.to_string(),
+            (_, Some(msg)) => msg.clone(),
+            _ => "unknown error".to_string(),
+        };
+        Err(anyhow!("panic: {msg}"))
+    })
+}
+
+/// Convert the result into the return code and save the error, if any
+fn to_code(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(&err);
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// `s` must be NULL or a valid nul-terminated string
+unsafe fn to_string(s: *const c_char, name: &str) -> Result<String> {
+    if s.is_null() {
+        return Err(anyhow!("{name} must not be NULL"));
+    }
+    Ok(CStr::from_ptr(s)
+        .to_str()
+        .map_err(|err| anyhow!("{name} is not valid UTF-8: {err}"))?
+        .to_string())
+}
+
+/// An opened repository; the password has been verified when opening. Without password, only
+/// unencrypted repositories can be used.
+#[derive(Debug)]
+pub struct RusticRepo {
+    repository: String,
+    password: Option<String>,
+}
+
+impl RusticRepo {
+    fn open(repository: String, password: Option<String>) -> Result<Self> {
+        let repo = Self {
+            repository,
+            password,
+        };
+        let be = ChooseBackend::from_url(&repo.repository)?;
+        let _ = repo.key(&be)?;
+        Ok(repo)
+    }
+
+    fn key(&self, be: &ChooseBackend) -> Result<RepoKey> {
+        let password = Direct(self.password.clone().unwrap_or_default());
+        Ok(get_repo_key(be, &password, self.password.is_none())?.1)
+    }
+
+    /// Run a rustic command on this repository
+    fn run(&self, args: &[&str]) -> Result<()> {
+        let password = match &self.password {
+            Some(password) => vec!["--password", password],
+            None => vec!["--allow-unencrypted"],
+        };
+        let command: Vec<OsString> = ["rustic", "-r", &self.repository]
+            .into_iter()
+            .chain(password)
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect();
+        execute_args(command)
+    }
+
+    fn snapshots_json(&self) -> Result<String> {
+        let be = ChooseBackend::from_url(&self.repository)?;
+        let dbe = DecryptBackend::new(&be, self.key(&be)?);
+        let snapshots = SnapshotFile::all_from_backend(&dbe, &SnapshotFilter::default())?;
+        Ok(serde_json::to_string(&snapshots)?)
+    }
+}
+
+/// Returns the error message of the last failed call in this thread or NULL if there was none.
+/// The string is valid until the next failing call in this thread.
+#[no_mangle]
+pub extern "C" fn rustic_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |msg| msg.as_ptr())
+    })
+}
+
+/// Initialize a new repository.
+///
+/// # Safety
+/// All arguments must be valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_init(repository: *const c_char, password: *const c_char) -> c_int {
+    to_code(catch(|| {
+        let repo = RusticRepo {
+            repository: to_string(repository, "repository")?,
+            password: Some(to_string(password, "password")?),
+        };
+        repo.run(&["init"])
+    }))
+}
+
+/// Open a repository and verify the password. The result must be freed using `rustic_close`.
+/// `password` may be NULL to open an unencrypted repository; encrypted repositories then fail to
+/// open and repositories without keys are only opened in this case.
+///
+/// # Safety
+/// `repository` must be a valid nul-terminated string, `password` must be NULL or a valid
+/// nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_open(
+    repository: *const c_char,
+    password: *const c_char,
+) -> *mut RusticRepo {
+    let repo = catch(|| {
+        let password = match password.is_null() {
+            true => None,
+            false => Some(to_string(password, "password")?),
+        };
+        RusticRepo::open(to_string(repository, "repository")?, password)
+    });
+    match repo {
+        Ok(repo) => Box::into_raw(Box::new(repo)),
+        Err(err) => {
+            set_last_error(&err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Close a repository opened by `rustic_open`.
+///
+/// # Safety
+/// `repo` must be NULL or returned by `rustic_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_close(repo: *mut RusticRepo) {
+    if !repo.is_null() {
+        let _ = to_code(catch(|| {
+            drop(Box::from_raw(repo));
+            Ok(())
+        }));
+    }
+}
+
+/// Backup the given path. Options are taken from the config profile like for `rustic backup`.
+///
+/// # Safety
+/// `repo` must be returned by `rustic_open`, `path` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_backup(repo: *const RusticRepo, path: *const c_char) -> c_int {
+    to_code(catch(|| {
+        let repo = repo
+            .as_ref()
+            .ok_or_else(|| anyhow!("repo must not be NULL"))?;
+        repo.run(&["backup", &to_string(path, "path")?])
+    }))
+}
+
+/// List all snapshots as JSON array. The result must be freed using `rustic_free_string`.
+///
+/// # Safety
+/// `repo` must be returned by `rustic_open`.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_snapshots(repo: *const RusticRepo) -> *mut c_char {
+    let json = catch(|| {
+        let repo = repo
+            .as_ref()
+            .ok_or_else(|| anyhow!("repo must not be NULL"))?;
+        Ok(CString::new(repo.snapshots_json()?)?)
+    });
+    match json {
+        Ok(json) => json.into_raw(),
+        Err(err) => {
+            set_last_error(&err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Restore `snapshot` (in the form SNAPSHOT[:PATH]) into `dest`.
+///
+/// # Safety
+/// `repo` must be returned by `rustic_open`, all other arguments must be valid nul-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_restore(
+    repo: *const RusticRepo,
+    snapshot: *const c_char,
+    dest: *const c_char,
+) -> c_int {
+    to_code(catch(|| {
+        let repo = repo
+            .as_ref()
+            .ok_or_else(|| anyhow!("repo must not be NULL"))?;
+        repo.run(&[
+            "restore",
+            &to_string(snapshot, "snapshot")?,
+            &to_string(dest, "dest")?,
+        ])
+    }))
+}
+
+/// Free a string returned by rustic.
+///
+/// # Safety
+/// `s` must be NULL or returned by rustic and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rustic_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        let _ = to_code(catch(|| {
+            drop(CString::from_raw(s));
+            Ok(())
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ffi_errors() {
+        let repo = CString::new("/nonexisting/rustic-ffi-test").unwrap();
+        let password = CString::new("test").unwrap();
+        let opened = unsafe { rustic_open(repo.as_ptr(), password.as_ptr()) };
+        assert!(opened.is_null());
+        assert!(!rustic_last_error().is_null());
+
+        let code = unsafe { rustic_backup(ptr::null(), ptr::null()) };
+        assert_eq!(code, -1);
+        let err = unsafe { CStr::from_ptr(rustic_last_error()) };
+        assert_eq!(err.to_str().unwrap(), "repo must not be NULL");
+
+        let code = to_code(catch(|| panic!("boom")));
+        assert_eq!(code, -1);
+        let err = unsafe { CStr::from_ptr(rustic_last_error()) };
+        assert_eq!(err.to_str().unwrap(), "panic: boom");
+    }
+}
@@ -0,0 +1,50 @@
+//! Parsers of repository files which are exposed to corrupt or malicious input. These are the
+//! entry points for the fuzz targets in `fuzz/` and are used by the property tests.
+//!
+//! All functions must not panic on arbitrary input, only return an error which is ignored here.
+
+use serde::de::DeserializeOwned;
+
+use crate::blob::Tree;
+use crate::repo::{IndexFile, PackHeader, SnapshotFile};
+
+fn parse_json<T: DeserializeOwned>(data: &[u8]) {
+    let _ = serde_json::from_slice::<T>(data);
+}
+
+/// Parse a decrypted pack header.
+pub fn parse_pack_header(data: &[u8]) {
+    let _ = PackHeader::from_binary(data);
+}
+
+/// Parse a decrypted index file.
+pub fn parse_index(data: &[u8]) {
+    parse_json::<IndexFile>(data);
+}
+
+/// Parse and validate a decrypted tree blob, including the unescaping of all node names.
+pub fn parse_tree(data: &[u8]) {
+    if let Ok(tree) = serde_json::from_slice::<Tree>(data) {
+        for node in tree.nodes() {
+            let _ = node.name();
+        }
+        let _ = tree.validate();
+    }
+}
+
+/// Parse a decrypted snapshot file.
+pub fn parse_snapshot(data: &[u8]) {
+    parse_json::<SnapshotFile>(data);
+}
+
+/// Change single bytes of a valid repository file and truncate it to get a corrupted one.
+#[cfg(test)]
+pub(crate) fn corrupt(data: &[u8], changes: Vec<(usize, u8)>, len: usize) -> Vec<u8> {
+    let mut data = data.to_vec();
+    for (pos, byte) in changes {
+        let pos = pos % data.len();
+        data[pos] = byte;
+    }
+    data.truncate(len % (data.len() + 1));
+    data
+}
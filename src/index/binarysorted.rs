@@ -10,7 +10,7 @@ use rayon::prelude::*;
 struct SortedEntry {
     id: Id,
     pack_idx: usize,
-    offset: u32,
+    offset: u64,
     length: u32,
     uncompressed_length: Option<NonZeroU32>,
 }
@@ -36,7 +36,7 @@ impl Default for EntriesVariants {
 
 #[derive(Default)]
 pub(crate) struct TypeIndexCollector {
-    packs: Vec<(Id, u32)>,
+    packs: Vec<(Id, u64)>,
     entries: EntriesVariants,
     total_size: u64,
 }
@@ -44,6 +44,7 @@ pub(crate) struct TypeIndexCollector {
 #[derive(Default)]
 pub(crate) struct IndexCollector(BlobTypeMap<TypeIndexCollector>);
 
+#[derive(Debug)]
 pub struct PackIndexes {
     c: Index,
     tpe: BlobType,
@@ -74,7 +75,7 @@ impl IndexCollector {
         collector
     }
 
-    pub fn tree_packs(&self) -> &Vec<(Id, u32)> {
+    pub fn tree_packs(&self) -> &Vec<(Id, u64)> {
         &self.0[BlobType::Tree].packs
     }
 
@@ -110,7 +111,7 @@ impl Extend<IndexPack> for IndexCollector {
             let idx = self.0[blob_type].packs.len();
             self.0[blob_type].packs.push((p.id, size));
 
-            self.0[blob_type].total_size += size as u64;
+            self.0[blob_type].total_size += size;
 
             match &mut self.0[blob_type].entries {
                 EntriesVariants::None => {}
@@ -240,6 +241,61 @@ impl ReadIndex for Index {
             EntriesVariants::None => false,
         }
     }
+
+    fn get_ids(&self, blob_type: &BlobType, ids: &[Id]) -> Vec<Option<IndexEntry>> {
+        let mut result = vec![None; ids.len()];
+        let vec = match &self.0[*blob_type].entries {
+            EntriesVariants::FullEntries(entries) => entries,
+            // get_ids() only gives results if index contains full entries
+            _ => return result,
+        };
+
+        // process the ids in sorted order such that each search only needs to
+        // consider the entries after the previous match
+        let mut start = 0;
+        for i in sorted_order(ids) {
+            match vec[start..].binary_search_by_key(&ids[i], |e| e.id) {
+                Ok(pos) => {
+                    start += pos;
+                    let be = &vec[start];
+                    result[i] = Some(IndexEntry::new(
+                        *blob_type,
+                        self.0[*blob_type].packs[be.pack_idx],
+                        be.offset,
+                        be.length,
+                        be.uncompressed_length,
+                    ));
+                }
+                Err(pos) => start += pos,
+            }
+        }
+        result
+    }
+
+    fn has_all(&self, blob_type: &BlobType, ids: &[Id]) -> bool {
+        fn contains_all<T>(vec: &[T], ids: &[Id], key: impl Fn(&T) -> Id) -> bool {
+            let mut start = 0;
+            sorted_order(ids).into_iter().all(|i| {
+                vec[start..]
+                    .binary_search_by_key(&ids[i], &key)
+                    .map(|pos| start += pos)
+                    .is_ok()
+            })
+        }
+
+        match &self.0[*blob_type].entries {
+            EntriesVariants::FullEntries(entries) => contains_all(entries, ids, |e| e.id),
+            EntriesVariants::Ids(entries) => contains_all(entries, ids, |id| *id),
+            EntriesVariants::None => ids.is_empty(),
+        }
+    }
+}
+
+// Returns the positions of ids in the order of the sorted ids
+fn sorted_order(ids: &[Id]) -> Vec<usize> {
+    let mut order: Vec<_> = (0..ids.len()).collect();
+    order.sort_unstable_by_key(|&i| ids[i]);
+    order
 }
 
 #[cfg(test)]
@@ -346,6 +402,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn batched_lookups() {
+        let ids = [
+            "fac5e908151e565267570108127b96e6bae22bcdda1d3d867f63ed1555fc8aef",
+            "458c0b9b656a6593b7ba85ecdbfe85d6cb32af70c2e9c5fd1871cf3dccc39044",
+            "fac5e908151e565267570108127b96e6bae22bcdda1d3d867f63ed1555fc8aef",
+            "a3e048f1073299310981d8f5447861df0eca26a706645b5e2fa355c31c2205ed",
+        ]
+        .map(parse);
+        let missing = parse("0000000000000000000000000000000000000000000000000000000000000000");
+
+        for it in [IndexType::FullTrees, IndexType::Full] {
+            let index = index(it);
+            assert!(index.has_all(&BlobType::Data, &ids));
+            assert!(!index.has_all(&BlobType::Data, &[ids[0], missing]));
+            assert!(!index.has_all(&BlobType::Tree, &ids));
+            assert!(index.has_all(&BlobType::Tree, &[]));
+        }
+
+        let index = index(IndexType::Full);
+        let entries = index.get_ids(&BlobType::Data, &[ids[0], missing, ids[1], ids[2]]);
+        assert_eq!(entries[0], index.get_id(&BlobType::Data, &ids[0]));
+        assert_eq!(entries[1], None);
+        assert_eq!(entries[2], index.get_id(&BlobType::Data, &ids[1]));
+        assert_eq!(entries[3], entries[0]);
+        assert!(entries[0].is_some() && entries[2].is_some());
+    }
+
     #[test]
     fn only_trees() {
         let index = index(IndexType::OnlyTrees);
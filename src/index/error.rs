@@ -0,0 +1,22 @@
+use thiserror::Error;
+
+use crate::backend::BackendError;
+use crate::id::Id;
+
+/// IndexError describes the errors that can be returned when reading or writing the index.
+///
+/// Errors of the backend are kept as [`BackendError`], such that e.g. missing pack files can be
+/// distinguished from blobs missing in the index.
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("blob {0} not found in index")]
+    BlobNotFound(Id),
+
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type IndexResult<T> = Result<T, IndexError>;
@@ -3,12 +3,12 @@ use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
-
 use crate::backend::DecryptWriteBackend;
 use crate::id::Id;
 use crate::repo::{IndexFile, IndexPack};
 
+use super::{IndexError, IndexResult};
+
 pub type SharedIndexer<BE> = Arc<RwLock<Indexer<BE>>>;
 
 pub struct Indexer<BE: DecryptWriteBackend> {
@@ -53,26 +53,26 @@ impl<BE: DecryptWriteBackend> Indexer<BE> {
         Arc::new(RwLock::new(self))
     }
 
-    pub fn finalize(&self) -> Result<()> {
+    pub fn finalize(&self) -> IndexResult<()> {
         self.save()
     }
 
-    pub fn save(&self) -> Result<()> {
+    pub fn save(&self) -> IndexResult<()> {
         if (self.file.packs.len() + self.file.packs_to_delete.len()) > 0 {
             self.be.save_file(&self.file)?;
         }
         Ok(())
     }
 
-    pub fn add(&mut self, pack: IndexPack) -> Result<()> {
+    pub fn add(&mut self, pack: IndexPack) -> IndexResult<()> {
         self.add_with(pack, false)
     }
 
-    pub fn add_remove(&mut self, pack: IndexPack) -> Result<()> {
+    pub fn add_remove(&mut self, pack: IndexPack) -> IndexResult<()> {
         self.add_with(pack, true)
     }
 
-    pub fn add_with(&mut self, pack: IndexPack, delete: bool) -> Result<()> {
+    pub fn add_with(&mut self, pack: IndexPack, delete: bool) -> IndexResult<()> {
         self.count += pack.blobs.len();
 
         if let Some(indexed) = &mut self.indexed {
@@ -84,17 +84,30 @@ impl<BE: DecryptWriteBackend> Indexer<BE> {
         self.file.add(pack, delete);
 
         // check if IndexFile needs to be saved
-        if self.count >= MAX_COUNT || self.created.elapsed()? >= MAX_AGE {
+        let elapsed = self
+            .created
+            .elapsed()
+            .map_err(|err| IndexError::Other(err.into()))?;
+        if self.count >= MAX_COUNT || elapsed >= MAX_AGE {
             self.save()?;
             self.reset();
         }
         Ok(())
     }
 
-    pub fn has(&self, id: &Id) -> bool {
-        match &self.indexed {
-            None => false,
-            Some(indexed) => indexed.contains(id),
+    /// Mark the blob as in-flight, i.e. it is about to be packed. Returns `false` if the
+    /// blob is already indexed or in-flight and therefore doesn't need to be packed again.
+    pub fn reserve(&mut self, id: &Id) -> bool {
+        match &mut self.indexed {
+            None => true,
+            Some(indexed) => indexed.insert(*id),
+        }
+    }
+
+    /// Remove the reservation of a blob which could not be packed.
+    pub fn release(&mut self, id: &Id) {
+        if let Some(indexed) = &mut self.indexed {
+            indexed.remove(id);
         }
     }
 }
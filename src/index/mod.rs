@@ -1,11 +1,12 @@
+use std::collections::BTreeMap;
 use std::num::NonZeroU32;
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use derive_getters::Getters;
 use derive_more::Constructor;
 use indicatif::ProgressBar;
+use serde::Serialize;
 
 use crate::backend::{DecryptReadBackend, FileType};
 use crate::blob::BlobType;
@@ -13,16 +14,18 @@ use crate::id::Id;
 use crate::repo::{IndexBlob, IndexFile};
 
 mod binarysorted;
+mod error;
 mod indexer;
 
 pub use binarysorted::*;
+pub use error::*;
 pub use indexer::*;
 
-#[derive(Debug, Clone, PartialEq, Eq, Constructor, Getters)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Constructor, Getters)]
 pub struct IndexEntry {
     blob_type: BlobType,
     pack: Id,
-    offset: u32,
+    offset: u64,
     length: u32,
     uncompressed_length: Option<NonZeroU32>,
 }
@@ -39,7 +42,7 @@ impl IndexEntry {
     }
 
     /// Get a blob described by IndexEntry from the backend
-    pub fn read_data<B: DecryptReadBackend>(&self, be: &B) -> Result<Bytes> {
+    pub fn read_data<B: DecryptReadBackend>(&self, be: &B) -> IndexResult<Bytes> {
         let data = be.read_encrypted_partial(
             FileType::Pack,
             &self.pack,
@@ -53,17 +56,70 @@ impl IndexEntry {
 
     pub fn data_length(&self) -> u32 {
         match self.uncompressed_length {
-            None => self.length - 32, // crypto overhead
+            None => self.length.saturating_sub(32), // crypto overhead
             Some(length) => length.get(),
         }
     }
 }
 
+/// Location of a blob within its pack
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LocatedBlob {
+    pub id: Id,
+    #[serde(rename = "type")]
+    pub blob_type: BlobType,
+    pub offset: u64,
+    pub length: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uncompressed_length: Option<NonZeroU32>,
+}
+
+/// Blobs of a pack, sorted by their offset
+#[derive(Debug, Serialize)]
+pub struct PackBlobs {
+    pub pack: Id,
+    pub blobs: Vec<LocatedBlob>,
+}
+
 pub trait ReadIndex {
     fn get_id(&self, tpe: &BlobType, id: &Id) -> Option<IndexEntry>;
     fn total_size(&self, tpe: &BlobType) -> u64;
     fn has(&self, tpe: &BlobType, id: &Id) -> bool;
 
+    /// Get the index entries of many blobs at once. The result has the same order as `ids`.
+    fn get_ids(&self, tpe: &BlobType, ids: &[Id]) -> Vec<Option<IndexEntry>> {
+        ids.iter().map(|id| self.get_id(tpe, id)).collect()
+    }
+
+    /// Check if all given blobs are contained in the index
+    fn has_all(&self, tpe: &BlobType, ids: &[Id]) -> bool {
+        ids.iter().all(|id| self.has(tpe, id))
+    }
+
+    /// Locate the given blobs and group them by pack.
+    /// Returns an error if a blob is not contained in the index.
+    fn locate(&self, tpe: &BlobType, ids: &[Id]) -> IndexResult<Vec<PackBlobs>> {
+        let mut packs: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (id, ie) in ids.iter().zip(self.get_ids(tpe, ids)) {
+            let ie = ie.ok_or(IndexError::BlobNotFound(*id))?;
+            packs.entry(ie.pack).or_default().push(LocatedBlob {
+                id: *id,
+                blob_type: ie.blob_type,
+                offset: ie.offset,
+                length: ie.length,
+                uncompressed_length: ie.uncompressed_length,
+            });
+        }
+        Ok(packs
+            .into_iter()
+            .map(|(pack, mut blobs)| {
+                blobs.sort_unstable_by_key(|blob| blob.offset);
+                blobs.dedup_by_key(|blob| blob.offset);
+                PackBlobs { pack, blobs }
+            })
+            .collect())
+    }
+
     fn get_tree(&self, id: &Id) -> Option<IndexEntry> {
         self.get_id(&BlobType::Tree, id)
     }
@@ -79,6 +135,14 @@ pub trait ReadIndex {
     fn has_data(&self, id: &Id) -> bool {
         self.has(&BlobType::Data, id)
     }
+
+    fn get_data_ids(&self, ids: &[Id]) -> Vec<Option<IndexEntry>> {
+        self.get_ids(&BlobType::Data, ids)
+    }
+
+    fn has_all_data(&self, ids: &[Id]) -> bool {
+        self.has_all(&BlobType::Data, ids)
+    }
 }
 
 pub trait IndexedBackend: ReadIndex + Clone + Sync + Send + 'static {
@@ -86,15 +150,15 @@ pub trait IndexedBackend: ReadIndex + Clone + Sync + Send + 'static {
 
     fn be(&self) -> &Self::Backend;
 
-    fn blob_from_backend(&self, tpe: &BlobType, id: &Id) -> Result<Bytes> {
+    fn blob_from_backend(&self, tpe: &BlobType, id: &Id) -> IndexResult<Bytes> {
         match self.get_id(tpe, id) {
-            None => Err(anyhow!("blob not found in index")),
+            None => Err(IndexError::BlobNotFound(*id)),
             Some(ie) => ie.read_data(self.be()),
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct IndexBackend<BE: DecryptReadBackend> {
     be: BE,
     index: Arc<Index>,
@@ -111,6 +175,14 @@ impl<BE: DecryptReadBackend> ReadIndex for IndexBackend<BE> {
     fn has(&self, tpe: &BlobType, id: &Id) -> bool {
         self.index.has(tpe, id)
     }
+
+    fn get_ids(&self, tpe: &BlobType, ids: &[Id]) -> Vec<Option<IndexEntry>> {
+        self.index.get_ids(tpe, ids)
+    }
+
+    fn has_all(&self, tpe: &BlobType, ids: &[Id]) -> bool {
+        self.index.has_all(tpe, ids)
+    }
 }
 
 impl<BE: DecryptReadBackend> IndexBackend<BE> {
@@ -121,7 +193,11 @@ impl<BE: DecryptReadBackend> IndexBackend<BE> {
         }
     }
 
-    fn new_from_collector(be: &BE, p: ProgressBar, mut collector: IndexCollector) -> Result<Self> {
+    fn new_from_collector(
+        be: &BE,
+        p: ProgressBar,
+        mut collector: IndexCollector,
+    ) -> IndexResult<Self> {
         p.set_prefix("reading index...");
         for (_, i) in be.stream_all::<IndexFile>(p.clone())? {
             collector.extend(i.packs);
@@ -132,11 +208,11 @@ impl<BE: DecryptReadBackend> IndexBackend<BE> {
         Ok(Self::new_from_index(be, collector.into_index()))
     }
 
-    pub fn new(be: &BE, p: ProgressBar) -> Result<Self> {
+    pub fn new(be: &BE, p: ProgressBar) -> IndexResult<Self> {
         Self::new_from_collector(be, p, IndexCollector::new(IndexType::Full))
     }
 
-    pub fn only_full_trees(be: &BE, p: ProgressBar) -> Result<Self> {
+    pub fn only_full_trees(be: &BE, p: ProgressBar) -> IndexResult<Self> {
         Self::new_from_collector(be, p, IndexCollector::new(IndexType::FullTrees))
     }
 
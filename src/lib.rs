@@ -0,0 +1,71 @@
+// TODO: add
+//    missing_docs,
+//    unused_results,
+//    trivial_casts??
+#![warn(
+    bad_style,
+    const_err,
+    dead_code,
+    improper_ctypes,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    non_shorthand_field_patterns,
+    no_mangle_generic_items,
+    overflowing_literals,
+    path_statements,
+    patterns_in_fns_without_body,
+    private_in_public,
+    trivial_numeric_casts,
+    unsafe_code,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unconditional_recursion,
+    unused,
+    unused_allocation,
+    unused_comparisons,
+    unused_parens,
+    while_true
+)]
+// On wasm32, the CLI, the archiver and the OS-specific backends are not built; only reading
+// repositories is supported, see `api`. The code to write repositories is built, but not used.
+#![cfg_attr(target_arch = "wasm32", allow(dead_code))]
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod archiver;
+mod backend;
+mod blob;
+#[cfg(not(target_arch = "wasm32"))]
+mod chunker;
+#[cfg(not(target_arch = "wasm32"))]
+mod commands;
+mod crypto;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ffi;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+mod id;
+mod index;
+mod repo;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use commands::{execute, execute_args};
+
+/// API to read repositories using a backend given by the caller, e.g. for a browser-based
+/// snapshot browser. On other targets, this also contains what is needed to save snapshots
+/// using the [`archiver`].
+pub mod api {
+    pub use crate::backend::{
+        BackendError, BackendResult, DecryptBackend, DecryptReadBackend, FileType, ReadBackend,
+    };
+    pub use crate::blob::{Node, NodeType, Tree};
+    pub use crate::id::Id;
+    pub use crate::index::{IndexBackend, IndexError, IndexResult, IndexedBackend};
+    pub use crate::repo::{find_key_in_backend, ConfigFile, SnapshotFile, SnapshotFilter};
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use crate::backend::{
+        DecryptWriteBackend, LocalBackend, LocalSource, LocalSourceOptions, ReadSource,
+        WriteBackend,
+    };
+}
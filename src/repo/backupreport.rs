@@ -0,0 +1,22 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use super::SnapshotSummary;
+
+/// Report of a backup run which may contain several sources.
+/// The report is saved as data blob and referenced by all snapshots of the run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupReport {
+    pub time: DateTime<Local>,
+    pub command: String,
+    pub sources: Vec<SourceReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceReport {
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<SnapshotSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
@@ -12,13 +12,15 @@ pub struct ConfigFile {
     pub id: Id,
     pub chunker_polynomial: String,
     pub is_hot: Option<bool>,
+    /// set for repositories created by `init --no-encryption`
+    pub unencrypted: Option<bool>,
     pub compression: Option<i32>, // note that Some(0) means no compression.
-    pub treepack_size: Option<u32>,
+    pub treepack_size: Option<u64>,
     pub treepack_growfactor: Option<u32>,
-    pub treepack_size_limit: Option<u32>,
-    pub datapack_size: Option<u32>,
+    pub treepack_size_limit: Option<u64>,
+    pub datapack_size: Option<u64>,
     pub datapack_growfactor: Option<u32>,
-    pub datapack_size_limit: Option<u32>,
+    pub datapack_size_limit: Option<u64>,
     pub min_packsize_tolerate_percent: Option<u32>,
     pub max_packsize_tolerate_percent: Option<u32>,
 }
@@ -27,15 +29,16 @@ impl RepoFile for ConfigFile {
     const TYPE: FileType = FileType::Config;
 }
 
-const KB: u32 = 1024;
-const MB: u32 = 1024 * KB;
+const KB: u64 = 1024;
+const MB: u64 = 1024 * KB;
 // default pack size
-const DEFAULT_TREE_SIZE: u32 = 4 * MB;
-const DEFAULT_DATA_SIZE: u32 = 32 * MB;
+const DEFAULT_TREE_SIZE: u64 = 4 * MB;
+const DEFAULT_DATA_SIZE: u64 = 32 * MB;
 // the default factor used for repo-size dependent pack size.
 // 32 * sqrt(reposize in bytes) = 1 MB * sqrt(reposize in GB)
 const DEFAULT_GROW_FACTOR: u32 = 32;
-const DEFAULT_SIZE_LIMIT: u32 = u32::MAX;
+// packs larger than 4 GiB are only created if a larger size limit is configured explicitly
+const DEFAULT_SIZE_LIMIT: u64 = u32::MAX as u64;
 
 impl ConfigFile {
     pub fn new(version: u32, id: Id, poly: u64) -> Self {
@@ -60,7 +63,7 @@ impl ConfigFile {
         }
     }
 
-    pub fn packsize(&self, blob: BlobType) -> (u32, u32, u32) {
+    pub fn packsize(&self, blob: BlobType) -> (u64, u32, u64) {
         match blob {
             BlobType::Tree => (
                 self.treepack_size.unwrap_or(DEFAULT_TREE_SIZE),
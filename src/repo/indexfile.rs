@@ -40,7 +40,7 @@ pub struct IndexPack {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) time: Option<DateTime<Local>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) size: Option<u32>,
+    pub(crate) size: Option<u64>,
 }
 
 impl IndexPack {
@@ -52,7 +52,7 @@ impl IndexPack {
         &mut self,
         id: Id,
         tpe: BlobType,
-        offset: u32,
+        offset: u64,
         length: u32,
         uncompressed_length: Option<NonZeroU32>,
     ) {
@@ -66,7 +66,7 @@ impl IndexPack {
     }
 
     // calculate the pack size from the contained blobs
-    pub fn pack_size(&self) -> u32 {
+    pub fn pack_size(&self) -> u64 {
         self.size
             .unwrap_or_else(|| PackHeaderRef::from_index_pack(self).pack_size())
     }
@@ -83,12 +83,12 @@ impl IndexPack {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
 pub struct IndexBlob {
     pub(crate) id: Id,
     #[serde(rename = "type")]
     pub(crate) tpe: BlobType,
-    pub(crate) offset: u32,
+    pub(crate) offset: u64,
     pub(crate) length: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) uncompressed_length: Option<NonZeroU32>,
@@ -105,3 +105,31 @@ impl Ord for IndexBlob {
         self.offset.cmp(&other.offset)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fuzz;
+    use quickcheck_macros::quickcheck;
+
+    const INDEX: &[u8] = br#"{"packs":[{"id":"217f145b63fbc10267f5a686186689ea3389bed0d6a54b50ffc84d71f99cb7fa","blobs":[{"id":"3ec79977ef0cf5de7b08cd12b874cd0f62bbaf7f07f3497a5b1bbcc8cb39b1ce","type":"data","offset":0,"length":38,"uncompressed_length":20},{"id":"9ccb846e60d90d4eb915848add7aa7ea1e4bbabfc60e573db9f7bfb2789afbae","type":"tree","offset":38,"length":112}]}],"supersedes":["fc4ed3bc9d0d6f48c5ff0a2e3d5ba62b7d24b1e3edd3fa01c49dd70a2a4cde54"]}"#;
+
+    #[test]
+    fn parse_index() {
+        assert!(serde_json::from_slice::<IndexFile>(INDEX).is_ok());
+    }
+
+    // corrupted or arbitrary index files must not panic, only return an error
+    #[quickcheck]
+    fn parse_corrupted_index(changes: Vec<(usize, u8)>, len: usize) -> bool {
+        fuzz::parse_index(&fuzz::corrupt(INDEX, changes, len));
+        true
+    }
+
+    #[quickcheck]
+    fn parse_arbitrary_index(data: Vec<u8>) -> bool {
+        fuzz::parse_index(&data);
+        true
+    }
+}
@@ -1,3 +1,4 @@
+mod backupreport;
 mod configfile;
 mod indexfile;
 mod keyfile;
@@ -5,6 +6,7 @@ mod packfile;
 mod snapshotfile;
 
 pub use super::id::*;
+pub use backupreport::*;
 pub use configfile::*;
 pub use indexfile::*;
 pub use keyfile::*;
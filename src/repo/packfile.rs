@@ -1,6 +1,6 @@
 use std::num::NonZeroU32;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use binrw::{io::Cursor, BinRead, BinWrite};
 
 use crate::backend::FileType;
@@ -102,7 +102,7 @@ impl HeaderEntry {
         }
     }
 
-    fn into_blob(self, offset: u32) -> IndexBlob {
+    fn into_blob(self, offset: u64) -> IndexBlob {
         match self {
             Self::Data { len, id } => IndexBlob {
                 id,
@@ -149,7 +149,9 @@ impl PackHeader {
                 Err(err) if err.is_eof() => break,
                 Err(err) => return Err(err.into()),
             };
-            offset += blob.length;
+            offset = offset
+                .checked_add(blob.length.into())
+                .ok_or_else(|| anyhow!("pack header: blob offsets exceed the maximum pack size"))?;
             blobs.push(blob);
         }
         Ok(Self(blobs))
@@ -160,7 +162,7 @@ impl PackHeader {
         be: &impl DecryptReadBackend,
         id: Id,
         size_hint: Option<u32>,
-        pack_size: u32,
+        pack_size: u64,
     ) -> Result<Self> {
         // guess the header size from size_hint and pack_size
         // If the guess is too small, we have to re-read. If the guess is too large, we have to have read too much
@@ -168,8 +170,12 @@ impl PackHeader {
         let size_guess = size_hint.unwrap_or(0);
 
         // read (guessed) header + length field
+        let size_guess =
+            u64::from(size_guess).min(pack_size.saturating_sub(LENGTH_LEN.into())) as u32;
         let read_size = size_guess + LENGTH_LEN;
-        let offset = pack_size - read_size;
+        let offset = pack_size
+            .checked_sub(read_size.into())
+            .ok_or_else(|| anyhow!("pack {id} is too small ({pack_size} bytes)"))?;
         let mut data = be.read_partial(FileType::Pack, &id, false, offset, read_size)?;
 
         // get header length from the file
@@ -182,7 +188,11 @@ impl PackHeader {
             data.split_off((size_guess - size_real) as usize)
         } else {
             // size_guess was too small; we have to read again
-            let offset = pack_size - size_real - LENGTH_LEN;
+            let offset = pack_size
+                .checked_sub(u64::from(size_real) + u64::from(LENGTH_LEN))
+                .ok_or_else(|| {
+                    anyhow!("pack {id}: header length {size_real} exceeds pack size {pack_size}")
+                })?;
             be.read_partial(FileType::Pack, &id, false, offset, size_real)?
         };
 
@@ -208,10 +218,12 @@ impl<'a> PackHeaderRef<'a> {
     }
 
     // calculate the pack size from the contained blobs
-    pub fn pack_size(&self) -> u32 {
-        self.0.iter().fold(COMP_OVERHEAD + LENGTH_LEN, |acc, blob| {
-            acc + blob.length + HeaderEntry::from_blob(blob).length()
-        })
+    pub fn pack_size(&self) -> u64 {
+        self.0
+            .iter()
+            .fold(u64::from(COMP_OVERHEAD + LENGTH_LEN), |acc, blob| {
+                acc + u64::from(blob.length) + u64::from(HeaderEntry::from_blob(blob).length())
+            })
     }
 
     /// generate the binary representation of the pack header
@@ -224,3 +236,44 @@ impl<'a> PackHeaderRef<'a> {
         Ok(writer.into_inner())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn header_length_from_arbitrary_bytes(data: Vec<u8>) -> bool {
+        PackHeaderLength::from_binary(&data).is_ok() == (data.len() >= 4)
+    }
+
+    #[quickcheck]
+    fn header_from_arbitrary_bytes(data: Vec<u8>) -> bool {
+        // must not panic on corrupt headers, only return an error
+        crate::fuzz::parse_pack_header(&data);
+        true
+    }
+
+    #[test]
+    fn header_offsets_beyond_4gib() {
+        let mut data = Vec::new();
+        for _ in 0..3 {
+            let mut writer = Cursor::new(Vec::new());
+            HeaderEntry::Data {
+                len: u32::MAX,
+                id: Id::default(),
+            }
+            .write(&mut writer)
+            .unwrap();
+            data.extend(writer.into_inner());
+        }
+        let offsets: Vec<_> = PackHeader::from_binary(&data)
+            .unwrap()
+            .into_blobs()
+            .iter()
+            .map(|blob| blob.offset)
+            .collect();
+        assert_eq!(offsets, [0, u64::from(u32::MAX), 2 * u64::from(u32::MAX)]);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::str::FromStr;
 use std::{cmp::Ordering, fmt::Display};
@@ -23,6 +24,8 @@ pub struct SnapshotSummary {
     pub files_new: u64,
     pub files_changed: u64,
     pub files_unmodified: u64,
+    #[serde(default)]
+    pub files_skipped: u64,
     pub dirs_new: u64,
     pub dirs_changed: u64,
     pub dirs_unmodified: u64,
@@ -48,7 +51,7 @@ pub struct SnapshotSummary {
     pub backup_duration: f64, // in seconds
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Derivative)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Derivative)]
 #[derivative(Default)]
 pub enum DeleteOption {
     #[derivative(Default)]
@@ -82,12 +85,17 @@ pub struct SnapshotFile {
     pub gid: u32,
     #[serde(default)]
     pub tags: StringList,
+    /// Free-text description of the snapshot
+    pub description: Option<String>,
     pub original: Option<Id>,
     #[serde(default, skip_serializing_if = "DeleteOption::is_not_set")]
     pub delete: DeleteOption,
 
     pub summary: Option<SnapshotSummary>,
 
+    /// Data blob containing the [`BackupReport`] of the backup run which created this snapshot
+    pub report: Option<Id>,
+
     #[serde(default, skip_serializing_if = "Id::is_null")]
     pub id: Id,
 }
@@ -96,8 +104,11 @@ impl RepoFile for SnapshotFile {
     const TYPE: FileType = FileType::Snapshot;
 }
 
+/// Name of the cache file which contains the decrypted snapshots
+const SNAPSHOT_CACHE_FILE: &str = "snapshots.json";
+
 impl SnapshotFile {
-    fn set_id(tuple: (Id, Self)) -> Self {
+    pub fn set_id(tuple: (Id, Self)) -> Self {
         let (id, mut snap) = tuple;
         snap.id = id;
         snap.original.get_or_insert(id);
@@ -128,26 +139,59 @@ impl SnapshotFile {
         p: ProgressBar,
     ) -> Result<Self> {
         p.set_prefix("getting latest snapshot...");
-        let mut latest: Option<Self> = None;
         let mut pred = predicate;
-
-        for (id, mut snap) in be.stream_all::<SnapshotFile>(p.clone())? {
-            if !pred(&snap) {
-                continue;
-            }
-
-            snap.id = id;
-            match &latest {
-                Some(l) if l.time > snap.time => {}
-                _ => {
-                    latest = Some(snap);
-                }
-            }
-        }
+        let latest = Self::all(be, p.clone())?
+            .into_iter()
+            .filter(|sn| pred(sn))
+            .max_by_key(|sn| sn.time);
         p.finish();
         latest.ok_or_else(|| anyhow!("no snapshots found"))
     }
 
+    /// Get the parent for the new snapshot `snap` which backs up `path`: the latest snapshot
+    /// which matches `snap` w.r.t. `crit`, where matching paths means containing `path`.
+    /// If there is none and `fallback` is set, the latest snapshot containing `path` is used,
+    /// preferring snapshots of the same host and then snapshots with the same tags.
+    pub fn latest_parent<B: DecryptReadBackend>(
+        be: &B,
+        snap: &Self,
+        path: &String,
+        crit: &SnapshotGroupCriterion,
+        fallback: bool,
+        p: ProgressBar,
+    ) -> Result<Self> {
+        p.set_prefix("getting parent snapshot...");
+        let snaps = Self::all(be, p.clone())?;
+        p.finish();
+
+        let same_host = |sn: &Self| sn.hostname == snap.hostname;
+        let same_tags = |sn: &Self| sn.tags == snap.tags;
+        let has_path = |sn: &Self| sn.paths.contains(path);
+        let in_group = |sn: &Self| {
+            (!crit.hostname || same_host(sn))
+                && (!crit.paths || has_path(sn))
+                && (!crit.tags || same_tags(sn))
+        };
+
+        let parent = snaps
+            .iter()
+            .filter(|sn| in_group(sn))
+            .max_by_key(|sn| sn.time)
+            .or_else(|| {
+                fallback
+                    .then(|| {
+                        snaps
+                            .iter()
+                            .filter(|sn| has_path(sn))
+                            .max_by_key(|sn| (same_host(sn), same_tags(sn), sn.time))
+                    })
+                    .flatten()
+            });
+        parent
+            .cloned()
+            .ok_or_else(|| anyhow!("no parent snapshot found"))
+    }
+
     /// Get a SnapshotFile from the backend by (part of the) id
     pub fn from_id<B: DecryptReadBackend>(be: &B, id: &str) -> Result<Self> {
         info!("getting snapshot...");
@@ -233,14 +277,66 @@ impl SnapshotFile {
         be: &B,
         filter: &SnapshotFilter,
     ) -> Result<Vec<Self>> {
-        Ok(be
-            .stream_all::<SnapshotFile>(ProgressBar::hidden())?
+        Ok(Self::all(be, ProgressBar::hidden())?
             .into_iter()
-            .map(Self::set_id)
             .filter(|sn| sn.matches(filter))
             .collect())
     }
 
+    /// Get all SnapshotFiles from the backend. If the backend uses a cache, the decrypted snapshots
+    /// are saved in the cache and only snapshots which are not cached yet are read and decrypted.
+    fn all<B: DecryptReadBackend>(be: &B, p: ProgressBar) -> Result<Vec<Self>> {
+        let ids = be.list(FileType::Snapshot)?;
+        let cache = match be.cache() {
+            Some(cache) => cache,
+            None => {
+                return Ok(be
+                    .stream_list::<Self>(ids, p)?
+                    .into_iter()
+                    .map(Self::set_id)
+                    .collect())
+            }
+        };
+
+        // snapshot files are content-addressed, so cached snapshots never change
+        let mut cached: HashMap<_, _> = cache
+            .read_decrypted(SNAPSHOT_CACHE_FILE)
+            .and_then(|data| serde_json::from_slice::<Vec<Self>>(&data).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|sn| (sn.id, sn))
+            .collect();
+        let mut snaps = Vec::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        for id in ids {
+            match cached.remove(&id) {
+                Some(sn) => snaps.push(sn),
+                None => missing.push(id),
+            }
+        }
+        // remaining cached snapshots have been removed from the repository
+        let changed = !missing.is_empty() || !cached.is_empty();
+        debug!(
+            "using {} cached snapshots, reading {} snapshots",
+            snaps.len(),
+            missing.len()
+        );
+        snaps.extend(
+            be.stream_list::<Self>(missing, p)?
+                .into_iter()
+                .map(Self::set_id),
+        );
+
+        if changed {
+            if let Err(err) =
+                cache.write_decrypted(SNAPSHOT_CACHE_FILE, &serde_json::to_vec(&snaps)?)
+            {
+                warn!("error saving snapshots to the cache: {err}");
+            }
+        }
+        Ok(snaps)
+    }
+
     pub fn matches(&self, filter: &SnapshotFilter) -> bool {
         self.paths.matches(&filter.filter_paths)
             && self.tags.matches(&filter.filter_tags)
@@ -273,6 +369,64 @@ impl SnapshotFile {
         old_tags != self.tags
     }
 
+    /// Returns the snapshots whose parent does not exist (anymore) in `snaps`.
+    /// Parents are also found by their original id, i.e. if they have been modified.
+    pub fn broken_chains(snaps: &[Self]) -> Vec<&Self> {
+        let ids: HashSet<_> = snaps
+            .iter()
+            .flat_map(|sn| [Some(sn.id), sn.original])
+            .flatten()
+            .collect();
+        snaps
+            .iter()
+            .filter(|sn| matches!(sn.parent, Some(parent) if !ids.contains(&parent)))
+            .collect()
+    }
+
+    /// Re-parent snapshots in `snaps` whose parent is going to be removed to their nearest
+    /// ancestor which is kept. Returns the changed snapshots which are not removed.
+    pub fn reparent(snaps: &[Self], remove: &[Id]) -> Vec<Self> {
+        let remove: HashSet<_> = remove.iter().collect();
+        let mut by_id = HashMap::new();
+        for sn in snaps {
+            by_id.insert(sn.id, sn);
+            if let Some(original) = sn.original {
+                by_id.entry(original).or_insert(sn);
+            }
+        }
+
+        snaps
+            .iter()
+            .filter(|sn| !remove.contains(&sn.id))
+            .filter_map(|sn| {
+                let mut parent = sn.parent?;
+                // walk up the chain until an ancestor is found which is not removed
+                let mut removed = HashSet::new();
+                let new_parent = loop {
+                    match by_id.get(&parent) {
+                        Some(p) if remove.contains(&p.id) => {
+                            if !removed.insert(p.id) {
+                                break None;
+                            }
+                            match p.parent {
+                                Some(id) => parent = id,
+                                None => break None,
+                            }
+                        }
+                        Some(p) => break Some(p.id),
+                        // unknown parent, keep the reference as it is
+                        None => break Some(parent),
+                    }
+                };
+                (!removed.is_empty()).then(|| {
+                    let mut sn = sn.clone();
+                    sn.parent = new_parent;
+                    sn
+                })
+            })
+            .collect()
+    }
+
     /// Returns whether a snapshot must be deleted now
     pub fn must_delete(&self, now: DateTime<Local>) -> bool {
         matches!(self.delete,DeleteOption::After(time) if time < now)
@@ -307,7 +461,7 @@ impl Ord for SnapshotFile {
 }
 
 #[serde_as]
-#[derive(Default, Parser, Deserialize, Merge)]
+#[derive(Default, Debug, Parser, Deserialize, Merge)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct SnapshotFilter {
     /// Path list to filter (can be specified multiple times)
@@ -328,7 +482,7 @@ pub struct SnapshotFilter {
     filter_host: Vec<String>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct SnapshotGroupCriterion {
     hostname: bool,
     paths: bool,
@@ -450,6 +604,11 @@ impl StringList {
         self.0.sort_unstable();
     }
 
+    /// Apply `f` to all strings of the list
+    pub fn try_map(&self, f: impl FnMut(&String) -> Result<String>) -> Result<Self> {
+        Ok(Self(self.0.iter().map(f).collect::<Result<_>>()?))
+    }
+
     pub fn formatln(&self) -> String {
         self.0
             .iter()
@@ -457,3 +616,63 @@ impl StringList {
             .collect::<String>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fuzz;
+    use quickcheck_macros::quickcheck;
+
+    const SNAPSHOT: &[u8] = br#"{"time":"2022-07-27T16:59:42.398957497+02:00","parent":"1bd34a0ff1cf2e4aa9b3a3ae0d8e7adcc1b1d2aea9b47ad0f24ac4b45c0ec3e4","tree":"9ccb846e60d90d4eb915848add7aa7ea1e4bbabfc60e573db9f7bfb2789afbae","paths":["/home/user"],"hostname":"host","username":"user","uid":1000,"gid":1000,"tags":["a","b"]}"#;
+
+    #[test]
+    fn parse_snapshot() {
+        assert!(serde_json::from_slice::<SnapshotFile>(SNAPSHOT).is_ok());
+    }
+
+    // corrupted or arbitrary snapshot files must not panic, only return an error
+    #[quickcheck]
+    fn parse_corrupted_snapshot(changes: Vec<(usize, u8)>, len: usize) -> bool {
+        fuzz::parse_snapshot(&fuzz::corrupt(SNAPSHOT, changes, len));
+        true
+    }
+
+    #[quickcheck]
+    fn parse_arbitrary_snapshot(data: Vec<u8>) -> bool {
+        fuzz::parse_snapshot(&data);
+        true
+    }
+
+    #[test]
+    fn reparent_chains() {
+        let ids: Vec<_> = (0..4).map(|_| Id::random()).collect();
+        // chain: 0 <- 1 <- 2 <- 3
+        let snaps: Vec<_> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| SnapshotFile {
+                id: *id,
+                original: Some(*id),
+                parent: i.checked_sub(1).map(|i| ids[i]),
+                ..Default::default()
+            })
+            .collect();
+        assert!(SnapshotFile::broken_chains(&snaps).is_empty());
+
+        let changed = SnapshotFile::reparent(&snaps, &[ids[1], ids[2]]);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, ids[3]);
+        assert_eq!(changed[0].parent, Some(ids[0]));
+
+        let changed = SnapshotFile::reparent(&snaps, &[ids[0]]);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].parent, None);
+
+        let broken: Vec<_> = SnapshotFile::broken_chains(&snaps[2..])
+            .iter()
+            .map(|sn| sn.id)
+            .collect();
+        assert_eq!(broken, vec![ids[2]]);
+    }
+}